@@ -0,0 +1,323 @@
+// [[file:../runners.note::4b0e7a91][4b0e7a91]]
+//! Accounting of historical jobs
+use super::*;
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+// 4b0e7a91 ends here
+
+// [[file:../runners.note::93c5d2e8][93c5d2e8]]
+/// A record of one completed job.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct AcctRecord {
+    /// When the job finished, in RFC 3339 format
+    pub date: String,
+    pub user: String,
+    /// The program called in job script
+    pub program: String,
+    /// The hash of job script for identifying the same jobs
+    pub script_hash: String,
+    /// Wall time in seconds
+    pub runtime: f64,
+    pub exit_code: Option<i32>,
+    /// CPU time (user + system) in seconds
+    pub cpu_seconds: f64,
+    /// Tags annotating the job
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl AcctRecord {
+    /// Construct a record for a job running `script`, finished just now.
+    pub fn new(script: &str, runtime: f64, exit_code: Option<i32>, cpu_seconds: f64) -> Self {
+        Self {
+            date: chrono::Local::now().to_rfc3339(),
+            user: std::env::var("USER").unwrap_or_else(|_| "unknown".into()),
+            program: script_program(script),
+            script_hash: format!("{:016x}", fnv1a(script.as_bytes())),
+            runtime,
+            exit_code,
+            cpu_seconds,
+            tags: BTreeMap::new(),
+        }
+    }
+
+    fn to_csv_line(&self) -> String {
+        let clean = |s: &str| s.replace(',', "_");
+        let code = self.exit_code.map(|c| c.to_string()).unwrap_or_default();
+        // tags are saved as `key=value;key=value` in one column
+        let clean_tag = |s: &str| s.replace([',', ';', '='], "_");
+        let tags = self
+            .tags
+            .iter()
+            .map(|(k, v)| format!("{}={}", clean_tag(k), clean_tag(v)))
+            .join(";");
+        format!(
+            "{},{},{},{},{:.3},{},{:.3},{}",
+            self.date,
+            clean(&self.user),
+            clean(&self.program),
+            self.script_hash,
+            self.runtime,
+            code,
+            self.cpu_seconds,
+            tags
+        )
+    }
+
+    fn from_csv_line(line: &str) -> Result<Self> {
+        let fields: Vec<_> = line.split(',').collect();
+        // records written before tags were added have 7 columns
+        ensure!(matches!(fields.len(), 7 | 8), "invalid accounting record: {:?}", line);
+        let exit_code = if fields[5].is_empty() { None } else { Some(fields[5].parse()?) };
+        let tags = fields
+            .get(7)
+            .into_iter()
+            .flat_map(|s| s.split(';'))
+            .filter_map(|tag| tag.split_once('='))
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        Ok(Self {
+            date: fields[0].into(),
+            user: fields[1].into(),
+            program: fields[2].into(),
+            script_hash: fields[3].into(),
+            runtime: fields[4].parse()?,
+            exit_code,
+            cpu_seconds: fields[6].parse()?,
+            tags,
+        })
+    }
+}
+
+/// A stable 64-bit FNV-1a hash.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Guess the program called in job `script`: the first command that is not
+/// a comment or a shell builtin for setting up environment.
+fn script_program(script: &str) -> String {
+    const SKIP: &[&str] = &["cd", "export", "set", "source", ".", "module", "ulimit", "echo"];
+    script
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        // skip env vars set before the command
+        .filter_map(|line| line.split_whitespace().find(|w| !w.contains('=')))
+        .find(|cmd| !SKIP.contains(cmd))
+        .map(|cmd| cmd.rsplit('/').next().unwrap_or(cmd).to_owned())
+        .unwrap_or_default()
+}
+// 93c5d2e8 ends here
+
+// [[file:../runners.note::1fe8b6c4][1fe8b6c4]]
+const CSV_HEADER: &str = "date,user,program,script_hash,runtime,exit_code,cpu_seconds,tags";
+
+/// An accounting sink appending records of completed jobs into a CSV file.
+#[derive(Debug)]
+pub struct Accounting {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl Accounting {
+    /// Create an accounting sink writing to CSV file in `path`.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// The default accounting file: `~/.gosh-runner/acct.csv`
+    pub fn default_path() -> PathBuf {
+        let home = std::env::var("HOME").unwrap_or_else(|_| ".".into());
+        Path::new(&home).join(".gosh-runner").join("acct.csv")
+    }
+
+    /// Append `record` to the accounting file.
+    pub fn append(&self, record: &AcctRecord) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let new = !self.path.exists();
+        let mut f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("open accounting file {:?}", self.path))?;
+        if new {
+            writeln!(f, "{}", CSV_HEADER)?;
+        }
+        writeln!(f, "{}", record.to_csv_line())?;
+        Ok(())
+    }
+
+    /// Read all records from the accounting file.
+    pub fn records(&self) -> Result<Vec<AcctRecord>> {
+        let _guard = self.lock.lock().unwrap();
+        gut::fs::read_file(&self.path)?
+            .lines()
+            // also skip the header of older files without tags column
+            .filter(|line| !line.starts_with("date,") && !line.is_empty())
+            .map(AcctRecord::from_csv_line)
+            .collect()
+    }
+}
+// 1fe8b6c4 ends here
+
+// [[file:../runners.note::c8a4f017][c8a4f017]]
+/// How to group accounting records in summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, gut::cli::ValueEnum)]
+pub enum GroupBy {
+    /// ISO week of finishing date, e.g. 2021-W07
+    Week,
+    User,
+    Program,
+}
+
+/// Summarized usage of a group of jobs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Usage {
+    pub jobs: usize,
+    pub failed: usize,
+    pub runtime: f64,
+    pub cpu_seconds: f64,
+}
+
+/// Summarize usage of `records` grouped `by` week, user or program.
+pub fn summarize(records: &[AcctRecord], by: GroupBy) -> BTreeMap<String, Usage> {
+    let mut summary: BTreeMap<String, Usage> = BTreeMap::new();
+    for r in records {
+        let key = match by {
+            GroupBy::User => r.user.clone(),
+            GroupBy::Program => r.program.clone(),
+            GroupBy::Week => chrono::DateTime::parse_from_rfc3339(&r.date)
+                .map(|d| d.format("%G-W%V").to_string())
+                .unwrap_or_else(|_| "unknown".into()),
+        };
+        let usage = summary.entry(key).or_default();
+        usage.jobs += 1;
+        if r.exit_code != Some(0) {
+            usage.failed += 1;
+        }
+        usage.runtime += r.runtime;
+        usage.cpu_seconds += r.cpu_seconds;
+    }
+    summary
+}
+// c8a4f017 ends here
+
+// [[file:../runners.note::2a6c9f14][2a6c9f14]]
+/// A filter on accounting records, parsed from query strings like
+/// `status=failed&since=2024-01-01&name~=opt`.
+///
+/// Supported keys are `status` (completed, failed or unknown), `since`
+/// and `until` (dates of finishing, `until` exclusive), `user`, `program`
+/// (or `name`), `hash`, and `tag.<key>` for job tags, e.g.
+/// `tag.project=perovskites`. `key=value` matches exactly, and `key~=value`
+/// matches substrings.
+#[derive(Debug, Clone, Default)]
+pub struct JobQuery {
+    conditions: Vec<(String, bool, String)>,
+}
+
+impl FromStr for JobQuery {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut conditions = vec![];
+        for part in s.trim_start_matches('?').split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = part.split_once('=').with_context(|| format!("invalid condition: {:?}", part))?;
+            let (key, fuzzy) = match key.strip_suffix('~') {
+                Some(key) => (key, true),
+                None => (key, false),
+            };
+            let key = if key == "name" { "program" } else { key };
+            ensure!(
+                ["status", "since", "until", "user", "program", "hash"].contains(&key) || key.starts_with("tag."),
+                "unknown query key: {:?}",
+                key
+            );
+            conditions.push((key.to_owned(), fuzzy, value.to_owned()));
+        }
+        Ok(Self { conditions })
+    }
+}
+
+impl JobQuery {
+    /// Test if `record` satisfies all conditions.
+    pub fn matches(&self, record: &AcctRecord) -> bool {
+        let status = match record.exit_code {
+            Some(0) => "completed",
+            Some(_) => "failed",
+            None => "unknown",
+        };
+        self.conditions.iter().all(|(key, fuzzy, value)| {
+            let field = match key.as_str() {
+                // dates in RFC 3339 format can be compared as strings
+                "since" => return record.date.as_str() >= value.as_str(),
+                "until" => return record.date.as_str() < value.as_str(),
+                "status" => status,
+                "user" => &record.user,
+                "program" => &record.program,
+                "hash" => &record.script_hash,
+                key => match record.tags.get(&key["tag.".len()..]) {
+                    Some(tag) => tag,
+                    None => return false,
+                },
+            };
+            if *fuzzy {
+                field.contains(value.as_str())
+            } else {
+                field == value
+            }
+        })
+    }
+}
+
+impl Accounting {
+    /// Return records matching `query`.
+    pub fn search(&self, query: &JobQuery) -> Result<Vec<AcctRecord>> {
+        let records = self.records()?.into_iter().filter(|r| query.matches(r)).collect();
+        Ok(records)
+    }
+}
+// 2a6c9f14 ends here
+
+// [[file:../runners.note::5d72e3b9][5d72e3b9]]
+#[test]
+fn test_accounting() -> Result<()> {
+    assert_eq!(script_program("#!/bin/sh\ncd /tmp\nOMP=1 /opt/bin/vasp_std > out"), "vasp_std");
+
+    let dir = tempfile::tempdir()?;
+    let acct = Accounting::new(dir.path().join("acct.csv"));
+    let r1 = AcctRecord::new("vasp", 10.0, Some(0), 40.0);
+    let r2 = AcctRecord::new("orca inp", 5.0, Some(1), 5.0);
+    acct.append(&r1)?;
+    acct.append(&r2)?;
+    let records = acct.records()?;
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].program, "vasp");
+
+    let summary = summarize(&records, GroupBy::Program);
+    assert_eq!(summary["orca"].failed, 1);
+    assert_eq!(summary["vasp"].cpu_seconds, 40.0);
+
+    let query: JobQuery = "status=failed&since=2000-01-01&name~=orc".parse()?;
+    assert_eq!(acct.search(&query)?, vec![r2]);
+    let query: JobQuery = "until=2000-01-01".parse()?;
+    assert!(acct.search(&query)?.is_empty());
+    assert!("foo=bar".parse::<JobQuery>().is_err());
+
+    let mut r3 = AcctRecord::new("cp2k", 1.0, Some(0), 1.0);
+    r3.tags.insert("project".into(), "perovskites".into());
+    r3.tags.insert("method".into(), "PBE0".into());
+    acct.append(&r3)?;
+    let query: JobQuery = "tag.project=perovskites&tag.method~=PBE".parse()?;
+    assert_eq!(acct.search(&query)?, vec![r3]);
+    Ok(())
+}
+// 5d72e3b9 ends here
@@ -0,0 +1,111 @@
+// [[file:../runners.note::b5d30e7f][b5d30e7f]]
+//! Adaptor for using the runner as remote execution layer of gosh model
+//! drivers
+use super::*;
+
+use crate::discovery::Endpoint;
+use crate::job::{Job, JobId};
+use crate::parser::JobResult;
+use serde_json::{json, Value};
+// b5d30e7f ends here
+
+// [[file:../runners.note::2e71c9a4][2e71c9a4]]
+/// A backend computing model inputs, such as a molecule rendered into the
+/// input format of a quantum chemistry program.
+pub trait ComputeBackend {
+    /// Submit computation of `input`, returning the job id.
+    fn submit(&self, input: &str) -> Result<JobId>;
+
+    /// Wait until job `id` finishes, and return its result with parsed
+    /// values.
+    fn wait(&self, id: JobId) -> Result<JobResult>;
+
+    /// Fetch the content of `file` in working directory of job `id`.
+    fn fetch(&self, id: JobId, file: &str) -> Result<String>;
+
+    /// Submit computation of `input`, and wait for its result.
+    fn compute(&self, input: &str) -> Result<JobResult> {
+        let id = self.submit(input)?;
+        self.wait(id)
+    }
+}
+
+/// A `ComputeBackend` driving a job server over JSON-RPC. Each input is
+/// computed as a job from `template`, which defines the script, parsers
+/// and resources, with the input fed into stdin.
+#[derive(Debug, Clone)]
+pub struct JobServerBackend {
+    endpoint: Endpoint,
+    // the job template in JSON for setting input
+    template: Value,
+}
+
+impl JobServerBackend {
+    /// Drive the job server at `endpoint`, creating jobs from `template`.
+    pub fn new(endpoint: Endpoint, template: Job) -> Self {
+        let template = json!(template);
+        Self { endpoint, template }
+    }
+
+    /// Drive the job server recorded in discovery file `path`.
+    pub fn from_discovery_file(path: &Path, template: Job) -> Result<Self> {
+        let endpoint = Endpoint::read(path)?;
+        Ok(Self::new(endpoint, template))
+    }
+}
+
+impl ComputeBackend for JobServerBackend {
+    fn submit(&self, input: &str) -> Result<JobId> {
+        let mut job = self.template.clone();
+        job["input"] = input.into();
+        let id = crate::jsonrpc::call(&self.endpoint, "submit", json!(job))?;
+        Ok(serde_json::from_value(id)?)
+    }
+
+    fn wait(&self, id: JobId) -> Result<JobResult> {
+        let result = crate::jsonrpc::call(&self.endpoint, "wait", json!({ "id": id }))?;
+        Ok(serde_json::from_value(result)?)
+    }
+
+    fn fetch(&self, id: JobId, file: &str) -> Result<String> {
+        // files are transferred in chunks
+        let rpc = crate::jsonrpc::RpcClient::new(self.endpoint.clone());
+        let mut content = vec![];
+        rpc.download_job_file(id, file.as_ref(), &mut content)?;
+        Ok(String::from_utf8_lossy(&content).into_owned())
+    }
+}
+// 2e71c9a4 ends here
+
+// [[file:../runners.note::c09a6f35][c09a6f35]]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_job_server_backend() -> Result<()> {
+    use crate::auth::{User, Users};
+    use crate::job::{Db, JobStatus};
+    use crate::parser::OutputParser;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let endpoint = Endpoint::new(&listener.local_addr()?.to_string())?;
+    let mut users = Users::default();
+    users.insert(&endpoint.token, User::current());
+    tokio::spawn(crate::jsonrpc::TcpServer::new().users(users).serve(Db::new(), listener));
+
+    let mut template = Job::new("#!/bin/sh\nawk '{print \"energy =\", $2 * 2}'\n");
+    template.add_parser(OutputParser::Regex {
+        name: "energy".into(),
+        pattern: r"energy = (\S+)".into(),
+    });
+    let backend = JobServerBackend::new(endpoint, template);
+    let (result, out) = tokio::task::spawn_blocking(move || {
+        let id = backend.submit("H -1.5\n")?;
+        let result = backend.wait(id)?;
+        let out = backend.fetch(id, "job.out")?;
+        Ok_((result, out))
+    })
+    .await??;
+    assert_eq!(result.status, JobStatus::Completed);
+    assert_eq!(result.values["energy"], json!(-3.0));
+    assert_eq!(out.trim(), "energy = -3");
+    Ok(())
+}
+// c09a6f35 ends here
@@ -0,0 +1,148 @@
+// [[file:../runners.note::8e1f6a3d][8e1f6a3d]]
+//! Append-only audit log of job operations
+use super::*;
+
+use crate::job::JobId;
+use std::sync::Mutex;
+// 8e1f6a3d ends here
+
+// [[file:../runners.note::2a9d7c05][2a9d7c05]]
+/// A record of one operation on jobs.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuditEntry {
+    /// When the operation happened
+    pub time: String,
+    /// The user requesting the operation
+    pub who: String,
+    /// The operation, e.g. "create", "delete"
+    pub action: String,
+    /// The job operated on
+    pub job: Option<JobId>,
+    /// "ok" or error message
+    pub result: String,
+}
+
+/// An audit log appending records in JSON lines into a file, rotated when
+/// exceeding size limit.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: usize,
+    user: String,
+    // serialize appending and rotating
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    /// Create an audit log writing to `path`. The operations are recorded
+    /// as requested by current user, unless recorded by `record_as`.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            max_bytes: 10 * 1024 * 1024,
+            keep: 5,
+            user: std::env::var("USER").unwrap_or_else(|_| "unknown".into()),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Rotate the log file when exceeding `max_bytes`, keeping at most
+    /// `keep` rotated files (`audit.log.1`, `audit.log.2`, ...).
+    pub fn rotate(mut self, max_bytes: u64, keep: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self.keep = keep;
+        self
+    }
+
+    /// Return the path to the `n`th rotated file.
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.path.display(), n))
+    }
+
+    fn rotate_files(&self) -> Result<()> {
+        for n in (1..self.keep).rev() {
+            let p = self.rotated_path(n);
+            if p.exists() {
+                std::fs::rename(&p, self.rotated_path(n + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        } else {
+            std::fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    /// Append a record of `action` on `job` with `result`.
+    pub fn record(&self, action: &str, job: Option<JobId>, result: &str) -> Result<()> {
+        self.record_as(&self.user, action, job, result)
+    }
+
+    /// Append a record of `action` on `job` with `result`, requested by
+    /// user `who`, e.g. an authenticated client.
+    pub fn record_as(&self, who: &str, action: &str, job: Option<JobId>, result: &str) -> Result<()> {
+        let entry = AuditEntry {
+            time: timestamp_now(),
+            who: who.into(),
+            action: action.into(),
+            job,
+            result: result.into(),
+        };
+        let line = serde_json::to_string(&entry)?;
+
+        let _guard = self.lock.lock().unwrap();
+        let size = self.path.metadata().map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 >= self.max_bytes {
+            self.rotate_files()?;
+        }
+        let mut f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("open audit log {:?}", self.path))?;
+        writeln!(f, "{}", line)?;
+        Ok(())
+    }
+
+    /// Return recorded entries in time order, optionally only for `job`.
+    pub fn query(&self, job: Option<JobId>) -> Result<Vec<AuditEntry>> {
+        let _guard = self.lock.lock().unwrap();
+        let mut files: Vec<_> = (1..=self.keep).rev().map(|n| self.rotated_path(n)).collect();
+        files.push(self.path.clone());
+
+        let mut entries = vec![];
+        for f in files.iter().filter(|f| f.exists()) {
+            for line in gut::fs::read_file(f)?.lines() {
+                let entry: AuditEntry = serde_json::from_str(line)?;
+                if job.is_none() || entry.job == job {
+                    entries.push(entry);
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+// 2a9d7c05 ends here
+
+// [[file:../runners.note::f6b3e812][f6b3e812]]
+#[test]
+fn test_audit_log() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let log = AuditLog::new(dir.path().join("audit.log")).rotate(200, 2);
+    for i in 0..10 {
+        log.record("create", Some(i), "ok")?;
+    }
+    log.record("delete", Some(3), "ok")?;
+    assert!(dir.path().join("audit.log.1").exists());
+    assert!(!dir.path().join("audit.log.3").exists());
+
+    let entries = log.query(Some(3))?;
+    assert_eq!(entries.last().unwrap().action, "delete");
+
+    log.record_as("alice", "delete", Some(4), "ok")?;
+    assert_eq!(log.query(Some(4))?.last().unwrap().who, "alice");
+    Ok(())
+}
+// f6b3e812 ends here
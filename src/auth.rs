@@ -0,0 +1,122 @@
+// [[file:../runners.note::4b9e7c13][4b9e7c13]]
+//! User identities for sharing a server among users
+use super::*;
+// 4b9e7c13 ends here
+
+// [[file:../runners.note::e25a8f6d][e25a8f6d]]
+/// The identity of a client. Normal users can only access jobs they own,
+/// while admins can access all jobs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub admin: bool,
+}
+
+impl User {
+    /// A normal user of `name`.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            admin: false,
+        }
+    }
+
+    /// An admin user of `name`.
+    pub fn admin(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            admin: true,
+        }
+    }
+
+    /// The user running current process, as admin.
+    pub fn current() -> Self {
+        let name = std::env::var("USER").unwrap_or_else(|_| "unknown".into());
+        Self::admin(&name)
+    }
+
+    /// Test if the user can access a job owned by `owner`. Jobs without
+    /// owner are only accessible to admins.
+    pub fn can_access(&self, owner: Option<&str>) -> bool {
+        self.admin || owner == Some(self.name.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct UserEntry {
+    name: String,
+    token: String,
+    #[serde(default)]
+    admin: bool,
+}
+
+/// Users allowed to access a server, identified by their tokens, read
+/// from a TOML file like:
+///
+/// ```toml
+/// [[user]]
+/// name = "alice"
+/// token = "secret-of-alice"
+///
+/// [[user]]
+/// name = "admin"
+/// token = "secret-of-admin"
+/// admin = true
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Users {
+    #[serde(rename = "user", default)]
+    users: Vec<UserEntry>,
+}
+
+impl Users {
+    /// Read users from TOML file in `path`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let users = Self::from_toml(&gut::fs::read_file(path)?).with_context(|| format!("invalid users file {:?}", path))?;
+        Ok(users)
+    }
+
+    /// Add `user` identified by `token`.
+    pub fn insert(&mut self, token: &str, user: User) {
+        let entry = UserEntry {
+            name: user.name,
+            token: token.into(),
+            admin: user.admin,
+        };
+        self.users.push(entry);
+    }
+
+    /// Return the user identified by `token`.
+    pub fn authenticate(&self, token: &str) -> Option<User> {
+        self.users.iter().find(|u| u.token == token).map(|u| User {
+            name: u.name.clone(),
+            admin: u.admin,
+        })
+    }
+}
+// e25a8f6d ends here
+
+// [[file:../runners.note::9a0f6e31][9a0f6e31]]
+#[test]
+fn test_users() -> Result<()> {
+    let toml = r#"
+[[user]]
+name = "alice"
+token = "a"
+
+[[user]]
+name = "root"
+token = "r"
+admin = true
+"#;
+    let users = Users::from_toml(toml)?;
+    let alice = users.authenticate("a").unwrap();
+    assert!(alice.can_access(Some("alice")));
+    assert!(!alice.can_access(Some("bob")));
+    assert!(!alice.can_access(None));
+    let root = users.authenticate("r").unwrap();
+    assert!(root.can_access(Some("bob")));
+    assert!(users.authenticate("x").is_none());
+    Ok(())
+}
+// 9a0f6e31 ends here
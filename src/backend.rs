@@ -0,0 +1,567 @@
+// [[file:../runners.note::4a9c1e67][4a9c1e67]]
+//! Execution backends for computational jobs
+use super::*;
+
+use crate::job::JobStatus;
+use serde::{Deserialize, Serialize};
+// 4a9c1e67 ends here
+
+// [[file:../runners.note::0b8f3d52][0b8f3d52]]
+/// Where a `Job` will be executed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum Backend {
+    /// Run in a local session (the default).
+    Local,
+    /// Submit to SLURM scheduler using `sbatch`.
+    Slurm(SlurmOptions),
+    /// Run on a remote host over SSH.
+    Ssh(SshOptions),
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self::Local
+    }
+}
+
+/// Call external `cmd` with `args`, returning its stdout.
+pub(crate) fn call(cmd: &str, args: &[&str], dir: Option<&Path>) -> Result<String> {
+    debug!("call {} with {:?}", cmd, args);
+    let mut command = std::process::Command::new(cmd);
+    command.args(args);
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+    let out = command.output().with_context(|| format!("failed to execute {}", cmd))?;
+    if !out.status.success() {
+        bail!("{} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Join `cmdline` into a shell command with proper escaping.
+pub(crate) fn shell_join(cmdline: &[String]) -> String {
+    cmdline.iter().map(|x| x.as_str().shell_escape().into_owned()).join(" ")
+}
+
+/// A job submitted to a non-local backend.
+pub(crate) enum Submitted {
+    Slurm(SlurmJob),
+    Ssh(SshJob),
+}
+
+impl Submitted {
+    /// Query current status of the submitted job.
+    pub fn status(&self) -> Result<JobStatus> {
+        match self {
+            Self::Slurm(job) => job.status(),
+            Self::Ssh(job) => job.status(),
+        }
+    }
+
+    /// Copy the job files back into local working directory if they are not
+    /// on a shared file system.
+    pub fn sync_back(&self) -> Result<()> {
+        match self {
+            Self::Slurm(_) => Ok(()),
+            Self::Ssh(job) => job.sync_back(),
+        }
+    }
+
+    /// Cancel the submitted job.
+    pub fn cancel(&self) -> Result<()> {
+        match self {
+            Self::Slurm(job) => job.cancel(),
+            Self::Ssh(job) => job.cancel(),
+        }
+    }
+
+    /// Query current status of the submitted job, and copy back job files
+    /// for user inspection. This blocks on external commands.
+    pub fn poll(&self) -> Result<JobStatus> {
+        let status = self.status()?;
+        if let Err(e) = self.sync_back() {
+            warn!("failed to copy back job files: {:?}", e);
+        }
+        Ok(status)
+    }
+}
+// 0b8f3d52 ends here
+
+// [[file:../runners.note::6e27a1bc][6e27a1bc]]
+mod slurm {
+    use super::*;
+
+    /// Resource requests passed to `sbatch`.
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct SlurmOptions {
+        /// The partition (queue) to submit to
+        pub partition: Option<String>,
+        /// The number of nodes
+        pub nodes: Option<usize>,
+        /// The number of tasks
+        pub ntasks: Option<usize>,
+        /// Wall time limit, e.g. "2-00:00:00"
+        pub time: Option<String>,
+        /// Extra `#SBATCH` options, e.g. "--mem=4G"
+        #[serde(default)]
+        pub extra: Vec<String>,
+    }
+
+    /// A job submitted to SLURM.
+    pub struct SlurmJob {
+        id: String,
+    }
+
+    impl SlurmOptions {
+        /// Generate sbatch script running `cmdline` in `wrk_dir`, reading
+        /// stdin from `inp_file`.
+        fn sbatch_script(&self, wrk_dir: &Path, cmdline: &[String], inp_file: &Path) -> Result<String> {
+            // a line break would end the directive and inject commands
+            let options = self.partition.iter().chain(&self.time).chain(&self.extra);
+            for x in options {
+                let invalid = x.contains(|c| c == '\n' || c == '\r');
+                ensure!(!invalid, "invalid sbatch option: {:?}", x);
+            }
+            let mut lines = vec!["#!/usr/bin/env bash".to_owned(), "#SBATCH --job-name=gosh-runner".to_owned()];
+            if let Some(p) = &self.partition {
+                lines.push(format!("#SBATCH --partition={}", p));
+            }
+            if let Some(n) = self.nodes {
+                lines.push(format!("#SBATCH --nodes={}", n));
+            }
+            if let Some(n) = self.ntasks {
+                lines.push(format!("#SBATCH --ntasks={}", n));
+            }
+            if let Some(t) = &self.time {
+                lines.push(format!("#SBATCH --time={}", t));
+            }
+            for x in &self.extra {
+                lines.push(format!("#SBATCH {}", x));
+            }
+            lines.push(format!("cd {}", wrk_dir.shell_escape_lossy()));
+            lines.push(format!("{} < {}", shell_join(cmdline), inp_file.shell_escape_lossy()));
+            Ok(lines.join("\n") + "\n")
+        }
+    }
+
+    impl SlurmJob {
+        /// Submit `cmdline` in `wrk_dir` using `sbatch`. `files` are paths to
+        /// the input, output and error files of the job.
+        pub fn submit(opts: &SlurmOptions, wrk_dir: &Path, cmdline: &[String], files: [&Path; 3]) -> Result<Self> {
+            let [inp_file, out_file, err_file] = files;
+            let script = opts.sbatch_script(wrk_dir, cmdline, inp_file)?;
+            let sbatch_file = wrk_dir.join("sbatch.sh");
+            gut::fs::write_script_file(&sbatch_file, &script)?;
+
+            // output files are passed as arguments, which need no quoting
+            let out_file = format!("--output={}", out_file.display());
+            let err_file = format!("--error={}", err_file.display());
+            // the output of --parsable: jobid[;cluster]
+            let args = ["--parsable", &out_file, &err_file, "sbatch.sh"];
+            let out = call("sbatch", &args, Some(wrk_dir))?;
+            let id = out.trim().split(';').next().unwrap_or_default().to_owned();
+            ensure!(!id.is_empty(), "invalid sbatch output: {:?}", out);
+            info!("submitted slurm job {}", id);
+            Ok(Self { id })
+        }
+
+        /// Return the SLURM job ID.
+        pub fn id(&self) -> &str {
+            &self.id
+        }
+
+        /// Query the job status using `squeue`, or `sacct` for finished jobs.
+        pub fn status(&self) -> Result<JobStatus> {
+            slurm_status(&self.id)
+        }
+
+        /// Cancel the job using `scancel`.
+        pub fn cancel(&self) -> Result<()> {
+            scancel(&self.id)
+        }
+    }
+
+    fn slurm_status(id: &str) -> Result<JobStatus> {
+        let state = call("squeue", &["-h", "-j", id, "-o", "%T"], None).unwrap_or_default();
+        let state = if state.trim().is_empty() {
+            call("sacct", &["-n", "-X", "-P", "-j", id, "-o", "State"], None)?
+        } else {
+            state
+        };
+        Ok(map_slurm_state(state.trim()))
+    }
+
+    fn scancel(id: &str) -> Result<()> {
+        info!("cancel slurm job {}", id);
+        call("scancel", &[id], None)?;
+        Ok(())
+    }
+
+    // Cancel the job on drop, as a local session does. The commands run in
+    // a thread, as the job could be dropped in async context.
+    impl Drop for SlurmJob {
+        fn drop(&mut self) {
+            let id = std::mem::take(&mut self.id);
+            std::thread::spawn(move || {
+                if let Ok(JobStatus::Pending | JobStatus::Running) = slurm_status(&id) {
+                    let _ = scancel(&id);
+                }
+            });
+        }
+    }
+
+    /// Map SLURM job state onto `JobStatus`.
+    fn map_slurm_state(state: &str) -> JobStatus {
+        // sacct could report "CANCELLED by 1000"
+        let state = state.split_whitespace().next().unwrap_or_default();
+        match state {
+            "PENDING" | "CONFIGURING" | "REQUEUED" | "RESV_DEL_HOLD" => JobStatus::Pending,
+            "RUNNING" | "COMPLETING" | "SUSPENDED" | "STOPPED" => JobStatus::Running,
+            "COMPLETED" => JobStatus::Completed,
+            "CANCELLED" | "PREEMPTED" => JobStatus::Cancelled,
+            "FAILED" | "TIMEOUT" | "OUT_OF_MEMORY" | "NODE_FAIL" | "BOOT_FAIL" | "DEADLINE" => JobStatus::Failed,
+            _ => JobStatus::Unknown,
+        }
+    }
+
+    #[test]
+    fn test_sbatch_script() {
+        let opts = SlurmOptions {
+            partition: Some("gpu".into()),
+            ..Default::default()
+        };
+        let cmdline = vec!["/bin/sh".to_owned(), "run file".to_owned()];
+        let script = opts.sbatch_script("/tmp/a b".as_ref(), &cmdline, "job.inp".as_ref());
+        let script = script.unwrap();
+        assert!(script.contains("cd '/tmp/a b'"));
+        assert!(script.contains("#SBATCH --partition=gpu"));
+
+        let opts = SlurmOptions {
+            extra: vec!["--mem=4G\nrm -rf ~".into()],
+            ..Default::default()
+        };
+        let script = opts.sbatch_script("/tmp".as_ref(), &cmdline, "job.inp".as_ref());
+        assert!(script.is_err());
+    }
+
+    #[test]
+    fn test_slurm_state() {
+        assert_eq!(map_slurm_state("PENDING"), JobStatus::Pending);
+        assert_eq!(map_slurm_state("CANCELLED by 1000"), JobStatus::Cancelled);
+        assert_eq!(map_slurm_state("OUT_OF_MEMORY"), JobStatus::Failed);
+    }
+}
+// 6e27a1bc ends here
+
+// [[file:../runners.note::f2a64c8d][f2a64c8d]]
+mod ssh {
+    use super::*;
+    use std::process::{Command, Stdio};
+
+    /// Options for running a job on a remote host over SSH.
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct SshOptions {
+        /// The remote host, such as "user@node01"
+        pub host: String,
+        /// The directory on the remote host for creating job working
+        /// directories. The default is "/tmp".
+        pub scratch_dir: Option<String>,
+        /// Extra arguments for `ssh`, e.g. ["-p", "2222"]
+        #[serde(default)]
+        pub ssh_args: Vec<String>,
+    }
+
+    /// A job running on a remote host under its own session.
+    pub struct SshJob {
+        remote: Remote,
+        // the local working directory
+        local_dir: PathBuf,
+    }
+
+    // The job on the remote host, which can be moved into a thread for
+    // cleaning up
+    #[derive(Clone)]
+    struct Remote {
+        opts: SshOptions,
+        // the working directory on the remote host
+        remote_dir: String,
+        // the remote session ID
+        sid: u32,
+    }
+
+    /// Quote `s` for remote shell.
+    fn quote(s: &str) -> String {
+        s.shell_escape().into_owned()
+    }
+
+    impl SshOptions {
+        /// Run shell `script` on the remote host, returning its stdout.
+        fn run(&self, script: &str) -> Result<String> {
+            let mut args: Vec<&str> = self.ssh_args.iter().map(|x| x.as_str()).collect();
+            args.push(&self.host);
+            args.push(script);
+            call("ssh", &args, None)
+        }
+
+        /// Copy contents of `src` directory to `dst` using tar over ssh.
+        fn copy_dir(&self, src: &str, dst: &str, upload: bool) -> Result<()> {
+            let (tar_c, tar_x) = (format!("tar -C {} -cf - .", quote(src)), format!("tar -C {} -xf -", quote(dst)));
+            let (local, remote) = if upload { (tar_c, tar_x) } else { (tar_x, tar_c) };
+            let mut ssh = Command::new("ssh");
+            ssh.args(&self.ssh_args).arg(&self.host).arg(remote);
+            let mut sh = Command::new("sh");
+            sh.arg("-c").arg(local);
+            let (mut first, mut second) = if upload { (sh, ssh) } else { (ssh, sh) };
+            let mut producer = first.stdout(Stdio::piped()).spawn()?;
+            let pipe = producer.stdout.take().expect("tar stdout");
+            let status = second.stdin(pipe).status()?;
+            ensure!(producer.wait()?.success() && status.success(), "failed to copy {} to {}", src, dst);
+            Ok(())
+        }
+
+        /// Copy changed files in remote `src` directory into local `dst`
+        /// directory using rsync.
+        fn rsync_back(&self, src: &str, dst: &str) -> Result<()> {
+            let rsh = std::iter::once("ssh".to_owned()).chain(self.ssh_args.iter().cloned()).collect_vec();
+            let rsh = shell_join(&rsh);
+            // --protect-args: the remote path is not interpreted by remote shell
+            let src = format!("{}:{}/", self.host, src);
+            let dst = format!("{}/", dst);
+            call("rsync", &["-a", "-s", "-e", &rsh, &src, &dst], None)?;
+            Ok(())
+        }
+    }
+
+    impl Remote {
+        fn status(&self) -> Result<JobStatus> {
+            let script = format!(
+                "if kill -0 {} 2>/dev/null; then echo running; else cat {}/.exit_status 2>/dev/null; fi",
+                self.sid,
+                quote(&self.remote_dir)
+            );
+            let status = match self.opts.run(&script)?.trim() {
+                "running" => JobStatus::Running,
+                "0" => JobStatus::Completed,
+                "" => JobStatus::Cancelled,
+                _ => JobStatus::Failed,
+            };
+            Ok(status)
+        }
+
+        fn cancel(&self) -> Result<()> {
+            info!("terminate remote session {} on {}", self.sid, self.opts.host);
+            self.opts.run(&format!("kill -TERM -- -{}", self.sid))?;
+            Ok(())
+        }
+
+        fn remove(&self) -> Result<()> {
+            self.opts.run(&format!("rm -rf {}", quote(&self.remote_dir)))?;
+            Ok(())
+        }
+    }
+
+    impl SshJob {
+        /// Stage local `wrk_dir` onto the remote host, and start `cmdline`
+        /// there in a new session. `cmdline` is relative to the remote working
+        /// directory. `files` are names of the input, output and error files
+        /// of the job.
+        pub fn submit(opts: &SshOptions, wrk_dir: &Path, cmdline: &[String], files: [&Path; 3]) -> Result<Self> {
+            let scratch = opts.scratch_dir.as_deref().unwrap_or("/tmp");
+            let remote_dir = opts.run(&format!("mktemp -d -p {} gosh-runner.XXXXXX", quote(scratch)))?;
+            let remote_dir = remote_dir.trim().to_owned();
+            let local_dir = wrk_dir.to_owned();
+            opts.copy_dir(&local_dir.to_string_lossy(), &remote_dir, true)?;
+
+            let name = |p: &Path| p.file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+            let [inp_file, out_file, err_file] = files;
+            // record the exit status for status query
+            let cmd = format!(
+                "{} < {} > {} 2> {}; echo $? > .exit_status",
+                shell_join(cmdline),
+                quote(&name(inp_file)),
+                quote(&name(out_file)),
+                quote(&name(err_file))
+            );
+            let script = format!(
+                "cd {} && setsid sh -c {} < /dev/null > /dev/null 2>&1 & echo $!",
+                quote(&remote_dir),
+                quote(&cmd)
+            );
+            let sid = opts.run(&script)?.trim().parse()?;
+            info!("job started on {} in session {}: {}", opts.host, sid, remote_dir);
+
+            let remote = Remote {
+                opts: opts.clone(),
+                remote_dir,
+                sid,
+            };
+            Ok(Self { remote, local_dir })
+        }
+
+        /// Query the job status on the remote host.
+        pub fn status(&self) -> Result<JobStatus> {
+            self.remote.status()
+        }
+
+        /// Copy changed files in the remote working directory back into
+        /// local working directory. Fall back to tar if rsync is not
+        /// available.
+        pub fn sync_back(&self) -> Result<()> {
+            let Remote { opts, remote_dir, .. } = &self.remote;
+            let local_dir = self.local_dir.to_string_lossy();
+            if let Err(e) = opts.rsync_back(remote_dir, &local_dir) {
+                debug!("rsync failed, fall back to tar: {:?}", e);
+                opts.copy_dir(remote_dir, &local_dir, false)?;
+            }
+            Ok(())
+        }
+
+        /// Terminate all processes in the remote session.
+        pub fn cancel(&self) -> Result<()> {
+            self.remote.cancel()
+        }
+    }
+
+    // Terminate the remote session and remove remote files on drop. The
+    // commands run in a thread, as the job could be dropped in async context.
+    impl Drop for SshJob {
+        fn drop(&mut self) {
+            let remote = self.remote.clone();
+            std::thread::spawn(move || {
+                if let Ok(JobStatus::Running) = remote.status() {
+                    let _ = remote.cancel();
+                }
+                let _ = remote.remove();
+            });
+        }
+    }
+
+    #[test]
+    fn test_ssh_quote() {
+        assert_eq!(quote("/tmp/a b"), "'/tmp/a b'");
+        assert_eq!(quote("/tmp/$(rm -rf ~)"), "'/tmp/$(rm -rf ~)'");
+    }
+}
+// f2a64c8d ends here
+
+// [[file:../runners.note::5cb09e1f][5cb09e1f]]
+mod container {
+    use super::*;
+
+    /// Options for running job script inside an Apptainer/Singularity
+    /// container.
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct ApptainerOptions {
+        /// The container image, e.g. "orca.sif"
+        pub image: String,
+        /// Bind paths in "src[:dest[:opts]]" format
+        #[serde(default)]
+        pub binds: Vec<String>,
+        /// Enable NVIDIA GPU support (--nv)
+        #[serde(default)]
+        pub nv: bool,
+        /// The container program, the default is "apptainer". Set it as
+        /// "singularity" for older installations.
+        pub program: Option<String>,
+    }
+
+    impl ApptainerOptions {
+        /// Return the command line running `run_file` inside the container.
+        /// The current working directory is bound by apptainer by default.
+        pub fn wrap(&self, run_file: &str) -> Vec<String> {
+            let program = self.program.as_deref().unwrap_or("apptainer");
+            let mut cmdline = vec![program.to_owned(), "exec".to_owned()];
+            if self.nv {
+                cmdline.push("--nv".into());
+            }
+            for bind in &self.binds {
+                cmdline.push("--bind".into());
+                cmdline.push(bind.into());
+            }
+            cmdline.push(self.image.clone());
+            cmdline.push(run_file.into());
+            cmdline
+        }
+    }
+
+    #[test]
+    fn test_apptainer_wrap() {
+        let opts = ApptainerOptions {
+            image: "orca.sif".into(),
+            binds: vec!["/scratch".into()],
+            nv: true,
+            program: None,
+        };
+        let cmdline = opts.wrap("./run");
+        assert_eq!(cmdline.join(" "), "apptainer exec --nv --bind /scratch orca.sif ./run");
+    }
+}
+// 5cb09e1f ends here
+
+// [[file:../runners.note::a4e6c90b][a4e6c90b]]
+mod sandbox {
+    use super::*;
+
+    /// Options for running job script in a bubblewrap sandbox, restricting
+    /// it to its working directory. The host filesystem is mounted
+    /// read-only, with private `/tmp` and hidden `/home`, and only the
+    /// working directory is writable.
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct SandboxOptions {
+        /// Allow access to network. The network is isolated by default.
+        #[serde(default)]
+        pub network: bool,
+        /// Extra host paths made visible read-only, e.g. a shared dataset
+        /// under /home
+        #[serde(default)]
+        pub ro_binds: Vec<PathBuf>,
+        /// The sandbox program, the default is "bwrap".
+        pub program: Option<String>,
+    }
+
+    impl SandboxOptions {
+        /// Return the command line running `cmdline` inside the sandbox
+        /// with `wrk_dir` writable.
+        pub fn wrap(&self, wrk_dir: &Path, cmdline: Vec<String>) -> Vec<String> {
+            let program = self.program.as_deref().unwrap_or("bwrap");
+            let wrk_dir = wrk_dir.to_string_lossy().into_owned();
+            let mut args: Vec<String> = vec![program.into()];
+            let mut push = |xs: &[&str]| args.extend(xs.iter().map(|x| x.to_string()));
+            push(&["--ro-bind", "/", "/"]);
+            push(&["--dev", "/dev", "--proc", "/proc"]);
+            push(&["--tmpfs", "/tmp", "--tmpfs", "/home"]);
+            for bind in &self.ro_binds {
+                let bind = bind.to_string_lossy().into_owned();
+                push(&["--ro-bind", &bind, &bind]);
+            }
+            // mounted last, as it may be under /tmp or /home
+            push(&["--bind", &wrk_dir, &wrk_dir, "--chdir", &wrk_dir]);
+            push(&["--unshare-pid", "--unshare-ipc", "--die-with-parent"]);
+            if !self.network {
+                push(&["--unshare-net"]);
+            }
+            args.push("--".into());
+            args.extend(cmdline);
+            args
+        }
+    }
+
+    #[test]
+    fn test_sandbox_wrap() {
+        let opts = SandboxOptions::default();
+        let cmdline = opts.wrap("/scratch/job".as_ref(), vec!["./run".into()]);
+        let cmdline = cmdline.join(" ");
+        assert!(cmdline.starts_with("bwrap --ro-bind / / "));
+        assert!(cmdline.contains("--bind /scratch/job /scratch/job --chdir /scratch/job"));
+        assert!(cmdline.ends_with("--unshare-net -- ./run"));
+    }
+}
+// a4e6c90b ends here
+
+// [[file:../runners.note::d7f0a318][d7f0a318]]
+pub use self::container::ApptainerOptions;
+pub use self::sandbox::SandboxOptions;
+pub use self::slurm::{SlurmJob, SlurmOptions};
+pub use self::ssh::{SshJob, SshOptions};
+// d7f0a318 ends here
@@ -2,9 +2,9 @@
 use gosh_core::gut::prelude::*;
 // imports:1 ends here
 
-// [[file:../../runners.note::0d2aca69][0d2aca69]]
+// [[file:../../runners.note::a2c7e95d][a2c7e95d]]
 fn main() -> Result<()> {
-    gosh_runner::cli::Apps::enter_main()?;
+    gosh_runner::cli::gosh_runner_enter_main()?;
     Ok(())
 }
-// 0d2aca69 ends here
+// a2c7e95d ends here
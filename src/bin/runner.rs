@@ -1,10 +0,0 @@
-// [[file:../../runners.note::*imports][imports:1]]
-use gosh_core::gut::prelude::*;
-// imports:1 ends here
-
-// [[file:../../runners.note::a6b47847][a6b47847]]
-fn main() -> Result<()> {
-    gosh_runner::cli::local_enter_main()?;
-    Ok(())
-}
-// a6b47847 ends here
@@ -0,0 +1,118 @@
+// [[file:../runners.note::8a3f6c21][8a3f6c21]]
+//! Content-addressed cache of uploaded input files
+use super::*;
+
+use sha2::{Digest, Sha256};
+// 8a3f6c21 ends here
+
+// [[file:../runners.note::e5b7d940][e5b7d940]]
+/// A server-side cache of input files keyed by their SHA-256 hash, so that
+/// identical inputs resubmitted by parameter sweeps are only transferred
+/// once.
+///
+/// Files are copied into and out of the cache, so that jobs modifying
+/// their inputs in place never change the cached content. The copy is a
+/// cheap reflink on filesystems supporting it, like btrfs and XFS.
+#[derive(Debug, Clone)]
+pub struct FileCache {
+    root: PathBuf,
+}
+
+impl FileCache {
+    /// Create a cache storing files in `root` directory.
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_owned(),
+        }
+    }
+
+    /// Return the SHA-256 hash of `file` in hex.
+    pub fn hash_file(file: &Path) -> Result<String> {
+        let mut f = std::fs::File::open(file).with_context(|| format!("open {:?}", file))?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut f, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Return the path to cached file of `hash`.
+    fn path_of(&self, hash: &str) -> Result<PathBuf> {
+        ensure!(
+            hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()),
+            "invalid SHA-256 hash: {:?}",
+            hash
+        );
+        let hash = hash.to_ascii_lowercase();
+        Ok(self.root.join(&hash[..2]).join(&hash[2..]))
+    }
+
+    /// Test if file of `hash` is cached.
+    pub fn contains(&self, hash: &str) -> bool {
+        self.path_of(hash).map_or(false, |p| p.exists())
+    }
+
+    /// Add `file` into the cache, returning its hash.
+    pub fn insert(&self, file: &Path) -> Result<String> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let hash = Self::hash_file(file)?;
+        let cached = self.path_of(&hash)?;
+        if cached.exists() {
+            return Ok(hash);
+        }
+        let dir = cached.parent().expect("cache dir");
+        std::fs::create_dir_all(dir)?;
+        // stage with a temporary name, so others never see partial content
+        let tmp = tempfile::NamedTempFile::new_in(dir)?.into_temp_path();
+        std::fs::remove_file(&tmp)?;
+        std::fs::copy(file, &tmp).with_context(|| format!("copy {:?} into cache", file))?;
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o444))?;
+        std::fs::rename(&tmp, &cached)?;
+        debug!("cached {:?} as {}", file, hash);
+        Ok(hash)
+    }
+
+    /// Copy cached file of `hash` into `dst`, writable by the job. Return
+    /// false if not cached.
+    pub fn copy_into(&self, hash: &str, dst: &Path) -> Result<bool> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let cached = self.path_of(hash)?;
+        if !cached.exists() {
+            return Ok(false);
+        }
+        if dst.exists() {
+            std::fs::remove_file(dst)?;
+        }
+        std::fs::copy(&cached, dst).with_context(|| format!("copy {:?} to {:?}", cached, dst))?;
+        // not read-only as the cached one
+        std::fs::set_permissions(dst, std::fs::Permissions::from_mode(0o644))?;
+        Ok(true)
+    }
+}
+// e5b7d940 ends here
+
+// [[file:../runners.note::27c9e4b6][27c9e4b6]]
+#[test]
+fn test_file_cache() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let cache = FileCache::new(tdir.path().join("cache"));
+    let input = tdir.path().join("input.xyz");
+    gut::fs::write_to_file(&input, "3\n\nO 0 0 0\n")?;
+
+    let hash = cache.insert(&input)?;
+    assert_eq!(hash.len(), 64);
+    assert!(cache.contains(&hash));
+    assert!(!cache.contains("../../etc/passwd"));
+
+    let dst = tdir.path().join("copy.xyz");
+    assert!(cache.copy_into(&hash, &dst)?);
+    assert_eq!(gut::fs::read_file(&dst)?, "3\n\nO 0 0 0\n");
+    // modifying the copy leaves the cached content and the input intact
+    gut::fs::write_to_file(&dst, "changed")?;
+    assert_eq!(gut::fs::read_file(&input)?, "3\n\nO 0 0 0\n");
+    assert!(cache.copy_into(&hash, &dst)?);
+    assert_eq!(gut::fs::read_file(&dst)?, "3\n\nO 0 0 0\n");
+    assert!(!cache.copy_into(&"0".repeat(64), &dst)?);
+    Ok(())
+}
+// 27c9e4b6 ends here
@@ -6,11 +6,20 @@ use gut::prelude::*;
 // ab80e3cc ends here
 
 // [[file:../runners.note::*mods][mods:1]]
+mod acct;
 mod apps;
+mod export;
+mod gosh;
 mod local;
+mod ng;
+mod replay;
+mod run;
+mod serve;
 // mods:1 ends here
 
 // [[file:../runners.note::a336ec24][a336ec24]]
 pub use self::apps::*;
+pub use self::gosh::*;
 pub use self::local::*;
+pub use self::ng::*;
 // a336ec24 ends here
@@ -0,0 +1,46 @@
+// [[file:../../runners.note::b3d0e6a8][b3d0e6a8]]
+use super::*;
+use crate::acct::{summarize, Accounting, GroupBy};
+// b3d0e6a8 ends here
+
+// [[file:../../runners.note::74e9c1f2][74e9c1f2]]
+use gut::cli::*;
+
+/// Summarize usage of historical jobs from accounting records
+#[derive(Args, Debug)]
+pub(super) struct AcctCli {
+    /// The accounting file. The default is ~/.gosh-runner/acct.csv
+    #[arg(long)]
+    file: Option<PathBuf>,
+
+    /// Group jobs by week, user or program
+    #[arg(long, value_enum, default_value = "week")]
+    by: GroupBy,
+}
+
+impl AcctCli {
+    pub(super) fn run(&self) -> Result<()> {
+        let path = self.file.clone().unwrap_or_else(Accounting::default_path);
+        let records = Accounting::new(&path).records()?;
+        println!(
+            "{:<20} {:>8} {:>8} {:>14} {:>14}",
+            format!("{:?}", self.by).to_lowercase(),
+            "jobs",
+            "failed",
+            "wall hours",
+            "cpu hours"
+        );
+        for (key, usage) in summarize(&records, self.by) {
+            println!(
+                "{:<20} {:>8} {:>8} {:>14.2} {:>14.2}",
+                key,
+                usage.jobs,
+                usage.failed,
+                usage.runtime / 3600.0,
+                usage.cpu_seconds / 3600.0
+            );
+        }
+        Ok(())
+    }
+}
+// 74e9c1f2 ends here
@@ -5,19 +5,26 @@ use super::*;
 // [[file:../../runners.note::8e91b7e1][8e91b7e1]]
 use gut::fs::ShellEscapeExt;
 
-#[derive(Debug, Clone, ValueEnum)]
+#[derive(Debug, Clone, Default, ValueEnum, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 enum PathOp {
     Append,
+    #[default]
     Prepend,
     Remove,
 }
 
 impl PathOp {
     fn apply(&self, path_key: &str, path_value: &str) -> String {
-        let path_key = path_key.trim();
+        let paths = std::env::var_os(path_key.trim());
+        self.apply_to(paths.as_deref(), path_value)
+    }
+
+    /// Apply the operation of `path_value` to current `paths` value.
+    fn apply_to(&self, paths: Option<&std::ffi::OsStr>, path_value: &str) -> String {
         let path_value = path_value.trim();
 
-        if let Some(paths) = std::env::var_os(path_key) {
+        if let Some(paths) = paths {
             let path_value_: std::path::PathBuf = path_value.into();
             let mut paths = std::env::split_paths(&paths).collect_vec();
             paths.retain(|path| path != &path_value_);
@@ -59,41 +66,339 @@ fn test_environ() {
 
 pub fn prepend_path(path_key: &str, new_path: &str) -> String {
     let path_value = PathOp::Prepend.apply(path_key, new_path);
-    format!("export {path_key}={}", path_value.as_str().shell_escape())
+    Shell::Bash.set_env(path_key, &path_value)
 }
 
 pub fn append_path(path_key: &str, new_path: &str) -> String {
     let path_value = PathOp::Append.apply(path_key, new_path);
-    format!("export {path_key}={}", path_value.as_str().shell_escape())
+    Shell::Bash.set_env(path_key, &path_value)
 }
 
 pub fn remove_path(path_key: &str, new_path: &str) -> String {
     let path_value = PathOp::Remove.apply(path_key, new_path);
-    format!("export {path_key}={}", path_value.as_str().shell_escape())
+    Shell::Bash.set_env(path_key, &path_value)
 }
 // 8e91b7e1 ends here
 
+// [[file:../../runners.note::5c3e9a17][5c3e9a17]]
+/// The shell to generate environment commands for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(super) enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Tcsh,
+    #[value(name = "powershell", alias = "pwsh")]
+    PowerShell,
+}
+
+impl Shell {
+    /// Detect the shell from `$SHELL` env var, default to bash.
+    pub fn detect() -> Self {
+        let shell = std::env::var("SHELL").unwrap_or_default();
+        let name = shell.rsplit('/').next().unwrap_or_default();
+        match name {
+            "zsh" => Self::Zsh,
+            "fish" => Self::Fish,
+            "tcsh" | "csh" => Self::Tcsh,
+            "pwsh" | "powershell" | "pwsh.exe" | "powershell.exe" => Self::PowerShell,
+            _ => Self::Bash,
+        }
+    }
+
+    /// Return the command setting env var `key` to `value`.
+    pub fn set_env(&self, key: &str, value: &str) -> String {
+        match self {
+            Self::Bash | Self::Zsh => format!("export {key}={}", value.shell_escape()),
+            Self::Fish => format!("set -gx {key} {}", value.shell_escape()),
+            Self::Tcsh => format!("setenv {key} {}", value.shell_escape()),
+            Self::PowerShell => format!("$env:{key} = '{}'", value.replace('\'', "''")),
+        }
+    }
+
+    /// Return the command removing env var `key`.
+    pub fn unset_env(&self, key: &str) -> String {
+        match self {
+            Self::Bash | Self::Zsh => format!("unset {key}"),
+            Self::Fish => format!("set -e {key}"),
+            Self::Tcsh => format!("unsetenv {key}"),
+            Self::PowerShell => format!("Remove-Item Env:{key} -ErrorAction SilentlyContinue"),
+        }
+    }
+
+    /// Translate `bash_script` into commands of this shell, by running it
+    /// in bash and collecting the changes of env vars.
+    pub fn translate(&self, bash_script: &str) -> Result<String> {
+        if matches!(self, Self::Bash | Self::Zsh) {
+            return Ok(bash_script.to_owned());
+        }
+        let mut lines = String::new();
+        for (k, v) in bash_env_changes(bash_script)? {
+            let line = match v {
+                Some(v) => self.set_env(&k, &v),
+                None => self.unset_env(&k),
+            };
+            lines.push_str(&format!("{line};"));
+        }
+        Ok(lines)
+    }
+}
+
+/// Run `bash_script` in bash, and return the env vars it changed. Removed
+/// env vars have `None` values.
+fn bash_env_changes(bash_script: &str) -> Result<Vec<(String, Option<String>)>> {
+    bash_env_changes_with(bash_script, |_| {})
+}
+
+/// Like `bash_env_changes`, with bash process set up by `prepare`.
+fn bash_env_changes_with(
+    bash_script: &str,
+    prepare: impl FnOnce(&mut std::process::Command),
+) -> Result<Vec<(String, Option<String>)>> {
+    // send env dump to fd 3, and discard other outputs
+    let script = format!("exec 3>&1 1>/dev/null; {bash_script}\nenv -0 >&3");
+    let mut bash = std::process::Command::new("bash");
+    bash.args(["-c", &script]);
+    prepare(&mut bash);
+    let out = bash.output().context("run bash")?;
+    ensure!(out.status.success(), "bash failed: {}", String::from_utf8_lossy(&out.stderr));
+
+    let new_vars: std::collections::BTreeMap<_, _> = String::from_utf8_lossy(&out.stdout)
+        .split('\0')
+        .filter_map(|kv| kv.split_once('='))
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect();
+    let mut changes = vec![];
+    for (k, v) in new_vars.iter() {
+        // variables maintained by bash itself
+        if ["_", "SHLVL", "PWD", "OLDPWD"].contains(&k.as_str()) {
+            continue;
+        }
+        if std::env::var(k).ok().as_ref() != Some(v) {
+            changes.push((k.to_owned(), Some(v.to_owned())));
+        }
+    }
+    for (k, _) in std::env::vars() {
+        if !new_vars.contains_key(&k) && !["_", "OLDPWD"].contains(&k.as_str()) {
+            changes.push((k, None));
+        }
+    }
+    Ok(changes)
+}
+
+/// Return env vars set by loading `modules` from apps root dir, for
+/// running programs with the modules without a shell. `prepare` sets up
+/// the bash process sourcing `.envrc` of modules, e.g. to run it as the
+/// user of a job.
+pub(crate) fn module_env_vars(
+    modules: &[String],
+    prepare: impl FnOnce(&mut std::process::Command),
+) -> Result<Vec<(String, String)>> {
+    let bash_script = set_module_env_vars(&apps_root_dir(), modules, false)?;
+    let vars = bash_env_changes_with(&bash_script, prepare)?
+        .into_iter()
+        .filter_map(|(k, v)| Some((k, v?)))
+        .collect();
+    Ok(vars)
+}
+
+#[test]
+fn test_shell_syntax() {
+    assert_eq!(Shell::Bash.set_env("FOO", "a b"), "export FOO='a b'");
+    assert_eq!(Shell::Fish.set_env("FOO", "a b"), "set -gx FOO 'a b'");
+    assert_eq!(Shell::Tcsh.set_env("FOO", "a b"), "setenv FOO 'a b'");
+    assert_eq!(Shell::PowerShell.set_env("FOO", "it's"), "$env:FOO = 'it''s'");
+}
+// 5c3e9a17 ends here
+
 // [[file:../../runners.note::b185bee5][b185bee5]]
-fn show_available_modules(apps_root_dir: &Path) -> Result<()> {
-    for entry in apps_root_dir.read_dir()? {
-        let path = entry?.path();
-        if path.is_dir() && path.join(".envrc").is_file() {
-            eprintln!("{:^10} => {path:?}", path.file_name().unwrap().to_string_lossy());
+/// The marker file in a module namespace dir containing the default
+/// version, such as `5.0` in `orca/.default`.
+const DEFAULT_MARKER: &str = ".default";
+
+/// The maximum levels of default versions followed in resolving a module,
+/// against symlink loops.
+const MAX_DEFAULT_DEPTH: usize = 8;
+
+fn is_module_dir(path: &Path) -> bool {
+    path.is_dir() && path.join(".envrc").is_file()
+}
+
+/// Find all modules under `dir` recursively, in name order. A module is a
+/// directory containing `.envrc`, and may be nested in namespaces or
+/// versions, such as `mpich/3.4.2`.
+fn find_modules(dir: &Path, modules: &mut Vec<PathBuf>) -> Result<()> {
+    let mut paths: Vec<_> = dir.read_dir()?.filter_map(|entry| Some(entry.ok()?.path())).collect();
+    paths.sort();
+    for path in paths {
+        if is_module_dir(&path) {
+            modules.push(path);
+        } else if path.is_dir() {
+            find_modules(&path, modules)?;
         }
     }
     Ok(())
 }
 
-fn set_module_env_vars(apps_root_dir: &Path, module_name: &str, remove: bool) -> Result<String> {
+/// Return the default version of module namespace in `dir`: the one in
+/// `.default` marker file, or the latest version otherwise.
+fn default_version(dir: &Path) -> Result<Option<String>> {
+    let marker = dir.join(DEFAULT_MARKER);
+    if marker.is_file() {
+        let version = gut::fs::read_file(&marker)?.trim().to_owned();
+        ensure!(
+            !matches!(version.as_str(), "" | "." | ".."),
+            "invalid default version {version:?} in {marker:?}"
+        );
+        return Ok(Some(version));
+    }
+    let mut versions = vec![];
+    find_modules(dir, &mut versions)?;
+    let version = versions
+        .iter()
+        .filter_map(|path| path.strip_prefix(dir).ok())
+        .max_by(|a, b| compare_versions(&a.to_string_lossy(), &b.to_string_lossy()))
+        .map(|path| path.to_string_lossy().into_owned());
+    Ok(version)
+}
+
+/// Compare version strings like "3.4.2" and "3.10" by numeric components.
+fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    let key = |s: &str| {
+        s.split(|c: char| c == '.' || c == '-' || c == '/')
+            .map(|x| x.parse::<u64>().map_err(|_| x.to_owned()))
+            .collect_vec()
+    };
+    key(a).cmp(&key(b))
+}
+
+/// Resolve root dir of module `module_name`. Module namespace without
+/// version such as `mpich` resolves to its default version.
+pub(super) fn resolve_module(apps_root_dir: &Path, module_name: &str) -> Result<PathBuf> {
+    resolve_module_within(apps_root_dir, module_name, MAX_DEFAULT_DEPTH)
+}
+
+/// Resolve module like `resolve_module`, following at most `depth` levels
+/// of default versions.
+fn resolve_module_within(apps_root_dir: &Path, module_name: &str, depth: usize) -> Result<PathBuf> {
+    use std::path::Component;
+
+    // stay inside apps root dir
+    let name = Path::new(module_name);
+    ensure!(
+        name.components().next().is_some() && name.components().all(|c| matches!(c, Component::Normal(_))),
+        "invalid module name: {module_name:?}"
+    );
     let mod_root = apps_root_dir.join(module_name);
-    let mod_bin = mod_root.join("bin");
+    ensure!(mod_root.is_dir(), "module not found: {module_name}");
+    if is_module_dir(&mod_root) {
+        return Ok(mod_root);
+    }
+    match default_version(&mod_root)? {
+        Some(version) => {
+            let module = format!("{module_name}/{version}");
+            debug!("resolve module {module_name} to {module}");
+            ensure!(depth > 0, "too many levels of default versions: {module}");
+            resolve_module_within(apps_root_dir, &module, depth - 1)
+        }
+        // plain directory without .envrc
+        None => Ok(mod_root),
+    }
+}
 
-    let mut lines = String::new();
+fn show_available_modules(apps_root_dir: &Path) -> Result<()> {
+    let mut modules = vec![];
+    find_modules(apps_root_dir, &mut modules)?;
+    for path in modules {
+        let name = path.strip_prefix(apps_root_dir)?.to_string_lossy();
+        let is_default = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(version)) if parent != apps_root_dir => {
+                default_version(parent)?.as_deref() == Some(&version.to_string_lossy())
+            }
+            _ => false,
+        };
+        let mark = if is_default { " (default)" } else { "" };
+        eprintln!("{:^10} => {path:?}", format!("{name}{mark}"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_resolve_module() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let root = tdir.path();
+    for dir in ["mpich/3.4.2", "mpich/3.10", "orca/4.2", "orca/5.0", "xtb"] {
+        std::fs::create_dir_all(root.join(dir))?;
+        gut::fs::write_to_file(root.join(dir).join(".envrc"), "")?;
+    }
+    gut::fs::write_to_file(root.join("orca").join(DEFAULT_MARKER), "4.2\n")?;
+
+    assert_eq!(resolve_module(root, "xtb")?, root.join("xtb"));
+    assert_eq!(resolve_module(root, "mpich")?, root.join("mpich/3.10"));
+    assert_eq!(resolve_module(root, "orca")?, root.join("orca/4.2"));
+    assert_eq!(resolve_module(root, "orca/5.0")?, root.join("orca/5.0"));
+    assert!(resolve_module(root, "vasp").is_err());
+    assert!(resolve_module(root, "../orca/4.2").is_err());
+    assert!(resolve_module(root, "/etc").is_err());
+    // bad default versions
+    std::fs::create_dir_all(root.join("gaussian"))?;
+    for version in ["", ".", "..", "../orca"] {
+        gut::fs::write_to_file(root.join("gaussian").join(DEFAULT_MARKER), version)?;
+        assert!(resolve_module(root, "gaussian").is_err());
+    }
+    std::os::unix::fs::symlink(root.join("gaussian"), root.join("gaussian/g16"))?;
+    gut::fs::write_to_file(root.join("gaussian").join(DEFAULT_MARKER), "g16")?;
+    assert!(resolve_module(root, "gaussian").is_err());
+    std::fs::remove_dir_all(root.join("gaussian"))?;
+
+    let mut modules = vec![];
+    find_modules(root, &mut modules)?;
+    assert_eq!(modules.len(), 5);
+    Ok(())
+}
 
+/// The root directory of app modules, set by `BBM_APPS_DIR` env var.
+pub(super) fn apps_root_dir() -> PathBuf {
+    std::env::var("BBM_APPS_DIR").unwrap_or("/share/apps".into()).into()
+}
+
+/// The env var tracking currently loaded modules, separated by colons.
+const LOADED_MODULES_VAR: &str = "GOSH_LOADED_MODULES";
+
+/// Environment variables updated when generating module commands, so
+/// that later modules see changes of earlier ones.
+type EnvVars = std::collections::HashMap<String, String>;
+
+/// Return the names of currently loaded modules, in loading order.
+fn loaded_modules() -> Vec<String> {
+    loaded_modules_in(&std::env::vars().collect())
+}
+
+fn loaded_modules_in(env: &EnvVars) -> Vec<String> {
+    env.get(LOADED_MODULES_VAR)
+        .map(|s| s.as_str())
+        .unwrap_or_default()
+        .split(':')
+        .filter(|m| !m.is_empty())
+        .map(|m| m.to_owned())
+        .collect()
+}
+
+/// Return the path env vars and the entries module in `mod_root` contributes.
+fn module_path_entries(mod_root: &Path) -> Vec<(&'static str, PathBuf)> {
+    let mut entries = vec![];
+    // MANPATH
+    for man in ["share/man", "man"] {
+        let mod_man = mod_root.join(man);
+        if mod_man.is_dir() {
+            entries.push(("MANPATH", mod_man));
+        }
+    }
+
+    let mod_bin = mod_root.join("bin");
     // PATH
     if mod_bin.is_dir() {
-        let line = path_env_cmd(&mod_bin, "PATH", remove);
-        lines.push_str(&format!("{line};"));
+        entries.push(("PATH", mod_bin));
     }
 
     // LD_LIBRARY_PATH
@@ -101,24 +406,200 @@ fn set_module_env_vars(apps_root_dir: &Path, module_name: &str, remove: bool) ->
         let mod_lib = mod_root.join(lib);
         if mod_lib.is_dir() {
             for path in ["LIBRARY_PATH", "LD_LIBRARY_PATH", "LD_RUN_PATH"] {
-                let line = path_env_cmd(&mod_lib, path, remove);
-                lines.push_str(&format!("{line};"));
+                entries.push((path, mod_lib.clone()));
             }
         }
         // PKG_CONFIG_PATH
         let root = mod_lib.join("pkgconfig");
         if root.is_dir() {
-            let line = path_env_cmd(&root, "PKG_CONFIG_PATH", remove);
-            lines.push_str(&format!("{line};"));
+            entries.push(("PKG_CONFIG_PATH", root));
         }
     }
 
     // CPATH
     let include = mod_root.join("include");
     if include.is_dir() {
-        let line = path_env_cmd(&include, "CPATH", remove);
+        entries.push(("CPATH", include));
+    }
+    entries
+}
+
+/// Test if module `name` such as `mpich/3.4.2` matches `module_name`
+/// with or without version.
+fn module_matches(name: &str, module_name: &str) -> bool {
+    name == module_name || name.strip_prefix(module_name).map_or(false, |v| v.starts_with('/'))
+}
+
+/// Return the name of the loaded module matching `module_name`, such as
+/// `mpich/3.4.2` for `mpich`.
+fn find_loaded_module(env: &EnvVars, module_name: &str) -> Option<String> {
+    loaded_modules_in(env).into_iter().find(|m| module_matches(m, module_name))
+}
+
+/// A path-like env var declared in `module.toml`, such as:
+///
+/// ```toml
+/// [[path]]
+/// var = "PYTHONPATH"
+/// path = "lib/python3"
+/// op = "append"
+/// ```
+#[derive(Debug, Deserialize, Serialize)]
+struct PathVar {
+    /// The name of env var
+    var: String,
+    /// The path entry, relative to module root dir
+    path: PathBuf,
+    /// How to update the env var on loading, prepend by default
+    #[serde(default)]
+    op: PathOp,
+}
+
+/// Module metadata in `module.toml` of module root dir.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct ModuleMeta {
+    /// Modules to be loaded before this module
+    requires: Vec<String>,
+    /// Modules that can not be loaded together with this module
+    conflicts: Vec<String>,
+    /// Extra path-like env vars
+    #[serde(rename = "path")]
+    paths: Vec<PathVar>,
+    /// Plain env var assignments. `{root}` in values is replaced with the
+    /// module root dir, e.g. `ORCA_ROOT = "{root}"`.
+    env: std::collections::BTreeMap<String, String>,
+}
+
+impl ModuleMeta {
+    fn read(mod_root: &Path) -> Result<Self> {
+        let path = mod_root.join("module.toml");
+        if path.is_file() {
+            let meta = Self::from_toml(&gut::fs::read_file(&path)?).with_context(|| format!("invalid {path:?}"))?;
+            Ok(meta)
+        } else {
+            Ok(Self::default())
+        }
+    }
+
+    /// Check if module `name` with this meta conflicts with `loaded`
+    /// modules, and `conflicts_of` gives conflicts declared by a loaded
+    /// module.
+    fn check_conflicts(&self, name: &str, loaded: &[String], conflicts_of: impl Fn(&str) -> Vec<String>) -> Result<()> {
+        for other in loaded {
+            if let Some(c) = self.conflicts.iter().find(|c| module_matches(other, c)) {
+                bail!("module {name} conflicts with loaded module {other} (declared as {c:?}), unload it first");
+            }
+            if let Some(c) = conflicts_of(other).iter().find(|c| module_matches(name, c)) {
+                bail!("loaded module {other} conflicts with module {name} (declared as {c:?}), unload it first");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_module_conflicts() -> Result<()> {
+    let meta = ModuleMeta::from_toml("requires = [\"mpich\"]\nconflicts = [\"openmpi\"]")?;
+    assert_eq!(meta.requires, ["mpich"]);
+    let no_conflicts = |_: &str| vec![];
+    let loaded = vec!["mpich/3.4.2".to_owned()];
+    assert!(meta.check_conflicts("orca/5.0", &loaded, no_conflicts).is_ok());
+    let loaded = vec!["openmpi/4.1".to_owned()];
+    assert!(meta.check_conflicts("orca/5.0", &loaded, no_conflicts).is_err());
+    let loaded = vec!["vasp".to_owned()];
+    let meta = ModuleMeta::default();
+    assert!(meta.check_conflicts("orca/5.0", &loaded, |_| vec!["orca".to_owned()]).is_err());
+    assert!(!module_matches("orca-ext", "orca"));
+
+    let toml = r#"
+env = { ORCA_ROOT = "{root}" }
+
+[[path]]
+var = "PYTHONPATH"
+path = "lib/python3"
+op = "append"
+
+[[path]]
+var = "MANPATH"
+path = "share/man"
+"#;
+    let meta = ModuleMeta::from_toml(toml)?;
+    assert_eq!(meta.paths.len(), 2);
+    assert!(matches!(meta.paths[1].op, PathOp::Prepend));
+    assert_eq!(meta.env["ORCA_ROOT"], "{root}");
+    Ok(())
+}
+
+/// Return bash commands to load or unload `modules`.
+pub(super) fn set_module_env_vars(apps_root_dir: &Path, modules: &[String], remove: bool) -> Result<String> {
+    let mut env: EnvVars = std::env::vars().collect();
+    let mut loading = vec![];
+    modules
+        .iter()
+        .map(|module| module_env_cmds(apps_root_dir, module, remove, &mut env, &mut loading))
+        .collect()
+}
+
+/// `loading` records modules being loaded for detecting circular
+/// dependencies.
+fn module_env_cmds(
+    apps_root_dir: &Path,
+    module_name: &str,
+    remove: bool,
+    env: &mut EnvVars,
+    loading: &mut Vec<String>,
+) -> Result<String> {
+    let loaded = if remove { find_loaded_module(env, module_name) } else { None };
+    let mod_root = match loaded {
+        Some(module) => apps_root_dir.join(module),
+        None => resolve_module(apps_root_dir, module_name)?,
+    };
+    let name = mod_root.strip_prefix(apps_root_dir)?.to_string_lossy().into_owned();
+
+    let mut lines = String::new();
+    let meta = ModuleMeta::read(&mod_root)?;
+    if !remove {
+        ensure!(!loading.contains(&name), "circular module dependency: {} -> {name}", loading.join(" -> "));
+        let loaded = loaded_modules_in(env);
+        meta.check_conflicts(&name, &loaded, |other| {
+            ModuleMeta::read(&apps_root_dir.join(other))
+                .map(|m| m.conflicts)
+                .unwrap_or_default()
+        })?;
+        // load dependencies first
+        loading.push(name.clone());
+        for dep in meta.requires.iter() {
+            if find_loaded_module(env, dep).is_none() {
+                info!("load module {dep} required by {name}");
+                lines.push_str(&module_env_cmds(apps_root_dir, dep, remove, env, loading)?);
+            }
+        }
+        loading.pop();
+    }
+    for (path, root) in module_path_entries(&mod_root) {
+        let line = path_env_cmd(env, &root, path, remove);
         lines.push_str(&format!("{line};"));
     }
+    for path_var in meta.paths.iter() {
+        let op = if remove { &PathOp::Remove } else { &path_var.op };
+        let line = path_op_cmd(env, op, &mod_root.join(&path_var.path), &path_var.var);
+        lines.push_str(&format!("{line};"));
+    }
+    for (key, value) in meta.env.iter() {
+        let line = if remove {
+            env.remove(key);
+            format!("unset {key}")
+        } else {
+            let value = value.replace("{root}", &mod_root.to_string_lossy());
+            let line = Shell::Bash.set_env(key, &value);
+            env.insert(key.to_owned(), value);
+            line
+        };
+        lines.push_str(&format!("{line};"));
+    }
+    let line = path_env_cmd(env, name.as_ref(), LOADED_MODULES_VAR, remove);
+    lines.push_str(&format!("{line};"));
 
     // source .envrc
     let mod_envrc = mod_root.join(".envrc");
@@ -134,11 +615,34 @@ fn set_module_env_vars(apps_root_dir: &Path, module_name: &str, remove: bool) ->
     Ok(lines)
 }
 
-fn path_env_cmd(root: &Path, path: &str, remove: bool) -> String {
-    if remove {
-        remove_path(path, &format!("{}", root.display()))
-    } else {
-        prepend_path(path, &format!("{}", root.display()))
+/// Return the command to update `path` env var with `root`, and record the
+/// change in `env`.
+fn path_env_cmd(env: &mut EnvVars, root: &Path, path: &str, remove: bool) -> String {
+    let op = if remove { PathOp::Remove } else { PathOp::Prepend };
+    path_op_cmd(env, &op, root, path)
+}
+
+/// Return the command to update `path` env var with `root` using `op`,
+/// and record the change in `env`.
+fn path_op_cmd(env: &mut EnvVars, op: &PathOp, root: &Path, path: &str) -> String {
+    let root = format!("{}", root.display());
+    let value = op.apply_to(env.get(path).map(std::ffi::OsStr::new), &root);
+    let line = Shell::Bash.set_env(path, &value);
+    env.insert(path.to_owned(), value);
+    line
+}
+
+fn show_loaded_modules(apps_root_dir: &Path) {
+    for module in loaded_modules() {
+        println!("{module}");
+        let mod_root = apps_root_dir.join(&module);
+        for (path, root) in module_path_entries(&mod_root) {
+            println!("    {path:<16} {}", root.display());
+        }
+        let meta = ModuleMeta::read(&mod_root).unwrap_or_default();
+        for path_var in meta.paths {
+            println!("    {:<16} {}", path_var.var, mod_root.join(&path_var.path).display());
+        }
     }
 }
 // b185bee5 ends here
@@ -150,48 +654,71 @@ use gut::cli::*;
 enum AppsOp {
     /// Load module environment variables
     Load {
-        /// The requested module to be loaded
-        module: String,
+        /// The requested modules to be loaded
+        #[clap(required = true)]
+        modules: Vec<String>,
     },
     /// Unload module environment variables
     Unload {
-        /// The requested module to be unloaded
-        module: String,
+        /// The requested modules to be unloaded
+        #[clap(required = true)]
+        modules: Vec<String>,
     },
     /// Show available modules
     Avail,
+    /// Show currently loaded modules and the path entries they contributed
+    List,
+    /// Unload all loaded modules
+    Purge,
 }
 
-/// A shell environment manager as a poor-man's modulefiles (for bash only now)
+/// A shell environment manager as a poor-man's modulefiles
 #[derive(Parser)]
 #[clap(disable_help_subcommand=true, disable_help_flag=true)]
 pub struct Apps {
     #[clap(flatten)]
     verbose: gut::cli::Verbosity,
 
+    /// The shell to generate commands for. Detected from `$SHELL` if not set.
+    #[clap(long, value_enum)]
+    shell: Option<Shell>,
+
     #[clap(subcommand)]
     action: AppsOp,
 }
 
 impl Apps {
     pub fn enter_main() -> Result<()> {
-        let args = Self::parse();
-        args.verbose.setup_logger();
+        Self::parse().enter()
+    }
+
+    pub(super) fn enter(&self) -> Result<()> {
+        self.verbose.setup_logger();
 
-        let apps_root_dir = std::env::var("BBM_APPS_DIR").unwrap_or("/share/apps".into());
-        match args.action {
-            AppsOp::Load { module } => {
-                let bash_script = set_module_env_vars(apps_root_dir.as_ref(), &module, false)?;
+        let apps_root_dir = apps_root_dir();
+        let shell = self.shell.unwrap_or_else(Shell::detect);
+        match &self.action {
+            AppsOp::Load { modules } => {
+                let bash_script = set_module_env_vars(&apps_root_dir, modules, false)?;
                 debug!("Load env vars in bash:\n{bash_script}");
-                println!("{bash_script}");
+                println!("{}", shell.translate(&bash_script)?);
             }
-            AppsOp::Unload { module } => {
-                let bash_script = set_module_env_vars(apps_root_dir.as_ref(), &module, true)?;
+            AppsOp::Unload { modules } => {
+                let bash_script = set_module_env_vars(&apps_root_dir, modules, true)?;
                 debug!("Unload env vars in bash:\n{bash_script}");
-                println!("{bash_script}");
+                println!("{}", shell.translate(&bash_script)?);
+            }
+            AppsOp::List => {
+                show_loaded_modules(&apps_root_dir);
+            }
+            AppsOp::Purge => {
+                let modules = loaded_modules().into_iter().rev().collect_vec();
+                let bash_script = set_module_env_vars(&apps_root_dir, &modules, true)?;
+                debug!("Purge env vars in bash:\n{bash_script}");
+                println!("{}", shell.translate(&bash_script)?);
             }
             AppsOp::Avail => {
-                show_available_modules(apps_root_dir.as_ref())?;
+                show_available_modules(&apps_root_dir)?;
             }
         }
 
@@ -0,0 +1,55 @@
+// [[file:../../runners.note::5b9d2f60][5b9d2f60]]
+use super::*;
+use crate::discovery::Endpoint;
+use crate::job::{Job, JobId};
+// 5b9d2f60 ends here
+
+// [[file:../../runners.note::c1e7a394][c1e7a394]]
+use gut::cli::*;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+/// Export the full spec of a job on a running server
+#[derive(Args, Debug)]
+pub(super) struct ExportJobCli {
+    /// The id of the job to export
+    id: JobId,
+
+    /// The output format
+    #[arg(long, value_enum, default_value = "toml")]
+    format: Format,
+
+    /// The discovery file written by the server. The default is
+    /// ~/.gosh-runner/endpoint.json
+    #[arg(long)]
+    discovery_file: Option<PathBuf>,
+
+    /// Write the job spec into the file instead of stdout.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+impl ExportJobCli {
+    pub(super) fn run(&self) -> Result<()> {
+        let path = self.discovery_file.clone().unwrap_or_else(Endpoint::default_path);
+        let endpoint = Endpoint::read(&path)?;
+        let spec = crate::jsonrpc::call(&endpoint, "export", serde_json::json!({ "id": self.id }))?;
+        let job: Job = serde_json::from_value(spec)?;
+        let s = match self.format {
+            Format::Toml => job.to_toml()?,
+            Format::Yaml => job.to_yaml()?,
+            Format::Json => job.to_json()?,
+        };
+        match &self.output {
+            Some(f) => gut::fs::write_to_file(f, &s)?,
+            None => print!("{}", s),
+        }
+        Ok(())
+    }
+}
+// c1e7a394 ends here
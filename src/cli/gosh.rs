@@ -0,0 +1,95 @@
+// [[file:../../runners.note::e0c5a7b2][e0c5a7b2]]
+use super::*;
+use super::acct::AcctCli;
+use super::apps::Apps;
+use super::export::ExportJobCli;
+use super::local::RunnerCli;
+use super::ng::{NgCli, NgServerCli};
+use super::replay::ReplayCli;
+use super::run::RunCli;
+use super::serve::ServeCli;
+// e0c5a7b2 ends here
+
+// [[file:../../runners.note::8f41d3a6][8f41d3a6]]
+use gut::cli::*;
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Serve jobs from JSON-RPC requests, spool directory or message queues
+    Serve(ServeCli),
+    /// Call commands on a Nailgun server
+    Client(NgCli),
+    /// Run a job script locally using the job pipeline
+    Run(RunCli),
+    /// Manage shell environment of app modules
+    Apps(Apps),
+    /// Run a program in a session that can make graceful exit
+    Session(RunnerCli),
+    /// Start a Nailgun server
+    NgServer(NgServerCli),
+    /// Summarize resource usage of finished jobs
+    Acct(AcctCli),
+    /// Export the full spec of a job on a running server
+    ExportJob(ExportJobCli),
+    /// Re-execute a session recorded by `session --record`
+    Replay(ReplayCli),
+    /// Enter the interactive shell for driving a remote server
+    Shell,
+}
+
+/// Tools for running gosh jobs
+#[derive(Parser)]
+struct GoshRunnerCli {
+    #[command(flatten)]
+    verbose: gut::cli::Verbosity,
+
+    #[command(subcommand)]
+    cmd: Cmd,
+}
+
+/// Dispatch by the name the binary invoked as, for compatibility with
+/// symlinks of the old split binaries. Return None for unknown name.
+fn enter_main_by_name(name: &str) -> Option<Result<()>> {
+    let r = match name {
+        "runner" => super::local_enter_main(),
+        "apps-module" => Apps::enter_main(),
+        "ng" => super::ng_enter_main(),
+        "ng-server" => super::ng_server_enter_main(),
+        "runner-rpc" => ServeCli::parse().run(),
+        // symlink magic for `foo.run`
+        _ if name.ends_with(".run") => super::local_enter_main(),
+        _ => return None,
+    };
+    Some(r)
+}
+
+pub fn gosh_runner_enter_main() -> Result<()> {
+    let invoke_path = std::env::args().next().unwrap_or_default();
+    let name = Path::new(&invoke_path).file_name().unwrap_or_default().to_string_lossy();
+    if let Some(r) = enter_main_by_name(&name) {
+        return r;
+    }
+
+    let args = GoshRunnerCli::parse();
+    match &args.cmd {
+        // subcommands with own verbosity option
+        Cmd::Client(ng) => ng.enter()?,
+        Cmd::Apps(apps) => apps.enter()?,
+        Cmd::Session(session) => super::local::exit_on_failure(session.enter()?)?,
+        Cmd::NgServer(server) => server.enter()?,
+        cmd => {
+            args.verbose.setup_logger();
+            match cmd {
+                Cmd::Serve(serve) => serve.run()?,
+                Cmd::Run(run) => run.run()?,
+                Cmd::Acct(acct) => acct.run()?,
+                Cmd::ExportJob(export) => export.run()?,
+                Cmd::Replay(replay) => replay.run()?,
+                Cmd::Shell => crate::client::enter_main()?,
+                _ => unreachable!(),
+            }
+        }
+    }
+    Ok(())
+}
+// 8f41d3a6 ends here
@@ -8,7 +8,7 @@ use gut::cli::*;
 
 /// A local runner that can make graceful exit
 #[derive(Parser, Debug, Default)]
-struct RunnerCli {
+pub(super) struct RunnerCli {
     #[command(flatten)]
     verbose: gut::cli::Verbosity,
 
@@ -16,30 +16,195 @@ struct RunnerCli {
     #[arg(long, short)]
     timeout: Option<u32>,
 
+    /// Load app modules before calling the program
+    #[arg(long = "module", short = 'm')]
+    modules: Vec<String>,
+
+    /// The working directory: "current" (default), "temp" for a new
+    /// temporary directory removed after run, or a directory path.
+    #[arg(long)]
+    work_dir: Option<String>,
+
+    /// Restart the program for at most N times if it fails, e.g. on
+    /// license server hiccups. Timeout or interruption is not retried.
+    #[arg(long, default_value = "0")]
+    retries: u32,
+
+    /// The delay in seconds before restarting the failed program.
+    #[arg(long, default_value = "10")]
+    retry_delay: f64,
+
+    /// Capture stdout and stderr of the program into the file, with a
+    /// timestamp on each line.
+    #[arg(long)]
+    log: Option<PathBuf>,
+
+    /// Also show the captured output in the terminal.
+    #[arg(long, requires = "log")]
+    tee: bool,
+
+    /// Terminate the program gracefully when the stop file is created,
+    /// "STOP" in current directory if no path given.
+    #[arg(long, num_args = 0..=1, default_missing_value = "STOP")]
+    stop_file: Option<PathBuf>,
+
+    /// Terminate the program when total RSS of all its processes exceeds
+    /// the limit, e.g. "4G". Grandchildren are covered, unlike ulimit.
+    #[arg(long, value_parser = parse_size)]
+    max_rss: Option<u64>,
+
+    /// Terminate the program when total CPU time of all its processes
+    /// exceeds the limit in seconds.
+    #[arg(long)]
+    max_cpu_seconds: Option<f64>,
+
+    /// Feed stdin of the program from the file.
+    #[arg(long)]
+    stdin: Option<PathBuf>,
+
+    /// Record the command, environment, signals and exit status into the
+    /// JSON file, which can be re-executed by `gosh-runner replay`.
+    #[arg(long)]
+    record: Option<PathBuf>,
+
     /// Command line to call a program
     #[arg(raw = true, required = true)]
     cmdline: Vec<String>,
 }
 
 impl RunnerCli {
-    fn enter_main<I>(iter: I) -> Result<()>
+    fn enter_main<I>(iter: I) -> Result<i32>
     where
         Self: Sized,
         I: IntoIterator,
         I::Item: Into<std::ffi::OsString> + Clone,
     {
-        let args = RunnerCli::try_parse_from(iter)?;
+        RunnerCli::try_parse_from(iter)?.enter()
+    }
+
+    /// Run the program in a session with parsed arguments, returning its
+    /// exit code. The temporary working directory is removed on return.
+    pub(super) fn enter(&self) -> Result<i32> {
+        let args = self;
         args.verbose.setup_logger();
 
-        let program = &args.cmdline[0];
-        let rest = &args.cmdline[1..];
+        let mut cmdline = args.cmdline.clone();
+        if !args.modules.is_empty() {
+            // load modules in bash before calling the program
+            let apps_root_dir = super::apps::apps_root_dir();
+            let mut script = super::apps::set_module_env_vars(&apps_root_dir, &args.modules, false)?;
+            script.push_str(r#"exec "$@""#);
+            let wrapper = ["bash".to_owned(), "-c".to_owned(), script, "bash".to_owned()];
+            cmdline.splice(0..0, wrapper);
+        }
+        let program = &cmdline[0];
+        let rest = &cmdline[1..];
 
-        Session::new(program)
+        let mut session = Session::new(program)
             .args(rest)
-            .timeout(args.timeout.unwrap_or(3600 * 24 * 30))
-            .run()?;
+            .timeout(args.timeout.unwrap_or(3600 * 24 * 30));
+        // keep temporary directory alive until the program exits
+        let mut _tmpdir = None;
+        match args.work_dir.as_deref() {
+            None | Some("current") => {}
+            Some("temp") => {
+                let tmpdir = tempfile::tempdir_in(".")?;
+                session = session.dir(tmpdir.path());
+                _tmpdir = Some(tmpdir);
+            }
+            Some(dir) => session = session.dir(dir),
+        }
+        if let Some(f) = &args.record {
+            session = session.record(f);
+        }
+        if let Some(f) = &args.stdin {
+            session = session.stdin_file(f);
+        }
+        if let Some(m) = args.max_rss {
+            session = session.max_rss(m);
+        }
+        if let Some(t) = args.max_cpu_seconds {
+            session = session.max_cpu_time(t);
+        }
+        if let Some(f) = &args.stop_file {
+            session = session.stop_file(f);
+        }
+        if let Some(f) = &args.log {
+            session = session.log(f, args.tee);
+        }
+        if args.retries > 0 {
+            let delay = std::time::Duration::from_secs_f64(args.retry_delay);
+            session = session.retries(args.retries).retry_delay(delay);
+        }
 
-        Ok(())
+        session.run()
+    }
+}
+
+/// Exit the process with `code` of the program if it failed. Call it only
+/// after cleaning up, as destructors are not run.
+pub(super) fn exit_on_failure(code: i32) -> Result<()> {
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+/// Parse memory size in bytes like "4G", "512M" or "1024", with binary
+/// units.
+fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let n: f64 = num.trim().parse().with_context(|| format!("invalid size: {:?}", s))?;
+    let scale: u64 = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => 1u64 << 10,
+        "M" => 1u64 << 20,
+        "G" => 1u64 << 30,
+        "T" => 1u64 << 40,
+        _ => bail!("invalid size unit: {:?}", s),
+    };
+    let bytes = n * scale as f64;
+    ensure!(bytes >= 0.0 && bytes < u64::MAX as f64, "invalid size: {:?}", s);
+    Ok(bytes as u64)
+}
+
+/// Per-program settings for symlink invocation, read from `foo.run.toml`
+/// next to the `foo.run` symlink.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct ProgramConfig {
+    /// Timeout in seconds
+    timeout: Option<u32>,
+    /// App modules loaded before calling the program
+    modules: Vec<String>,
+    /// The working directory policy, see `RunnerCli::work_dir`
+    work_dir: Option<String>,
+}
+
+impl ProgramConfig {
+    /// Return runner options for the config of `invoke_path` if any.
+    fn runner_args(invoke_path: &Path) -> Result<Vec<String>> {
+        let config_file = PathBuf::from(format!("{}.toml", invoke_path.display()));
+        let mut args = vec![];
+        if !config_file.is_file() {
+            return Ok(args);
+        }
+        let config = Self::from_toml(&gut::fs::read_file(&config_file)?)
+            .with_context(|| format!("invalid config: {:?}", config_file))?;
+        if let Some(t) = config.timeout {
+            args.push(format!("--timeout={}", t));
+        }
+        for m in config.modules {
+            args.push(format!("--module={}", m));
+        }
+        if let Some(d) = config.work_dir {
+            args.push(format!("--work-dir={}", d));
+        }
+        Ok(args)
     }
 }
 
@@ -62,53 +227,32 @@ pub fn local_enter_main() -> Result<()> {
         let real_exe = real_path.file_name().context("real exe name")?;
 
         if real_exe != invoke_exe {
-            let runner_args = [&real_exe.to_string_lossy(), "-v", "--", &invoke_exe.to_string_lossy()];
-
-            let cmdline: Vec<_> = runner_args
-                .iter()
-                .map(|s| s.to_string())
+            let config_args = ProgramConfig::runner_args(invoke_path)?;
+            let cmdline: Vec<_> = [real_exe.to_string_lossy().into_owned(), "-v".to_owned()]
+                .into_iter()
+                .chain(config_args)
+                .chain(["--".to_owned(), invoke_exe.to_string_lossy().into_owned()])
                 .chain(args.iter().cloned().skip(1))
                 .collect();
             println!("runner will call {:?} with {:?}", invoke_exe, cmdline.join(" "));
-            return RunnerCli::enter_main(cmdline);
+            return exit_on_failure(RunnerCli::enter_main(cmdline)?);
         }
     }
     // run in a normal way
-    RunnerCli::enter_main(std::env::args())
+    exit_on_failure(RunnerCli::enter_main(std::env::args())?)
 }
 // bff78206 ends here
 
-// [[file:../../runners.note::*ctrlc][ctrlc:1]]
-use gut::prelude::*;
-
-/// Run main process with ctrl-c handler
-pub fn ctrlc_enter_main(enter_main: fn() -> Result<()>) -> Result<()> {
-    // Create the runtime
-    let rt = tokio::runtime::Runtime::new().unwrap();
-    // Execute the future, blocking the current thread until completion
-    rt.block_on(ctrlc_enter_main_(enter_main))?;
-
-    Ok(())
-}
-
-async fn ctrlc_enter_main_(enter_main: fn() -> Result<()>) -> Result<()> {
-    let ctrl_c = tokio::signal::ctrl_c();
-    let main_task = tokio::task::spawn_blocking(move || {
-        // This is running on a blocking thread.
-        enter_main()
-    });
-
-    tokio::select! {
-        result = main_task => {
-            result?;
-            info!("Done");
-        }
-        result = ctrl_c => {
-            result?;
-            info!("Received SIGINT, exiting");
-        }
+// [[file:../../runners.note::4e8b2d91][4e8b2d91]]
+#[test]
+fn test_parse_size() -> Result<()> {
+    assert_eq!(parse_size("1024")?, 1024);
+    assert_eq!(parse_size("1.5K")?, 1536);
+    assert_eq!(parse_size("4GiB")?, 4 << 30);
+    assert_eq!(parse_size("2T")?, 2 << 40);
+    for s in ["-1G", "NaN", "99999999T", "4X"] {
+        assert!(parse_size(s).is_err());
     }
-
     Ok(())
 }
-// ctrlc:1 ends here
+// 4e8b2d91 ends here
@@ -0,0 +1,111 @@
+// [[file:../../runners.note::4d9c1e70][4d9c1e70]]
+use super::*;
+use crate::discovery::Endpoint;
+use crate::nailgun::{NailgunClient, NailgunServer};
+// 4d9c1e70 ends here
+
+// [[file:../../runners.note::a1f5b83e][a1f5b83e]]
+use gut::cli::*;
+
+/// A client for calling commands on a Nailgun server
+#[derive(Parser, Debug)]
+pub(super) struct NgCli {
+    #[command(flatten)]
+    verbose: gut::cli::Verbosity,
+
+    /// The address of Nailgun server
+    #[arg(long, env = "NAILGUN_SERVER", default_value = "127.0.0.1:2113")]
+    server: String,
+
+    /// The token of the server, read from the endpoint file by default
+    #[arg(long, env = "NAILGUN_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// The endpoint file written by the server, ~/.gosh-runner/nailgun.json
+    /// by default
+    #[arg(long)]
+    endpoint_file: Option<PathBuf>,
+
+    /// The command to call and its arguments
+    #[arg(raw = true, required = true)]
+    cmdline: Vec<String>,
+}
+
+pub fn ng_enter_main() -> Result<()> {
+    NgCli::parse().enter()
+}
+
+impl NgCli {
+    pub(super) fn enter(&self) -> Result<()> {
+        self.verbose.setup_logger();
+
+        let token = match &self.token {
+            Some(token) => token.clone(),
+            None => Endpoint::read(&self.endpoint_file.clone().unwrap_or_else(default_endpoint_file))?.token,
+        };
+        let rt = tokio::runtime::Runtime::new().context("tokio runtime failure")?;
+        let code = rt.block_on(async {
+            let client = NailgunClient::connect(&self.server).await?.token(&token);
+            let cwd = std::env::current_dir()?;
+            let (stdin, stdout, stderr) = (tokio::io::stdin(), tokio::io::stdout(), tokio::io::stderr());
+            client
+                .run(&self.cmdline[0], &self.cmdline[1..], &cwd, stdin, stdout, stderr)
+                .await
+        })?;
+        std::process::exit(code);
+    }
+}
+// a1f5b83e ends here
+
+// [[file:../../runners.note::85e3c0b7][85e3c0b7]]
+/// A Nailgun server executing commands in a pre-initialized environment
+#[derive(Parser, Debug)]
+pub(super) struct NgServerCli {
+    #[command(flatten)]
+    verbose: gut::cli::Verbosity,
+
+    /// The address to listen on
+    #[arg(long, default_value = "127.0.0.1:2113")]
+    address: String,
+
+    /// Shell script sourced once for preparing environment of commands
+    #[arg(long)]
+    init_script: Option<PathBuf>,
+
+    /// The file for writing the address and a new token clients must
+    /// present, ~/.gosh-runner/nailgun.json by default
+    #[arg(long)]
+    endpoint_file: Option<PathBuf>,
+}
+
+pub fn ng_server_enter_main() -> Result<()> {
+    NgServerCli::parse().enter()
+}
+
+impl NgServerCli {
+    pub(super) fn enter(&self) -> Result<()> {
+        self.verbose.setup_logger();
+
+        let mut server = NailgunServer::new();
+        if let Some(script) = &self.init_script {
+            server = server.init_script(script)?;
+        }
+        // readable only by the owner, so that other users cannot run
+        // commands as us
+        let endpoint = Endpoint::new(&self.address)?;
+        let path = self.endpoint_file.clone().unwrap_or_else(default_endpoint_file);
+        endpoint.write(&path)?;
+        info!("nailgun endpoint written into {:?}", path);
+        let server = server.token(&endpoint.token);
+        let rt = tokio::runtime::Runtime::new().context("tokio runtime failure")?;
+        rt.block_on(server.serve(&self.address))?;
+        Ok(())
+    }
+}
+
+/// Return the default endpoint file shared by Nailgun server and clients.
+fn default_endpoint_file() -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_else(|_| ".".into());
+    Path::new(&home).join(".gosh-runner").join("nailgun.json")
+}
+// 85e3c0b7 ends here
@@ -0,0 +1,39 @@
+// [[file:../../runners.note::4d8b2f91][4d8b2f91]]
+use super::*;
+use crate::session::SessionTrace;
+// 4d8b2f91 ends here
+
+// [[file:../../runners.note::a7e30c58][a7e30c58]]
+use gut::cli::*;
+
+/// Re-execute a session recorded by `session --record`
+#[derive(Args, Debug)]
+pub(super) struct ReplayCli {
+    /// The trace file recorded from a session
+    trace: PathBuf,
+
+    /// Record the replayed session into another trace file for comparison
+    #[arg(long)]
+    record: Option<PathBuf>,
+}
+
+impl ReplayCli {
+    pub(super) fn run(&self) -> Result<()> {
+        let trace = SessionTrace::from_file(&self.trace)?;
+        println!("replay: {} {}", trace.program, trace.args.join(" "));
+        for e in &trace.events {
+            println!("recorded {:8.2}s: {}", e.time, e.event);
+        }
+        let mut session = trace.to_session();
+        if let Some(f) = &self.record {
+            session = session.record(f);
+        }
+        let code = session.run()?;
+        println!("exit code: {} (recorded {:?})", code, trace.exit_code);
+        if code != 0 {
+            std::process::exit(code);
+        }
+        Ok(())
+    }
+}
+// a7e30c58 ends here
@@ -0,0 +1,71 @@
+// [[file:../../runners.note::5a1e9c73][5a1e9c73]]
+use super::*;
+use crate::job::{Db, Job};
+// 5a1e9c73 ends here
+
+// [[file:../../runners.note::c2b84f06][c2b84f06]]
+use gut::cli::*;
+
+/// Run a job script locally using the same job pipeline as the server
+#[derive(Args, Debug)]
+pub(super) struct RunCli {
+    /// The script file for running the job
+    script: PathBuf,
+
+    /// The file fed into stdin of the job
+    #[arg(long)]
+    stdin: Option<PathBuf>,
+
+    /// Files copied into the job working directory
+    #[arg(long = "file", short = 'f')]
+    files: Vec<PathBuf>,
+
+    /// Files copied back from the job working directory when done
+    #[arg(long = "output", short = 'o')]
+    outputs: Vec<PathBuf>,
+}
+
+impl RunCli {
+    async fn run_job(&self) -> Result<Option<i32>> {
+        let mut job = Job::new(&gut::fs::read_file(&self.script)?);
+        if let Some(f) = &self.stdin {
+            job.set_input(&gut::fs::read_file(f)?);
+        }
+        let (out_file, err_file) = (job.out_file().to_owned(), job.err_file().to_owned());
+
+        let mut db = Db::new();
+        let id = db.insert_job(job).await?;
+        for f in &self.files {
+            let name = f.file_name().with_context(|| format!("invalid file: {:?}", f))?;
+            let body = std::fs::read(f).with_context(|| format!("read {:?}", f))?;
+            db.put_job_file(id, name.to_string_lossy().into_owned(), body.into()).await?;
+        }
+        let result = db.wait_job(id).await?;
+
+        std::io::stdout().write_all(&db.get_job_file(id, &out_file).await?)?;
+        std::io::stderr().write_all(&db.get_job_file(id, &err_file).await?)?;
+        for f in &self.outputs {
+            let body = db.get_job_file(id, f).await?;
+            let name = f.file_name().with_context(|| format!("invalid file: {:?}", f))?;
+            std::fs::write(name, body)?;
+            info!("copied back {:?}", f);
+        }
+        info!("job finished: {:?}", result.status);
+        if !result.values.is_empty() {
+            println!("{}", serde_json::to_string_pretty(&result.values)?);
+        }
+        db.delete_job(id).await?;
+        Ok(result.exit_code)
+    }
+
+    pub(super) fn run(&self) -> Result<()> {
+        let rt = tokio::runtime::Runtime::new().context("tokio runtime failure")?;
+        let code = rt.block_on(self.run_job())?;
+        match code {
+            Some(0) => Ok(()),
+            Some(code) => std::process::exit(code),
+            None => bail!("job terminated by signal"),
+        }
+    }
+}
+// c2b84f06 ends here
@@ -0,0 +1,475 @@
+// [[file:../../runners.note::3e8d51c2][3e8d51c2]]
+use super::*;
+use crate::auth::{User, Users};
+use crate::discovery::Endpoint;
+use crate::job::Db;
+use crate::retention::RetentionPolicy;
+use crate::signals::run_until_shutdown;
+use crate::throttle::SensorThrottle;
+use crate::validate::ScriptPolicy;
+// 3e8d51c2 ends here
+
+// [[file:../../runners.note::9c85a1e3][9c85a1e3]]
+use gut::cli::*;
+
+/// Drive the job runner with JSON-RPC requests on stdin or TCP.
+#[derive(Parser, Debug)]
+pub(super) struct ServeCli {
+    /// The address to bind for serving JSON-RPC over TCP.
+    #[arg(long, default_value = "127.0.0.1")]
+    bind: String,
+
+    /// Serve JSON-RPC requests over TCP on the port instead of stdin. Port
+    /// 0 picks a free port, which is printed on startup.
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Write the bound `host:port` address into the file.
+    #[arg(long)]
+    port_file: Option<PathBuf>,
+
+    /// Write the bound address and an access token into the discovery
+    /// file for clients. Inside a batch allocation the file is written to
+    /// the default path even if not set.
+    #[arg(long)]
+    discovery_file: Option<PathBuf>,
+
+    /// Announce the server on the LAN with mDNS, as `_gosh-runner._tcp`.
+    #[cfg(feature = "mdns")]
+    #[arg(long)]
+    mdns: bool,
+
+    /// The TOML file of users allowed to connect over TCP, identified by
+    /// their tokens. Each user can only access own jobs unless admin.
+    #[arg(long)]
+    users: Option<PathBuf>,
+
+    /// Limit each TCP client to the number of requests per second.
+    #[arg(long)]
+    rate_limit: Option<f64>,
+
+    /// Run as a gateway on the port, forwarding jobs to workers joined by
+    /// --join with most free resources, instead of running jobs locally.
+    /// Requires --users.
+    #[arg(long, requires = "port")]
+    gateway: bool,
+
+    /// Join the gateway at the address, e.g. "gateway:3030", registering
+    /// this server as a worker with its free resources.
+    #[arg(long, requires = "port", requires = "gateway_token")]
+    join: Option<String>,
+
+    /// The token of an admin user of the gateway for joining it.
+    #[arg(long, env = "GOSH_GATEWAY_TOKEN", hide_env_values = true)]
+    gateway_token: Option<String>,
+
+    /// Refuse new jobs when the number of jobs waiting to start reaches
+    /// the limit, telling clients to retry later.
+    #[arg(long)]
+    max_pending: Option<usize>,
+
+    /// Refuse new jobs when working directories of all jobs take more
+    /// than the GiB of disk space, telling clients to retry later.
+    #[arg(long)]
+    scratch_quota: Option<f64>,
+
+    /// The sendmail-compatible command for sending job notifications by
+    /// email.
+    #[arg(long, default_value = "sendmail")]
+    sendmail: String,
+
+    /// The sender address of notification emails.
+    #[arg(long)]
+    mail_from: Option<String>,
+
+    /// The default webhook URL for jobs requesting `webhook` notification.
+    #[arg(long)]
+    webhook: Option<String>,
+
+    /// Append accounting records of finished jobs into the CSV file, for
+    /// `gosh acct` and the `search` method.
+    #[arg(long)]
+    acct_file: Option<PathBuf>,
+
+    /// Append a record of each operation on jobs into the audit log file,
+    /// as JSON lines, for the `audit` method.
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Remove finished jobs except the last N, deleting their working
+    /// directories.
+    #[arg(long)]
+    keep_last: Option<usize>,
+
+    /// Remove finished jobs older than the hours, deleting their working
+    /// directories.
+    #[arg(long)]
+    max_age: Option<f64>,
+
+    /// Archive working directories of removed jobs into tarballs in the
+    /// directory.
+    #[arg(long)]
+    archive_dir: Option<PathBuf>,
+
+    /// Run in background as a daemon.
+    #[arg(long)]
+    daemon: bool,
+
+    /// Write the process id of the server into the file.
+    #[arg(long)]
+    pidfile: Option<PathBuf>,
+
+    /// Cache uploaded job files in the directory by content hash, so that
+    /// identical files are not transferred again. Put it on the same
+    /// filesystem as the scratch dir for cheap reflink copies.
+    #[arg(long)]
+    file_cache: Option<PathBuf>,
+
+    /// Validate submitted job scripts by the policy in TOML file, with
+    /// keys `max_size`, `syntax_check` and `denylist`.
+    #[arg(long)]
+    script_policy: Option<PathBuf>,
+
+    /// Point scratch env vars of jobs into their working directories by
+    /// the TOML file mapping var names to sub-directories, e.g.
+    /// `TMPDIR = "tmp"`. The default covers TMPDIR, GAUSS_SCRDIR,
+    /// ORCA_TMPDIR and others.
+    #[arg(long)]
+    scratch_vars: Option<PathBuf>,
+
+    /// Only run jobs in execution windows, e.g. "20:00-08:00, weekends",
+    /// unless jobs define their own. Queued jobs wait outside windows.
+    #[arg(long)]
+    window: Option<crate::window::ExecWindows>,
+
+    /// Hold back jobs when CPU temperature in Celsius exceeds the limit.
+    #[arg(long)]
+    max_temperature: Option<f64>,
+
+    /// Hold back jobs when 1-minute load average exceeds the limit.
+    #[arg(long)]
+    max_load: Option<f64>,
+
+    /// Also pause running jobs while held back by temperature or load.
+    #[arg(long)]
+    throttle_pause: bool,
+
+    /// Reduce running jobs to this many while held back by temperature or
+    /// load, pausing jobs started later, instead of pausing all of them.
+    #[arg(long, value_name = "N")]
+    throttle_pool: Option<usize>,
+
+    /// The directory for creating job working directories. The default is
+    /// current directory.
+    #[arg(long)]
+    scratch_dir: Option<PathBuf>,
+
+    /// Watch the spool directory for `*.job.toml` files instead of serving
+    /// requests on stdin.
+    #[arg(long)]
+    spool: Option<PathBuf>,
+
+    /// Serve requests from ZeroMQ REQ clients on the endpoint instead of
+    /// stdin, e.g. "tcp://*:5555". Endpoints reachable from other hosts
+    /// require --users, with the token in each request.
+    #[cfg(feature = "zmq")]
+    #[arg(long)]
+    zmq: Option<String>,
+
+    /// Serve gRPC requests on the address instead of stdin, e.g.
+    /// "127.0.0.1:3031". Addresses other than loopback require --users,
+    /// with the token in `authorization` metadata.
+    #[cfg(feature = "grpc")]
+    #[arg(long)]
+    grpc: Option<String>,
+
+    /// Consume jobs from MQTT broker at the address instead of stdin, e.g.
+    /// "localhost:1883".
+    #[cfg(feature = "mqtt")]
+    #[arg(long)]
+    mqtt: Option<String>,
+
+    /// The topic prefix for consuming jobs from MQTT broker.
+    #[cfg(feature = "mqtt")]
+    #[arg(long, default_value = "gosh-runner")]
+    mqtt_topic: String,
+}
+
+impl ServeCli {
+    pub(super) fn run(&self) -> Result<()> {
+        // resolve paths before changing into scratch dir
+        let cwd = std::env::current_dir()?;
+        let port_file = self.port_file.as_ref().map(|p| cwd.join(p));
+        let pidfile = self.pidfile.as_ref().map(|p| cwd.join(p));
+        let mut users = self.users.as_ref().map(|p| Users::from_file(&cwd.join(p))).transpose()?;
+        let in_allocation = ["SLURM_JOB_ID", "PBS_JOBID"].iter().any(|v| std::env::var_os(v).is_some());
+        let discovery_file = match &self.discovery_file {
+            Some(p) => Some(cwd.join(p)),
+            None if in_allocation && self.port.is_some() => Some(Endpoint::default_path()),
+            None => None,
+        };
+        let spool = self.spool.as_ref().map(|p| cwd.join(p));
+        let retention = self.retention_policy(&cwd)?;
+        if let Some(gib) = self.scratch_quota {
+            ensure!(gib.is_finite() && gib > 0.0, "invalid scratch quota: {}", gib);
+        }
+        let acct_file = self.acct_file.as_ref().map(|p| cwd.join(p));
+        let audit_log = self.audit_log.as_ref().map(|p| cwd.join(p));
+        let file_cache = self.file_cache.as_ref().map(|p| cwd.join(p));
+        let scratch_vars = match &self.scratch_vars {
+            Some(p) => {
+                let p = cwd.join(p);
+                let vars = std::collections::BTreeMap::<String, String>::from_toml(&gut::fs::read_file(&p)?)
+                    .with_context(|| format!("invalid scratch vars file {:?}", p))?;
+                Some(vars)
+            }
+            None => None,
+        };
+        let script_policy = self
+            .script_policy
+            .as_ref()
+            .map(|p| ScriptPolicy::from_file(&cwd.join(p)))
+            .transpose()?;
+        if let Some(dir) = &self.scratch_dir {
+            std::fs::create_dir_all(dir).with_context(|| format!("create scratch dir {:?}", dir))?;
+            std::env::set_current_dir(dir).with_context(|| format!("change into scratch dir {:?}", dir))?;
+        }
+
+        // bind before daemonizing, so that callers can read the address
+        // once we return.
+        let listener = match self.port {
+            Some(port) => {
+                // clients are only authenticated by users file, discovery
+                // token or gateway token
+                let loopback = ["127.0.0.1", "::1", "localhost"].contains(&self.bind.as_str());
+                ensure!(
+                    loopback || users.is_some() || discovery_file.is_some() || self.join.is_some(),
+                    "binding to {} requires --users, --discovery-file or --join for authentication",
+                    self.bind
+                );
+                let listener = std::net::TcpListener::bind((self.bind.as_str(), port))
+                    .with_context(|| format!("bind to {}:{}", self.bind, port))?;
+                let addr = listener.local_addr()?;
+                println!("{}", addr);
+                if let Some(f) = &port_file {
+                    gut::fs::write_to_file(f, &format!("{}\n", addr))?;
+                }
+                Some(listener)
+            }
+            None => None,
+        };
+        let mut endpoint = None;
+        if let Some(f) = &discovery_file {
+            let listener = listener.as_ref().context("discovery file requires --port")?;
+            endpoint = Endpoint::new(&reachable_address(listener)?)?.into();
+            info!("write discovery file: {:?}", f);
+        }
+        // the gateway calls this server with its own token
+        let mut join = None;
+        if let (Some(address), Some(token)) = (&self.join, &self.gateway_token) {
+            let listener = listener.as_ref().context("joining gateway requires --port")?;
+            let worker = Endpoint::new(&reachable_address(listener)?)?;
+            let users = users.get_or_insert_with(Users::default);
+            users.insert(&worker.token, User::admin("gateway"));
+            let gateway = Endpoint {
+                address: address.clone(),
+                token: token.clone(),
+                pid: 0,
+            };
+            join = (gateway, worker).into();
+        }
+        if self.daemon {
+            ensure!(
+                listener.is_some() || spool.is_some(),
+                "daemon mode requires --port or --spool, as stdin is not available"
+            );
+            nix::unistd::daemon(true, false).context("daemonize")?;
+        }
+        if let Some(f) = &pidfile {
+            gut::fs::write_to_file(f, &format!("{}\n", std::process::id()))?;
+        }
+        // written after daemonizing for the right pid
+        if let (Some(f), Some(endpoint)) = (&discovery_file, endpoint.as_mut()) {
+            endpoint.pid = std::process::id();
+            endpoint.write(f)?;
+        }
+
+        let mut notifier = crate::notify::Notifier::default().sendmail(&self.sendmail);
+        if let Some(addr) = &self.mail_from {
+            notifier = notifier.from(addr);
+        }
+        if let Some(url) = &self.webhook {
+            notifier = notifier.webhook(url);
+        }
+        let mut db = Db::new().with_notifier(notifier);
+        if let Some(f) = &acct_file {
+            db = db.with_accounting(crate::acct::Accounting::new(f));
+        }
+        if let Some(f) = &audit_log {
+            db = db.with_audit(crate::audit::AuditLog::new(f));
+        }
+        if let Some(n) = self.max_pending {
+            db = db.with_max_pending(n);
+        }
+        if let Some(gib) = self.scratch_quota {
+            db = db.with_scratch_quota((gib * (1u64 << 30) as f64) as u64);
+        }
+        if let Some(dir) = &file_cache {
+            db = db.with_file_cache(crate::cache::FileCache::new(dir));
+        }
+        if let Some(policy) = script_policy {
+            db = db.with_script_policy(policy);
+        }
+        if let Some(vars) = scratch_vars {
+            db = db.with_scratch_vars(vars);
+        }
+        if let Some(window) = &self.window {
+            db = db.with_window(window.clone());
+        }
+        if self.max_temperature.is_some() || self.max_load.is_some() {
+            let mut throttle = SensorThrottle::new(self.max_temperature, self.max_load).pause(self.throttle_pause);
+            if let Some(n) = self.throttle_pool {
+                throttle = throttle.reduce_pool(n);
+            }
+            db = db.with_throttle(throttle);
+        }
+        let rt = tokio::runtime::Runtime::new().context("tokio runtime failure")?;
+        // jobs left by a previous server in the scratch dir
+        let recovered = rt.block_on(db.recover_jobs(".".as_ref()))?;
+        if !recovered.is_empty() {
+            info!("recovered {} jobs: {:?}", recovered.len(), recovered);
+        }
+        {
+            // background tasks run along with the server
+            let _guard = rt.enter();
+            if let Some(policy) = retention {
+                db.spawn_gc(policy, GC_INTERVAL);
+            }
+            db.spawn_reaper(REAPER_INTERVAL);
+            if let Some((gateway, worker)) = join {
+                let join = crate::federation::join_gateway(db.clone(), gateway, worker, JOIN_INTERVAL);
+                tokio::spawn(join);
+            }
+        }
+        #[cfg(feature = "zmq")]
+        if let Some(endpoint) = &self.zmq {
+            return crate::zmq_server::serve(db, endpoint, users, &rt);
+        }
+        let grace = std::time::Duration::from_secs(10);
+        #[cfg(feature = "grpc")]
+        if let Some(addr) = &self.grpc {
+            let mut service = crate::grpc::RunnerService::new(db.clone());
+            if let Some(users) = users {
+                service = service.users(users);
+            }
+            return rt.block_on(async {
+                let serve = service.serve(addr);
+                run_until_shutdown(&db, serve, grace).await
+            });
+        }
+        if let Some(listener) = listener {
+            // announce after daemonizing, as the responder runs in a thread
+            #[cfg(feature = "mdns")]
+            let _announcement = match self.mdns {
+                true => {
+                    let name = std::fs::read_to_string("/proc/sys/kernel/hostname")?.trim().to_owned();
+                    crate::mdns::Announcement::new(&name, listener.local_addr()?.port())?.into()
+                }
+                false => None,
+            };
+            // the discovery token identifies the owner of the server
+            if let Some(endpoint) = &endpoint {
+                users.get_or_insert_with(Users::default).insert(&endpoint.token, User::current());
+            }
+            let r = rt.block_on(async {
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                if self.gateway {
+                    let users = users.context("gateway requires --users")?;
+                    let serve = crate::federation::GatewayServer::new().serve(listener, users);
+                    return run_until_shutdown(&db, serve, grace).await;
+                }
+                let mut server = crate::jsonrpc::TcpServer::new();
+                if let Some(users) = users {
+                    server = server.users(users);
+                }
+                if let Some(rate) = self.rate_limit {
+                    // allow short bursts of a few seconds
+                    server = server.rate_limit(rate, (rate * 5.0).ceil() as usize);
+                }
+                let serve = server.serve(db.clone(), listener);
+                run_until_shutdown(&db, serve, grace).await
+            });
+            if let Some(f) = &discovery_file {
+                let _ = std::fs::remove_file(f);
+            }
+            return r;
+        }
+        if let Some(dir) = &spool {
+            return rt.block_on(async {
+                let watch = crate::spool::watch_spool(db.clone(), dir, 2.0);
+                run_until_shutdown(&db, watch, grace).await
+            });
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(addr) = &self.mqtt {
+            let (host, port) = addr.rsplit_once(':').unwrap_or((addr, "1883"));
+            let consumer = crate::mqtt::MqttConsumer::new(host, port.parse()?, &self.mqtt_topic);
+            return rt.block_on(async {
+                let consume = consumer.run(db.clone());
+                run_until_shutdown(&db, consume, grace).await
+            });
+        }
+        rt.block_on(async {
+            let serve = crate::jsonrpc::serve_stdio(db.clone());
+            run_until_shutdown(&db, serve, grace).await
+        })?;
+        Ok(())
+    }
+
+    /// Return the retention policy for finished jobs if any limit is set,
+    /// with paths relative to `cwd`.
+    fn retention_policy(&self, cwd: &Path) -> Result<Option<RetentionPolicy>> {
+        if self.keep_last.is_none() && self.max_age.is_none() {
+            ensure!(
+                self.archive_dir.is_none(),
+                "--archive-dir requires --keep-last or --max-age"
+            );
+            return Ok(None);
+        }
+        let mut policy = RetentionPolicy::default();
+        if let Some(n) = self.keep_last {
+            policy = policy.keep_last(n);
+        }
+        if let Some(hours) = self.max_age {
+            ensure!(hours.is_finite() && hours >= 0.0, "invalid max age: {}", hours);
+            policy = policy.max_age(std::time::Duration::from_secs_f64(hours * 3600.0));
+        }
+        if let Some(dir) = &self.archive_dir {
+            policy = policy.archive_into(cwd.join(dir));
+        }
+        Ok(Some(policy))
+    }
+}
+
+/// The interval in seconds for removing finished jobs by retention policy.
+const GC_INTERVAL: f64 = 60.0;
+
+/// The interval in seconds for registering to the gateway.
+const JOIN_INTERVAL: f64 = 10.0;
+
+/// Return the address of `listener` reachable from other hosts, with the
+/// host name for a wildcard address.
+fn reachable_address(listener: &std::net::TcpListener) -> Result<String> {
+    let addr = listener.local_addr()?;
+    let host = if addr.ip().is_unspecified() {
+        std::fs::read_to_string("/proc/sys/kernel/hostname")?.trim().to_owned()
+    } else {
+        addr.ip().to_string()
+    };
+    Ok(format!("{}:{}", host, addr.port()))
+}
+
+/// The interval in seconds for cleaning up orphaned processes of jobs.
+const REAPER_INTERVAL: f64 = 30.0;
+// 9c85a1e3 ends here
@@ -1,37 +1,60 @@
 // [[file:../runners.note::310bb968][310bb968]]
-use std::path::{Path, PathBuf};
-
 use super::*;
-use crate::server::*;
 
-use crate::job::{Job, JobId};
+use crate::discovery::Endpoint;
+use crate::job::{FileStat, Job, JobId, WaitPolicy};
+use crate::jsonrpc::RpcClient;
+use crate::parser::JobResult;
+use serde_json::json;
 // 310bb968 ends here
 
 // [[file:../runners.note::c49b4af1][c49b4af1]]
-/// The client side for remote computation
+/// The client side for remote computation, using JSON-RPC over TCP.
 #[derive(Clone, Debug)]
 pub struct Client {
-    server_addr: String,
+    rpc: RpcClient,
 }
 
-impl Default for Client {
-    fn default() -> Self {
+impl Client {
+    /// Create a client for the server at `addr` like "127.0.0.1:3030",
+    /// which requires no access token.
+    pub fn new(addr: &str) -> Self {
+        let endpoint = Endpoint {
+            address: addr.into(),
+            ..Default::default()
+        };
+        Self::from_endpoint(endpoint)
+    }
+
+    /// Create a client for the server at `endpoint`.
+    pub fn from_endpoint(endpoint: Endpoint) -> Self {
         Self {
-            server_addr: format!("http://{}", DEFAULT_SERVER_ADDRESS),
+            rpc: RpcClient::new(endpoint),
         }
     }
-}
 
-impl Client {
-    /// Create a client with specific server address.
-    pub fn new(addr: &str) -> Self {
-        let server_addr = if addr.starts_with("http://") {
-            addr.into()
-        } else {
-            format!("http://{}", addr)
-        };
+    /// Create a client for the server found in the default discovery file,
+    /// which is written by the server started in the same batch allocation.
+    pub fn connect_auto() -> Result<Self> {
+        Self::connect_discovery(&Endpoint::default_path())
+    }
+
+    /// Create a client for the server found in discovery file `path`.
+    pub fn connect_discovery(path: &Path) -> Result<Self> {
+        let endpoint = Endpoint::read(path).context("no running server found")?;
+        debug!("found server {} in {:?}", endpoint.address, path);
+        Ok(Self::from_endpoint(endpoint))
+    }
+
+    /// Return job servers announced on the LAN with mDNS.
+    #[cfg(feature = "mdns")]
+    pub fn discover() -> Result<Vec<crate::mdns::DiscoveredServer>> {
+        crate::mdns::discover(std::time::Duration::from_secs(3))
+    }
 
-        Self { server_addr }
+    /// Return the access token of the server, if any.
+    pub fn token(&self) -> Option<&str> {
+        Some(self.rpc.endpoint().token.as_str()).filter(|t| !t.is_empty())
     }
 }
 // c49b4af1 ends here
@@ -39,116 +62,260 @@ impl Client {
 // [[file:../runners.note::f2bffcbd][f2bffcbd]]
 impl Client {
     pub fn server_address(&self) -> &str {
-        self.server_addr.as_ref()
+        &self.rpc.endpoint().address
     }
 
     /// Request server to delete a job from queue.
     pub fn delete_job(&self, id: JobId) -> Result<()> {
-        let url = format!("{}/jobs/{}", self.server_addr, id);
-        let new = reqwest::blocking::Client::new().delete(&url).send()?;
-        dbg!(new.text());
-
-        Ok(())
+        self.rpc.call("delete", json!({ "id": id }))
     }
 
-    /// Wait job to be done.
-    pub fn wait_job(&self, id: JobId) -> Result<()> {
-        let url = format!("{}/jobs/{}", self.server_addr, id);
-
-        // NOTE: the default request timeout is 30 seconds. Here we disable
-        // timeout using reqwest builder.
-        //
-        let new = reqwest::blocking::Client::builder()
-            // .timeout(Duration::from_millis(500))
-            .timeout(None)
-            .build()
-            .unwrap()
-            .get(&url)
-            .send()?;
+    /// Wait job to be done, returning its result.
+    pub fn wait_job(&self, id: JobId) -> Result<JobResult> {
+        self.rpc.call("wait", json!({ "id": id }))
+    }
 
-        dbg!(new);
+    /// Wait until all jobs `ids` are done in a single request, returning
+    /// their results in the order they finished.
+    pub fn wait_all(&self, ids: &[JobId]) -> Result<Vec<(JobId, JobResult)>> {
+        self.rpc.wait_all(ids)
+    }
 
-        Ok(())
+    /// Wait for jobs `ids` according to `policy` in a single request,
+    /// returning results of finished jobs in the order they finished.
+    pub fn wait_jobs(&self, ids: &[JobId], policy: WaitPolicy) -> Result<Vec<(JobId, JobResult)>> {
+        self.rpc.wait_jobs(ids, policy)
     }
 
     /// Request server to create a job.
     pub fn create_job(&self, script: &str) -> Result<JobId> {
-        let url = format!("{}/jobs/", self.server_addr);
-        let job = Job::new(script);
-        let new = reqwest::blocking::Client::new().post(&url).json(&job).send()?;
+        self.create_job_with_key(script, None)
+    }
+
+    /// Request server to create a job with idempotency `key`. Retrying with
+    /// the same key returns the job created earlier instead of a new one.
+    pub fn create_job_with_key(&self, script: &str, key: Option<&str>) -> Result<JobId> {
+        let mut params = serde_json::to_value(Job::new(script))?;
+        if let Some(key) = key {
+            params["idempotency_key"] = key.into();
+        }
+        let id = self.rpc.call("submit", params)?;
+        debug!("created job {}", id);
+        Ok(id)
+    }
+
+    /// Request server to create `job` built with options other than the
+    /// script, such as `Job::keep_stdin_open`.
+    pub fn submit_job(&self, job: &Job) -> Result<JobId> {
+        let id = self.rpc.call("submit", serde_json::to_value(job)?)?;
+        debug!("created job {}", id);
+        Ok(id)
+    }
+
+    /// Request server to create a job array from `script` with indices in
+    /// `range` like "1..100". Return the array id and its child jobs.
+    pub fn create_job_array(&self, script: &str, range: &str) -> Result<(u64, Vec<JobId>)> {
+        #[derive(Deserialize)]
+        struct Created {
+            array: u64,
+            jobs: Vec<JobId>,
+        }
+
+        let mut job = Job::new(script);
+        job.array(range);
+        let Created { array, jobs } = self.rpc.call("submit_array", serde_json::to_value(job)?)?;
+        debug!("created job array {} of {} jobs", array, jobs.len());
+        Ok((array, jobs))
+    }
+
+    /// Return aggregate status of child jobs in job `array`.
+    pub fn get_array_status(&self, array: u64) -> Result<crate::job::ArrayStatus> {
+        self.rpc.call("array_status", json!({ "array": array }))
+    }
 
-        let resp = new.text().context("client requests to create job")?;
-        debug!("server response: {}", resp);
-        let job_id: JobId = resp.trim().parse()?;
-        Ok(job_id)
+    /// Request server to cancel all child jobs in job `array`.
+    pub fn cancel_array(&self, array: u64) -> Result<()> {
+        self.rpc.call("cancel_array", json!({ "array": array }))
+    }
+
+    /// Write `data` into stdin of running job `id`, e.g. "stop\n" for
+    /// finishing cleanly. The job must keep its stdin open.
+    pub fn send_stdin(&self, id: JobId, data: &str) -> Result<()> {
+        self.rpc.call("stdin", json!({ "id": id, "data": data }))
+    }
+
+    /// Close stdin of running job `id` kept open, so that it reads end of
+    /// file.
+    pub fn close_stdin(&self, id: JobId) -> Result<()> {
+        self.rpc.call("stdin", json!({ "id": id, "close": true }))
+    }
+
+    /// Search finished jobs in server history with `query`, like
+    /// `status=failed&since=2024-01-01&name~=opt`.
+    pub fn search_jobs(&self, query: &str) -> Result<Vec<crate::acct::AcctRecord>> {
+        let query = query.trim_start_matches('?');
+        self.rpc.call("search", json!({ "query": query }))
     }
 
     /// Request server to list current jobs in queue.
-    pub fn list_jobs(&self) -> Result<()> {
-        let url = format!("{}/jobs", self.server_addr);
-        let x = reqwest::blocking::get(&url)?.text()?;
-        dbg!(x);
-        Ok(())
+    pub fn list_jobs(&self) -> Result<Vec<JobId>> {
+        self.rpc.call("list_jobs", json!(null))
+    }
+
+    /// Request server to list current jobs having all the `tags`, e.g.
+    /// `&[("project", "perovskites")]`.
+    pub fn list_jobs_tagged(&self, tags: &[(&str, &str)]) -> Result<Vec<JobId>> {
+        let tags: std::collections::BTreeMap<_, _> = tags.iter().copied().collect();
+        self.rpc.call("list_jobs", json!({ "tags": tags }))
+    }
+
+    /// Request server to update tags of job `id`, e.g. `&[("method",
+    /// "PBE0")]`. A tag with empty value is removed.
+    pub fn tag_job(&self, id: JobId, tags: &[(&str, &str)]) -> Result<()> {
+        let tags: std::collections::BTreeMap<_, _> = tags.iter().copied().collect();
+        self.rpc.call("tag", json!({ "id": id, "tags": tags }))
+    }
+
+    /// Request server to create a fresh job from the spec of job `id`,
+    /// copying `files` in its working directory as restart inputs.
+    pub fn clone_job(&self, id: JobId, files: &[&str]) -> Result<JobId> {
+        let new_id = self.rpc.call("clone", json!({ "id": id, "files": files }))?;
+        debug!("job {} cloned from job {}", new_id, id);
+        Ok(new_id)
+    }
+
+    /// Return resource usage samples of job `id` in CSV format, with
+    /// columns of time, number of processes, CPU time and RSS in bytes.
+    pub fn get_job_usage(&self, id: JobId) -> Result<String> {
+        self.rpc.call("usage", json!({ "id": id }))
     }
 
     /// Request server to list files of specified job `id`.
-    pub fn list_job_files(&self, id: JobId) -> Result<()> {
-        let url = format!("{}/jobs/{}/files", self.server_addr, id);
-        let x = reqwest::blocking::get(&url)?.text()?;
-        dbg!(x);
-        Ok(())
+    pub fn list_job_files(&self, id: JobId) -> Result<Vec<PathBuf>> {
+        self.rpc.call("list_files", json!({ "id": id }))
     }
 
-    /// Download a job file from the server.
-    pub fn get_job_file(&self, id: JobId, fname: &str) -> Result<()> {
-        let url = format!("{}/jobs/{}/files/{}", self.server_addr, id, fname);
-        let mut resp = reqwest::blocking::get(&url)?;
-        let mut f = std::fs::File::create(fname)?;
-        let m = resp.copy_to(&mut f)?;
-        info!("copyed {} bytes.", m);
+    /// Request server to show the process tree of running job `id`.
+    pub fn list_job_processes(&self, id: JobId) -> Result<Vec<crate::process::ProcessNode>> {
+        self.rpc.get_job_processes(id)
+    }
 
+    /// Request server to checkpoint running job `id` into `dir` inside its
+    /// working directory, returning the full path.
+    pub fn checkpoint_job(&self, id: JobId, dir: &str) -> Result<PathBuf> {
+        self.rpc.checkpoint_job(id, dir)
+    }
+
+    /// Request server to show audit log of operations on jobs.
+    pub fn get_audit(&self, id: Option<JobId>) -> Result<Vec<crate::audit::AuditEntry>> {
+        self.rpc.get_audit(id)
+    }
+
+    /// Download a job file from the server into current directory.
+    pub fn get_job_file(&self, id: JobId, fname: &str) -> Result<()> {
+        let f = std::io::BufWriter::new(std::fs::File::create(fname)?);
+        let n = self.rpc.download_job_file(id, fname.as_ref(), f)?;
+        info!("copied {} bytes.", n);
         Ok(())
     }
 
-    /// Upload a job file to the server.
-    pub fn put_job_file<P: AsRef<Path>>(&self, id: JobId, path: P) -> Result<()> {
-        use std::io::*;
+    /// Wait until a file matching glob `pattern` appears in working
+    /// directory of job `id`, e.g. "CONVERGED". The server watches the
+    /// directory with inotify, so no polling is required. Return the file
+    /// name, or None if `timeout` elapsed. The server limits the timeout
+    /// to an hour.
+    pub fn wait_for_file(&self, id: JobId, pattern: &str, timeout: Option<f64>) -> Result<Option<String>> {
+        let params = json!({ "id": id, "pattern": pattern, "timeout": timeout });
+        let name: Option<PathBuf> = self.rpc.call("wait_file", params)?;
+        Ok(name.map(|p| p.to_string_lossy().into_owned()))
+    }
 
-        let path = path.as_ref();
-        assert!(path.is_file(), "{}: is not a file!", path.display());
+    /// Mirror working directory of job `id` into `local_dir`, downloading
+    /// files that are new or changed since last sync. It can be called
+    /// repeatedly while the job runs. Return names of downloaded files.
+    pub fn sync_job_dir<P: AsRef<Path>>(&self, id: JobId, local_dir: P) -> Result<Vec<PathBuf>> {
+        let local_dir = local_dir.as_ref();
+        let stats: Vec<FileStat> = self.rpc.call("file_stats", json!({ "id": id }))?;
+        let mut synced = vec![];
+        for stat in stats {
+            let dst = local_dir.join(&stat.name);
+            let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs_f64(stat.modified);
+            // modification time of synced file is set to the remote one
+            let unchanged = dst
+                .metadata()
+                .map_or(false, |m| m.len() == stat.size && m.modified().ok() == Some(modified));
+            if unchanged {
+                continue;
+            }
+            if let Some(dir) = dst.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            // download into a temporary file, as analysis scripts may be
+            // reading the old one
+            let tmp = dst.with_extension("sync-tmp");
+            let mut f = std::fs::File::create(&tmp)?;
+            self.rpc.download_job_file(id, &stat.name, &mut f)?;
+            f.set_modified(modified)?;
+            std::fs::rename(&tmp, &dst)?;
+            debug!("synced {:?}", stat.name);
+            synced.push(stat.name);
+        }
+        Ok(synced)
+    }
 
-        if let Some(fname) = &path.file_name() {
-            let fname = fname.to_str().expect("invalid filename");
-            let url = format!("{}/jobs/{}/files/{}", self.server_addr, id, fname);
+    /// Upload a job file to the server, sending only blocks changed since
+    /// the copy on server. Useful for updated restart files.
+    pub fn put_job_file_delta<P: AsRef<Path>>(&self, id: JobId, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let fname: &Path = path.file_name().context("not a file")?.as_ref();
+        let sig = self.rpc.get_job_file_signature(id, fname)?;
+        let delta = crate::delta::delta_file(&sig, path)?;
+        debug!("upload {} bytes delta for {:?}", delta.len(), fname);
+        self.rpc.patch_job_file(id, fname, &delta)?;
+        Ok(())
+    }
 
-            // read the whole file into bytes
-            let mut bytes = vec![];
-            let mut f = std::fs::File::open(path)?;
-            f.read_to_end(&mut bytes)?;
+    /// Download a job file from the server like `get_job_file`, receiving
+    /// only blocks changed since the local copy.
+    pub fn get_job_file_delta(&self, id: JobId, fname: &str) -> Result<()> {
+        let sig = crate::delta::signature_file(fname.as_ref())?;
+        let delta = self.rpc.get_job_file_delta(id, fname.as_ref(), &sig)?;
+        debug!("received {} bytes delta for {}", delta.len(), fname);
+        crate::delta::patch_file(fname.as_ref(), &delta)?;
+        Ok(())
+    }
 
-            // send the raw bytes using PUT request
-            let res = reqwest::blocking::Client::new().put(&url).body(bytes).send()?;
-        } else {
-            bail!("{}: not a file!", path.display());
+    /// Upload a job file to the server.
+    pub fn put_job_file<P: AsRef<Path>>(&self, id: JobId, path: P) -> Result<()> {
+        let path = path.as_ref();
+        ensure!(path.is_file(), "{}: is not a file!", path.display());
+        let fname = path.file_name().context("not a file")?.to_string_lossy();
+
+        // skip the upload if the server has cached the same content
+        let hash = crate::cache::FileCache::hash_file(path)?;
+        let params = json!({ "id": id, "file": fname, "hash": hash });
+        if self.rpc.call("link_cached_file", params)? {
+            debug!("{} linked from server cache", fname);
+            return Ok(());
         }
 
+        // stream the file content in chunks
+        let f = std::fs::File::open(path)?;
+        let n = self.rpc.upload_job_file(id, &fname, std::io::BufReader::new(f))?;
+        debug!("uploaded {} bytes for {}", n, fname);
         Ok(())
     }
 
-    /// Shutdown app server. This will kill all running processes and remove all
-    /// job files.
+    /// Request server to remove all jobs. This will kill all running
+    /// processes and remove all job files.
     pub fn shutdown_server(&self) -> Result<()> {
-        let url = format!("{}/jobs", self.server_addr);
-        let new = reqwest::blocking::Client::new().delete(&url).send()?;
-        dbg!(new);
-
-        Ok(())
+        self.rpc.call("clear", json!(null))
     }
 }
 // f2bffcbd ends here
 
 // [[file:../runners.note::899c0fa6][899c0fa6]]
-use gut::{cli::*, prelude::*};
+use gut::cli::*;
 
 /// A commander for interactive interpreter
 #[derive(Default)]
@@ -162,82 +329,129 @@ impl Command {
     }
 }
 
-#[derive(ValueEnum)]
-// #[clap(setting = clap::clap::AppSettings::VersionlessSubcommands)]
+#[derive(Parser)]
+#[command(name = "app>", no_binary_name = true)]
 enum Action {
     /// Quit REPL shell.
-    #[clap(name = "quit", alias = "q", alias = "exit")]
+    #[command(name = "quit", alias = "q", alias = "exit")]
     Quit {},
 
     /// Show available commands.
-    #[clap(name = "help", alias = "h", alias = "?")]
+    #[command(name = "help", alias = "h", alias = "?")]
     Help {},
 
     /// List job/jobs submited in the server.
-    #[clap(name = "ls", alias = "l", alias = "ll")]
+    #[command(name = "ls", alias = "l", alias = "ll")]
     List {
         /// Job id
-        #[clap(name = "JOB-ID")]
+        #[arg(value_name = "JOB-ID")]
         id: Option<JobId>,
+
+        /// Only list jobs having the tag.
+        #[arg(value_name = "KEY=VALUE", long = "tag")]
+        tags: Vec<String>,
+    },
+
+    /// Update tags of a job. A tag with empty value is removed.
+    #[command(name = "tag")]
+    Tag {
+        /// Job id
+        #[arg(value_name = "JOB-ID")]
+        id: JobId,
+
+        /// Tags to be set.
+        #[arg(value_name = "KEY=VALUE", required = true)]
+        tags: Vec<String>,
     },
 
     /// Request to delete a job from the server.
-    #[clap(name = "delete", alias = "del")]
+    #[command(name = "delete", alias = "del")]
     Delete {
         /// Job id
-        #[clap(name = "JOB-ID")]
+        #[arg(value_name = "JOB-ID")]
         id: JobId,
     },
 
     /// Wait until job is done.
-    #[clap(name = "wait")]
+    #[command(name = "wait")]
     Wait {
         /// Job id
-        #[clap(name = "JOB-ID")]
+        #[arg(value_name = "JOB-ID")]
         id: JobId,
     },
 
     /// Submit a job to the server.
-    #[clap(name = "submit", alias = "sub")]
+    #[command(name = "submit", alias = "sub")]
     Submit {
         /// Set script file.
-        #[clap(name = "SCRIPT-FILE", parse(from_os_str))]
+        #[arg(value_name = "SCRIPT-FILE")]
         script_file: PathBuf,
+
+        /// Prepare the job in a local working directory and print what
+        /// would be executed, without submitting it.
+        #[arg(long)]
+        dry_run: bool,
     },
 
     /// Download a job file from the server.
-    #[clap(name = "get", alias = "download")]
+    #[command(name = "get", alias = "download")]
     Get {
         /// Job file name to be downloaded from the server.
-        #[clap(name = "FILE-NAME")]
+        #[arg(value_name = "FILE-NAME")]
         file_name: String,
 
         /// Job id
-        #[clap(name = "JOB-ID", long = "id")]
+        #[arg(value_name = "JOB-ID", long = "id")]
         id: JobId,
     },
 
     ///Shutdown the remote server.
-    #[clap(name = "shutdown")]
+    #[command(name = "shutdown")]
     Shutdown {},
 
     /// Upload a job file to the server.
-    #[clap(name = "put", alias = "upload")]
+    #[command(name = "put", alias = "upload")]
     Put {
         /// Job file name to be uploaded to the server.
-        #[clap(name = "FILE-NAME")]
+        #[arg(value_name = "FILE-NAME")]
         file_name: String,
 
         /// Job id
-        #[clap(name = "JOB-ID", long = "id")]
+        #[arg(value_name = "JOB-ID", long = "id")]
+        id: JobId,
+    },
+
+    /// Show resource usage samples of a job in CSV format.
+    #[command(name = "usage")]
+    Usage {
+        /// Job id
+        #[arg(value_name = "JOB-ID")]
         id: JobId,
     },
 
+    /// Mirror working directory of a job into a local directory.
+    #[command(name = "sync")]
+    Sync {
+        /// Job id
+        #[arg(value_name = "JOB-ID")]
+        id: JobId,
+
+        /// The local directory for downloaded files.
+        #[arg(value_name = "LOCAL-DIR", default_value = ".")]
+        local_dir: PathBuf,
+    },
+
+    /// Find job servers announced on the LAN.
+    #[cfg(feature = "mdns")]
+    #[command(name = "discover")]
+    Discover {},
+
     /// Connect to app server.
-    #[clap(name = "connect")]
+    #[command(name = "connect")]
     Connect {
-        /// Application server.
-        #[clap(name = "SERVER-ADDRESS")]
+        /// Application server. The server in the default discovery file is
+        /// used if not set.
+        #[arg(value_name = "SERVER-ADDRESS")]
         server_address: Option<String>,
     },
 }
@@ -246,30 +460,45 @@ impl Command {
     pub fn apply(&mut self, action: &Action) -> Result<()> {
         match action {
             Action::Connect { server_address } => {
-                let c = if let Some(addr) = &server_address {
-                    Client::new(addr)
-                } else {
-                    Client::default()
+                let c = match server_address {
+                    Some(addr) => Client::new(addr),
+                    None => Client::connect_auto()?,
                 };
                 println!("connected to {}.", c.server_address());
                 self.client = Some(c);
             }
-            Action::List { id } => {
+            #[cfg(feature = "mdns")]
+            Action::Discover {} => {
+                for server in Client::discover()? {
+                    println!("{}\t{}", server.name, server.address);
+                }
+            }
+            Action::List { id, tags } => {
                 let client = self.client()?;
                 if let Some(id) = id {
-                    client.list_job_files(*id)?;
+                    for f in client.list_job_files(*id)? {
+                        println!("{}", f.display());
+                    }
                 } else {
-                    client.list_jobs()?;
+                    for id in client.list_jobs_tagged(&parse_tags(tags)?)? {
+                        println!("{}", id);
+                    }
                 }
             }
-            Action::Submit { script_file } => {
-                use std::io::Read;
-
+            Action::Tag { id, tags } => {
                 let client = self.client()?;
-                let mut f = std::fs::File::open(script_file)?;
-                let mut buf = String::new();
-                let _ = f.read_to_string(&mut buf)?;
-                client.create_job(&buf)?;
+                client.tag_job(*id, &parse_tags(tags)?)?;
+            }
+            Action::Submit { script_file, dry_run } => {
+                let buf = gut::fs::read_file(script_file)?;
+                if *dry_run {
+                    let wdir = Job::new(&buf).dry_run()?;
+                    println!("dry run: job files kept in {}", wdir.display());
+                    return Ok(());
+                }
+                let client = self.client()?;
+                let id = client.create_job(&buf)?;
+                println!("submitted job {}", id);
             }
             Action::Delete { id } => {
                 let client = self.client()?;
@@ -277,7 +506,8 @@ impl Command {
             }
             Action::Wait { id } => {
                 let client = self.client()?;
-                client.wait_job(*id)?;
+                let result = client.wait_job(*id)?;
+                println!("{:?}", result.status);
             }
             Action::Get { file_name, id } => {
                 let client = self.client()?;
@@ -287,13 +517,21 @@ impl Command {
                 let client = self.client()?;
                 client.put_job_file(*id, file_name)?;
             }
+            Action::Usage { id } => {
+                let client = self.client()?;
+                print!("{}", client.get_job_usage(*id)?);
+            }
+            Action::Sync { id, local_dir } => {
+                let client = self.client()?;
+                for f in client.sync_job_dir(*id, local_dir)? {
+                    println!("{}", f.display());
+                }
+            }
             Action::Shutdown {} => {
                 let client = self.client()?;
                 client.shutdown_server()?;
             }
-            _ => {
-                eprintln!("not implemented yet.");
-            }
+            Action::Quit {} | Action::Help {} => {}
         }
 
         Ok(())
@@ -309,57 +547,197 @@ impl Command {
     }
 }
 
-pub fn enter_main() -> Result<()> {
-    use linefeed::{Interface, ReadResult};
+/// Parse tags given as "KEY=VALUE".
+fn parse_tags(tags: &[String]) -> Result<Vec<(&str, &str)>> {
+    tags.iter()
+        .map(|t| {
+            t.split_once('=')
+                .ok_or_else(|| format_err!("invalid tag {:?}, expect KEY=VALUE", t))
+        })
+        .collect()
+}
 
-    let interface = Interface::new("application runner client")?;
+/// Enter the interactive shell for driving a remote server.
+pub fn enter_main() -> Result<()> {
+    use std::io::{BufRead, Write};
 
     let version = env!("CARGO_PKG_VERSION");
     println!("This is the rusty gosh shell version {}.", version);
     println!("Enter \"help\" or \"?\" for a list of commands.");
     println!("Press Ctrl-D or enter \"quit\" or \"q\" to exit.");
-    println!("");
-
-    interface.set_prompt("app> ")?;
+    println!();
 
     let mut command = Command::new();
-    while let ReadResult::Input(line) = interface.read_line()? {
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        print!("app> ");
+        std::io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
         let line = line.trim();
-        if !line.is_empty() {
-            interface.add_history(line.to_owned());
-
-            let mut args: Vec<_> = line.split_whitespace().collect();
-            args.insert(0, "app>");
-
-            match Action::try_parse_from(&args) {
-                // show subcommands
-                Ok(Action::Help {}) => {
-                    let mut app = Action::into_app();
-                    app.print_help();
-                    println!("");
-                }
+        if line.is_empty() {
+            continue;
+        }
+        match Action::try_parse_from(line.split_whitespace()) {
+            // show subcommands
+            Ok(Action::Help {}) => {
+                Action::command().print_help()?;
+                println!();
+            }
 
-                Ok(Action::Quit {}) => {
-                    break;
-                }
+            Ok(Action::Quit {}) => {
+                break;
+            }
 
-                // apply subcommand
-                Ok(x) => {
-                    if let Err(e) = command.apply(&x) {
-                        eprintln!("{:?}", e);
-                    }
+            // apply subcommand
+            Ok(x) => {
+                if let Err(e) = command.apply(&x) {
+                    eprintln!("{:?}", e);
                 }
+            }
 
-                // show subcommand usage
-                Err(e) => {
-                    e.print()?;
-                }
+            // show subcommand usage
+            Err(e) => {
+                e.print()?;
             }
-        } else {
-            println!("");
         }
     }
 
     Ok(())
 }
 // 899c0fa6 ends here
+
+// [[file:../runners.note::b58e0d3a][b58e0d3a]]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_client_sync_job_dir() -> Result<()> {
+    use crate::auth::{User, Users};
+    use crate::job::{Db, JobStatus};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let endpoint = Endpoint::new(&listener.local_addr()?.to_string())?;
+    let mut users = Users::default();
+    users.insert(&endpoint.token, User::current());
+    tokio::spawn(crate::jsonrpc::TcpServer::new().users(users).serve(Db::new(), listener));
+
+    let tdir = tempfile::tempdir()?;
+    let local_dir = tdir.path().join("mirror");
+    // found by the discovery file written by the server
+    let path = tdir.path().join("endpoint.json");
+    endpoint.write(&path)?;
+    let client = Client::connect_discovery(&path)?;
+    assert_eq!(client.token(), Some(endpoint.token.as_str()));
+    tokio::task::spawn_blocking(move || {
+        let id = client.create_job("#!/bin/sh\nmkdir -p out\necho 42 > out/result\n")?;
+        assert_eq!(client.wait_job(id)?.status, JobStatus::Completed);
+        let synced = client.sync_job_dir(id, &local_dir)?;
+        assert!(synced.contains(&PathBuf::from("out/result")));
+        assert_eq!(gut::fs::read_file(local_dir.join("out/result"))?, "42\n");
+        // unchanged files are skipped
+        assert!(client.sync_job_dir(id, &local_dir)?.is_empty());
+        Ok_(())
+    })
+    .await??;
+    Ok(())
+}
+// b58e0d3a ends here
+
+// [[file:../runners.note::e2d7a4c1][e2d7a4c1]]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_client_clone_job() -> Result<()> {
+    use crate::auth::{User, Users};
+    use crate::job::{Db, JobStatus};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let endpoint = Endpoint::new(&listener.local_addr()?.to_string())?;
+    let mut users = Users::default();
+    users.insert(&endpoint.token, User::current());
+    tokio::spawn(crate::jsonrpc::TcpServer::new().users(users).serve(Db::new(), listener));
+
+    let client = Client::from_endpoint(endpoint);
+    tokio::task::spawn_blocking(move || {
+        // the clone only completes with the restart file of the first run
+        let id = client.create_job("#!/bin/sh\ntest -f restart && echo done > result || echo 1 > restart\n")?;
+        assert_eq!(client.wait_job(id)?.status, JobStatus::Completed);
+        let cloned = client.clone_job(id, &["restart"])?;
+        assert_ne!(cloned, id);
+        assert_eq!(client.wait_job(cloned)?.status, JobStatus::Completed);
+        assert!(client.list_job_files(cloned)?.iter().any(|f| f.ends_with("result")));
+        Ok_(())
+    })
+    .await??;
+    Ok(())
+}
+// e2d7a4c1 ends here
+
+// [[file:../runners.note::5b0c93fe][5b0c93fe]]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_client_send_stdin() -> Result<()> {
+    use crate::auth::{User, Users};
+    use crate::job::{Db, JobStatus};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let endpoint = Endpoint::new(&listener.local_addr()?.to_string())?;
+    let mut users = Users::default();
+    users.insert(&endpoint.token, User::current());
+    tokio::spawn(crate::jsonrpc::TcpServer::new().users(users).serve(Db::new(), listener));
+
+    let client = Client::from_endpoint(endpoint);
+    tokio::task::spawn_blocking(move || {
+        let mut job = Job::new("#!/bin/sh\nread cmd\ntest \"$cmd\" = stop && cat > rest\n");
+        job.keep_stdin_open(true);
+        let id = client.submit_job(&job)?;
+        // the job starts when waited
+        let waiting = {
+            let client = client.clone();
+            std::thread::spawn(move || client.wait_job(id))
+        };
+        let mut sent = client.send_stdin(id, "stop\n");
+        for _ in 0..50 {
+            if sent.is_ok() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            sent = client.send_stdin(id, "stop\n");
+        }
+        sent?;
+        client.close_stdin(id)?;
+        let result = waiting.join().unwrap()?;
+        assert_eq!(result.status, JobStatus::Completed);
+        assert!(client.list_job_files(id)?.iter().any(|f| f.ends_with("rest")));
+        Ok_(())
+    })
+    .await??;
+    Ok(())
+}
+// 5b0c93fe ends here
+
+// [[file:../runners.note::a61f0d27][a61f0d27]]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_client_job_array() -> Result<()> {
+    use crate::auth::{User, Users};
+    use crate::job::Db;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let endpoint = Endpoint::new(&listener.local_addr()?.to_string())?;
+    let mut users = Users::default();
+    users.insert(&endpoint.token, User::current());
+    tokio::spawn(crate::jsonrpc::TcpServer::new().users(users).serve(Db::new(), listener));
+
+    let client = Client::from_endpoint(endpoint);
+    tokio::task::spawn_blocking(move || {
+        let (array, jobs) = client.create_job_array("#!/bin/sh\necho $GOSH_ARRAY_INDEX\n", "1..3")?;
+        assert_eq!(jobs.len(), 3);
+        client.wait_all(&jobs)?;
+        let status = client.get_array_status(array)?;
+        assert_eq!(status.jobs, jobs);
+        assert!(status.is_finished());
+        client.cancel_array(array)?;
+        assert!(client.list_jobs()?.is_empty());
+        Ok_(())
+    })
+    .await??;
+    Ok(())
+}
+// a61f0d27 ends here
@@ -0,0 +1,167 @@
+// [[file:../runners.note::3c7e0b94][3c7e0b94]]
+//! rsync-like delta transfer of job files
+use super::*;
+
+use fast_rsync::{Signature, SignatureOptions};
+use std::io::Read;
+// 3c7e0b94 ends here
+
+// [[file:../runners.note::a91f5d26][a91f5d26]]
+/// The block size for matching unchanged content.
+const BLOCK_SIZE: u32 = 4096;
+
+/// Calculate the signature of `base` content. The peer holding the new
+/// content computes a delta against it, containing only changed blocks.
+pub fn signature(base: &[u8]) -> Vec<u8> {
+    let options = SignatureOptions {
+        block_size: BLOCK_SIZE,
+        crypto_hash_size: 8,
+    };
+    Signature::calculate(base, options).into_serialized()
+}
+
+/// Compute the delta turning content with `signature` into `data`.
+pub fn delta(signature: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let signature = Signature::deserialize(signature.to_vec()).map_err(|e| format_err!("invalid signature: {}", e))?;
+    let mut delta = vec![];
+    fast_rsync::diff(&signature.index(), data, &mut delta).map_err(|e| format_err!("diff failed: {}", e))?;
+    Ok(delta)
+}
+
+/// Apply `delta` on `base` content, returning the new content.
+pub fn patch(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    fast_rsync::apply(base, delta, &mut out).map_err(|e| format_err!("invalid delta: {}", e))?;
+    Ok(out)
+}
+
+/// Files are processed in segments of this size, so that large files are
+/// not read into memory at once.
+const SEGMENT_SIZE: u64 = 64 << 20;
+
+/// Read the next segment of `reader` into `buf`, returning false at the
+/// end.
+fn read_segment(reader: &mut impl Read, buf: &mut Vec<u8>) -> Result<bool> {
+    buf.clear();
+    reader.take(SEGMENT_SIZE).read_to_end(buf)?;
+    Ok(!buf.is_empty())
+}
+
+/// Join `frames` into a message, each prefixed with its length.
+fn join_frames(frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut out = vec![];
+    for f in frames {
+        out.extend_from_slice(&(f.len() as u64).to_le_bytes());
+        out.extend_from_slice(f);
+    }
+    out
+}
+
+/// Split the message of `join_frames` into frames.
+fn split_frames(mut msg: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut frames = vec![];
+    while !msg.is_empty() {
+        ensure!(msg.len() >= 8, "truncated frame header");
+        let (n, rest) = msg.split_at(8);
+        let n = u64::from_le_bytes(n.try_into()?) as usize;
+        ensure!(rest.len() >= n, "truncated frame");
+        let (frame, rest) = rest.split_at(n);
+        frames.push(frame);
+        msg = rest;
+    }
+    Ok(frames)
+}
+
+/// Calculate the signature of `file` segment by segment. The signature of
+/// a missing file is empty.
+pub fn signature_file(file: &Path) -> Result<Vec<u8>> {
+    if !file.exists() {
+        return Ok(vec![]);
+    }
+    let mut reader = std::fs::File::open(file).with_context(|| format!("open {:?}", file))?;
+    let mut buf = vec![];
+    let mut frames = vec![];
+    while read_segment(&mut reader, &mut buf)? {
+        frames.push(signature(&buf));
+    }
+    Ok(join_frames(&frames))
+}
+
+/// Compute the delta turning the file with `signature` of `signature_file`
+/// into `file`, segment by segment.
+pub fn delta_file(signature: &[u8], file: &Path) -> Result<Vec<u8>> {
+    let sigs = split_frames(signature)?;
+    let empty = self::signature(&[]);
+    let mut reader = std::fs::File::open(file).with_context(|| format!("open {:?}", file))?;
+    let mut buf = vec![];
+    let mut frames = vec![];
+    for i in 0.. {
+        if !read_segment(&mut reader, &mut buf)? {
+            break;
+        }
+        // new segments are diffed against empty content
+        let sig = sigs.get(i).copied().unwrap_or(&empty);
+        frames.push(delta(sig, &buf)?);
+    }
+    Ok(join_frames(&frames))
+}
+
+/// Apply `delta` of `delta_file` on `file` in place, segment by segment,
+/// returning the new size. The file is replaced atomically.
+pub fn patch_file(file: &Path, delta: &[u8]) -> Result<u64> {
+    use std::io::Write;
+
+    let deltas = split_frames(delta)?;
+    let mut reader = match file.exists() {
+        true => Some(std::fs::File::open(file).with_context(|| format!("open {:?}", file))?),
+        false => None,
+    };
+    let tmp = file.with_extension("delta-tmp");
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+    let mut base = vec![];
+    let mut size = 0;
+    for d in deltas {
+        // the base is empty beyond its end
+        if let Some(r) = reader.as_mut() {
+            read_segment(r, &mut base)?;
+        }
+        let data = patch(&base, d)?;
+        writer.write_all(&data)?;
+        size += data.len() as u64;
+    }
+    writer.flush()?;
+    drop(writer);
+    std::fs::rename(&tmp, file)?;
+    Ok(size)
+}
+// a91f5d26 ends here
+
+// [[file:../runners.note::5e08c3f7][5e08c3f7]]
+#[test]
+fn test_delta_transfer() -> Result<()> {
+    let old: Vec<u8> = (0..100_000u32).flat_map(|i| i.to_le_bytes()).collect();
+    let mut new = old.clone();
+    new[200_000..200_010].copy_from_slice(b"0123456789");
+
+    let sig = signature(&old);
+    let delta = delta(&sig, &new)?;
+    // only the changed block is sent
+    assert!(delta.len() < 2 * BLOCK_SIZE as usize);
+    assert_eq!(patch(&old, &delta)?, new);
+
+    // files are patched in place
+    let tdir = tempfile::tempdir()?;
+    let (a, b) = (tdir.path().join("a"), tdir.path().join("b"));
+    std::fs::write(&a, &old)?;
+    std::fs::write(&b, &new)?;
+    let delta = delta_file(&signature_file(&a)?, &b)?;
+    assert_eq!(patch_file(&a, &delta)?, new.len() as u64);
+    assert_eq!(std::fs::read(&a)?, new);
+    // a missing file is created
+    let c = tdir.path().join("c");
+    let delta = delta_file(&signature_file(&c)?, &b)?;
+    patch_file(&c, &delta)?;
+    assert_eq!(std::fs::read(&c)?, new);
+    Ok(())
+}
+// 5e08c3f7 ends here
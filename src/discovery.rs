@@ -0,0 +1,94 @@
+// [[file:../runners.note::b47e2a90][b47e2a90]]
+//! Discovery file for locating a running server endpoint
+use super::*;
+// b47e2a90 ends here
+
+// [[file:../runners.note::1c6f93d8][1c6f93d8]]
+/// The endpoint of a running server, written into a discovery file for
+/// clients started later, e.g. workers in the same batch allocation.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Endpoint {
+    /// The server address as `host:port`
+    pub address: String,
+    /// The token clients must present when connecting
+    pub token: String,
+    /// The process id of the server
+    pub pid: u32,
+}
+
+impl Endpoint {
+    /// Create an endpoint of current process at `address` with a new random
+    /// token.
+    pub fn new(address: &str) -> Result<Self> {
+        let mut bytes = [0u8; 16];
+        std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+        let token = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let endpoint = Self {
+            address: address.into(),
+            token,
+            pid: std::process::id(),
+        };
+        Ok(endpoint)
+    }
+
+    /// Return the default path to the discovery file, set by
+    /// `GOSH_RUNNER_DISCOVERY` env var. Inside a SLURM allocation the file
+    /// is specific to the job, so that concurrent allocations do not clash.
+    pub fn default_path() -> PathBuf {
+        if let Ok(path) = std::env::var("GOSH_RUNNER_DISCOVERY") {
+            return path.into();
+        }
+        let home = std::env::var("HOME").unwrap_or_else(|_| ".".into());
+        let name = match std::env::var("SLURM_JOB_ID") {
+            Ok(id) => format!("endpoint-{}.json", id),
+            Err(_) => "endpoint.json".into(),
+        };
+        Path::new(&home).join(".gosh-runner").join(name)
+    }
+
+    /// Write the endpoint into discovery file `path`, readable only by the
+    /// owner as it contains the token.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // write to a temporary file first, so readers never see partial content
+        let tmp = path.with_extension("tmp");
+        let mut f = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .with_context(|| format!("create discovery file {:?}", tmp))?;
+        f.write_all(self.to_json()?.as_bytes())?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Read the endpoint from discovery file `path`.
+    pub fn read(path: &Path) -> Result<Self> {
+        let s = gut::fs::read_file(path)?;
+        let endpoint = Self::from_json(&s).with_context(|| format!("invalid discovery file {:?}", path))?;
+        Ok(endpoint)
+    }
+}
+// 1c6f93d8 ends here
+
+// [[file:../runners.note::5e8a0d71][5e8a0d71]]
+#[test]
+fn test_discovery_file() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let path = tdir.path().join("endpoint.json");
+    let endpoint = Endpoint::new("127.0.0.1:2345")?;
+    assert_eq!(endpoint.token.len(), 32);
+    endpoint.write(&path)?;
+
+    let found = Endpoint::read(&path)?;
+    assert_eq!(found.address, "127.0.0.1:2345");
+    assert_eq!(found.token, endpoint.token);
+    Ok(())
+}
+// 5e8a0d71 ends here
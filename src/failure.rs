@@ -0,0 +1,103 @@
+// [[file:../runners.note::a83f52d6][a83f52d6]]
+//! Classification of job failure modes from job output
+use super::*;
+
+use serde::{Deserialize, Serialize};
+// a83f52d6 ends here
+
+// [[file:../runners.note::0e9d7b14][0e9d7b14]]
+/// A machine-readable reason why a job failed, for workflow drivers to
+/// decide whether to retry with different settings.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FailureReason {
+    /// The failure mode, e.g. "scf_not_converged"
+    pub kind: String,
+    /// The output line showing the failure
+    pub detail: String,
+}
+
+/// Classify the failure of a job from its stdout and stderr.
+pub trait FailureClassifier: Send + Sync {
+    /// Return the failure reason, or None if not recognized.
+    fn classify(&self, stdout: &str, stderr: &str) -> Option<FailureReason>;
+}
+
+/// A rule classifying a failure as `kind` if regex `pattern` matches a line
+/// in stdout or stderr.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FailureRule {
+    pub kind: String,
+    pub pattern: String,
+}
+
+impl FailureRule {
+    /// A rule for failure `kind` matching regex `pattern`.
+    pub fn new(kind: &str, pattern: &str) -> Self {
+        Self {
+            kind: kind.into(),
+            pattern: pattern.into(),
+        }
+    }
+
+    /// Rules for failure modes common in quantum chemistry codes.
+    pub fn builtin() -> Vec<Self> {
+        vec![
+            Self::new(
+                "scf_not_converged",
+                r"(?i)scf.*(not converged|failed to converge)|convergence failure in scf",
+            ),
+            Self::new("geometry_step_rejected", r"(?i)step (was )?rejected|bad geometry step"),
+            Self::new(
+                "out_of_memory",
+                r"(?i)out of memory|cannot allocate memory|insufficient memory|memory allocation failed|std::bad_alloc",
+            ),
+            Self::new(
+                "license_error",
+                r"(?i)licen[cs]e.*(error|failed|expired|not found|denied)|no licen[cs]e",
+            ),
+        ]
+    }
+}
+
+impl FailureClassifier for FailureRule {
+    fn classify(&self, stdout: &str, stderr: &str) -> Option<FailureReason> {
+        let re = match regex::Regex::new(&self.pattern) {
+            Ok(re) => re,
+            Err(e) => {
+                warn!("invalid failure rule {:?}: {}", self.kind, e);
+                return None;
+            }
+        };
+        let line = stdout.lines().chain(stderr.lines()).find(|line| re.is_match(line))?;
+        Some(FailureReason {
+            kind: self.kind.clone(),
+            detail: line.trim().to_owned(),
+        })
+    }
+}
+
+/// Classify failure with `classifiers` in order, returning the first
+/// recognized reason.
+pub fn classify<'a, I>(classifiers: I, stdout: &str, stderr: &str) -> Option<FailureReason>
+where
+    I: IntoIterator<Item = &'a dyn FailureClassifier>,
+{
+    classifiers.into_iter().find_map(|c| c.classify(stdout, stderr))
+}
+// 0e9d7b14 ends here
+
+// [[file:../runners.note::51c8e3a7][51c8e3a7]]
+#[test]
+fn test_failure_classify() {
+    let rules = FailureRule::builtin();
+    let classifiers = || rules.iter().map(|r| r as &dyn FailureClassifier);
+    let out = "cycle 128\n  SCF NOT CONVERGED after 128 cycles\n";
+    let reason = classify(classifiers(), out, "").unwrap();
+    assert_eq!(reason.kind, "scf_not_converged");
+    assert_eq!(reason.detail, "SCF NOT CONVERGED after 128 cycles");
+
+    let reason = classify(classifiers(), "", "Error: license checkout failed\n").unwrap();
+    assert_eq!(reason.kind, "license_error");
+    assert!(classify(classifiers(), "Normal termination\n", "").is_none());
+}
+// 51c8e3a7 ends here
@@ -0,0 +1,372 @@
+// [[file:../runners.note::9a4c7e12][9a4c7e12]]
+//! Federation of job servers on multiple nodes behind a gateway
+use super::*;
+
+use crate::auth::{User, Users};
+use crate::discovery::Endpoint;
+use crate::job::{Db, JobId};
+use crate::scheduler::Resources;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+// 9a4c7e12 ends here
+
+// [[file:../runners.note::5f0d2b86][5f0d2b86]]
+/// The status reported by a worker when registering to the gateway.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct WorkerStatus {
+    /// The address of worker's job server, e.g. "node01:3030"
+    pub address: String,
+    /// Free resources on the worker
+    pub free: Resources,
+}
+
+/// The location of a job running on a worker.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct JobLocation {
+    /// The address of worker's job server
+    pub address: String,
+    /// The job ID on the worker
+    pub id: JobId,
+}
+
+/// A gateway that load-balances jobs across registered workers, and keeps
+/// track of where jobs are running for proxying file access.
+#[derive(Debug, Default)]
+pub struct Gateway {
+    workers: HashMap<String, WorkerStatus>,
+    jobs: HashMap<JobId, JobLocation>,
+    next_id: JobId,
+}
+
+impl Gateway {
+    /// Create a gateway without any workers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a worker, or update its status if already registered.
+    pub fn register(&mut self, status: WorkerStatus) {
+        info!("worker registered: {:?}", status);
+        self.workers.insert(status.address.clone(), status);
+    }
+
+    /// Remove the worker at `address`.
+    pub fn unregister(&mut self, address: &str) {
+        if self.workers.remove(address).is_some() {
+            info!("worker unregistered: {}", address);
+        }
+    }
+
+    /// Return addresses of registered workers.
+    pub fn workers(&self) -> Vec<&str> {
+        self.workers.keys().map(|x| x.as_str()).collect()
+    }
+
+    /// Choose a worker for a job requiring `req` resources. The worker with
+    /// most free cores (then memory) is preferred.
+    pub fn select_worker(&self, req: &Resources) -> Option<&str> {
+        self.workers
+            .values()
+            .filter(|w| crate::scheduler::fits(req, &w.free))
+            .max_by_key(|w| (w.free.cpus, w.free.memory))
+            .map(|w| w.address.as_str())
+    }
+
+    /// Record that a job has been created on a worker, returning the job
+    /// ID on the gateway side.
+    pub fn add_job(&mut self, location: JobLocation) -> JobId {
+        self.next_id += 1;
+        let id = self.next_id;
+        debug!("gateway job {} => {:?}", id, location);
+        self.jobs.insert(id, location);
+        id
+    }
+
+    /// Return where gateway job `id` is running.
+    pub fn locate_job(&self, id: JobId) -> Result<&JobLocation> {
+        self.jobs.get(&id).ok_or(format_err!("Job id not found: {}", id))
+    }
+
+    /// Remove gateway job `id`.
+    pub fn remove_job(&mut self, id: JobId) -> Result<JobLocation> {
+        self.jobs.remove(&id).ok_or(format_err!("Job id not found: {}", id))
+    }
+}
+// 5f0d2b86 ends here
+
+// [[file:../runners.note::c6e2a9f4][c6e2a9f4]]
+/// The registration of a worker, with the token for the gateway to call
+/// its job server.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Registration {
+    #[serde(flatten)]
+    status: WorkerStatus,
+    token: String,
+}
+
+/// Job methods forwarded to the worker running the job.
+const JOB_METHODS: &[&str] = &[
+    "wait",
+    "status",
+    "history",
+    "usage",
+    "processes",
+    "checkpoint",
+    "progress",
+    "tag",
+    "list_files",
+    "file_stats",
+    "get_file",
+    "wait_file",
+    "stdin",
+    "put_file",
+    "file_signature",
+    "file_delta",
+    "patch_file",
+    "link_cached_file",
+    "export",
+    "clone",
+    "delete",
+];
+
+#[derive(Debug, Default)]
+struct GatewayState {
+    gateway: Gateway,
+    // endpoints of registered workers by address
+    endpoints: HashMap<String, Endpoint>,
+    // owners of gateway jobs
+    owners: HashMap<JobId, String>,
+}
+
+/// Call `method` with `params` on the server at `endpoint`.
+async fn forward(endpoint: Endpoint, method: &str, params: Value) -> Result<Value> {
+    let method = method.to_owned();
+    tokio::task::spawn_blocking(move || crate::jsonrpc::call(&endpoint, &method, params)).await?
+}
+
+/// A JSON-RPC server of the gateway, forwarding job requests to workers.
+#[derive(Debug, Clone, Default)]
+pub struct GatewayServer {
+    state: std::sync::Arc<tokio::sync::Mutex<GatewayState>>,
+}
+
+impl GatewayServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serve clients and workers accepted on `listener`. They are
+    /// authenticated by tokens of `users` as the JSON-RPC server, and only
+    /// admins may register workers.
+    pub async fn serve(self, listener: tokio::net::TcpListener, users: Users) -> Result<()> {
+        info!("serving gateway on {}", listener.local_addr()?);
+        let users = std::sync::Arc::new(users);
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            debug!("accepted connection from {}", peer);
+            let server = self.clone();
+            let users = users.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut reader = tokio::io::BufReader::new(reader);
+                let user = match crate::jsonrpc::authenticate(&users, &mut reader, &mut writer, peer).await {
+                    Some(user) => user,
+                    None => return,
+                };
+                let handler = move |line: String| {
+                    let (server, user) = (server.clone(), user.clone());
+                    async move { server.handle(&user, &line).await }
+                };
+                if let Err(e) = crate::jsonrpc::serve_requests(handler, None, reader, writer).await {
+                    warn!("connection from {} failed: {:?}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Handle one line of JSON-RPC request from `user`.
+    async fn handle(&self, user: &User, line: &str) -> Value {
+        let req: Value = serde_json::from_str(line).unwrap_or_default();
+        let method = req["method"].as_str().unwrap_or_default();
+        match self.dispatch(user, method, req["params"].clone()).await {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": req["id"], "result": result}),
+            Err(e) => {
+                let error = json!({"code": -32000, "message": format!("{:?}", e)});
+                json!({"jsonrpc": "2.0", "id": req["id"], "error": error})
+            }
+        }
+    }
+
+    async fn dispatch(&self, user: &User, method: &str, mut params: Value) -> Result<Value> {
+        match method {
+            "register" => {
+                ensure!(user.admin, "user {} may not register workers", user.name);
+                let Registration { status, token } = serde_json::from_value(params)?;
+                let endpoint = Endpoint {
+                    address: status.address.clone(),
+                    token,
+                    pid: 0,
+                };
+                let mut state = self.state.lock().await;
+                state.endpoints.insert(endpoint.address.clone(), endpoint);
+                state.gateway.register(status);
+                Ok(Value::Null)
+            }
+            "workers" => Ok(json!(self.state.lock().await.gateway.workers())),
+            "submit" => {
+                let req: Resources = serde_json::from_value(params["resources"].clone()).unwrap_or_default();
+                let endpoint = {
+                    let state = self.state.lock().await;
+                    let address = state.gateway.select_worker(&req).context("no worker for the job")?;
+                    state.endpoints[address].clone()
+                };
+                let id = serde_json::from_value(forward(endpoint.clone(), method, params).await?)?;
+                let location = JobLocation {
+                    address: endpoint.address,
+                    id,
+                };
+                let mut state = self.state.lock().await;
+                let id = state.gateway.add_job(location);
+                state.owners.insert(id, user.name.clone());
+                Ok(json!(id))
+            }
+            "list_jobs" => {
+                let state = self.state.lock().await;
+                let mut ids: Vec<_> = state
+                    .owners
+                    .iter()
+                    .filter(|(_, owner)| user.can_access(Some(owner)))
+                    .map(|(&id, _)| id)
+                    .collect();
+                ids.sort();
+                Ok(json!(ids))
+            }
+            _ if JOB_METHODS.contains(&method) => {
+                let id: JobId = serde_json::from_value(params["id"].clone()).context("invalid job id")?;
+                let endpoint = {
+                    let state = self.state.lock().await;
+                    let location = state.gateway.locate_job(id)?;
+                    let owner = state.owners.get(&id).map(|x| x.as_str());
+                    ensure!(user.can_access(owner), "job {} is not owned by {}", id, user.name);
+                    params["id"] = json!(location.id);
+                    let endpoint = state.endpoints.get(&location.address).cloned();
+                    endpoint.ok_or(format_err!("worker {} is gone", location.address))?
+                };
+                let address = endpoint.address.clone();
+                let result = forward(endpoint, method, params).await?;
+                let mut state = self.state.lock().await;
+                match method {
+                    "delete" => {
+                        state.gateway.remove_job(id)?;
+                        state.owners.remove(&id);
+                    }
+                    // the cloned job is created on the same worker
+                    "clone" => {
+                        let id = state.gateway.add_job(JobLocation {
+                            address,
+                            id: serde_json::from_value(result.clone())?,
+                        });
+                        state.owners.insert(id, user.name.clone());
+                        return Ok(json!(id));
+                    }
+                    _ => {}
+                }
+                Ok(result)
+            }
+            _ => bail!("unsupported method on gateway: {}", method),
+        }
+    }
+}
+
+/// Register the job server of `db` at `worker` to the gateway at
+/// `gateway` every `interval` seconds, reporting its free resources. The
+/// token of `worker` is used by the gateway for forwarding requests.
+pub async fn join_gateway(db: Db, gateway: Endpoint, worker: Endpoint, interval: f64) {
+    loop {
+        let registration = Registration {
+            status: WorkerStatus {
+                address: worker.address.clone(),
+                free: db.free_resources().await,
+            },
+            token: worker.token.clone(),
+        };
+        let r = forward(gateway.clone(), "register", json!(registration)).await;
+        match r {
+            Ok(_) => debug!("registered to gateway"),
+            Err(e) => warn!("failed to register to gateway: {:?}", e),
+        }
+        tokio::time::sleep(std::time::Duration::from_secs_f64(interval)).await;
+    }
+}
+// c6e2a9f4 ends here
+
+// [[file:../runners.note::d31b6a70][d31b6a70]]
+#[test]
+fn test_gateway_select_worker() {
+    let mut gw = Gateway::new();
+    let free = |cpus, memory| Resources {
+        cpus,
+        memory,
+        ..Default::default()
+    };
+    gw.register(WorkerStatus {
+        address: "node01".into(),
+        free: free(4, 1 << 30),
+    });
+    gw.register(WorkerStatus {
+        address: "node02".into(),
+        free: free(16, 1 << 30),
+    });
+    assert_eq!(gw.select_worker(&free(2, 0)), Some("node02"));
+    assert_eq!(gw.select_worker(&free(32, 0)), None);
+
+    let id = gw.add_job(JobLocation {
+        address: "node02".into(),
+        id: 1,
+    });
+    assert_eq!(gw.locate_job(id).unwrap().address, "node02");
+}
+// d31b6a70 ends here
+
+// [[file:../runners.note::f84b1e27][f84b1e27]]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_gateway_server() -> Result<()> {
+    use crate::jsonrpc::TcpServer;
+
+    // a worker serving its job server to the gateway
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let worker = Endpoint::new(&listener.local_addr()?.to_string())?;
+    let mut users = Users::default();
+    users.insert(&worker.token, User::admin("gateway"));
+    let db = Db::new();
+    tokio::spawn(TcpServer::new().users(users).serve(db.clone(), listener));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let gateway = Endpoint::new(&listener.local_addr()?.to_string())?;
+    let mut users = Users::default();
+    users.insert(&gateway.token, User::admin("admin"));
+    users.insert("secret-of-alice", User::new("alice"));
+    tokio::spawn(GatewayServer::new().serve(listener, users));
+    tokio::spawn(join_gateway(db, gateway.clone(), worker, 60.0));
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+    let alice = Endpoint {
+        token: "secret-of-alice".into(),
+        ..gateway.clone()
+    };
+    let r = tokio::task::spawn_blocking(move || {
+        let id = crate::jsonrpc::call(&alice, "submit", json!({"script": "#!/bin/sh\necho hi\n"}))?;
+        let result = crate::jsonrpc::call(&alice, "wait", json!({ "id": id }))?;
+        // workers are only registered by admins
+        let registration = json!({"address": "x:1", "free": {}, "token": "x"});
+        assert!(crate::jsonrpc::call(&alice, "register", registration).is_err());
+        // the worker is not reachable directly for any method
+        assert!(crate::jsonrpc::call(&alice, "clear", json!({ "id": id })).is_err());
+        Result::<Value>::Ok(result)
+    })
+    .await??;
+    assert_eq!(r["status"], json!("Completed"));
+    Ok(())
+}
+// f84b1e27 ends here
@@ -0,0 +1,212 @@
+// [[file:../runners.note::1e7d4b3a][1e7d4b3a]]
+//! gRPC API mirroring operations of the job DB
+use super::*;
+
+use crate::auth::{User, Users};
+use crate::job::{Db, Job, JobId, ScratchFull};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tonic::{Request, Response, Status, Streaming};
+
+/// Types generated from `proto/runner.proto`
+pub mod proto {
+    tonic::include_proto!("runner");
+}
+
+use self::proto::runner_server::{Runner, RunnerServer};
+use self::proto::*;
+// 1e7d4b3a ends here
+
+// [[file:../runners.note::b58f2e07][b58f2e07]]
+/// Convert errors into gRPC status.
+fn to_status(e: Error) -> Status {
+    Status::internal(format!("{:?}", e))
+}
+
+/// Convert errors of job access into gRPC status.
+fn to_denied(e: Error) -> Status {
+    Status::permission_denied(e.to_string())
+}
+
+/// The gRPC service for jobs in `Db`.
+#[derive(Clone)]
+pub struct RunnerService {
+    db: Db,
+    users: Option<std::sync::Arc<Users>>,
+}
+
+impl RunnerService {
+    /// Create a service for jobs in `db`.
+    pub fn new(db: Db) -> Self {
+        Self { db, users: None }
+    }
+
+    /// Authenticate clients by tokens of `users`, sent in `authorization`
+    /// metadata as "Bearer <token>".
+    pub fn users(mut self, users: Users) -> Self {
+        self.users = std::sync::Arc::new(users).into();
+        self
+    }
+
+    /// Return the client of `request`. Without users set, clients are not
+    /// authenticated, each as a normal user named by its address.
+    fn user<T>(&self, request: &Request<T>) -> Result<User, Status> {
+        match &self.users {
+            Some(users) => request
+                .metadata()
+                .get("authorization")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .and_then(|token| users.authenticate(token))
+                .ok_or_else(|| Status::unauthenticated("invalid token")),
+            None => {
+                let addr = request.remote_addr().map(|a| a.ip().to_string());
+                Ok(User::new(&addr.unwrap_or_else(|| "unknown".into())))
+            }
+        }
+    }
+
+    /// Serve gRPC requests on `addr`, e.g. "127.0.0.1:3031". Without users
+    /// set, only loopback address is allowed.
+    pub async fn serve(self, addr: &str) -> Result<()> {
+        let addr: std::net::SocketAddr = addr.parse()?;
+        ensure!(
+            self.users.is_some() || addr.ip().is_loopback(),
+            "refuse to serve on {} without authentication",
+            addr
+        );
+        info!("gRPC server listening on {}", addr);
+        tonic::transport::Server::builder()
+            .add_service(RunnerServer::new(self))
+            .serve(addr)
+            .await?;
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl Runner for RunnerService {
+    async fn submit(&self, request: Request<SubmitRequest>) -> Result<Response<proto::JobId>, Status> {
+        let user = self.user(&request)?;
+        let job = Job::from_json(&request.into_inner().job).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let mut db = self.db.as_user(&user);
+        let id = db.try_insert_job_as(job, &user).await.map_err(|e| {
+            if e.is::<ScratchFull>() {
+                Status::resource_exhausted(e.to_string())
+            } else {
+                to_status(e)
+            }
+        })?;
+        Ok(Response::new(proto::JobId { id: id as u64 }))
+    }
+
+    async fn wait(&self, request: Request<proto::JobId>) -> Result<Response<WaitReply>, Status> {
+        let user = self.user(&request)?;
+        let id = request.into_inner().id as JobId;
+        self.db.check_job_owner(id, &user).await.map_err(to_denied)?;
+        self.db.wait_job(id).await.map_err(to_status)?;
+        let status = self.db.get_job_status(id).await.map_err(to_status)?;
+        Ok(Response::new(WaitReply {
+            status: format!("{:?}", status),
+        }))
+    }
+
+    type StreamStream = ReceiverStream<Result<FileChunk, Status>>;
+
+    async fn stream(&self, request: Request<FileRequest>) -> Result<Response<Self::StreamStream>, Status> {
+        use tokio::io::AsyncReadExt;
+
+        let user = self.user(&request)?;
+        let FileRequest { id, file } = request.into_inner();
+        let jid = id as JobId;
+        self.db.check_job_owner(jid, &user).await.map_err(to_denied)?;
+        let path = self.db.get_job_file_path(jid, file.as_ref()).await.map_err(to_denied)?;
+        let db = self.db.clone();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move {
+            // the file may not be created yet when the job is pending
+            let mut f = None;
+            loop {
+                // check status before reading for not missing the tail
+                let finished = match db.get_job_status(jid).await {
+                    Ok(status) => status.is_finished(),
+                    Err(e) => {
+                        let _ = tx.send(Err(to_status(e))).await;
+                        break;
+                    }
+                };
+                if f.is_none() {
+                    f = tokio::fs::File::open(&path).await.ok();
+                }
+                // only read the data appended since last time
+                if let Some(f) = f.as_mut() {
+                    loop {
+                        let mut data = Vec::with_capacity(CHUNK_SIZE);
+                        match (&mut *f).take(CHUNK_SIZE as u64).read_to_end(&mut data).await {
+                            Ok(0) => break,
+                            Ok(_) => {
+                                let chunk = FileChunk {
+                                    id,
+                                    file: file.clone(),
+                                    data,
+                                };
+                                if tx.send(Ok(chunk)).await.is_err() {
+                                    return;
+                                }
+                            }
+                            Err(e) => {
+                                let _ = tx.send(Err(Status::internal(e.to_string()))).await;
+                                return;
+                            }
+                        }
+                    }
+                }
+                if finished {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn file_transfer(&self, request: Request<Streaming<FileChunk>>) -> Result<Response<FileTransferReply>, Status> {
+        use tokio::io::AsyncWriteExt;
+
+        let user = self.user(&request)?;
+        let mut stream = request.into_inner();
+        let first = match stream.next().await {
+            Some(chunk) => chunk?,
+            None => return Err(Status::invalid_argument("no file uploaded")),
+        };
+        let (id, file) = (first.id as JobId, first.file);
+        self.db.check_job_owner(id, &user).await.map_err(to_denied)?;
+
+        // stream chunks into the file through a pipe, without buffering the
+        // whole file in memory
+        let (mut tx, rx) = tokio::io::duplex(64 * 1024);
+        let feeder = tokio::spawn(async move {
+            tx.write_all(&first.data).await?;
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+                tx.write_all(&chunk.data).await?;
+            }
+            std::io::Result::Ok(())
+        });
+        let size = self
+            .db
+            .as_user(&user)
+            .put_job_file_stream(id, file, rx)
+            .await
+            .map_err(to_status)?;
+        feeder
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::aborted(e.to_string()))?;
+        Ok(Response::new(FileTransferReply { size }))
+    }
+}
+
+/// The maximum size of file chunks streamed to clients.
+const CHUNK_SIZE: usize = 64 * 1024;
+// b58f2e07 ends here
@@ -0,0 +1,137 @@
+// [[file:../runners.note::3f9a6e20][3f9a6e20]]
+//! Post-processing hooks executed after job script exits
+use super::*;
+
+use serde::{Deserialize, Serialize};
+// 3f9a6e20 ends here
+
+// [[file:../runners.note::c1d8e4b7][c1d8e4b7]]
+/// A post-run hook executed in the working directory of a job.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Hook {
+    /// Run a shell snippet
+    Shell(String),
+    /// Compress captured stdout/stderr files with gzip
+    GzipOutputs,
+    /// Extract the last match of regex `pattern` in `file`. The first
+    /// capture group is used if any.
+    Extract { name: String, pattern: String, file: PathBuf },
+}
+
+/// The output of a hook recorded in job metadata.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct HookOutput {
+    /// A short description of the hook
+    pub hook: String,
+    pub success: bool,
+    /// Captured output, extracted value or error message
+    pub output: String,
+}
+
+impl Hook {
+    /// Run the hook in `wrk_dir`, with `outputs` for captured stdout/stderr
+    /// files.
+    pub fn run(&self, wrk_dir: &Path, outputs: &[PathBuf]) -> HookOutput {
+        let shell = |cmd: &str| {
+            let mut command = std::process::Command::new("sh");
+            command.args(&["-c", cmd]).current_dir(wrk_dir);
+            command
+        };
+        self.run_with(wrk_dir, outputs, &shell)
+    }
+
+    /// Run the hook like `run`, with shell snippets run by the command from
+    /// `shell`, e.g. inside the sandbox of the job.
+    pub fn run_with(
+        &self,
+        wrk_dir: &Path,
+        outputs: &[PathBuf],
+        shell: &dyn Fn(&str) -> std::process::Command,
+    ) -> HookOutput {
+        let (hook, result) = match self {
+            Self::Shell(cmd) => (format!("shell: {}", cmd), run_shell(shell(cmd))),
+            Self::GzipOutputs => ("gzip outputs".to_owned(), gzip_files(outputs)),
+            Self::Extract { name, pattern, file } => {
+                let file = wrk_dir.join(file);
+                (format!("extract {}", name), extract(pattern, &file))
+            }
+        };
+        match result {
+            Ok(output) => HookOutput {
+                hook,
+                success: true,
+                output,
+            },
+            Err(e) => {
+                warn!("hook {:?} failed: {:?}", hook, e);
+                HookOutput {
+                    hook,
+                    success: false,
+                    output: format!("{:?}", e),
+                }
+            }
+        }
+    }
+}
+
+fn run_shell(mut command: std::process::Command) -> Result<String> {
+    let out = command.output()?;
+    let mut output = String::from_utf8_lossy(&out.stdout).into_owned();
+    output.push_str(&String::from_utf8_lossy(&out.stderr));
+    ensure!(out.status.success(), "{}\n{}", out.status, output);
+    Ok(output)
+}
+
+fn gzip_files(files: &[PathBuf]) -> Result<String> {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    let mut compressed = vec![];
+    for path in files.iter().filter(|p| p.is_file()) {
+        let gz = PathBuf::from(format!("{}.gz", path.display()));
+        let mut reader = std::fs::File::open(path)?;
+        let mut encoder = GzEncoder::new(std::fs::File::create(&gz)?, Compression::default());
+        std::io::copy(&mut reader, &mut encoder)?;
+        encoder.finish()?;
+        std::fs::remove_file(path)?;
+        compressed.push(gz.display().to_string());
+    }
+    Ok(compressed.join("\n"))
+}
+
+/// Return the last match of regex `pattern` in `file`.
+pub(crate) fn extract(pattern: &str, file: &Path) -> Result<String> {
+    let re = regex::Regex::new(pattern)?;
+    let text = gut::fs::read_file(file)?;
+    let caps = re
+        .captures_iter(&text)
+        .last()
+        .ok_or(format_err!("pattern {:?} not found in {:?}", pattern, file))?;
+    let m = caps.get(1).or_else(|| caps.get(0)).unwrap();
+    Ok(m.as_str().to_owned())
+}
+// c1d8e4b7 ends here
+
+// [[file:../runners.note::0a6f52d9][0a6f52d9]]
+#[test]
+fn test_hooks() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let out = dir.path().join("job.out");
+    gut::fs::write_to_file(&out, "E = -1.0\nE = -2.5\n")?;
+
+    let hook = Hook::Extract {
+        name: "energy".into(),
+        pattern: r"E = (\S+)".into(),
+        file: "job.out".into(),
+    };
+    assert_eq!(hook.run(dir.path(), &[]).output, "-2.5");
+
+    let o = Hook::Shell("ls job.out".into()).run(dir.path(), &[]);
+    assert!(o.success);
+    let o = Hook::GzipOutputs.run(dir.path(), &[out.clone()]);
+    assert!(o.success);
+    assert!(!out.exists());
+    Ok(())
+}
+// 0a6f52d9 ends here
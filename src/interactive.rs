@@ -43,7 +43,7 @@ mod stdin {
 mod stdout {
     use super::*;
 
-    use std::io::{self, BufRead, Write};
+    use std::io::{self, BufRead};
     use std::process::ChildStdout;
 
     pub struct StdoutReader {
@@ -3,12 +3,30 @@
 use super::*;
 
 use serde::{Deserialize, Serialize};
-use tempfile::{tempdir, tempdir_in, TempDir};
+use tempfile::{tempdir, TempDir};
+
+use crate::acct::{Accounting, AcctRecord, JobQuery};
+use crate::audit::{AuditEntry, AuditLog};
+use crate::auth::User;
+use crate::hooks::{Hook, HookOutput};
+use crate::notify::{JobSummary, Notifier};
+use crate::parser::{JobResult, OutputParser};
+use crate::retention::RetentionPolicy;
+use crate::backend::{ApptainerOptions, Backend, SandboxOptions, SlurmJob, SshJob, Submitted};
+use crate::cache::FileCache;
+use crate::failure::{FailureClassifier, FailureReason, FailureRule};
+use crate::runner::{JobRunner, RunContext, RunOutcome};
+use crate::scheduler::{Allocation, Resources, Scheduler};
+use crate::templates::TemplateSpec;
+use crate::validate::ScriptPolicy;
+use crate::throttle::ThrottlePolicy;
+use crate::window::ExecWindows;
 // 9b1f2893 ends here
 
 // [[file:../runners.note::*job][job:1]]
 /// Represents a computational job inputted by user.
 #[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Job {
     /// Input string for stdin
     input: String,
@@ -30,6 +48,131 @@ pub struct Job {
 
     /// Extra files required for computation
     extra_files: Vec<PathBuf>,
+
+    /// Size limit on captured stdout/stderr files
+    #[serde(default)]
+    output_limit: Option<OutputLimit>,
+
+    /// Where the job will be executed
+    #[serde(default)]
+    backend: Backend,
+
+    /// Run the job script inside a container
+    #[serde(default)]
+    container: Option<ApptainerOptions>,
+
+    /// Run the job script in a sandbox restricted to working directory
+    #[serde(default)]
+    sandbox: Option<SandboxOptions>,
+
+    /// Run the job script as the Unix user
+    #[serde(default)]
+    run_as: Option<String>,
+
+    /// Keep stdin open after writing input for sending more data
+    #[serde(default)]
+    keep_stdin_open: bool,
+
+    /// Input sections fed into stdin on triggers after initial input
+    #[serde(default)]
+    input_phases: Vec<InputPhase>,
+
+    /// Outputs required for a successful job
+    #[serde(default)]
+    expected_outputs: Vec<ExpectedOutput>,
+
+    /// Rules classifying failure of the job from its output
+    #[serde(default)]
+    failure_rules: Vec<FailureRule>,
+
+    /// The file mode creation mask for running the job script
+    #[serde(default)]
+    umask: Option<u32>,
+
+    /// Make files created in working directory inherit its group
+    #[serde(default)]
+    group_sticky: bool,
+
+    /// Resources required by the job
+    #[serde(default)]
+    resources: Resources,
+
+    /// Hooks executed after the job script exits
+    #[serde(default)]
+    hooks: Vec<Hook>,
+
+    /// Parsers extracting results from stdout
+    #[serde(default)]
+    parsers: Vec<OutputParser>,
+
+    /// Heartbeat for detecting hung jobs
+    #[serde(default)]
+    heartbeat: Option<Heartbeat>,
+
+    /// Interval in seconds for sampling resource usage
+    #[serde(default)]
+    usage_interval: Option<f64>,
+
+    /// Action on files created outside working directory
+    #[serde(default)]
+    stray_files: Option<StrayFiles>,
+
+    /// Index range for submitting as a job array, e.g. "1..100"
+    #[serde(default)]
+    array: Option<String>,
+
+    /// Scheduling priority, higher first
+    #[serde(default)]
+    priority: i32,
+
+    /// Execution windows when the job is allowed to run
+    #[serde(default)]
+    window: Option<ExecWindows>,
+
+    /// Pause the running job when execution windows close
+    #[serde(default)]
+    pause_outside_window: bool,
+
+    /// Wall time limit in seconds
+    #[serde(default)]
+    timeout: Option<f64>,
+
+    /// Marker in stdout for estimating progress
+    #[serde(default)]
+    progress_marker: Option<ProgressMarker>,
+
+    /// Where to send notifications when the job finished, e.g.
+    /// "email:me@uni.edu" or "webhook:https://hooks.slack.com/..."
+    #[serde(default)]
+    notify: Vec<String>,
+
+    /// App modules loaded before running the script, such as "orca/5.0"
+    #[serde(default)]
+    modules: Vec<String>,
+
+    /// Annotations for organizing jobs, such as "project=perovskites"
+    #[serde(default)]
+    tags: std::collections::BTreeMap<String, String>,
+
+    /// Environment variables set for running the script
+    #[serde(default)]
+    env: std::collections::BTreeMap<String, String>,
+
+    /// The template the script was rendered from
+    #[serde(default)]
+    template: Option<TemplateSpec>,
+
+    /// Run in this directory instead of a new temporary one
+    #[serde(default)]
+    wrk_dir_hint: Option<PathBuf>,
+
+    /// Files on the node running the job, staged into working directory
+    #[serde(default)]
+    attachments: Vec<Attachment>,
+
+    /// The interpreter running the script, such as "bash" or "python"
+    #[serde(default)]
+    interpreter: Option<String>,
 }
 
 impl Job {
@@ -49,300 +192,3443 @@ impl Job {
             run_file: "run".into(),
             inp_file: "job.inp".into(),
             extra_files: vec![],
+            output_limit: None,
+            backend: Backend::default(),
+            container: None,
+            sandbox: None,
+            run_as: None,
+            umask: None,
+            keep_stdin_open: false,
+            input_phases: vec![],
+            expected_outputs: vec![],
+            failure_rules: vec![],
+            group_sticky: false,
+            resources: Resources::default(),
+            hooks: vec![],
+            parsers: vec![],
+            heartbeat: None,
+            usage_interval: None,
+            stray_files: None,
+            array: None,
+            priority: 0,
+            window: None,
+            pause_outside_window: false,
+            timeout: None,
+            progress_marker: None,
+            notify: vec![],
+            modules: vec![],
+            tags: Default::default(),
+            env: Default::default(),
+            template: None,
+            wrk_dir_hint: None,
+            attachments: vec![],
+            interpreter: None,
         }
     }
 
-    /// Add a new file into extra-files list.
-    pub fn attach_file<P: AsRef<Path>>(&mut self, file: P) {
-        let file: PathBuf = file.as_ref().into();
-        if !self.extra_files.contains(&file) {
-            self.extra_files.push(file);
-        } else {
-            warn!("try to attach a dumplicated file: {}!", file.display());
+    /// Construct a Job from run-script template `name` with `params`, e.g.
+    /// `Job::from_template("orca", &[("program", "/opt/orca/orca")])`.
+    /// Builtin templates can be overridden by user defined ones in
+    /// `~/.config/gosh-runner/templates/`.
+    pub fn from_template(name: &str, params: &[(&str, &str)]) -> Result<Self> {
+        let script = crate::templates::render_template(name, params)?;
+        let mut job = Self::new(&script);
+        job.template = TemplateSpec {
+            name: name.into(),
+            params: params.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
         }
+        .into();
+        Ok(job)
     }
-}
-// job:1 ends here
 
-// [[file:../runners.note::*base][base:1]]
-/// Computation represents a submitted `Job`
-pub struct Computation {
-    job: Job,
+    /// Declare resources required by the job. The job will be queued until
+    /// the resources are free.
+    pub fn set_resources(&mut self, resources: Resources) {
+        self.resources = resources;
+    }
 
-    // command session. The drop order is above Tempdir
-    session: Option<crate::process::Session<tokio::process::Child>>,
-    
-    /// The working directory of computation
-    wrk_dir: TempDir,
-}
-// base:1 ends here
+    /// Request `n` GPU devices for the job. `CUDA_VISIBLE_DEVICES` will be
+    /// set for the allocated devices.
+    pub fn set_gpus(&mut self, n: usize) {
+        self.resources.gpus = n;
+    }
 
-// [[file:../runners.note::*paths][paths:1]]
-impl Computation {
-    /// The full path to the working directory for running the job.
-    pub fn wrk_dir(&self) -> &Path {
-        self.wrk_dir.path()
+    /// Run the job script inside an Apptainer/Singularity container.
+    pub fn set_container(&mut self, container: ApptainerOptions) {
+        self.container = container.into();
     }
 
-    /// The full path to computation input file (stdin).
-    pub fn inp_file(&self) -> PathBuf {
-        self.wrk_dir().join(&self.job.inp_file)
+    /// Run the job script in a bubblewrap sandbox, which can only write
+    /// into its working directory and has no network access unless
+    /// allowed. Useful for running scripts from less-trusted users.
+    pub fn set_sandbox(&mut self, sandbox: SandboxOptions) {
+        self.sandbox = sandbox.into();
     }
 
-    /// The full path to computation output file (stdout).
-    pub fn out_file(&self) -> PathBuf {
-        self.wrk_dir().join(&self.job.out_file)
+    /// Set the backend where the job will be executed.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
     }
 
-    /// The full path to computation error file (stderr).
-    pub fn err_file(&self) -> PathBuf {
-        self.wrk_dir().join(&self.job.err_file)
+    /// Rotate captured stdout/stderr files into gzip compressed backups
+    /// (`job.out.1.gz`, `job.out.2.gz`, ...) when exceeding `max_bytes`,
+    /// keeping at most `keep` backups.
+    pub fn rotate_output(&mut self, max_bytes: u64, keep: usize) {
+        self.set_output_limit(max_bytes, OutputLimitAction::RotateGzip { keep });
     }
 
-    /// The full path to the script for running the job.
-    pub fn run_file(&self) -> PathBuf {
-        self.wrk_dir().join(&self.job.run_file)
+    /// Limit the size of captured stdout/stderr files to `max_bytes`, and
+    /// apply `action` when exceeded.
+    pub fn set_output_limit(&mut self, max_bytes: u64, action: OutputLimitAction) {
+        self.output_limit = OutputLimit { max_bytes, action }.into();
     }
-}
-// paths:1 ends here
 
-// [[file:../runners.note::*core][core:1]]
-use tokio::io::AsyncWriteExt;
+    /// Add a hook executed in working directory after the job script exits.
+    /// The hook outputs are recorded in job metadata.
+    pub fn add_hook(&mut self, hook: Hook) {
+        self.hooks.push(hook);
+    }
 
-impl Job {
-    /// Submit the job and turn it into Computation.
-    pub fn submit(self) -> Computation {
-        Computation::new(self)
+    /// Add a parser extracting a value from stdout into `JobResult` when the
+    /// job finished.
+    pub fn add_parser(&mut self, parser: OutputParser) {
+        self.parsers.push(parser);
     }
-}
 
-impl Computation {
-    /// Construct `Computation` of user inputted `Job`.
-    pub fn new(job: Job) -> Self {
-        use std::fs::File;
-        use std::os::unix::fs::OpenOptionsExt;
+    /// Touch a `HEARTBEAT` file in working directory every `interval`
+    /// seconds as long as the job makes progress (consuming CPU time or
+    /// writing output). The job is reported as `JobStatus::Stalled` if the
+    /// heartbeat is older than `stale_after` seconds.
+    pub fn set_heartbeat(&mut self, interval: f64, stale_after: f64) {
+        self.heartbeat = Heartbeat { interval, stale_after }.into();
+    }
 
-        // create working directory in scratch space.
-        let wdir = tempfile::TempDir::new_in(".").expect("temp dir");
-        let session = Computation {
-            job,
-            wrk_dir: wdir.into(),
-            session: None,
-        };
+    /// Sample CPU time and memory of the running job every `interval`
+    /// seconds into `usage.csv` in working directory, for spotting memory
+    /// blowups afterwards.
+    pub fn record_usage(&mut self, interval: f64) {
+        self.usage_interval = interval.into();
+    }
 
-        // create run file
-        let file = session.run_file();
+    /// Detect files the job created in `/tmp` or home directory by
+    /// comparing their entries before and after the run, and report or
+    /// clean them by `action`. Some codes ignore the working directory for
+    /// scratch files. Files created by other processes of the same user
+    /// meanwhile can not be told apart, so they are only cleaned for jobs
+    /// run as a dedicated user, see `StrayFiles::Clean`.
+    pub fn track_stray_files(&mut self, action: StrayFiles) {
+        self.stray_files = action.into();
+    }
 
-        // make run script executable
-        match std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .mode(0o770)
-            .open(&file)
-        {
-            Ok(mut f) => {
-                let _ = f.write_all(session.job.script.as_bytes());
-                trace!("script content wrote to: {}.", file.display());
-            }
-            Err(e) => {
-                panic!("Error whiling creating job run file: {}", e);
-            }
-        }
-        let file = session.inp_file();
-        match File::create(&session.inp_file()) {
-            Ok(mut f) => {
-                let _ = f.write_all(session.job.input.as_bytes());
-                trace!("input content wrote to: {}.", file.display());
-            }
-            Err(e) => {
-                panic!("Error while creating job input file: {}", e);
-            }
-        }
+    /// Submit the job as an array of child jobs with indices in `range`
+    /// like "1..100" (both ends included, as `1-100` in SLURM), for
+    /// parameter scans. Each child gets its index in `GOSH_ARRAY_INDEX` env
+    /// var.
+    pub fn array(&mut self, range: &str) {
+        self.array = range.to_owned().into();
+    }
 
-        session
+    /// Set scheduling priority of the job, 0 by default. When the node is
+    /// full, a job with higher priority preempts running jobs with lower
+    /// priority, which are paused until it finishes. Only admins may set
+    /// priority above 0.
+    pub fn priority(&mut self, priority: i32) {
+        self.priority = priority;
     }
 
-    /// Wait for background command to complete.
-    async fn wait(&mut self) -> Result<()> {
-        if let Some(s) = self.session.as_mut() {
-            let ecode = s.child.wait().await?;
-            info!("job session exited: {}", ecode);
-        } else {
-            error!("Job not started yet.");
+    /// Only run the job in execution `windows`, e.g. "20:00-08:00,
+    /// weekends", waiting in queue otherwise. If `pause` is true, the
+    /// running job is also paused when the windows close, and resumed when
+    /// they open again.
+    pub fn set_window(&mut self, windows: ExecWindows, pause: bool) {
+        self.window = windows.into();
+        self.pause_outside_window = pause;
+    }
+
+    /// Set the content fed into stdin of the job.
+    pub fn set_input(&mut self, input: &str) {
+        self.input = input.into();
+    }
+
+    /// Terminate the job if running longer than `secs` seconds.
+    pub fn set_timeout(&mut self, secs: f64) {
+        self.timeout = secs.into();
+    }
+
+    /// Estimate progress by counting lines matching regex `pattern` in
+    /// stdout, `expected` times in total, e.g. "SCF cycle".
+    pub fn set_progress_marker(&mut self, pattern: &str, expected: usize) {
+        self.progress_marker = ProgressMarker {
+            pattern: pattern.into(),
+            expected,
         }
-        Ok(())
+        .into();
     }
 
-    /// Run command in background.
-    async fn start(&mut self) -> Result<()> {
-        use crate::process::SpawnSessionExt;
+    /// Send a notification to `target` when the job finished, e.g.
+    /// "email:me@uni.edu", "webhook:<url>", or "webhook" for the default
+    /// webhook of the server.
+    pub fn notify(&mut self, target: &str) {
+        self.notify.push(target.into());
+    }
 
-        let wdir = self.wrk_dir();
-        info!("job work direcotry: {}", wdir.display());
+    /// Load app `modules` such as `&["orca/5.0", "mpich"]` before running
+    /// the script. The modules are resolved on the node running the job.
+    pub fn use_modules(&mut self, modules: &[&str]) {
+        self.modules.extend(modules.iter().map(|m| m.to_string()));
+    }
 
-        let mut session = tokio::process::Command::new(&self.run_file())
-            .current_dir(wdir)
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped())
-            .spawn_session()?;
+    /// Run the job in directory `path`, e.g. to reuse pre-staged data or
+    /// a RAM disk, instead of a new temporary directory. The directory is
+    /// kept after the job is removed.
+    ///
+    /// Relative paths are resolved against the scratch directory. The
+    /// directory must be inside the scratch directory or one of the dirs
+    /// listed in `GOSH_RUNNER_WORK_DIRS` (separated by `:`). Jobs of normal
+    /// users are limited to the latter, in dirs owned by the job user, and
+    /// never share a directory with other jobs.
+    pub fn wrk_dir_hint<P: AsRef<Path>>(&mut self, path: P) {
+        self.wrk_dir_hint = path.as_ref().to_owned().into();
+    }
 
-        let mut stdin = session
-            .child
-            .stdin
-            .take()
-            .expect("child did not have a handle to stdout");
-        let mut stdout = session
-            .child
-            .stdout
-            .take()
-            .expect("child did not have a handle to stdout");
-        let mut stderr = session
-            .child
-            .stderr
-            .take()
-            .expect("child did not have a handle to stderr");
+    /// Run the script with `interpreter` such as "bash", "python" or a
+    /// custom command like "julia --project", so the script does not need a
+    /// shebang line.
+    pub fn interpreter(&mut self, interpreter: &str) {
+        self.interpreter = interpreter.to_owned().into();
+    }
 
-        // NOTE: suppose stdin stream is small.
-        stdin.write_all(self.job.input.as_bytes()).await;
+    /// Run the job script as Unix `user`, so that files in working
+    /// directory are owned by the user and quotas apply. This requires the
+    /// server running as root, unless `user` is the server user.
+    pub fn run_as(&mut self, user: &str) {
+        self.run_as = user.to_owned().into();
+    }
 
-        // redirect stdout and stderr to files for user inspection.
-        let mut fout = tokio::fs::File::create(self.out_file()).await?;
-        let mut ferr = tokio::fs::File::create(self.err_file()).await?;
-        tokio::io::copy(&mut stdout, &mut fout).await?;
-        tokio::io::copy(&mut stderr, &mut ferr).await?;
+    /// Keep stdin of the job script open after writing input, so that
+    /// more data can be sent while it runs, e.g. "stop" for codes
+    /// accepting runtime control on stdin.
+    pub fn keep_stdin_open(&mut self, on: bool) {
+        self.keep_stdin_open = on;
+    }
 
-        let sid = session.handler().id();
-        info!("command running in session {:?}", sid);
-        self.session = session.into();
+    /// Feed `data` into stdin of the running job on `trigger`, after the
+    /// initial input and previous phases. Stdin is kept open until all
+    /// phases are fed, for semi-interactive programs prompting for more
+    /// input. Data sent by `Computation::send_stdin` is queued until then.
+    pub fn add_input_phase(&mut self, trigger: InputTrigger, data: &str) {
+        self.input_phases.push(InputPhase {
+            trigger,
+            data: data.into(),
+        });
+    }
 
-        Ok(())
+    /// Require `file` in working directory for the job to succeed, and
+    /// optionally regex `pattern` in its content, e.g. "Normal
+    /// termination" in "job.out". A job exited with 0 but missing expected
+    /// outputs is reported as `JobStatus::Incomplete`.
+    pub fn expect_output<P: AsRef<Path>>(&mut self, file: P, pattern: Option<&str>) {
+        self.expected_outputs.push(ExpectedOutput {
+            file: file.as_ref().to_owned(),
+            pattern: pattern.map(|s| s.to_owned()),
+        });
     }
 
-    /// Return true if session already has been started.
-    fn is_started(&self) -> bool {
-        self.session.is_some()
+    /// Classify failure of the job as `kind` if regex `pattern` matches a
+    /// line in stdout or stderr, e.g. `("basis_missing", "Unknown basis")`.
+    /// The rules are checked before the builtin ones.
+    pub fn add_failure_rule(&mut self, kind: &str, pattern: &str) {
+        self.failure_rules.push(FailureRule::new(kind, pattern));
     }
-}
-// core:1 ends here
 
-// [[file:../runners.note::*extra][extra:1]]
-impl Computation {
-    /// Return a list of full path to extra files required for computation.
-    pub fn extra_files(&self) -> Vec<PathBuf> {
-        self.job.extra_files.iter().map(|f| self.wrk_dir().join(f)).collect()
+    /// Run the job script with file mode creation `mask`, e.g. `0o027` for
+    /// outputs readable by group members. Captured stdout/stderr files
+    /// follow the mask too.
+    pub fn umask(&mut self, mask: u32) {
+        self.umask = Some(mask & 0o777);
     }
 
-    /// Check if job has been done correctly.
-    pub fn is_done(&self) -> bool {
-        let inpfile = self.inp_file();
-        let outfile = self.out_file();
-        let errfile = self.err_file();
+    /// Set the setgid bit on directories in working directory, so that
+    /// files created there belong to the group of the directory instead of
+    /// the primary group of the user. Useful with `wrk_dir_hint` in a
+    /// shared project directory.
+    pub fn group_sticky(&mut self, on: bool) {
+        self.group_sticky = on;
+    }
 
-        if self.wrk_dir().is_dir() {
-            if outfile.is_file() && inpfile.is_file() {
-                if let Ok(time2) = outfile.metadata().and_then(|m| m.modified()) {
-                    if let Ok(time1) = inpfile.metadata().and_then(|m| m.modified()) {
-                        if time2 >= time1 {
-                            return true;
-                        }
-                    }
-                }
-            }
+    /// Set env var `key` to `value` for running the script.
+    pub fn set_env(&mut self, key: &str, value: &str) {
+        self.env.insert(key.into(), value.into());
+    }
+
+    /// Annotate the job with tag `key` of `value`, e.g. `("method", "PBE0")`.
+    pub fn set_tag(&mut self, key: &str, value: &str) {
+        self.tags.insert(key.into(), value.into());
+    }
+
+    /// Return true if the script is run by a shell, judged from the
+    /// interpreter or the shebang line.
+    fn is_shell_script(&self) -> bool {
+        let interpreter = match self.interpreter.as_deref() {
+            Some(s) => s.split_whitespace().next(),
+            None => self
+                .script
+                .lines()
+                .next()
+                .and_then(|line| line.strip_prefix("#!"))
+                .and_then(|line| line.split_whitespace().last()),
+        };
+        interpreter.map_or(false, |interpreter| interpreter.ends_with("sh"))
+    }
+
+    /// Return tags annotating the job.
+    pub fn tags(&self) -> &std::collections::BTreeMap<String, String> {
+        &self.tags
+    }
+
+    /// Return the path to the file for saving output stream of computation.
+    pub fn out_file(&self) -> &Path {
+        &self.out_file
+    }
+
+    /// Return the path to the file for saving error stream of computation.
+    pub fn err_file(&self) -> &Path {
+        &self.err_file
+    }
+
+    /// Return extra files required for computation.
+    pub fn extra_files(&self) -> &[PathBuf] {
+        &self.extra_files
+    }
+
+    /// Add a new file into extra-files list.
+    pub fn attach_file<P: AsRef<Path>>(&mut self, file: P) {
+        let file: PathBuf = file.as_ref().into();
+        if !self.extra_files.contains(&file) {
+            self.extra_files.push(file);
+        } else {
+            warn!("try to attach a dumplicated file: {}!", file.display());
         }
+    }
+}
 
-        false
+impl Job {
+    /// Stage file or directory `src` on the node running the job into
+    /// working directory by `mode` before the job starts. Large read-only
+    /// inputs such as training sets or pseudopotential libraries can be
+    /// linked instead of copied for every job.
+    pub fn attach_local_file<P: AsRef<Path>>(&mut self, src: P, mode: AttachMode) {
+        let src = src.as_ref().to_owned();
+        self.attachments.push(Attachment { src, mode });
     }
+}
 
-    /// Update file timestamps to make sure `is_done` call return true.
-    pub fn fake_done(&self) {
-        todo!()
+impl Default for Job {
+    fn default() -> Self {
+        Self::new("")
     }
 }
-// extra:1 ends here
+// job:1 ends here
 
-// [[file:../runners.note::f4436dc6][f4436dc6]]
-mod db {
-    use super::*;
+// [[file:../runners.note::7c3e9a51][7c3e9a51]]
+impl Job {
+    /// Deserialize a job spec from YAML text. Like `from_toml`, missing
+    /// fields take default values.
+    pub fn from_yaml(s: &str) -> Result<Self> {
+        let job = serde_yaml::from_str(s)?;
+        Ok(job)
+    }
 
-    use bytes::Bytes;
-    use std::sync::Arc;
-    use tokio::sync::Mutex;
+    /// Serialize the full job spec into YAML text.
+    pub fn to_yaml(&self) -> Result<String> {
+        let s = serde_yaml::to_string(self)?;
+        Ok(s)
+    }
+}
+// 7c3e9a51 ends here
 
-    pub use super::impl_jobs_slotmap::Id;
-    use super::impl_jobs_slotmap::JobKey;
-    use super::impl_jobs_slotmap::Jobs;
+// [[file:../runners.note::91d5b3e0][91d5b3e0]]
+/// The status of a submitted job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum JobStatus {
+    /// Not started yet
+    Pending,
+    Running,
+    /// Exited successfully
+    Completed,
+    Failed,
+    /// Exited successfully, but expected outputs are missing
+    Incomplete,
+    /// Killed by kernel OOM killer
+    OutOfMemory,
+    Cancelled,
+    /// Paused for a preempting job with higher priority
+    Paused,
+    /// Still running, but no progress for a long time
+    Stalled,
+    Unknown,
+}
 
-    /// A simple in-memory DB for computational jobs.
-    #[derive(Clone)]
-    pub struct Db {
-        inner: Arc<Mutex<Jobs>>,
+impl JobStatus {
+    /// Return true if the job will not change its status any more.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            Self::Completed | Self::Failed | Self::Incomplete | Self::OutOfMemory | Self::Cancelled
+        )
     }
+}
+// 91d5b3e0 ends here
 
-    impl Db {
-        /// Create an empty `Db`
-        pub fn new() -> Self {
-            Self {
-                inner: Arc::new(Mutex::new(Jobs::new())),
-            }
-        }
+// [[file:../runners.note::61f0d4a8][61f0d4a8]]
+/// A marker in stdout for estimating job progress.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ProgressMarker {
+    /// Regex matching lines of progress
+    pub pattern: String,
+    /// Expected number of matched lines when finished
+    pub expected: usize,
+}
 
-        /// Update the job in `id` with a `new_job`. Return error if job `id`
-        /// has been started.
-        pub async fn update_job(&mut self, id: JobId, new_job: Job) -> Result<()> {
-            debug!("update_job: id={}, job={:?}", id, new_job);
-            let mut jobs = self.inner.lock().await;
-            let k = jobs.check_job(id)?;
-            if jobs[k].is_started() {
-                bail!("job {} has been started", id);
-            } else {
-                jobs[k] = new_job.submit();
-            }
+/// Estimated progress of a job.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Progress {
+    /// Elapsed wall time in seconds
+    pub elapsed: f64,
+    /// Declared timeout in seconds
+    pub timeout: Option<f64>,
+    /// The number of progress markers found in stdout
+    pub markers: Option<usize>,
+    /// Estimated percentage of completion
+    pub percent: Option<f64>,
+}
 
-            Ok(())
+/// Count lines matching `re` in `file` after byte `offset`. Return the
+/// offset after the last complete line and the count, or None if the file
+/// is shorter than `offset`.
+fn count_markers(file: &Path, offset: u64, re: &regex::Regex) -> Result<Option<(u64, usize)>> {
+    use std::io::{BufRead, Seek, SeekFrom};
+
+    let f = std::fs::File::open(file)?;
+    if f.metadata()?.len() < offset {
+        return Ok(None);
+    }
+    let mut reader = std::io::BufReader::new(f);
+    reader.seek(SeekFrom::Start(offset))?;
+    let (mut offset, mut n) = (offset, 0);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        let size = reader.read_until(b'\n', &mut line)?;
+        // leave incomplete line for next call
+        if size == 0 || line.last() != Some(&b'\n') {
+            break;
+        }
+        offset += size as u64;
+        if re.is_match(String::from_utf8_lossy(&line).trim_end_matches('\n')) {
+            n += 1;
         }
+    }
+    Ok(Some((offset, n)))
+}
 
-        /// Return a full list of submitted jobs
-        pub async fn get_job_list(&self) -> Vec<JobId> {
-            self.inner.lock().await.iter().map(|(k, _)| k).collect()
+impl Computation {
+    /// Estimate progress of the job, preferring progress markers over
+    /// elapsed time relative to timeout.
+    fn progress(&mut self) -> Progress {
+        let elapsed = self.started.map(|t| t.elapsed().as_secs_f64()).unwrap_or_default();
+        // only scan stdout appended since last call
+        let out_file = self.out_file();
+        let seen = &mut self.markers_seen;
+        let markers = self.job.progress_marker.as_ref().and_then(|m| {
+            let re = regex::Regex::new(&m.pattern).ok()?;
+            let (offset, n) = *seen;
+            *seen = match count_markers(&out_file, offset, &re) {
+                // the output file was truncated
+                Ok(None) => count_markers(&out_file, 0, &re).ok()??,
+                Ok(Some((offset, new))) => (offset, n + new),
+                Err(_) => return None,
+            };
+            Some(seen.1)
+        });
+        let timeout = self.job.timeout;
+        let percent = if self.status().is_finished() {
+            Some(100.0)
+        } else if self.started.is_none() {
+            Some(0.0)
+        } else if let (Some(n), Some(m)) = (markers, self.job.progress_marker.as_ref()) {
+            Some((100.0 * n as f64 / m.expected.max(1) as f64).min(99.0))
+        } else {
+            timeout.map(|t| (100.0 * elapsed / t).min(99.0))
+        };
+        Progress {
+            elapsed,
+            timeout,
+            markers,
+            percent,
         }
+    }
+}
+// 61f0d4a8 ends here
 
-        /// Put a new file on working directory of job `id`
-        pub async fn put_job_file(&mut self, id: JobId, file: String, body: Bytes) -> Result<()> {
-            debug!("put_job_file: id={}", id);
+// [[file:../runners.note::3c8e0f6d][3c8e0f6d]]
+/// Settings of heartbeat file for detecting hung jobs.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct Heartbeat {
+    /// Check progress every `interval` seconds
+    pub interval: f64,
+    /// Consider job stalled without heartbeat in `stale_after` seconds
+    pub stale_after: f64,
+}
 
-            let jobs = self.inner.lock().await;
-            let id = jobs.check_job(id)?;
+mod heartbeat {
+    use super::*;
+
+    /// Return a signature changing as the job makes progress: total CPU
+    /// time of processes in session `sid`, and size of output `files`.
+    fn progress(sid: u32, files: &[PathBuf]) -> Option<(u64, u64)> {
+        let processes = crate::process::get_processes_in_session(sid).ok()?;
+        if processes.is_empty() {
+            return None;
+        }
+        let cpu: f64 = processes.iter().filter_map(|p| p.get_cpu_time().ok()).sum();
+        let size = files.iter().filter_map(|f| f.metadata().ok()).map(|m| m.len()).sum();
+        Some(((cpu * 100.0) as u64, size))
+    }
+
+    /// Spawn a task touching `file` every `interval` seconds while processes
+    /// in session `sid` make progress. The task exits when all processes
+    /// in session exit.
+    pub fn spawn(sid: u32, file: PathBuf, outputs: Vec<PathBuf>, interval: f64) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut last = None;
+            loop {
+                let current = progress(sid, &outputs);
+                if current.is_none() {
+                    break;
+                }
+                if current != last {
+                    if let Err(e) = gut::fs::write_to_file(&file, &timestamp_now()) {
+                        warn!("failed to touch heartbeat file: {:?}", e);
+                    }
+                    last = current;
+                }
+                tokio::time::sleep(std::time::Duration::from_secs_f64(interval)).await;
+            }
+        })
+    }
+
+    /// Return true if heartbeat `file` is older than `stale_after` seconds.
+    pub fn is_stale(file: &Path, stale_after: f64) -> bool {
+        let age = file
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.elapsed().ok());
+        age.map_or(false, |age| age.as_secs_f64() > stale_after)
+    }
+}
+// 3c8e0f6d ends here
+
+// [[file:../runners.note::8b3e1f6a][8b3e1f6a]]
+mod usage {
+    use super::*;
+    use crate::process::SessionUsage;
+
+    /// Spawn a task appending resource usage of processes in session `sid`
+    /// into CSV `file` every `interval` seconds. The task exits when all
+    /// processes in session exit.
+    pub fn spawn(sid: u32, file: PathBuf, interval: f64) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let t0 = std::time::Instant::now();
+            let mut csv = String::from("time,nprocs,cpu_time,rss\n");
+            loop {
+                let processes = crate::process::get_processes_in_session(sid).unwrap_or_default();
+                if processes.is_empty() {
+                    break;
+                }
+                let u = SessionUsage::collect(&processes);
+                let time = t0.elapsed().as_secs_f64();
+                csv.push_str(&format!("{:.1},{},{:.2},{}\n", time, u.nprocs, u.cpu_time, u.rss));
+                // rewritten in full, so readers never see a partial line
+                if let Err(e) = gut::fs::write_to_file(&file, &csv) {
+                    warn!("failed to write usage file: {:?}", e);
+                }
+                tokio::time::sleep(std::time::Duration::from_secs_f64(interval)).await;
+            }
+        })
+    }
+}
+// 8b3e1f6a ends here
+
+// [[file:../runners.note::f1c7d82e][f1c7d82e]]
+mod oom {
+    use std::collections::BTreeSet;
+    use std::sync::{Arc, Mutex};
+
+    /// Processes and peak memory seen in a job session.
+    #[derive(Debug, Default)]
+    pub struct OomWatch {
+        /// The peak total RSS in bytes
+        pub peak_rss: u64,
+        pids: BTreeSet<u32>,
+    }
+
+    /// The interval for sampling processes in job session.
+    pub const SAMPLE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+    /// Spawn a task recording peak RSS and PIDs of processes in session
+    /// `sid` every `interval` into `watch`.
+    pub fn spawn(sid: u32, watch: Arc<Mutex<OomWatch>>, interval: std::time::Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let processes = crate::process::get_processes_in_session(sid).unwrap_or_default();
+                if processes.is_empty() {
+                    break;
+                }
+                let rss = crate::process::SessionUsage::collect(&processes).rss;
+                {
+                    let mut w = watch.lock().unwrap();
+                    w.peak_rss = w.peak_rss.max(rss);
+                    w.pids.extend(processes.iter().map(|p| p.id()));
+                }
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+
+    /// Return the number of processes killed by OOM killer in the memory
+    /// cgroup of current process, read from cgroup v2 `memory.events`.
+    pub fn cgroup_oom_kills() -> Option<u64> {
+        let cgroup = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+        let path = cgroup.lines().find_map(|line| line.strip_prefix("0::"))?;
+        let events = format!("/sys/fs/cgroup{}/memory.events", path.trim());
+        let events = std::fs::read_to_string(events).ok()?;
+        events
+            .lines()
+            .find_map(|line| line.strip_prefix("oom_kill "))
+            .and_then(|n| n.trim().parse().ok())
+    }
+
+    /// Return PIDs of processes killed by OOM killer from kernel log, which
+    /// may require privileges to read.
+    fn kernel_oom_pids() -> Vec<u32> {
+        let out = match std::process::Command::new("dmesg").output() {
+            Ok(out) if out.status.success() => out.stdout,
+            _ => return vec![],
+        };
+        let re = regex::Regex::new(r"(?:Killed process|oom-kill:.*?pid=)\s*(\d+)").unwrap();
+        re.captures_iter(&String::from_utf8_lossy(&out))
+            .filter_map(|c| c[1].parse().ok())
+            .collect()
+    }
+
+    impl OomWatch {
+        /// Test if any process of the job was OOM-killed, by kernel log or
+        /// by increased OOM kill count since `kills_before` in cgroup.
+        pub fn detect(&self, kills_before: Option<u64>) -> bool {
+            if kernel_oom_pids().iter().any(|pid| self.pids.contains(pid)) {
+                return true;
+            }
+            match (kills_before, cgroup_oom_kills()) {
+                (Some(n0), Some(n1)) => n1 > n0,
+                _ => false,
+            }
+        }
+    }
+}
+// f1c7d82e ends here
+
+// [[file:../runners.note::3e9b0d47][3e9b0d47]]
+/// What to do with stray files a job created in `/tmp` or home directory
+/// instead of its working directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrayFiles {
+    /// List them in `stray-files.txt` in working directory
+    Report,
+    /// Remove them, and list them as in `Report`. Only applies to jobs run
+    /// as a dedicated user other than the server's, which are reported
+    /// only otherwise.
+    Clean,
+}
+
+mod stray {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::os::unix::fs::MetadataExt;
+
+    /// Entries in scratch locations before the job starts.
+    #[derive(Debug, Default)]
+    pub struct Snapshot {
+        roots: Vec<PathBuf>,
+        entries: BTreeSet<PathBuf>,
+    }
+
+    /// List top-level entries in `dir`.
+    fn list(dir: &Path) -> impl Iterator<Item = PathBuf> {
+        std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok().map(|e| e.path()))
+    }
+
+    impl Snapshot {
+        /// Record entries in `/tmp`, temporary directory and `home`.
+        pub fn take(home: Option<PathBuf>) -> Self {
+            let mut roots = vec![PathBuf::from("/tmp"), std::env::temp_dir()];
+            roots.extend(home);
+            roots.sort();
+            roots.dedup();
+            let entries = roots.iter().flat_map(|d| list(d)).collect();
+            Self { roots, entries }
+        }
+
+        /// Return entries owned by `uid` created since the snapshot,
+        /// excluding working directories of other jobs.
+        pub fn new_entries(&self, uid: u32) -> Vec<PathBuf> {
+            self.roots
+                .iter()
+                .flat_map(|d| list(d))
+                .filter(|p| !self.entries.contains(p))
+                .filter(|p| p.symlink_metadata().map_or(false, |m| m.uid() == uid))
+                .filter(|p| !p.join(WRAPPER_FILE).exists())
+                .collect()
+        }
+    }
+}
+// 3e9b0d47 ends here
+
+// [[file:../runners.note::c4e8a1b9][c4e8a1b9]]
+/// Return the default scratch variables for common programs, mapped to
+/// directories relative to working directory.
+pub fn default_scratch_vars() -> std::collections::BTreeMap<String, String> {
+    [
+        ("TMPDIR", "tmp"),
+        ("TMP", "tmp"),
+        ("TEMP", "tmp"),
+        ("GAUSS_SCRDIR", "tmp"),
+        ("ORCA_TMPDIR", "tmp"),
+        ("QCSCRATCH", "tmp"),
+        ("PSI_SCRATCH", "tmp"),
+        ("TURBOTMPDIR", "tmp"),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect()
+}
+
+impl Computation {
+    /// Create scratch directories in working directory, and return env
+    /// vars pointing to them, so that scratch files are cleaned up with the
+    /// job.
+    fn setup_scratch_dirs(&self) -> Result<Vec<(String, String)>> {
+        let wdir = self.wrk_dir().canonicalize()?;
+        let mut vars = vec![];
+        for (k, d) in &self.scratch_vars {
+            let d = Path::new(d);
+            ensure!(
+                d.is_relative() && d.components().all(|c| matches!(c, std::path::Component::Normal(_))),
+                "scratch dir of {} is not inside working directory: {:?}",
+                k,
+                d
+            );
+            let dir = wdir.join(d);
+            std::fs::create_dir_all(&dir)?;
+            vars.push((k.clone(), dir.to_string_lossy().into_owned()));
+        }
+        Ok(vars)
+    }
+}
+// c4e8a1b9 ends here
+
+// [[file:../runners.note::9e2d6b40][9e2d6b40]]
+/// The maximum number of child jobs in a job array.
+const MAX_ARRAY_SIZE: usize = 10000;
+
+/// Parse job array `range` like "1..100" or "1-100", both ends included.
+fn parse_array_range(range: &str) -> Result<std::ops::RangeInclusive<usize>> {
+    let invalid = || format!("invalid job array range: {:?}", range);
+    let (a, b) = range
+        .split_once("..")
+        .or_else(|| range.split_once('-'))
+        .with_context(invalid)?;
+    let a: usize = a.trim().parse().with_context(invalid)?;
+    let b: usize = b.trim().parse().with_context(invalid)?;
+    ensure!(a <= b, "empty job array range: {:?}", range);
+    ensure!(b - a < MAX_ARRAY_SIZE, "job array larger than {}", MAX_ARRAY_SIZE);
+    Ok(a..=b)
+}
+
+/// Aggregate status of child jobs in a job array.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ArrayStatus {
+    /// The child jobs ordered by index
+    pub jobs: Vec<JobId>,
+    /// The number of child jobs in each status
+    pub counts: std::collections::BTreeMap<String, usize>,
+    /// The number of finished child jobs
+    pub finished: usize,
+}
+
+impl ArrayStatus {
+    /// Return true if all child jobs finished.
+    pub fn is_finished(&self) -> bool {
+        self.finished == self.jobs.len()
+    }
+}
+// 9e2d6b40 ends here
+
+// [[file:../runners.note::3d7c0a95][3d7c0a95]]
+/// When to stop waiting for multiple jobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WaitPolicy {
+    /// Wait until all jobs finish
+    All,
+    /// Wait until any job finishes
+    Any,
+    /// Wait until all jobs finish, or stop at the first failed job
+    FirstFailure,
+}
+
+impl Default for WaitPolicy {
+    fn default() -> Self {
+        Self::All
+    }
+}
+// 3d7c0a95 ends here
+
+// [[file:../runners.note::a5e71c3b][a5e71c3b]]
+/// Error returned when job working directories have used up the scratch
+/// disk budget. Clients may back off and submit again later.
+#[derive(Debug, Clone, Copy)]
+pub struct ScratchFull {
+    /// Current usage in bytes
+    pub used: u64,
+    /// The budget in bytes
+    pub budget: u64,
+}
+
+impl std::fmt::Display for ScratchFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "scratch full: {} bytes used, budget {} bytes", self.used, self.budget)
+    }
+}
+
+impl std::error::Error for ScratchFull {}
+
+/// Error returned when too many submitted jobs are waiting to start.
+/// Clients may back off and submit again later.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueFull {
+    /// The number of jobs waiting to start
+    pub pending: usize,
+    /// The maximum number of waiting jobs
+    pub max: usize,
+}
+
+impl std::fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "queue full: {} jobs waiting, max {}", self.pending, self.max)
+    }
+}
+
+impl std::error::Error for QueueFull {}
+// a5e71c3b ends here
+
+// [[file:../runners.note::2f6d0c58][2f6d0c58]]
+/// The run conditions of a job recorded for reproducibility.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RunMeta {
+    /// Environment variables of the spawned session
+    pub env: std::collections::BTreeMap<String, String>,
+    /// The working directory
+    pub cwd: PathBuf,
+    /// The command line to start the job
+    pub cmdline: Vec<String>,
+    /// The host running the job
+    pub hostname: String,
+    /// The date the job started
+    pub date: String,
+    /// Outputs of post-run hooks
+    #[serde(default)]
+    pub hooks: Vec<HookOutput>,
+    /// Executables seen running in the job session
+    #[serde(default)]
+    pub programs: Vec<ProgramInfo>,
+}
+
+/// An executable run by a job. The size and modification time tell apart
+/// different builds of the same program.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ProgramInfo {
+    pub exe: PathBuf,
+    pub size: u64,
+    /// The modification time in RFC 3339 format
+    pub modified: String,
+}
+
+/// Timing and exit status of job script recorded by the wrapper into
+/// `run.stat.json`, independent of the runner process surviving.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct RunStat {
+    /// When the script started, in seconds since the Unix epoch
+    pub start: f64,
+    /// When the script exited, in seconds since the Unix epoch
+    pub end: f64,
+    /// The exit status, or 128 + signal number if killed by a signal
+    pub exit_code: i32,
+    /// User CPU time of the script and its children in seconds
+    pub user_time: f64,
+    /// System CPU time of the script and its children in seconds
+    pub system_time: f64,
+}
+
+/// The wrapper running job script, recording its exit status and timing.
+/// Termination signals are caught so that the records are written after
+/// the script exits.
+const WRAPPER_SCRIPT: &str = r#"#!/bin/sh
+# generated by gosh-runner: record exit status and timing of job script
+trap : HUP INT TERM
+start=$(date +%s.%N)
+"$@"
+code=$?
+end=$(date +%s.%N)
+times > .run-times.tmp
+awk -v start="$start" -v end="$end" -v code="$code" '
+function secs(t) { split(t, a, /[ms]/); return a[1] * 60 + a[2] }
+NR == 2 {
+  printf "{\n  \"start\": %s,\n  \"end\": %s,\n  \"exit_code\": %d,\n", start, end, code
+  printf "  \"user_time\": %.3f,\n  \"system_time\": %.3f\n}\n", secs($1), secs($2)
+}' .run-times.tmp > run.stat.json.tmp && mv -f run.stat.json.tmp run.stat.json
+rm -f .run-times.tmp
+echo $code > .exit-status.tmp && mv -f .exit-status.tmp .exit-status
+exit $code
+"#;
+
+/// The name of wrapper script in working directory.
+const WRAPPER_FILE: &str = ".run-wrapper";
+
+impl ProgramInfo {
+    fn from_exe(exe: &Path) -> Result<Self> {
+        let m = std::fs::metadata(exe)?;
+        let modified: chrono::DateTime<chrono::Local> = m.modified()?.into();
+        let info = Self {
+            exe: exe.to_owned(),
+            size: m.len(),
+            modified: modified.to_rfc3339(),
+        };
+        Ok(info)
+    }
+}
+
+/// Environment variables of the runner holding secrets, which are not
+/// passed to jobs.
+const RUNNER_SECRETS: &[&str] = &["GOSH_GATEWAY_TOKEN", "NAILGUN_TOKEN"];
+
+impl RunMeta {
+    /// Capture current run conditions for command line `cmdline` in `cwd`.
+    /// The job inherits environment of the runner except secrets, with
+    /// `vars` set on top of it.
+    fn capture(cwd: &Path, cmdline: Vec<String>, vars: &[(String, String)]) -> Self {
+        let mut env: std::collections::BTreeMap<_, _> = std::env::vars_os()
+            .filter(|(k, _)| !RUNNER_SECRETS.iter().any(|x| k == x))
+            .map(|(k, v)| (k.to_string_lossy().into_owned(), v.to_string_lossy().into_owned()))
+            .collect();
+        env.extend(vars.iter().cloned());
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|s| s.trim().to_owned())
+            .unwrap_or_default();
+        Self {
+            env,
+            cwd: cwd.to_owned(),
+            cmdline,
+            hostname,
+            date: timestamp_now(),
+            hooks: vec![],
+            programs: vec![],
+        }
+    }
+}
+// 2f6d0c58 ends here
+
+// [[file:../runners.note::8d41c2fa][8d41c2fa]]
+/// What to do when captured output exceeds the size limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum OutputLimitAction {
+    /// Stop writing with a marker line. The remaining output is discarded.
+    Truncate,
+    /// Move current file to a backup with suffix `.1`, and start a new one.
+    Rotate,
+    /// Terminate the job.
+    Kill,
+    /// Compress current file into a backup with suffix `.1.gz`, and start a
+    /// new one. At most `keep` backups are kept.
+    #[serde(serialize_with = "serialize_rotate_gzip")]
+    RotateGzip { keep: usize },
+}
+
+/// Serialize `RotateGzip` as a newtype variant holding `keep`, which has the
+/// same JSON form, as TOML does not support struct variants.
+fn serialize_rotate_gzip<S: serde::Serializer>(keep: &usize, s: S) -> std::result::Result<S::Ok, S::Error> {
+    use serde::ser::SerializeStruct;
+
+    let mut st = s.serialize_struct("RotateGzip", 1)?;
+    st.serialize_field("keep", keep)?;
+    st.end()
+}
+
+/// Size limit on captured output of a job.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct OutputLimit {
+    /// The maximum size in bytes
+    pub max_bytes: u64,
+    pub action: OutputLimitAction,
+}
+
+mod output {
+    use super::*;
+    use crate::process::SessionHandler;
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+    /// Copy all `reader` data into file `path`, applying output `limit`.
+    /// Return the number of bytes read from `reader`.
+    pub async fn copy_output<R>(
+        mut reader: R,
+        path: &Path,
+        limit: Option<OutputLimit>,
+        handler: &SessionHandler,
+    ) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut f = tokio::fs::File::create(path).await?;
+        let mut buf = vec![0u8; 8192];
+        let mut total = 0;
+        // bytes written into current file
+        let mut written = 0;
+        // true if output is discarded
+        let mut discarding = false;
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            total += n as u64;
+            if discarding {
+                continue;
+            }
+            let max = limit.map(|l| l.max_bytes).unwrap_or(u64::MAX);
+            if written + n as u64 <= max {
+                f.write_all(&buf[..n]).await?;
+                written += n as u64;
+                continue;
+            }
+
+            // the limit exceeded
+            let limit = limit.unwrap();
+            let m = (max - written) as usize;
+            f.write_all(&buf[..m]).await?;
+            match limit.action {
+                OutputLimitAction::Truncate => {
+                    warn!("output truncated: {}", path.display());
+                    let marker = format!("\n[output truncated at {} bytes]\n", max);
+                    f.write_all(marker.as_bytes()).await?;
+                    discarding = true;
+                }
+                OutputLimitAction::Rotate => {
+                    f.flush().await?;
+                    let backup = PathBuf::from(format!("{}.1", path.display()));
+                    info!("rotate output file {} to {}", path.display(), backup.display());
+                    tokio::fs::rename(path, &backup).await?;
+                    f = tokio::fs::File::create(path).await?;
+                    f.write_all(&buf[m..n]).await?;
+                    written = (n - m) as u64;
+                }
+                OutputLimitAction::RotateGzip { keep } => {
+                    f.flush().await?;
+                    drop(f);
+                    let path_ = path.to_owned();
+                    tokio::task::spawn_blocking(move || rotate_gzip(&path_, keep)).await??;
+                    f = tokio::fs::File::create(path).await?;
+                    f.write_all(&buf[m..n]).await?;
+                    written = (n - m) as u64;
+                }
+                OutputLimitAction::Kill => {
+                    error!("output exceeds {} bytes, terminating job: {}", max, path.display());
+                    let marker = format!("\n[job terminated: output exceeds {} bytes]\n", max);
+                    f.write_all(marker.as_bytes()).await?;
+                    handler.terminate()?;
+                    discarding = true;
+                }
+            }
+        }
+        f.flush().await?;
+
+        Ok(total)
+    }
+
+    /// Compress `path` into `path.1.gz`, shifting older backups up to `keep`.
+    fn rotate_gzip(path: &Path, keep: usize) -> Result<()> {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+
+        let backup = |i: usize| PathBuf::from(format!("{}.{}.gz", path.display(), i));
+        for i in (1..keep).rev() {
+            if backup(i).exists() {
+                std::fs::rename(backup(i), backup(i + 1))?;
+            }
+        }
+        if keep > 0 {
+            info!("rotate output file {} to {}", path.display(), backup(1).display());
+            let mut reader = std::fs::File::open(path)?;
+            let writer = std::fs::File::create(backup(1))?;
+            let mut encoder = GzEncoder::new(writer, Compression::default());
+            std::io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?;
+        }
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
+// 8d41c2fa ends here
+
+// [[file:../runners.note::f27c80d5][f27c80d5]]
+/// When to feed an input phase into stdin of running job.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputTrigger {
+    /// Seconds elapsed since previous phase was fed
+    After(f64),
+    /// Regex pattern matched in stdout since previous phase was fed
+    Output(String),
+}
+
+/// A section of input fed into stdin of running job on `trigger`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct InputPhase {
+    pub trigger: InputTrigger,
+    pub data: String,
+}
+
+/// Feed input `phases` into `stdin` in order, watching `out_file` for
+/// output triggers.
+async fn feed_input_phases(
+    stdin: &mut tokio::process::ChildStdin,
+    phases: &[InputPhase],
+    out_file: &Path,
+) -> Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    let interval = std::time::Duration::from_millis(200);
+    // bytes of stdout read so far, and those not matched by patterns yet
+    let mut offset = 0;
+    let mut pending = vec![];
+    for phase in phases {
+        match &phase.trigger {
+            InputTrigger::After(secs) => tokio::time::sleep(std::time::Duration::from_secs_f64(*secs)).await,
+            InputTrigger::Output(pattern) => {
+                let re = regex::bytes::Regex::new(pattern)?;
+                loop {
+                    // only read output appended since last time
+                    if let Ok(mut f) = tokio::fs::File::open(out_file).await {
+                        f.seek(std::io::SeekFrom::Start(offset)).await?;
+                        offset += f.read_to_end(&mut pending).await? as u64;
+                    }
+                    if let Some(m) = re.find(&pending) {
+                        pending.drain(..m.end());
+                        break;
+                    }
+                    tokio::time::sleep(interval).await;
+                }
+            }
+        }
+        debug!("feed input phase on {:?}", phase.trigger);
+        stdin.write_all(phase.data.as_bytes()).await?;
+        stdin.flush().await?;
+    }
+    Ok(())
+}
+// f27c80d5 ends here
+
+// [[file:../runners.note::*base][base:1]]
+/// The working directory of a computation.
+enum WorkDir {
+    /// A temporary directory removed with the job
+    Temp(TempDir),
+    /// A user specified directory kept after the job
+    Fixed(PathBuf),
+}
+
+impl WorkDir {
+    /// Create the working directory for a job, in `hint` if set, or a new
+    /// temporary directory in scratch space.
+    fn create(hint: Option<&Path>) -> Result<Self> {
+        match hint {
+            Some(hint) => Ok(Self::Fixed(resolve_wrk_dir_hint(hint)?)),
+            None => Ok(Self::Temp(TempDir::new_in(".")?)),
+        }
+    }
+
+    fn path(&self) -> &Path {
+        match self {
+            Self::Temp(d) => d.path(),
+            Self::Fixed(d) => d,
+        }
+    }
+
+    /// Keep the directory, and return its path. The directory is not
+    /// removed on drop any more.
+    fn keep(&mut self) -> PathBuf {
+        let path = match std::mem::replace(self, Self::Fixed(PathBuf::new())) {
+            Self::Temp(d) => d.keep(),
+            Self::Fixed(d) => d,
+        };
+        *self = Self::Fixed(path.clone());
+        path
+    }
+}
+
+/// Return the full path to `file` in working directory `wdir` of a job.
+/// Return error for absolute paths or `..`, or if the path resolves
+/// outside `wdir` through symlinks, so that clients cannot access other
+/// files by job file APIs.
+pub(crate) fn job_file_path(wdir: &Path, file: &Path) -> Result<PathBuf> {
+    use std::path::Component;
+
+    ensure!(
+        file.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)),
+        "invalid job file {:?}: only relative path inside work dir allowed",
+        file
+    );
+    let root = wdir.canonicalize().with_context(|| format!("invalid work dir {:?}", wdir))?;
+    let path = wdir.join(file);
+    // the file may not exist yet, check the nearest existing ancestor. A
+    // dangling symlink fails to resolve.
+    if let Some(existing) = path.ancestors().find(|p| p.symlink_metadata().is_ok()) {
+        let resolved = existing
+            .canonicalize()
+            .with_context(|| format!("invalid job file {:?}", file))?;
+        ensure!(
+            resolved.starts_with(&root),
+            "job file {:?} resolves to {:?} outside work dir",
+            file,
+            resolved
+        );
+    }
+    Ok(path)
+}
+
+/// Return the dirs working directories of jobs may be in: the dirs listed
+/// in `GOSH_RUNNER_WORK_DIRS`, and the scratch directory if `scratch` is
+/// true.
+fn wrk_dir_roots(scratch: bool) -> Result<Vec<PathBuf>> {
+    let mut roots = vec![];
+    if scratch {
+        roots.push(std::env::current_dir()?.canonicalize()?);
+    }
+    if let Some(dirs) = std::env::var_os("GOSH_RUNNER_WORK_DIRS") {
+        roots.extend(std::env::split_paths(&dirs).filter_map(|d| d.canonicalize().ok()));
+    }
+    Ok(roots)
+}
+
+/// Return the canonical path of working directory `hint`, which may not
+/// exist yet. Return error if it is not inside `roots`, so that jobs cannot
+/// escape with `..` or symlinks.
+fn canonicalize_wrk_dir(hint: &Path, roots: &[PathBuf]) -> Result<PathBuf> {
+    use std::path::Component;
+
+    ensure!(
+        !hint.components().any(|c| c == Component::ParentDir),
+        "invalid work dir {:?}: parent dir not allowed",
+        hint
+    );
+    let path = std::env::current_dir()?.join(hint);
+    let path = if path.exists() {
+        path.canonicalize()?
+    } else {
+        let parent = path.parent().context("invalid work dir")?;
+        let name = path.file_name().context("invalid work dir")?;
+        parent
+            .canonicalize()
+            .with_context(|| format!("invalid work dir {:?}", hint))?
+            .join(name)
+    };
+    ensure!(
+        roots.iter().any(|root| path.starts_with(root)),
+        "work dir {:?} resolves to {:?} outside allowed dirs {:?}",
+        hint,
+        path,
+        roots
+    );
+    Ok(path)
+}
+
+/// Resolve working directory `hint` of a job, creating it if not exists.
+/// Return error if it is not inside the scratch directory or the dirs
+/// allowed by `GOSH_RUNNER_WORK_DIRS`.
+fn resolve_wrk_dir_hint(hint: &Path) -> Result<PathBuf> {
+    let roots = wrk_dir_roots(true)?;
+    let path = canonicalize_wrk_dir(hint, &roots)?;
+    if !path.exists() {
+        std::fs::create_dir(&path).with_context(|| format!("create work dir {:?}", path))?;
+    }
+    // the dir itself may be a symlink
+    canonicalize_wrk_dir(&path, &roots)
+}
+
+/// Check working directory `hint` of a job from a normal user, running as
+/// Unix user `run_as`: it must be inside the dirs allowed by
+/// `GOSH_RUNNER_WORK_DIRS`, and owned by the job user if it exists.
+fn check_wrk_dir_hint(hint: &Path, run_as: Option<&str>) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = canonicalize_wrk_dir(hint, &wrk_dir_roots(false)?)?;
+    if path.exists() {
+        let owner = path.metadata()?.uid();
+        ensure!(
+            owner == job_uid(run_as)?,
+            "work dir {:?} is not owned by the job user",
+            hint
+        );
+    }
+    Ok(())
+}
+
+/// Return the uid a job runs as: of Unix user `run_as` if set, or of the
+/// server.
+fn job_uid(run_as: Option<&str>) -> Result<u32> {
+    match run_as {
+        Some(name) => Ok(RunAs::resolve(name)?.uid.as_raw()),
+        None => Ok(nix::unistd::geteuid().as_raw()),
+    }
+}
+
+/// Why a running job is paused. The job is resumed when no reason is left.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum PauseReason {
+    Preempted,
+    Window,
+    Throttle,
+}
+
+impl std::fmt::Display for PauseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Preempted => write!(f, "for a preempting job"),
+            Self::Window => write!(f, "outside execution window"),
+            Self::Throttle => write!(f, "by throttling"),
+        }
+    }
+}
+
+/// Computation represents a submitted `Job`
+pub struct Computation {
+    job: Job,
+
+    // command session. The drop order is above Tempdir
+    session: Option<crate::process::Session<tokio::process::Child>>,
+
+    // job submitted to a non-local backend. The drop order is above Tempdir
+    submitted: Option<std::sync::Arc<Submitted>>,
+    // the last polled status of submitted job
+    submitted_status: JobStatus,
+
+    // resources allocated by the scheduler
+    allocation: Allocation,
+
+    // job status when run by a custom `JobRunner`
+    runner_status: Option<JobStatus>,
+
+    // set for a job recovered from its working directory after the runner
+    // restarts, with its session if still running
+    recovered: bool,
+    reattached: Option<crate::process::SessionHandler>,
+
+    // background task touching heartbeat file
+    heartbeat_task: Option<tokio::task::JoinHandle<()>>,
+
+    // background task sampling resource usage
+    usage_task: Option<tokio::task::JoinHandle<()>>,
+
+    // background task feeding input into stdin, which may wait for output
+    // never coming
+    input_task: Option<tokio::task::JoinHandle<()>>,
+
+    // for detecting OOM killed jobs: the watch task, peak memory and PIDs
+    // seen, and OOM kill count in cgroup before the job started
+    oom_task: Option<tokio::task::JoinHandle<()>>,
+    oom_watch: std::sync::Arc<std::sync::Mutex<oom::OomWatch>>,
+    oom_kills_before: Option<u64>,
+    // set to peak RSS if the job was OOM killed
+    out_of_memory: Option<u64>,
+
+    // env vars of scratch dirs created in working directory
+    scratch_vars: std::collections::BTreeMap<String, String>,
+
+    // scratch locations before the job started, and stray files found
+    stray_snapshot: Option<stray::Snapshot>,
+    stray_files: Vec<PathBuf>,
+
+    // when the job was submitted
+    created: std::time::Instant,
+
+    // transitions of the job, and reasons it is paused for
+    history: Vec<JobEvent>,
+    paused: std::collections::BTreeSet<PauseReason>,
+
+    // time spent paused, not counted against the timeout
+    paused_since: Option<std::time::Instant>,
+    paused_total: std::time::Duration,
+
+    // bytes of stdout scanned for progress markers, and markers found
+    markers_seen: (u64, usize),
+
+    // for accounting of finished job
+    started: Option<std::time::Instant>,
+    finished: Option<std::time::Instant>,
+    exit_code: Option<i32>,
+    cpu_time: f64,
+
+    // background tasks copying stdout/stderr into files
+    copiers: Vec<tokio::task::JoinHandle<Result<u64>>>,
+
+    // for feeding more input into stdin kept open
+    stdin_tx: Option<tokio::sync::mpsc::UnboundedSender<Vec<u8>>>,
+
+    // set by the first waiter running the job, for sharing its result with
+    // later waiters
+    waited: Option<tokio::sync::watch::Sender<Option<Result<JobResult, String>>>>,
+
+    /// The name of user submitted the job
+    owner: Option<String>,
+
+    /// The working directory of computation
+    wrk_dir: WorkDir,
+}
+// base:1 ends here
+
+// [[file:../runners.note::*paths][paths:1]]
+impl Computation {
+    /// Return the name of user submitted the job.
+    pub fn owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+
+    /// The full path to the working directory for running the job.
+    pub fn wrk_dir(&self) -> &Path {
+        self.wrk_dir.path()
+    }
+
+    /// The full path to computation input file (stdin).
+    pub fn inp_file(&self) -> PathBuf {
+        self.wrk_dir().join(&self.job.inp_file)
+    }
+
+    /// The full path to computation output file (stdout).
+    pub fn out_file(&self) -> PathBuf {
+        self.wrk_dir().join(&self.job.out_file)
+    }
+
+    /// The full path to computation error file (stderr).
+    pub fn err_file(&self) -> PathBuf {
+        self.wrk_dir().join(&self.job.err_file)
+    }
+
+    /// The full path to the script for running the job.
+    pub fn run_file(&self) -> PathBuf {
+        self.wrk_dir().join(&self.job.run_file)
+    }
+
+    /// The full path to the heartbeat file of running job.
+    pub fn heartbeat_file(&self) -> PathBuf {
+        self.wrk_dir().join("HEARTBEAT")
+    }
+
+    /// The full path to the file of resource usage samples.
+    pub fn usage_file(&self) -> PathBuf {
+        self.wrk_dir().join("usage.csv")
+    }
+
+    /// The full path to the file recording run conditions of the job.
+    pub fn meta_file(&self) -> PathBuf {
+        self.wrk_dir().join("run.meta.json")
+    }
+
+    /// The full path to the file recording timing and exit status of the
+    /// job script, written by the wrapper when the script exits.
+    pub fn stat_file(&self) -> PathBuf {
+        self.wrk_dir().join("run.stat.json")
+    }
+
+    /// The full path to the sentinel file holding exit status of the job
+    /// script, written by the wrapper when the script exits.
+    pub fn exit_status_file(&self) -> PathBuf {
+        self.wrk_dir().join(EXIT_STATUS_FILE)
+    }
+
+    /// The full path to the file recording the session leader of running
+    /// job.
+    pub fn session_file(&self) -> PathBuf {
+        self.wrk_dir().join(".session.json")
+    }
+
+    /// The full path to the file saving the job for recovering it after
+    /// runner restarts.
+    pub fn recovery_file(&self) -> PathBuf {
+        self.wrk_dir().join(RECOVERY_FILE)
+    }
+}
+// paths:1 ends here
+
+// [[file:../runners.note::*core][core:1]]
+use tokio::io::AsyncWriteExt;
+
+impl Job {
+    /// Submit the job and turn it into Computation. Return error if the
+    /// working directory could not be set up, e.g. scratch disk is full.
+    pub fn submit(self) -> Result<Computation> {
+        Computation::new(self)
+    }
+
+    /// Materialize the working directory with run script and input files,
+    /// and print what would be executed without spawning any process.
+    /// Return the path to the kept working directory.
+    pub fn dry_run(self) -> Result<PathBuf> {
+        self.submit()?.dry_run()
+    }
+}
+
+/// The maximum of durations in job spec in seconds, about ten years.
+const MAX_DURATION_SECS: f64 = 3.2e8;
+
+/// Check duration `secs` of `what` in job spec is positive and not longer
+/// than `MAX_DURATION_SECS`, so that it can be converted to `Duration`.
+fn check_duration(what: &str, secs: f64) -> Result<()> {
+    let valid = secs > 0.0 && secs <= MAX_DURATION_SECS;
+    ensure!(valid, "invalid {}: {} seconds", what, secs);
+    Ok(())
+}
+
+impl Computation {
+    /// Construct `Computation` of user inputted `Job`.
+    pub fn new(job: Job) -> Result<Self> {
+        use std::fs::File;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        if let Some(t) = job.timeout {
+            check_duration("timeout", t)?;
+        }
+        if let Some(hb) = job.heartbeat {
+            check_duration("heartbeat interval", hb.interval)?;
+            check_duration("heartbeat stale time", hb.stale_after)?;
+        }
+        if let Some(interval) = job.usage_interval {
+            check_duration("usage interval", interval)?;
+        }
+        for phase in &job.input_phases {
+            // zero delay feeds right after previous phase
+            if let InputTrigger::After(secs) = phase.trigger {
+                if secs != 0.0 {
+                    check_duration("input phase delay", secs)?;
+                }
+            }
+        }
+        ensure!(
+            job.sandbox.is_none() || !matches!(job.backend, Backend::Ssh(_)),
+            "sandbox is not supported on ssh backend"
+        );
+        if let Some(name) = job.run_as.as_deref() {
+            ensure!(
+                matches!(job.backend, Backend::Local),
+                "running as another user is only supported on local backend"
+            );
+            RunAs::resolve(name)?;
+        }
+        // create working directory in scratch space.
+        let wdir = WorkDir::create(job.wrk_dir_hint.as_deref()).context("create job work dir")?;
+        for f in [&job.inp_file, &job.out_file, &job.err_file, &job.run_file]
+            .into_iter()
+            .chain(&job.extra_files)
+        {
+            job_file_path(wdir.path(), f)?;
+        }
+        let session = Self::from_parts(job, wdir);
+
+        // create run file
+        let file = session.run_file();
+
+        // make run script executable
+        std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .mode(0o770)
+            .open(&file)
+            .and_then(|mut f| f.write_all(session.job.script.as_bytes()))
+            .with_context(|| format!("create job run file {:?}", file))?;
+        trace!("script content wrote to: {}.", file.display());
+
+        let file = session.inp_file();
+        File::create(&file)
+            .and_then(|mut f| f.write_all(session.job.input.as_bytes()))
+            .with_context(|| format!("create job input file {:?}", file))?;
+        trace!("input content wrote to: {}.", file.display());
+
+        gut::fs::write_to_file(session.wrk_dir().join(WRAPPER_FILE), WRAPPER_SCRIPT)
+            .context("create job wrapper file")?;
+
+        Ok(session)
+    }
+
+    /// Recover the job started in working directory `dir` before the runner
+    /// restarts, reattaching to its session if still running. The
+    /// directory is kept after the job is removed.
+    pub fn recover(dir: &Path) -> Result<Self> {
+        let file = dir.join(RECOVERY_FILE);
+        let s = gut::fs::read_file(&file).with_context(|| format!("read job recovery file {:?}", file))?;
+        let saved: SavedJob = serde_json::from_str(&s).with_context(|| format!("parse {:?}", file))?;
+        let mut session = Self::from_parts(saved.job, WorkDir::Fixed(dir.to_owned()));
+        session.owner = saved.owner;
+        session.recovered = true;
+        // the session could have exited, or its PID reused
+        match crate::process::SessionHandler::load(session.session_file()) {
+            Ok(handler) => session.reattached = handler.into(),
+            Err(e) => info!("job in {:?} not running: {:?}", dir, e),
+        }
+        session.started = std::time::Instant::now().into();
+        session.record_event("recovered");
+        Ok(session)
+    }
+
+    fn from_parts(job: Job, wrk_dir: WorkDir) -> Self {
+        Computation {
+            job,
+            wrk_dir,
+            session: None,
+            submitted: None,
+            submitted_status: JobStatus::Pending,
+            allocation: Allocation::default(),
+            runner_status: None,
+            recovered: false,
+            reattached: None,
+            heartbeat_task: None,
+            usage_task: None,
+            input_task: None,
+            oom_task: None,
+            oom_watch: Default::default(),
+            oom_kills_before: None,
+            out_of_memory: None,
+            scratch_vars: default_scratch_vars(),
+            stray_snapshot: None,
+            stray_files: vec![],
+            created: std::time::Instant::now(),
+            history: vec![],
+            paused: Default::default(),
+            paused_since: None,
+            paused_total: std::time::Duration::ZERO,
+            markers_seen: (0, 0),
+            started: None,
+            finished: None,
+            exit_code: None,
+            cpu_time: 0.0,
+            copiers: vec![],
+            stdin_tx: None,
+            waited: None,
+            owner: None,
+        }
+    }
+
+    /// Wait for background command to complete, and all its output has
+    /// been captured.
+    async fn wait(&mut self) -> Result<()> {
+        if let Some(s) = self.session.as_mut() {
+            let ecode = s.child.wait().await?;
+            info!("job session exited: {}", ecode);
+            self.exit_code = ecode.code();
+            // CPU time of the script and its reaped children, as recorded by
+            // the wrapper at exit
+            self.cpu_time = self.run_stat().map(|s| s.user_time + s.system_time).unwrap_or_default();
+            if let Some(task) = self.heartbeat_task.take() {
+                task.abort();
+            }
+            if let Some(task) = self.usage_task.take() {
+                task.abort();
+            }
+            if let Some(task) = self.input_task.take() {
+                task.abort();
+            }
+            if let Some(task) = self.oom_task.take() {
+                task.abort();
+            }
+            if !ecode.success() {
+                let watch = self.oom_watch.lock().unwrap();
+                if watch.detect(self.oom_kills_before) {
+                    warn!("job was killed by OOM killer, peak RSS {} bytes", watch.peak_rss);
+                    self.out_of_memory = watch.peak_rss.into();
+                }
+            }
+            for copier in self.copiers.drain(..) {
+                let n = copier.await??;
+                trace!("captured {} bytes of output", n);
+            }
+            self.handle_stray_files()?;
+        } else if self.submitted.is_some() {
+            // polled until finished without holding the job queue
+            info!("submitted job finished: {:?}", self.submitted_status);
+        } else if self.recovered {
+            if let Some(handler) = self.reattached.as_ref() {
+                handler.on_exit().await?;
+            }
+            self.exit_code = self.recorded_exit_status();
+            self.cpu_time = self.run_stat().map(|s| s.user_time + s.system_time).unwrap_or_default();
+        } else {
+            error!("Job not started yet.");
+            return Ok(());
+        }
+        let status = self.status();
+        self.finished = std::time::Instant::now().into();
+        self.record_event(format!("finished: {:?}", status));
+        Ok(())
+    }
+
+    /// Return the post-run work of the finished job, to be run without
+    /// holding the job queue.
+    fn post_run(&self) -> Result<PostRun> {
+        // hooks only run for jobs started by us, having run metadata
+        let hooks = match self.session.is_some() || self.submitted.is_some() || self.recovered {
+            true => self.job.hooks.clone(),
+            false => vec![],
+        };
+        let post_run = PostRun {
+            shell: self.shell()?,
+            parsers: self.job.parsers.clone(),
+            hooks,
+            out_file: self.out_file(),
+            err_file: self.err_file(),
+            meta_file: self.meta_file(),
+        };
+        Ok(post_run)
+    }
+
+    /// Return the shell for running snippets in working directory like the
+    /// job script.
+    fn shell(&self) -> Result<JobShell> {
+        let wdir = self.wrk_dir();
+        let shell = JobShell {
+            wrk_dir: wdir.canonicalize().unwrap_or_else(|_| wdir.to_owned()),
+            sandbox: self.job.sandbox.clone(),
+            run_as: self.job.run_as.as_deref().map(RunAs::resolve).transpose()?,
+        };
+        Ok(shell)
+    }
+
+    /// Record executables of processes in running session into job
+    /// metadata.
+    fn record_programs(&self) -> Result<()> {
+        let session = self.session.as_ref().context("job not running")?;
+        let processes = session.handler().get_processes()?;
+        let mut meta = RunMeta::from_json(&gut::fs::read_file(self.meta_file())?)?;
+        for p in processes {
+            // the process may have exited already
+            let exe = match p.get_exe() {
+                Ok(exe) => exe,
+                Err(_) => continue,
+            };
+            if !meta.programs.iter().any(|x| x.exe == exe) {
+                meta.programs.push(ProgramInfo::from_exe(&exe)?);
+            }
+        }
+        gut::fs::write_to_file(self.meta_file(), &meta.to_json()?)?;
+        Ok(())
+    }
+
+    /// Run command in background.
+    async fn start(&mut self) -> Result<()> {
+        use crate::process::SpawnSessionExt;
+        use std::os::unix::fs::PermissionsExt;
+
+        let wdir = self.wrk_dir().to_owned();
+        info!("job work direcotry: {}", wdir.display());
+
+        let run_file = self.run_file();
+        let cmdline = self.cmdline(&run_file.to_string_lossy());
+        let vars = self.stage()?;
+        let meta = RunMeta::capture(&wdir, cmdline.clone(), &vars);
+        gut::fs::write_to_file(self.meta_file(), &meta.to_json()?)?;
+        self.started = std::time::Instant::now().into();
+        self.record_event("started");
+
+        // external commands for submission run in blocking threads
+        let files = [self.inp_file(), self.out_file(), self.err_file()];
+        if let Backend::Slurm(opts) = &self.job.backend {
+            let (opts, wdir) = (opts.clone(), wdir.to_owned());
+            let job = tokio::task::spawn_blocking(move || {
+                let [inp, out, err] = &files;
+                SlurmJob::submit(&opts, &wdir, &cmdline, [inp, out, err].map(|f| f.as_path()))
+            })
+            .await??;
+            self.submitted = std::sync::Arc::new(Submitted::Slurm(job)).into();
+            return Ok(());
+        }
+        if let Backend::Ssh(opts) = &self.job.backend {
+            // the working directory will be different on remote host
+            let cmdline = self.cmdline(&format!("./{}", self.job.run_file.display()));
+            let (opts, wdir) = (opts.clone(), wdir.to_owned());
+            let job = tokio::task::spawn_blocking(move || {
+                let [inp, out, err] = &files;
+                SshJob::submit(&opts, &wdir, &cmdline, [inp, out, err].map(|f| f.as_path()))
+            })
+            .await??;
+            self.submitted = std::sync::Arc::new(Submitted::Ssh(job)).into();
+            return Ok(());
+        }
+
+        let mut command = tokio::process::Command::new(&cmdline[0]);
+        command
+            .args(&cmdline[1..])
+            .envs(vars)
+            .current_dir(&wdir)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped());
+        for k in RUNNER_SECRETS {
+            command.env_remove(k);
+        }
+        if let Some(mask) = self.job.umask {
+            // create output files beforehand with permissions of the mask
+            let mode = 0o666 & !mask;
+            for f in [self.out_file(), self.err_file()] {
+                std::fs::File::create(&f)?;
+                std::fs::set_permissions(&f, std::fs::Permissions::from_mode(mode))?;
+            }
+            let mask = nix::sys::stat::Mode::from_bits_truncate(mask);
+            unsafe {
+                command.pre_exec(move || {
+                    nix::sys::stat::umask(mask);
+                    Ok(())
+                });
+            }
+        }
+        if self.job.group_sticky {
+            set_group_sticky(&wdir)?;
+        }
+        if let Some(name) = self.job.run_as.as_deref() {
+            let run_as = RunAs::resolve(name)?;
+            // create output files beforehand, so they are owned by the user
+            std::fs::File::create(self.out_file())?;
+            std::fs::File::create(self.err_file())?;
+            run_as.chown_recursive(&wdir)?;
+            run_as.apply(&mut command);
+        }
+        let mut session = command.spawn_session()?;
+
+        let mut stdin = session
+            .child
+            .stdin
+            .take()
+            .expect("child did not have a handle to stdout");
+        let stdout = session
+            .child
+            .stdout
+            .take()
+            .expect("child did not have a handle to stdout");
+        let stderr = session
+            .child
+            .stderr
+            .take()
+            .expect("child did not have a handle to stderr");
+
+        // feed stdin in background, and close it when done, or when no
+        // more input will be sent if kept open.
+        let input = self.job.input.clone();
+        let phases = self.job.input_phases.clone();
+        let out_file = self.out_file();
+        let mut rx = match self.job.keep_stdin_open {
+            true => {
+                let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+                self.stdin_tx = tx.into();
+                Some(rx)
+            }
+            false => None,
+        };
+        let task = tokio::spawn(async move {
+            if let Err(e) = stdin.write_all(input.as_bytes()).await {
+                warn!("failed to write job input into stdin: {:?}", e);
+                return;
+            }
+            if let Err(e) = feed_input_phases(&mut stdin, &phases, &out_file).await {
+                warn!("failed to feed input phases into stdin: {:?}", e);
+                return;
+            }
+            if let Some(rx) = rx.as_mut() {
+                while let Some(data) = rx.recv().await {
+                    if let Err(e) = stdin.write_all(&data).await.and(stdin.flush().await) {
+                        warn!("failed to send data into stdin: {:?}", e);
+                        return;
+                    }
+                }
+            }
+        });
+        self.input_task = task.into();
+
+        // redirect stdout and stderr to files for user inspection. The two
+        // streams are copied concurrently to avoid deadlock when the child
+        // fills one pipe while we are reading the other.
+        let handler = session.handler().clone();
+        let limit = self.job.output_limit;
+        let out_file = self.out_file();
+        let h = handler.clone();
+        let copier = tokio::spawn(async move { output::copy_output(stdout, &out_file, limit, &h).await });
+        self.copiers.push(copier);
+        let err_file = self.err_file();
+        let h = handler.clone();
+        let copier = tokio::spawn(async move { output::copy_output(stderr, &err_file, limit, &h).await });
+        self.copiers.push(copier);
+
+        let sid = session.handler().id();
+        info!("command running in session {:?}", sid);
+        if let (Some(hb), Some(sid)) = (self.job.heartbeat, sid) {
+            let outputs = vec![self.out_file(), self.err_file()];
+            let task = heartbeat::spawn(sid, self.heartbeat_file(), outputs, hb.interval);
+            self.heartbeat_task = task.into();
+        }
+        if self.job.stray_files.is_some() {
+            let home = match self.job.run_as.as_deref() {
+                Some(name) => RunAs::resolve(name)?.home.into(),
+                None => std::env::var_os("HOME").map(PathBuf::from),
+            };
+            self.stray_snapshot = stray::Snapshot::take(home).into();
+        }
+        if let Some(sid) = sid {
+            self.oom_kills_before = oom::cgroup_oom_kills();
+            self.oom_task = oom::spawn(sid, self.oom_watch.clone(), oom::SAMPLE_INTERVAL).into();
+        }
+        if let (Some(interval), Some(sid)) = (self.job.usage_interval, sid) {
+            self.usage_task = usage::spawn(sid, self.usage_file(), interval).into();
+        }
+        // for reattaching the session after runner restarts
+        if let Err(e) = session.handler().save(self.session_file()) {
+            warn!("failed to save session leader: {:?}", e);
+        }
+        let saved = serde_json::json!({"job": &self.job, "owner": &self.owner});
+        if let Err(e) = gut::fs::write_to_file(self.recovery_file(), &saved.to_string()) {
+            warn!("failed to save job for recovery: {:?}", e);
+        }
+        self.session = session.into();
+
+        Ok(())
+    }
+
+    /// Write `data` into stdin of the running job, which requires stdin
+    /// kept open by `Job::keep_stdin_open`.
+    pub fn send_stdin(&self, data: &[u8]) -> Result<()> {
+        let tx = self
+            .stdin_tx
+            .as_ref()
+            .context("stdin is closed or not kept open for the job")?;
+        tx.send(data.to_vec()).map_err(|_| format_err!("job stdin is closed"))?;
+        Ok(())
+    }
+
+    /// Close stdin of the running job kept open, so that the program reads
+    /// end of file.
+    pub fn close_stdin(&mut self) {
+        self.stdin_tx = None;
+    }
+
+    /// Find files created by the job in `/tmp` or home directory, and
+    /// report or remove them as requested.
+    fn handle_stray_files(&mut self) -> Result<()> {
+        let (action, snapshot) = match (self.job.stray_files, self.stray_snapshot.take()) {
+            (Some(action), Some(snapshot)) => (action, snapshot),
+            _ => return Ok(()),
+        };
+        let server_uid = nix::unistd::geteuid().as_raw();
+        let uid = match self.job.run_as.as_deref() {
+            Some(name) => RunAs::resolve(name)?.uid.as_raw(),
+            None => server_uid,
+        };
+        let stray = snapshot.new_entries(uid);
+        if stray.is_empty() {
+            return Ok(());
+        }
+        warn!("job created {} files outside working directory", stray.len());
+        // files of a shared user may belong to anything else it runs
+        // meanwhile, so they are only removed for a dedicated user
+        let dedicated = uid != server_uid;
+        if action == StrayFiles::Clean && !dedicated {
+            warn!("stray files not removed, as the job does not run as a dedicated user");
+        }
+        if action == StrayFiles::Clean && dedicated {
+            for p in &stray {
+                let r = match p.symlink_metadata()?.is_dir() {
+                    true => std::fs::remove_dir_all(p),
+                    false => std::fs::remove_file(p),
+                };
+                if let Err(e) = r {
+                    warn!("failed to remove stray file {:?}: {:?}", p, e);
+                }
+            }
+        }
+        let list: String = stray.iter().map(|p| format!("{}\n", p.display())).collect();
+        gut::fs::write_to_file(self.wrk_dir().join("stray-files.txt"), &list)?;
+        self.stray_files = stray;
+        Ok(())
+    }
+
+    /// Return stray files the finished job created outside its working
+    /// directory, if tracked by `Job::track_stray_files`.
+    pub fn stray_files(&self) -> &[PathBuf] {
+        &self.stray_files
+    }
+
+    /// Return the context for running the job with a custom `JobRunner`.
+    fn run_context(&self) -> RunContext {
+        let run_file = self.run_file();
+        RunContext {
+            wrk_dir: self.wrk_dir().to_owned(),
+            cmdline: self.cmdline(&run_file.to_string_lossy()),
+            input: self.job.input.clone(),
+            inp_file: self.inp_file(),
+            out_file: self.out_file(),
+            err_file: self.err_file(),
+            allocation: self.allocation.clone(),
+        }
+    }
+
+    /// Stage app modules, attachments and scratch dirs in the working
+    /// directory as a real run does. Return env vars in the order they are
+    /// set for the session.
+    fn stage(&self) -> Result<Vec<(String, String)>> {
+        let module_env = self.setup_modules()?;
+        self.stage_attachments()?;
+        let scratch_env = self.setup_scratch_dirs()?;
+        let mut vars = self.allocation.env_vars();
+        vars.extend(scratch_env);
+        vars.extend(module_env);
+        Ok(vars)
+    }
+
+    /// Print what would be executed for the job, and keep the working
+    /// directory for inspection.
+    pub fn dry_run(mut self) -> Result<PathBuf> {
+        let wdir = self.wrk_dir();
+        let cmdline = self.cmdline(&self.run_file().to_string_lossy());
+        let vars = self.stage()?;
+        let meta = RunMeta::capture(wdir, cmdline.clone(), &vars);
+        gut::fs::write_to_file(self.meta_file(), &meta.to_json()?)?;
+
+        println!("working directory: {}", wdir.display());
+        println!("backend: {:?}", self.job.backend);
+        println!("command: {}", crate::backend::shell_join(&cmdline));
+        println!("stdin: {}", self.inp_file().display());
+        println!("stdout: {}", self.out_file().display());
+        println!("stderr: {}", self.err_file().display());
+        for f in self.extra_files() {
+            let missing = if f.exists() { "" } else { " (missing)" };
+            println!("extra file: {}{}", f.display(), missing);
+        }
+        for a in &self.job.attachments {
+            println!("attachment: {} ({:?})", a.src.display(), a.mode);
+        }
+        for (k, v) in &vars {
+            println!("env: {}={}", k, v.as_str().shell_escape());
+        }
+        println!("resources: {:?}", self.job.resources);
+        if let Some(t) = self.job.timeout {
+            println!("timeout: {} seconds", t);
+        }
+        if let Some(limit) = &self.job.output_limit {
+            println!("output limit: {:?}", limit);
+        }
+        println!("full environment: recorded in {}", self.meta_file().display());
+
+        Ok(self.wrk_dir.keep())
+    }
+
+    /// Set up environment of app modules and env vars required by the job.
+    /// The env vars are written into run script if it is a shell script,
+    /// and returned for running the script locally and recording them.
+    fn setup_modules(&self) -> Result<Vec<(String, String)>> {
+        if self.job.modules.is_empty() && self.job.env.is_empty() {
+            return Ok(vec![]);
+        }
+        // .envrc of modules is sourced with credentials of the job
+        let run_as = self.job.run_as.as_deref().map(RunAs::resolve).transpose()?;
+        let mut vars = match self.job.modules.is_empty() {
+            true => vec![],
+            false => crate::cli::module_env_vars(&self.job.modules, |bash| {
+                if let Some(run_as) = &run_as {
+                    run_as.apply_std(bash);
+                }
+            })?,
+        };
+        // job env vars take precedence over those of modules
+        vars.extend(self.job.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        let script = &self.job.script;
+        if self.job.is_shell_script() {
+            let (shebang, body) = match script.starts_with("#!") {
+                true => script.split_once('\n').unwrap_or((script, "")),
+                false => ("", script.as_str()),
+            };
+            let exports: String = vars
+                .iter()
+                .map(|(k, v)| format!("export {k}={}\n", v.as_str().shell_escape()))
+                .collect();
+            let header = match self.job.modules.is_empty() {
+                true => exports,
+                false => format!("# app modules: {}\n{exports}", self.job.modules.join(" ")),
+            };
+            let script = match shebang.is_empty() {
+                true => format!("{header}{body}"),
+                false => format!("{shebang}\n{header}{body}"),
+            };
+            gut::fs::write_to_file(self.run_file(), &script)?;
+            Ok(vars)
+        } else {
+            ensure!(
+                matches!(self.job.backend, Backend::Local),
+                "app modules or env vars require a shell script for running on remote backend"
+            );
+            Ok(vars)
+        }
+    }
+
+    /// Return the command line of the interpreter set for the job script.
+    fn interpreter_cmdline(&self) -> Option<Vec<String>> {
+        let interpreter = self.job.interpreter.as_deref()?;
+        let interpreter = match interpreter {
+            "python" => "python3",
+            s => s,
+        };
+        let cmd: Vec<_> = interpreter.split_whitespace().map(|s| s.to_owned()).collect();
+        if cmd.is_empty() {
+            None
+        } else {
+            Some(cmd)
+        }
+    }
+
+    /// Return the command line for running `run_file`, wrapped in container
+    /// if required.
+    fn cmdline(&self, run_file: &str) -> Vec<String> {
+        let mut cmdline = if let Some(container) = &self.job.container {
+            container.wrap(run_file)
+        } else {
+            vec![run_file.to_owned()]
+        };
+        // run the script by its interpreter instead of the shebang line
+        if let Some(interpreter) = self.interpreter_cmdline() {
+            let n = cmdline.len() - 1;
+            cmdline.splice(n..n, interpreter);
+        }
+        // the wrapper is run in working directory on all backends
+        let cmdline = ["/bin/sh".to_owned(), WRAPPER_FILE.to_owned()].into_iter().chain(cmdline).collect();
+        match &self.job.sandbox {
+            Some(sandbox) => {
+                // bwrap requires absolute paths for mount points
+                let wdir = self.wrk_dir();
+                let wdir = wdir.canonicalize().unwrap_or_else(|_| wdir.to_owned());
+                sandbox.wrap(&wdir, cmdline)
+            }
+            None => cmdline,
+        }
+    }
+
+    /// Return true if session already has been started.
+    fn is_started(&self) -> bool {
+        self.session.is_some() || self.submitted.is_some() || self.runner_status.is_some() || self.recovered
+    }
+
+    /// Return current status of the job. A successfully exited job is
+    /// `Incomplete` if expected outputs are missing.
+    fn status(&mut self) -> JobStatus {
+        match self.process_status() {
+            JobStatus::Running | JobStatus::Stalled if !self.paused.is_empty() => JobStatus::Paused,
+            JobStatus::Completed if !self.missing_outputs().is_empty() => JobStatus::Incomplete,
+            JobStatus::Failed if self.out_of_memory.is_some() => JobStatus::OutOfMemory,
+            status => status,
+        }
+    }
+
+    /// Return current status of the job process.
+    fn process_status(&mut self) -> JobStatus {
+        if let Some(s) = self.session.as_mut() {
+            match s.child.try_wait() {
+                Ok(None) => match self.job.heartbeat {
+                    Some(hb) if heartbeat::is_stale(&self.heartbeat_file(), hb.stale_after) => JobStatus::Stalled,
+                    _ => JobStatus::Running,
+                },
+                Ok(Some(ecode)) if ecode.success() => JobStatus::Completed,
+                Ok(Some(_)) => JobStatus::Failed,
+                Err(e) => {
+                    warn!("failed to check job status: {:?}", e);
+                    JobStatus::Unknown
+                }
+            }
+        } else if self.submitted.is_some() {
+            self.submitted_status
+        } else if let Some(status) = self.runner_status {
+            status
+        } else if self.recovered {
+            match self.recorded_exit_status() {
+                Some(0) => JobStatus::Completed,
+                Some(_) => JobStatus::Failed,
+                None if self.reattached.as_ref().map_or(false, |h| h.is_alive()) => JobStatus::Running,
+                // exited without recording its status, e.g. killed
+                None => JobStatus::Failed,
+            }
+        } else {
+            JobStatus::Pending
+        }
+    }
+
+    /// Return the structured result of the finished job. Failures are
+    /// classified by rules of the job, then `classifiers`, then builtin
+    /// rules.
+    fn result(&mut self, classifiers: &[std::sync::Arc<dyn FailureClassifier>]) -> JobResult {
+        let status = self.status();
+        let failure = match status {
+            JobStatus::Failed | JobStatus::Incomplete => self.classify_failure(classifiers),
+            JobStatus::OutOfMemory => Some(FailureReason {
+                kind: "out_of_memory".into(),
+                detail: format!("killed by OOM killer, peak RSS {} bytes", self.out_of_memory.unwrap_or(0)),
+            }),
+            _ => None,
+        };
+        let peak_rss = self.oom_watch.lock().unwrap().peak_rss;
+        JobResult {
+            status,
+            exit_code: self.exit_code,
+            // filled by output parsers in `PostRun`
+            values: Default::default(),
+            failure,
+            peak_rss: (peak_rss > 0).then(|| peak_rss),
+        }
+    }
+
+    /// Scan stdout and stderr for the reason of failure.
+    fn classify_failure(&self, classifiers: &[std::sync::Arc<dyn FailureClassifier>]) -> Option<FailureReason> {
+        let read = |f: PathBuf| String::from_utf8_lossy(&std::fs::read(f).unwrap_or_default()).into_owned();
+        let (stdout, stderr) = (read(self.out_file()), read(self.err_file()));
+        let builtin = FailureRule::builtin();
+        let job_rules = self.job.failure_rules.iter().map(|r| r as &dyn FailureClassifier);
+        let others = classifiers.iter().map(|c| c.as_ref());
+        let builtin = builtin.iter().map(|r| r as &dyn FailureClassifier);
+        crate::failure::classify(job_rules.chain(others).chain(builtin), &stdout, &stderr)
+    }
+
+    /// Return the summary of the finished job for notification.
+    fn summary(&mut self, id: JobId) -> JobSummary {
+        let text = gut::fs::read_file(self.out_file()).unwrap_or_default();
+        JobSummary {
+            id,
+            status: self.status(),
+            runtime: self.started.map(|t| t.elapsed().as_secs_f64()).unwrap_or_default(),
+            exit_code: self.exit_code,
+            tail: crate::notify::tail_lines(&text, JobSummary::TAIL_LINES),
+        }
+    }
+
+    /// Return the accounting record of the finished job.
+    fn acct_record(&self) -> AcctRecord {
+        let runtime = self.started.map(|t| t.elapsed().as_secs_f64()).unwrap_or_default();
+        let mut record = AcctRecord::new(&self.job.script, runtime, self.exit_code, self.cpu_time);
+        if let Some(owner) = &self.owner {
+            record.user = owner.clone();
+        }
+        record.tags = self.job.tags.clone();
+        record
+    }
+
+    /// Record `event` into job history.
+    fn record_event(&mut self, event: impl Into<String>) {
+        let event = JobEvent::new(event);
+        info!("job event: {}", event.event);
+        self.history.push(event);
+    }
+
+    /// Pause the running job for `reason`. Return false if there is no
+    /// local session to pause, or it is already paused for `reason`.
+    fn pause(&mut self, reason: PauseReason) -> Result<bool> {
+        match self.session.as_ref() {
+            Some(s) if !self.paused.contains(&reason) => {
+                if self.paused.is_empty() {
+                    s.handler().pause()?;
+                    self.paused_since = std::time::Instant::now().into();
+                }
+                self.paused.insert(reason);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Resume the job paused for `reason` by `pause`, unless it is still
+    /// paused for other reasons.
+    fn resume(&mut self, reason: PauseReason) -> Result<()> {
+        if let Some(s) = self.session.as_ref() {
+            if self.paused.len() == 1 && self.paused.contains(&reason) {
+                s.handler().resume()?;
+                if let Some(t) = self.paused_since.take() {
+                    self.paused_total += t.elapsed();
+                }
+                self.record_event("resumed");
+            }
+            self.paused.remove(&reason);
+        }
+        Ok(())
+    }
+
+    /// Return the time the job has been running since started, excluding
+    /// the time it was paused.
+    fn run_time(&self) -> std::time::Duration {
+        let elapsed = self.started.map(|t| t.elapsed()).unwrap_or_default();
+        let paused = self.paused_total + self.paused_since.map(|t| t.elapsed()).unwrap_or_default();
+        elapsed.saturating_sub(paused)
+    }
+
+    /// Return true if the job could be paused for a preempting job.
+    fn is_preemptible(&mut self) -> bool {
+        self.paused.is_empty() && self.session.is_some() && self.process_status() == JobStatus::Running
+    }
+
+    /// Return the session ID of the running job.
+    fn session_id(&self) -> Option<u32> {
+        match self.session.as_ref() {
+            Some(s) => s.handler().id(),
+            None => self.reattached.as_ref().and_then(|h| h.id()),
+        }
+    }
+}
+
+impl Drop for Computation {
+    /// Stop feeding input of cancelled or removed jobs.
+    fn drop(&mut self) {
+        if let Some(task) = self.input_task.take() {
+            task.abort();
+        }
+    }
+}
+// core:1 ends here
+
+// [[file:../runners.note::*extra][extra:1]]
+/// A transition in lifetime of a job, such as started or preempted.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JobEvent {
+    /// The time in RFC 3339 format
+    pub time: String,
+    pub event: String,
+}
+
+impl JobEvent {
+    fn new(event: impl Into<String>) -> Self {
+        Self {
+            time: chrono::Local::now().to_rfc3339(),
+            event: event.into(),
+        }
+    }
+}
+
+/// An output file expected from a successful job.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ExpectedOutput {
+    /// The file relative to working directory
+    pub file: PathBuf,
+    /// Regex pattern required in the file content
+    #[serde(default)]
+    pub pattern: Option<String>,
+}
+
+/// The name of the sentinel file holding exit status of job script.
+const EXIT_STATUS_FILE: &str = ".exit-status";
+
+/// The name of the file saving the job in its working directory.
+const RECOVERY_FILE: &str = ".job.json";
+
+/// The job saved in `RECOVERY_FILE` when started.
+#[derive(Deserialize)]
+struct SavedJob {
+    job: Job,
+    owner: Option<String>,
+}
+
+impl Computation {
+    /// Return a list of full path to extra files required for computation.
+    pub fn extra_files(&self) -> Vec<PathBuf> {
+        self.job.extra_files.iter().map(|f| self.wrk_dir().join(f)).collect()
+    }
+
+    /// Return the exit status of job script recorded in sentinel file, if
+    /// the script has exited.
+    pub fn recorded_exit_status(&self) -> Option<i32> {
+        let s = std::fs::read_to_string(self.exit_status_file()).ok()?;
+        s.trim().parse().ok()
+    }
+
+    /// Return timing and exit status of the job script recorded by the
+    /// wrapper.
+    pub fn run_stat(&self) -> Result<RunStat> {
+        let f = self.stat_file();
+        let s = gut::fs::read_file(&f).with_context(|| format!("job script not finished? {:?}", f))?;
+        RunStat::from_json(&s)
+    }
+
+    /// Wait until the job script exits, watching the sentinel file with
+    /// inotify. Return false if `timeout` elapsed.
+    pub fn wait_done(&self, timeout: Option<std::time::Duration>) -> Result<bool> {
+        let found = crate::watch::wait_for_file(self.wrk_dir(), EXIT_STATUS_FILE, timeout)?;
+        Ok(found.is_some())
+    }
+
+    /// Return expected outputs not found in working directory, as
+    /// descriptions like `"job.out: no match for Normal termination"`.
+    pub fn missing_outputs(&self) -> Vec<String> {
+        let mut missing = vec![];
+        for expected in &self.job.expected_outputs {
+            let file = &expected.file;
+            let path = self.wrk_dir().join(file);
+            if !path.is_file() {
+                missing.push(format!("{}: not found", file.display()));
+                continue;
+            }
+            if let Some(pattern) = &expected.pattern {
+                let found = regex::Regex::new(pattern).ok().map_or(false, |re| {
+                    let text = std::fs::read(&path).unwrap_or_default();
+                    re.is_match(&String::from_utf8_lossy(&text))
+                });
+                if !found {
+                    missing.push(format!("{}: no match for {}", file.display(), pattern));
+                }
+            }
+        }
+        missing
+    }
+
+    /// Check if job has been done correctly. The exit status in sentinel
+    /// file is checked first. For jobs without it, the job is done if the
+    /// output is newer than the input. Expected outputs of the job are
+    /// required in both cases.
+    pub fn is_done(&self) -> bool {
+        if !self.missing_outputs().is_empty() {
+            return false;
+        }
+        if let Some(code) = self.recorded_exit_status() {
+            return code == 0;
+        }
+
+        let inpfile = self.inp_file();
+        let outfile = self.out_file();
+
+        if self.wrk_dir().is_dir() {
+            if outfile.is_file() && inpfile.is_file() {
+                if let Ok(time2) = outfile.metadata().and_then(|m| m.modified()) {
+                    if let Ok(time1) = inpfile.metadata().and_then(|m| m.modified()) {
+                        if time2 >= time1 {
+                            return true;
+                        }
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    /// Write a successful exit status to make sure `is_done` call return
+    /// true.
+    pub fn fake_done(&self) {
+        let _ = gut::fs::write_to_file(self.exit_status_file(), "0\n");
+    }
+}
+// extra:1 ends here
+
+// [[file:../runners.note::4e1a7c08][4e1a7c08]]
+/// How to stage an attached file into working directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum AttachMode {
+    /// Copy the file or directory recursively.
+    #[default]
+    Copy,
+    /// Create a symbolic link to the file or directory.
+    Symlink,
+    /// Create a hard link to the file, or copy it if crossing filesystems.
+    Hardlink,
+}
+
+/// A file on the node running the job, staged into working directory.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct Attachment {
+    src: PathBuf,
+    #[serde(default)]
+    mode: AttachMode,
+}
+
+/// Copy file or directory `src` into `dst` recursively.
+fn copy_recursive(src: &Path, dst: &Path) -> Result<()> {
+    if src.is_dir() {
+        std::fs::create_dir_all(dst)?;
+        for entry in std::fs::read_dir(src)? {
+            let entry = entry?;
+            copy_recursive(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+    } else {
+        std::fs::copy(src, dst).with_context(|| format!("copy {:?} to {:?}", src, dst))?;
+    }
+    Ok(())
+}
 
-            let job = &jobs[id];
-            let p = job.wrk_dir().join(&file);
-            info!("client request to put a file: {}", p.display());
-            match std::fs::File::create(p) {
-                Ok(mut f) => {
-                    f.write_all(&body).context("write job file")?;
-                    Ok(())
+impl Computation {
+    /// Stage attached files into working directory.
+    fn stage_attachments(&self) -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        // hard links share the inode chowned to the job user later, so
+        // files of others are copied instead
+        let job_uid = job_uid(self.job.run_as.as_deref())?;
+        for a in &self.job.attachments {
+            let name = a.src.file_name().with_context(|| format!("invalid attachment: {:?}", a.src))?;
+            let dst = self.wrk_dir().join(name);
+            ensure!(a.src.exists(), "attachment not found: {:?}", a.src);
+            debug!("stage attachment {:?} by {:?}", a.src, a.mode);
+            match a.mode {
+                AttachMode::Copy => copy_recursive(&a.src, &dst)?,
+                AttachMode::Symlink => std::os::unix::fs::symlink(&a.src, &dst)
+                    .with_context(|| format!("link {:?} to {:?}", a.src, dst))?,
+                AttachMode::Hardlink => {
+                    ensure!(!a.src.is_dir(), "cannot hard link directory {:?}, use symlink", a.src);
+                    if a.src.metadata()?.uid() != job_uid {
+                        info!("{:?} is not owned by the job user, copy instead", a.src);
+                        copy_recursive(&a.src, &dst)?;
+                        continue;
+                    }
+                    match std::fs::hard_link(&a.src, &dst) {
+                        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                            info!("{:?} is on another filesystem, copy instead", a.src);
+                            copy_recursive(&a.src, &dst)?;
+                        }
+                        r => r.with_context(|| format!("link {:?} to {:?}", a.src, dst))?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+// 4e1a7c08 ends here
+
+// [[file:../runners.note::6d2e8b15][6d2e8b15]]
+/// Size and modification time of a file in job working directory, for
+/// clients mirroring the directory.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct FileStat {
+    /// The path relative to working directory
+    pub name: PathBuf,
+    pub size: u64,
+    /// The modification time in seconds since the Unix epoch
+    pub modified: f64,
+}
+
+/// Collect stats of files in `dir` recursively, with names relative to
+/// `root`.
+fn collect_file_stats(root: &Path, dir: &Path, stats: &mut Vec<FileStat>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let p = entry.path();
+        let m = entry.metadata()?;
+        if m.is_dir() {
+            collect_file_stats(root, &p, stats)?;
+        } else if m.is_file() {
+            let modified = m.modified()?.duration_since(std::time::UNIX_EPOCH)?.as_secs_f64();
+            stats.push(FileStat {
+                name: p.strip_prefix(root)?.to_owned(),
+                size: m.len(),
+                modified,
+            });
+        }
+    }
+    Ok(())
+}
+// 6d2e8b15 ends here
+
+// [[file:../runners.note::5b07e3c9][5b07e3c9]]
+/// The shell running snippets of post-run hooks and output parser scripts
+/// in working directory of a job, inside its sandbox and as its user like
+/// the job script.
+#[derive(Debug, Clone)]
+struct JobShell {
+    wrk_dir: PathBuf,
+    sandbox: Option<SandboxOptions>,
+    run_as: Option<RunAs>,
+}
+
+impl JobShell {
+    /// Return the command running shell snippet `cmd`.
+    fn command(&self, cmd: &str) -> std::process::Command {
+        let sh = vec!["sh".to_owned(), "-c".to_owned(), cmd.to_owned()];
+        let cmdline = match &self.sandbox {
+            Some(sandbox) => sandbox.wrap(&self.wrk_dir, sh),
+            None => sh,
+        };
+        let mut command = std::process::Command::new(&cmdline[0]);
+        command.args(&cmdline[1..]).current_dir(&self.wrk_dir);
+        if let Some(run_as) = &self.run_as {
+            run_as.apply_std(&mut command);
+        }
+        command
+    }
+}
+
+/// The post-run work of a finished job, which may take long and is run
+/// in a blocking thread without holding the job queue.
+struct PostRun {
+    shell: JobShell,
+    parsers: Vec<OutputParser>,
+    hooks: Vec<Hook>,
+    out_file: PathBuf,
+    err_file: PathBuf,
+    meta_file: PathBuf,
+}
+
+impl PostRun {
+    /// Run output parsers, then post-run hooks recording their outputs in
+    /// job metadata. Return the values extracted by parsers.
+    fn run(&self) -> Result<std::collections::BTreeMap<String, serde_json::Value>> {
+        let shell = |cmd: &str| self.shell.command(cmd);
+        let values = crate::parser::parse_all_with(&self.parsers, &self.out_file, &shell);
+        if self.hooks.is_empty() {
+            return Ok(values);
+        }
+        let mut meta = RunMeta::from_json(&gut::fs::read_file(&self.meta_file)?)?;
+        let outputs = [self.out_file.clone(), self.err_file.clone()];
+        // compressing removes the outputs other hooks may read, so it goes last
+        let (gzip, others): (Vec<_>, Vec<_>) = self.hooks.iter().partition(|h| matches!(h, Hook::GzipOutputs));
+        for hook in others.into_iter().chain(gzip) {
+            meta.hooks.push(hook.run_with(&self.shell.wrk_dir, &outputs, &shell));
+        }
+        gut::fs::write_to_file(&self.meta_file, &meta.to_json()?)?;
+        Ok(values)
+    }
+}
+
+/// The Unix user for running a job.
+#[derive(Debug, Clone)]
+struct RunAs {
+    name: String,
+    uid: nix::unistd::Uid,
+    gid: nix::unistd::Gid,
+    groups: Vec<nix::unistd::Gid>,
+    home: PathBuf,
+}
+
+impl RunAs {
+    /// Look up Unix user `name`. Switching to another user requires the
+    /// server running as root.
+    fn resolve(name: &str) -> Result<Self> {
+        use nix::unistd::{getgrouplist, geteuid, User};
+
+        let user = User::from_name(name)?.with_context(|| format!("no such Unix user: {:?}", name))?;
+        ensure!(
+            geteuid().is_root() || geteuid() == user.uid,
+            "running job as user {:?} requires root privileges",
+            name
+        );
+        let cname = std::ffi::CString::new(name)?;
+        let groups = getgrouplist(&cname, user.gid)?;
+        Ok(Self {
+            name: user.name,
+            uid: user.uid,
+            gid: user.gid,
+            groups,
+            home: user.dir,
+        })
+    }
+
+    /// Test if it is the user running current process, so there is no need
+    /// to switch.
+    fn is_current(&self) -> bool {
+        nix::unistd::geteuid() == self.uid
+    }
+
+    /// Change owner of `path` and all files under it to the user.
+    fn chown_recursive(&self, path: &Path) -> Result<()> {
+        use nix::unistd::{fchownat, FchownatFlags};
+
+        fchownat(None, path, Some(self.uid), Some(self.gid), FchownatFlags::NoFollowSymlink)
+            .with_context(|| format!("chown {:?} to {}", path, self.name))?;
+        if path.symlink_metadata()?.is_dir() {
+            for entry in std::fs::read_dir(path)? {
+                self.chown_recursive(&entry?.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `command` as the user, with `HOME`, `USER` and `LOGNAME` set
+    /// accordingly.
+    fn apply(&self, command: &mut tokio::process::Command) {
+        command
+            .env("HOME", &self.home)
+            .env("USER", &self.name)
+            .env("LOGNAME", &self.name);
+        if let Some(switch) = self.switch_user() {
+            unsafe {
+                command.pre_exec(switch);
+            }
+        }
+    }
+
+    /// Run std `command` as the user like `apply`.
+    fn apply_std(&self, command: &mut std::process::Command) {
+        use std::os::unix::process::CommandExt;
+
+        command
+            .env("HOME", &self.home)
+            .env("USER", &self.name)
+            .env("LOGNAME", &self.name);
+        if let Some(switch) = self.switch_user() {
+            unsafe {
+                command.pre_exec(switch);
+            }
+        }
+    }
+
+    /// Return the closure switching to the user after fork, or None if no
+    /// need to switch.
+    fn switch_user(&self) -> Option<impl FnMut() -> std::io::Result<()> + Send + Sync + 'static> {
+        if self.is_current() {
+            return None;
+        }
+        let (uid, gid, groups) = (self.uid, self.gid, self.groups.clone());
+        // only async-signal-safe calls are allowed after fork, so the user
+        // is resolved beforehand
+        let switch = move || {
+            let last_error = |_| std::io::Error::last_os_error();
+            nix::unistd::setgroups(&groups).map_err(last_error)?;
+            nix::unistd::setgid(gid).map_err(last_error)?;
+            nix::unistd::setuid(uid).map_err(last_error)?;
+            Ok(())
+        };
+        Some(switch)
+    }
+}
+
+/// Set the setgid bit and group write permission on `dir` and its
+/// sub-directories.
+fn set_group_sticky(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut perms = dir.metadata()?.permissions();
+    perms.set_mode(perms.mode() | 0o2070);
+    std::fs::set_permissions(dir, perms).with_context(|| format!("set group sticky on {:?}", dir))?;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            set_group_sticky(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+// 5b07e3c9 ends here
+
+// [[file:../runners.note::f4436dc6][f4436dc6]]
+mod db {
+    use super::*;
+
+    use bytes::Bytes;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    pub use super::impl_jobs_slotmap::Id;
+    use super::impl_jobs_slotmap::Jobs;
+
+    /// Interval for polling jobs submitted to other backends.
+    const SUBMITTED_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+    /// A simple in-memory DB for computational jobs.
+    #[derive(Clone)]
+    pub struct Db {
+        inner: Arc<Mutex<Jobs>>,
+        scheduler: Scheduler,
+        runner: Option<Arc<dyn JobRunner>>,
+        audit: Option<Arc<AuditLog>>,
+        accounting: Option<Arc<Accounting>>,
+        scratch_budget: Option<u64>,
+        max_pending: Option<usize>,
+        file_cache: Option<Arc<FileCache>>,
+        script_policy: Arc<ScriptPolicy>,
+        scratch_vars: Option<Arc<BTreeMap<String, String>>>,
+        failure_classifiers: Vec<Arc<dyn FailureClassifier>>,
+        notifier: Arc<Notifier>,
+        // jobs created with client supplied idempotency keys
+        idempotency_keys: Arc<Mutex<std::collections::HashMap<String, JobId>>>,
+        // child jobs of job arrays
+        arrays: Arc<Mutex<BTreeMap<u64, Vec<JobId>>>>,
+        // jobs paused by preempting jobs, with their resources released
+        preempted: Arc<Mutex<std::collections::HashSet<JobId>>>,
+        // execution windows for jobs not defining their own
+        window: Option<ExecWindows>,
+        // for holding back jobs when the machine is busy or hot
+        throttle: Option<Arc<dyn ThrottlePolicy>>,
+        // the authenticated user requesting operations, for audit log
+        client: Option<String>,
+    }
+
+    impl Db {
+        /// Create an empty `Db`
+        pub fn new() -> Self {
+            Self::with_scheduler(Scheduler::new())
+        }
+
+        /// Create an empty `Db` starting jobs with `scheduler`.
+        pub fn with_scheduler(scheduler: Scheduler) -> Self {
+            Self {
+                inner: Arc::new(Mutex::new(Jobs::new())),
+                scheduler,
+                runner: None,
+                audit: None,
+                accounting: None,
+                scratch_budget: None,
+                max_pending: None,
+                file_cache: None,
+                script_policy: Default::default(),
+                scratch_vars: None,
+                failure_classifiers: vec![],
+                idempotency_keys: Default::default(),
+                arrays: Default::default(),
+                preempted: Default::default(),
+                window: None,
+                throttle: None,
+                notifier: Arc::new(Notifier::default()),
+                client: None,
+            }
+        }
+
+        /// Send job notifications using `notifier` settings.
+        pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+            self.notifier = Arc::new(notifier);
+            self
+        }
+
+        /// Limit total size of all job working directories to `bytes`. New
+        /// submissions via `try_insert_job` are refused when exceeded.
+        pub fn with_scratch_quota(mut self, bytes: u64) -> Self {
+            self.scratch_budget = bytes.into();
+            self
+        }
+
+        /// Add uploaded job files into content-addressed `cache`, so that
+        /// identical files can be linked by hash instead of uploaded again.
+        pub fn with_file_cache(mut self, cache: FileCache) -> Self {
+            self.file_cache = Some(Arc::new(cache));
+            self
+        }
+
+        /// Validate job scripts submitted via `try_insert_job` with
+        /// `policy`. By default only empty scripts, NUL bytes and scripts
+        /// larger than 1 MiB are refused.
+        pub fn with_script_policy(mut self, policy: ScriptPolicy) -> Self {
+            self.script_policy = Arc::new(policy);
+            self
+        }
+
+        /// Point env vars in `vars` to directories in job working directory,
+        /// e.g. `{"TMPDIR": "tmp", "GAUSS_SCRDIR": "gauss"}`, instead of the
+        /// defaults from `default_scratch_vars`.
+        pub fn with_scratch_vars(mut self, vars: BTreeMap<String, String>) -> Self {
+            self.scratch_vars = Some(Arc::new(vars));
+            self
+        }
+
+        /// Only run jobs in execution `windows`, unless jobs define their own.
+        pub fn with_window(mut self, windows: ExecWindows) -> Self {
+            self.window = windows.into();
+            self
+        }
+
+        /// Hold back starting jobs while `policy` is throttled, and also
+        /// pause running ones if the policy asks for it.
+        pub fn with_throttle(mut self, policy: impl ThrottlePolicy + 'static) -> Self {
+            self.throttle = Some(Arc::new(policy));
+            self
+        }
+
+        /// Classify failures of jobs with `classifier`, in addition to
+        /// rules of the job and builtin rules.
+        pub fn with_failure_classifier(mut self, classifier: impl FailureClassifier + 'static) -> Self {
+            self.failure_classifiers.push(Arc::new(classifier));
+            self
+        }
+
+        /// Limit the number of submitted jobs waiting to start to `n`. New
+        /// submissions via `try_insert_job` are refused when exceeded.
+        pub fn with_max_pending(mut self, n: usize) -> Self {
+            self.max_pending = n.into();
+            self
+        }
+
+        /// Return resources currently free for running jobs.
+        pub async fn free_resources(&self) -> Resources {
+            self.scheduler.free_resources().await
+        }
+
+        /// Return the total size of working directories of all jobs.
+        pub async fn get_scratch_usage(&self) -> u64 {
+            let dirs: Vec<_> = {
+                let jobs = self.inner.lock().await;
+                jobs.iter().map(|(_, job)| job.wrk_dir().to_owned()).collect()
+            };
+            // walking large directories takes a while, without locking jobs
+            let size = |d: &PathBuf| crate::node::dir_size(d).unwrap_or(0);
+            let usage = tokio::task::spawn_blocking(move || dirs.iter().map(size).sum());
+            usage.await.unwrap_or_default()
+        }
+
+        /// Insert job into the queue like `insert_job`, but return
+        /// `InvalidScript` error if the job script is rejected by script
+        /// policy, `ScratchFull` error if scratch disk budget is exceeded,
+        /// `QueueFull` error if too many jobs are waiting to start, or error
+        /// if the working directory could not be set up.
+        pub async fn try_insert_job(&mut self, job: Job) -> Result<JobId> {
+            if job.array.is_some() {
+                let r = Err(format_err!("job array requires try_insert_job_array_as"));
+                self.audit("create", None, &r);
+                return r;
+            }
+            if let Err(e) = self.script_policy.validate(&job.script, job.is_shell_script()) {
+                let r = Err(e.into());
+                self.audit("create", None, &r);
+                return r;
+            }
+            if let Some(max) = self.max_pending {
+                let pending = self.inner.lock().await.iter().filter(|(_, job)| !job.is_started()).count();
+                if pending >= max {
+                    let r = Err(QueueFull { pending, max }.into());
+                    self.audit("create", None, &r);
+                    return r;
+                }
+            }
+            if let Some(budget) = self.scratch_budget {
+                let used = self.get_scratch_usage().await;
+                if used >= budget {
+                    let r = Err(ScratchFull { used, budget }.into());
+                    self.audit("create", None, &r);
+                    return r;
+                }
+            }
+            self.insert_job(job).await
+        }
+
+        /// Insert job into the queue like `try_insert_job`, recording `user`
+        /// as its owner. On a server running as root, jobs of normal users
+        /// run as the Unix user of the same name.
+        pub async fn try_insert_job_as(&mut self, mut job: Job, user: &User) -> Result<JobId> {
+            // only admins may raise priority above the default, which
+            // preempts jobs of others
+            if !user.admin && job.priority > 0 {
+                let r = Err(format_err!("user {} may not set priority {}", user.name, job.priority));
+                self.audit("create", None, &r);
+                return r;
+            }
+            // attachments are read with privileges of the server, so only
+            // admins may stage files from the node
+            if !user.admin && !job.attachments.is_empty() {
+                let r = Err(format_err!("user {} may not attach local files", user.name));
+                self.audit("create", None, &r);
+                return r;
+            }
+            // only admins may run jobs as others, or as root
+            if let Some(name) = job.run_as.as_deref() {
+                if !user.admin && (name != user.name || name == "root") {
+                    let r = Err(format_err!("user {} is not allowed to run job as {}", user.name, name));
+                    self.audit("create", None, &r);
+                    return r;
+                }
+            }
+            // jobs of normal users never run with root privileges of the
+            // server, but as the Unix user of the same name
+            if !user.admin && job.run_as.is_none() && nix::unistd::geteuid().is_root() {
+                let known = matches!(nix::unistd::User::from_name(&user.name), Ok(Some(u)) if !u.uid.is_root());
+                if !known {
+                    let r = Err(format_err!("user {} has no Unix account for running jobs", user.name));
+                    self.audit("create", None, &r);
+                    return r;
                 }
+                job.run_as = user.name.clone().into();
+            }
+            // normal users may not work in the scratch directory shared by
+            // all jobs, or in dirs of others
+            if let Some(hint) = job.wrk_dir_hint.as_deref().filter(|_| !user.admin) {
+                if let Err(e) = check_wrk_dir_hint(hint, job.run_as.as_deref()) {
+                    let r = Err(e);
+                    self.audit("create", None, &r);
+                    return r;
+                }
+            }
+            let id = self.try_insert_job(job).await?;
+            let mut jobs = self.inner.lock().await;
+            let k = jobs.check_job(id)?;
+            jobs[k].owner = user.name.clone().into();
+            Ok(id)
+        }
+
+        /// Insert child jobs of job array `job` for `user`, returning the
+        /// array id and ids of child jobs. Child jobs are tagged with
+        /// `array` and `array_index`. No child job is created on error.
+        pub async fn try_insert_job_array_as(&mut self, job: Job, user: &User) -> Result<(u64, Vec<JobId>)> {
+            let range = match job.array.as_deref().map(parse_array_range).transpose() {
+                Ok(Some(range)) => range,
+                Ok(None) => bail!("not a job array"),
                 Err(e) => {
-                    bail!("create file error:\n{}", e);
+                    let r = Err(e);
+                    self.audit("create", None, &r);
+                    return r;
+                }
+            };
+            // a clone not borrowing `self`, for holding the lock while
+            // inserting child jobs
+            let arrays = self.arrays.clone();
+            let mut arrays = arrays.lock().await;
+            let array = arrays.keys().next_back().map_or(1, |n| n + 1);
+            let spec = job.to_json()?;
+            let children = range
+                .map(|i| {
+                    let mut child = Job::from_json(&spec)?;
+                    child.array = None;
+                    child.set_env("GOSH_ARRAY_INDEX", &i.to_string());
+                    child.set_tag("array", &array.to_string());
+                    child.set_tag("array_index", &i.to_string());
+                    Ok((i, child))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let mut ids = vec![];
+            for (i, child) in children {
+                match self.try_insert_job_as(child, user).await {
+                    Ok(id) => ids.push(id),
+                    Err(e) => {
+                        for id in ids {
+                            let _ = self.delete_job(id).await;
+                        }
+                        return Err(e.context(format!("create child job {} of job array", i)));
+                    }
+                }
+            }
+            info!("job array {} created with {} jobs", array, ids.len());
+            arrays.insert(array, ids.clone());
+            Ok((array, ids))
+        }
+
+        /// Return child jobs of job `array`.
+        pub async fn get_array_jobs(&self, array: u64) -> Result<Vec<JobId>> {
+            let arrays = self.arrays.lock().await;
+            let ids = arrays.get(&array).with_context(|| format!("no such job array: {}", array))?;
+            Ok(ids.clone())
+        }
+
+        /// Return aggregate status of child jobs in job `array`.
+        pub async fn get_array_status(&self, array: u64) -> Result<ArrayStatus> {
+            let ids = self.get_array_jobs(array).await?;
+            let mut jobs = self.inner.lock().await;
+            let mut status = ArrayStatus::default();
+            for id in ids {
+                // deleted child jobs are skipped
+                if let Ok(k) = jobs.check_job(id) {
+                    let s = jobs[k].status();
+                    if s.is_finished() {
+                        status.finished += 1;
+                    }
+                    *status.counts.entry(format!("{:?}", s)).or_default() += 1;
+                    status.jobs.push(id);
+                }
+            }
+            Ok(status)
+        }
+
+        /// Remove all child jobs in job `array`, terminating running ones.
+        pub async fn delete_job_array(&mut self, array: u64) -> Result<()> {
+            let ids = self.arrays.lock().await.remove(&array);
+            let ids = ids.with_context(|| format!("no such job array: {}", array))?;
+            for id in ids {
+                // may have been deleted individually
+                let _ = self.delete_job(id).await;
+            }
+            Ok(())
+        }
+
+        /// Insert job like `try_insert_job_as`, but return the id of the
+        /// job created earlier by `user` with the same idempotency `key`, so
+        /// that retried submissions do not create duplicate jobs.
+        pub async fn try_insert_job_idempotent(&mut self, job: Job, user: &User, key: &str) -> Result<JobId> {
+            // hold the lock so that concurrent retries wait for the first
+            // one, through a clone not borrowing `self` for the insertion
+            let keys = self.idempotency_keys.clone();
+            let mut keys = keys.lock().await;
+            let user_key = format!("{}\0{}", user.name, key);
+            if let Some(&id) = keys.get(&user_key) {
+                // the job may have been deleted since
+                if self.inner.lock().await.check_job(id).is_ok() {
+                    info!("job {} already created with idempotency key {:?}", id, key);
+                    return Ok(id);
+                }
+            }
+            let id = self.try_insert_job_as(job, user).await?;
+            keys.insert(user_key, id);
+            Ok(id)
+        }
+
+        /// Create a fresh job from the same spec of job `id`, copying
+        /// `files` in its working directory as restart inputs. Return the id
+        /// of the new job.
+        pub async fn clone_job(&mut self, id: JobId, files: &[PathBuf]) -> Result<JobId> {
+            let r = async {
+                let (job, owner, old_dir) = {
+                    let jobs = self.inner.lock().await;
+                    let k = jobs.check_job(id)?;
+                    let old = &jobs[k];
+                    let job = Job::from_json(&old.job.to_json()?)?;
+                    (job, old.owner.clone(), old.wrk_dir().to_owned())
+                };
+                let new_id = self.try_insert_job(job).await?;
+                let mut jobs = self.inner.lock().await;
+                let k = jobs.check_job(new_id)?;
+                jobs[k].owner = owner;
+                for f in files {
+                    let src = job_file_path(&old_dir, f)?;
+                    let dst = job_file_path(jobs[k].wrk_dir(), f)?;
+                    if let Some(dir) = dst.parent() {
+                        std::fs::create_dir_all(dir)?;
+                    }
+                    std::fs::copy(&src, &dst).with_context(|| format!("copy restart file {:?}", src))?;
+                }
+                info!("job {} cloned from job {}", new_id, id);
+                Ok(new_id)
+            }
+            .await;
+            self.audit("clone", id.into(), &r);
+            r
+        }
+
+        /// Update tags of job `id` with `tags`. Tags with empty value are
+        /// removed. Unlike `update_job`, tags can be changed at any time.
+        pub async fn set_job_tags(&mut self, id: JobId, tags: &BTreeMap<String, String>) -> Result<()> {
+            let r = async {
+                let mut jobs = self.inner.lock().await;
+                let k = jobs.check_job(id)?;
+                let job_tags = &mut jobs[k].job.tags;
+                for (key, value) in tags {
+                    if value.is_empty() {
+                        job_tags.remove(key);
+                    } else {
+                        job_tags.insert(key.into(), value.into());
+                    }
+                }
+                Ok(())
+            }
+            .await;
+            self.audit("tag", id.into(), &r);
+            r
+        }
+
+        /// Return the list of jobs `user` is allowed to access having all
+        /// `tags`.
+        pub async fn get_job_list_tagged(&self, user: &User, tags: &BTreeMap<String, String>) -> Vec<JobId> {
+            let jobs = self.inner.lock().await;
+            jobs.iter()
+                .filter(|(_, job)| user.can_access(job.owner()))
+                .filter(|(_, job)| tags.iter().all(|(k, v)| job.job.tags.get(k) == Some(v)))
+                .map(|(k, _)| k)
+                .collect()
+        }
+
+        /// Return a copy of the full spec of job `id`, for saving it under
+        /// version control or sharing with others.
+        pub async fn export_job(&self, id: JobId) -> Result<Job> {
+            let jobs = self.inner.lock().await;
+            let k = jobs.check_job(id)?;
+            Job::from_json(&jobs[k].job.to_json()?)
+        }
+
+        /// Return error if `user` is not allowed to access job `id`.
+        pub async fn check_job_owner(&self, id: JobId, user: &User) -> Result<()> {
+            let jobs = self.inner.lock().await;
+            let k = jobs.check_job(id)?;
+            let owner = jobs[k].owner();
+            ensure!(user.can_access(owner), "job {} is not owned by {}", id, user.name);
+            Ok(())
+        }
+
+        /// Return the list of jobs `user` is allowed to access.
+        pub async fn get_job_list_of(&self, user: &User) -> Vec<JobId> {
+            let jobs = self.inner.lock().await;
+            jobs.iter().filter(|(_, job)| user.can_access(job.owner())).map(|(k, _)| k).collect()
+        }
+
+        /// Remove job `id` like `delete_job` if `user` owns it.
+        pub async fn delete_job_as(&mut self, id: JobId, user: &User) -> Result<()> {
+            self.check_job_owner(id, user).await?;
+            self.delete_job(id).await
+        }
+
+        /// Remove all jobs `user` is allowed to access.
+        pub async fn clear_jobs_as(&mut self, user: &User) -> Result<()> {
+            if user.admin {
+                self.clear_jobs().await;
+                return Ok(());
+            }
+            for id in self.get_job_list_of(user).await {
+                self.delete_job(id).await?;
+            }
+            Ok(())
+        }
+
+        /// Append records of completed jobs into `accounting`.
+        pub fn with_accounting(mut self, accounting: Accounting) -> Self {
+            self.accounting = Some(Arc::new(accounting));
+            self
+        }
+
+        /// Search records of finished jobs in accounting storage matching
+        /// `query`.
+        pub fn search_jobs(&self, query: &JobQuery) -> Result<Vec<AcctRecord>> {
+            let acct = self.accounting.as_ref().context("no accounting storage for job history")?;
+            acct.search(query)
+        }
+
+        /// Record operations on jobs into audit `log`.
+        pub fn with_audit(mut self, log: AuditLog) -> Self {
+            self.audit = Some(Arc::new(log));
+            self
+        }
+
+        /// Return a handle sharing the jobs, recording operations requested
+        /// through it as by `user` in the audit log.
+        pub fn as_user(&self, user: &User) -> Self {
+            let mut db = self.clone();
+            db.client = user.name.clone().into();
+            db
+        }
+
+        /// Return audit log entries, optionally only for job `id`.
+        pub async fn get_audit(&self, id: Option<JobId>) -> Result<Vec<AuditEntry>> {
+            match self.audit.clone() {
+                Some(log) => tokio::task::spawn_blocking(move || log.query(id)).await?,
+                None => Ok(vec![]),
+            }
+        }
+
+        /// Record `action` on job `id` with `result` into audit log.
+        fn audit<T>(&self, action: &str, id: Option<JobId>, result: &Result<T>) {
+            if let Some(log) = self.audit.as_ref() {
+                let result = match result {
+                    Ok(_) => "ok".to_owned(),
+                    Err(e) => format!("{:?}", e),
+                };
+                let r = match self.client.as_deref() {
+                    Some(who) => log.record_as(who, action, id, &result),
+                    None => log.record(action, id, &result),
+                };
+                if let Err(e) = r {
+                    warn!("failed to write audit log: {:?}", e);
+                }
+            }
+        }
+
+        /// Run jobs using custom launch logic in `runner` instead of the
+        /// builtin backends.
+        pub fn with_runner(mut self, runner: impl JobRunner + 'static) -> Self {
+            self.runner = Some(Arc::new(runner));
+            self
+        }
+
+        /// Update the job in `id` with a `new_job`. Return error if job `id`
+        /// has been started.
+        pub async fn update_job(&mut self, id: JobId, new_job: Job) -> Result<()> {
+            debug!("update_job: id={}, job={:?}", id, new_job);
+            let r = async {
+                let mut jobs = self.inner.lock().await;
+                let k = jobs.check_job(id)?;
+                if jobs[k].is_started() {
+                    bail!("job {} has been started", id);
+                } else {
+                    jobs[k] = new_job.submit()?;
+                }
+                Ok(())
+            }
+            .await;
+            self.audit("update", id.into(), &r);
+            r
+        }
+
+        /// Return a full list of submitted jobs
+        pub async fn get_job_list(&self) -> Vec<JobId> {
+            self.inner.lock().await.iter().map(|(k, _)| k).collect()
+        }
+
+        /// Put a new file on working directory of job `id`
+        pub async fn put_job_file(&mut self, id: JobId, file: String, body: Bytes) -> Result<()> {
+            self.put_job_file_stream(id, file, &body[..]).await?;
+            Ok(())
+        }
+
+        /// Put a new file on working directory of job `id`, with content
+        /// streamed from `reader` without buffering it in memory. Return
+        /// the number of bytes written.
+        pub async fn put_job_file_stream<R>(&mut self, id: JobId, file: String, mut reader: R) -> Result<u64>
+        where
+            R: tokio::io::AsyncRead + Unpin,
+        {
+            debug!("put_job_file: id={}", id);
+
+            let r = async {
+                // not holding the lock while copying
+                let p = self.get_job_file_path(id, file.as_ref()).await?;
+                info!("client request to put a file: {}", p.display());
+                let mut f = tokio::fs::File::create(&p)
+                    .await
+                    .with_context(|| format!("create file error: {:?}", p))?;
+                let n = tokio::io::copy(&mut reader, &mut f).await.context("write job file")?;
+                f.flush().await?;
+                if let Some(cache) = self.file_cache.clone() {
+                    // hashing large files takes a while
+                    let hash = tokio::task::spawn_blocking(move || cache.insert(&p)).await??;
+                    debug!("job file {} cached as {}", file, hash);
                 }
+                Ok(n)
+            }
+            .await;
+            self.audit(&format!("put_file {}", file), id.into(), &r);
+            r
+        }
+
+        /// Write `data` at `offset` of `file` in working directory of job
+        /// `id`, for uploading large files in chunks. The file is truncated
+        /// at `offset` first, so an interrupted upload could be resumed.
+        /// Return the size of the file.
+        pub async fn put_job_file_chunk(&mut self, id: JobId, file: String, offset: u64, data: &[u8]) -> Result<u64> {
+            use tokio::io::AsyncSeekExt;
+
+            let r = async {
+                let p = self.get_job_file_path(id, file.as_ref()).await?;
+                let mut f = tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .open(&p)
+                    .await
+                    .with_context(|| format!("open file error: {:?}", p))?;
+                let size = f.metadata().await?.len();
+                ensure!(offset <= size, "offset {} beyond the end of file {:?}", offset, p);
+                f.set_len(offset).await?;
+                f.seek(std::io::SeekFrom::Start(offset)).await?;
+                f.write_all(data).await.context("write job file")?;
+                f.flush().await?;
+                Ok(offset + data.len() as u64)
+            }
+            .await;
+            self.audit(&format!("put_file {} at {}", file, offset), id.into(), &r);
+            r
+        }
+
+        /// Return the signature of `file` in working directory of job `id`
+        /// for delta transfer. The signature of a missing file is empty.
+        pub async fn get_job_file_signature(&self, id: JobId, file: &Path) -> Result<Vec<u8>> {
+            let p = self.get_job_file_path(id, file).await?;
+            tokio::task::spawn_blocking(move || crate::delta::signature_file(&p)).await?
+        }
+
+        /// Update `file` in working directory of job `id` by applying
+        /// `delta` computed against its signature, so that only changed
+        /// blocks are transferred. Return the new size of the file.
+        pub async fn patch_job_file(&mut self, id: JobId, file: String, delta: Bytes) -> Result<u64> {
+            let r = async {
+                let p = self.get_job_file_path(id, file.as_ref()).await?;
+                tokio::task::spawn_blocking(move || crate::delta::patch_file(&p, &delta)).await?
+            }
+            .await;
+            self.audit(&format!("patch_file {}", file), id.into(), &r);
+            r
+        }
+
+        /// Return the delta turning the file with `signature` into `file` in
+        /// working directory of job `id`, for downloading only changed
+        /// blocks.
+        pub async fn get_job_file_delta(&self, id: JobId, file: &Path, signature: Bytes) -> Result<Vec<u8>> {
+            let p = self.get_job_file_path(id, file).await?;
+            tokio::task::spawn_blocking(move || crate::delta::delta_file(&signature, &p)).await?
+        }
+
+        /// Put file of `hash` from the file cache into working directory of
+        /// job `id`, skipping the upload. Return false if not cached, and
+        /// the file should be uploaded instead.
+        pub async fn link_cached_job_file(&mut self, id: JobId, file: String, hash: &str) -> Result<bool> {
+            let r = async {
+                let cache = match self.file_cache.clone() {
+                    Some(cache) => cache,
+                    None => return Ok(false),
+                };
+                let p = self.get_job_file_path(id, file.as_ref()).await?;
+                let hash = hash.to_owned();
+                tokio::task::spawn_blocking(move || cache.copy_into(&hash, &p)).await?
             }
+            .await;
+            self.audit(&format!("link_file {}", file), id.into(), &r);
+            r
+        }
+
+        /// Open file in working directory of job `id` for streaming its
+        /// content. Return the opened file with its size and content type.
+        pub async fn open_job_file(&self, id: JobId, file: &Path) -> Result<(tokio::fs::File, u64, &'static str)> {
+            let p = self.get_job_file_path(id, file).await?;
+            info!("client request file: {}", p.display());
+            let f = tokio::fs::File::open(&p)
+                .await
+                .with_context(|| format!("open file {:?}", p))?;
+            let size = f.metadata().await?.len();
+            // sniffing content reads the file
+            let mime = tokio::task::spawn_blocking(move || crate::node::content_type(&p)).await??;
+            Ok((f, size, mime))
+        }
+
+        /// Read at most `len` bytes at `offset` of `file` in working
+        /// directory of job `id`, for transferring large files in chunks.
+        /// Return the data with size and content type of the file.
+        pub async fn read_job_file_chunk(
+            &self,
+            id: JobId,
+            file: &Path,
+            offset: u64,
+            len: u64,
+        ) -> Result<(Vec<u8>, u64, &'static str)> {
+            use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+            let (mut f, size, mime) = self.open_job_file(id, file).await?;
+            f.seek(std::io::SeekFrom::Start(offset)).await?;
+            let mut data = vec![];
+            f.take(len).read_to_end(&mut data).await?;
+            Ok((data, size, mime))
         }
 
         /// Return the content of `file` for job `id`
         pub async fn get_job_file(&self, id: JobId, file: &Path) -> Result<Vec<u8>> {
             debug!("get_job_file: id={}", id);
+            // not holding the lock while reading
+            let p = self.get_job_file_path(id, file).await?;
+            info!("client request file: {}", p.display());
+            let buffer = tokio::fs::read(&p)
+                .await
+                .with_context(|| format!("read file {:?}", p))?;
+            Ok(buffer)
+        }
+
+        /// Return the full path to `file` in working directory of job `id`.
+        /// Return error if `file` is not a relative path inside the
+        /// working directory.
+        pub async fn get_job_file_path(&self, id: JobId, file: &Path) -> Result<PathBuf> {
             let jobs = self.inner.lock().await;
             let k = jobs.check_job(id)?;
-            let job = &jobs[k];
-            let p = job.wrk_dir().join(&file);
-            info!("client request file: {}", p.display());
-
-            let mut buffer = Vec::new();
-            let _ = std::fs::File::open(p)
-                .context("open file")?
-                .read_to_end(&mut buffer)
-                .context("read file")?;
-            Ok(buffer)
+            job_file_path(jobs[k].wrk_dir(), file)
         }
 
         /// List files in working directory of Job `id`.
@@ -364,38 +3650,762 @@ mod db {
             Ok(list)
         }
 
+        /// List stats of all files in working directory of job `id`,
+        /// including those in sub-directories.
+        pub async fn list_job_file_stats(&self, id: JobId) -> Result<Vec<FileStat>> {
+            let wdir = self.get_job_file_path(id, "".as_ref()).await?;
+            tokio::task::spawn_blocking(move || {
+                let mut stats = vec![];
+                collect_file_stats(&wdir, &wdir, &mut stats).context("list dir")?;
+                Ok(stats)
+            })
+            .await?
+        }
+
+        /// Wait until a file matching glob `pattern` appears in working
+        /// directory of job `id`, e.g. "CONVERGED" or "forces.*". Return
+        /// the file name, or None if `timeout` elapsed.
+        pub async fn wait_job_file(
+            &self,
+            id: JobId,
+            pattern: &str,
+            timeout: Option<std::time::Duration>,
+        ) -> Result<Option<String>> {
+            let wdir = self.get_job_file_path(id, "".as_ref()).await?;
+            let pattern = pattern.to_owned();
+            tokio::task::spawn_blocking(move || crate::watch::wait_for_file(&wdir, &pattern, timeout)).await?
+        }
+
         /// Remove all jobs from `Db`. If the job has been started, the child
         /// processes will be terminated.
         pub async fn clear_jobs(&mut self) {
             self.inner.lock().await.clear();
+            self.audit("clear", None, &Ok(()));
         }
 
         /// Remove the job `id` from `Db`. If the job has been started, it will
         /// be terminated.
         pub async fn delete_job(&mut self, id: JobId) -> Result<()> {
             info!("delete_job: id={}", id);
-            self.inner.lock().await.remove(id)?;
-            Ok(())
+            let r = self.inner.lock().await.remove(id);
+            self.audit("delete", id.into(), &r);
+            r
         }
 
-        /// Insert job into the queue.
-        pub async fn insert_job(&mut self, mut job: Job) -> JobId {
+        /// Insert job into the queue. Return error if the working
+        /// directory could not be set up.
+        pub async fn insert_job(&mut self, job: Job) -> Result<JobId> {
             info!("create_job: {:?}", job);
+            let mut comp = match job.submit() {
+                Ok(comp) => comp,
+                Err(e) => {
+                    let r = Err(e);
+                    self.audit("create", None, &r);
+                    return r;
+                }
+            };
+            if let Some(vars) = self.scratch_vars.as_ref() {
+                comp.scratch_vars = vars.as_ref().clone();
+            }
             let mut jobs = self.inner.lock().await;
-            let jid = jobs.insert(job.submit());
+            // never share working directory with other jobs
+            let canonical = |p: &Path| p.canonicalize().unwrap_or_else(|_| p.to_owned());
+            let wdir = canonical(comp.wrk_dir());
+            let shared = jobs.iter().any(|(_, job)| {
+                let other = canonical(job.wrk_dir());
+                wdir.starts_with(&other) || other.starts_with(&wdir)
+            });
+            if shared {
+                let r = Err(format_err!("work dir {:?} is used by another job", wdir));
+                self.audit("create", None, &r);
+                return r;
+            }
+            let jid = jobs.insert(comp);
             info!("Job {} created.", jid);
-            jid
+            self.audit("create", jid.into(), &Ok(()));
+            Ok(jid)
+        }
+
+        /// Return estimated progress of job `id`.
+        pub async fn get_job_progress(&self, id: JobId) -> Result<Progress> {
+            let mut jobs = self.inner.lock().await;
+            let k = jobs.check_job(id)?;
+            Ok(jobs[k].progress())
+        }
+
+        /// Return current status of job `id`.
+        pub async fn get_job_status(&self, id: JobId) -> Result<JobStatus> {
+            debug!("get_job_status: id={}", id);
+            let mut jobs = self.inner.lock().await;
+            let k = jobs.check_job(id)?;
+            Ok(jobs[k].status())
+        }
+
+        /// Return recorded transitions of job `id`, such as started,
+        /// preempted and resumed.
+        pub async fn get_job_history(&self, id: JobId) -> Result<Vec<JobEvent>> {
+            debug!("get_job_history: id={}", id);
+            let jobs = self.inner.lock().await;
+            let k = jobs.check_job(id)?;
+            Ok(jobs[k].history.clone())
+        }
+
+        /// Return a snapshot of the process tree of running job `id`.
+        pub async fn get_job_processes(&self, id: JobId) -> Result<Vec<crate::process::ProcessNode>> {
+            debug!("get_job_processes: id={}", id);
+            let handler = self.job_handler(id).await?;
+            // scanning processes takes a while, without locking jobs
+            tokio::task::spawn_blocking(move || handler.process_tree()).await?
+        }
+
+        /// Return the session handler of started job `id`.
+        async fn job_handler(&self, id: JobId) -> Result<crate::process::SessionHandler> {
+            let jobs = self.inner.lock().await;
+            let k = jobs.check_job(id)?;
+            let handler = jobs[k]
+                .session
+                .as_ref()
+                .map(|s| s.handler().clone())
+                .ok_or(format_err!("job {} not started yet", id))?;
+            Ok(handler)
+        }
+
+        /// Wait until the started job `id` exits. Unlike `wait_job`, the
+        /// job queue is not locked while waiting.
+        pub async fn wait_job_exit(&self, id: JobId) -> Result<()> {
+            let handler = self.job_handler(id).await?;
+            handler.on_exit().await?;
+            info!("job {} exited", id);
+            Ok(())
+        }
+
+        /// Checkpoint the running job `id` into `dir` using CRIU.
+        pub async fn checkpoint_job(&self, id: JobId, dir: &Path) -> Result<()> {
+            info!("checkpoint_job: id={}", id);
+            let r = async {
+                let handler = self.job_handler(id).await?;
+                // dumping takes a while, without locking jobs
+                let dir = dir.to_owned();
+                tokio::task::spawn_blocking(move || handler.checkpoint(dir)).await?
+            }
+            .await;
+            self.audit("checkpoint", id.into(), &r);
+            r
+        }
+
+        /// Return timing and exit status of finished job `id` recorded in
+        /// its working directory.
+        pub async fn get_job_run_stat(&self, id: JobId) -> Result<RunStat> {
+            let jobs = self.inner.lock().await;
+            let k = jobs.check_job(id)?;
+            jobs[k].run_stat()
+        }
+
+        /// Write `data` into stdin of running job `id`. Close the stdin
+        /// afterwards if `close` is true.
+        pub async fn send_job_stdin(&self, id: JobId, data: &[u8], close: bool) -> Result<()> {
+            let r = async {
+                let mut jobs = self.inner.lock().await;
+                let k = jobs.check_job(id)?;
+                if !data.is_empty() {
+                    jobs[k].send_stdin(data)?;
+                }
+                if close {
+                    jobs[k].close_stdin();
+                }
+                Ok(())
+            }
+            .await;
+            self.audit("stdin", id.into(), &r);
+            r
+        }
+
+        /// Return resource usage samples of job `id` in CSV format.
+        pub async fn get_job_usage(&self, id: JobId) -> Result<String> {
+            let jobs = self.inner.lock().await;
+            let k = jobs.check_job(id)?;
+            let f = jobs[k].usage_file();
+            let s = gut::fs::read_file(&f).with_context(|| format!("no usage recorded for job {}", id))?;
+            Ok(s)
+        }
+
+        /// Return the recorded run conditions of started job `id`.
+        pub async fn get_job_metadata(&self, id: JobId) -> Result<RunMeta> {
+            debug!("get_job_metadata: id={}", id);
+            let jobs = self.inner.lock().await;
+            let k = jobs.check_job(id)?;
+            let f = jobs[k].meta_file();
+            let s = gut::fs::read_file(&f).with_context(|| format!("job {} not started yet?", id))?;
+            RunMeta::from_json(&s)
+        }
+
+        /// Record executables run by job `id` into its metadata. Programs
+        /// can only be seen while the job is running.
+        pub async fn record_job_programs(&self, id: JobId) -> Result<()> {
+            let jobs = self.inner.lock().await;
+            let k = jobs.check_job(id)?;
+            jobs[k].record_programs()
+        }
+
+        /// Write a reproducibility bundle of job `id` into gzipped tarball
+        /// `path`, containing the job spec, run script, inputs and metadata
+        /// with environment and programs run by the job.
+        pub async fn bundle_job(&self, id: JobId, path: &Path) -> Result<()> {
+            let r = async {
+                let stage = tempdir()?;
+                let name = format!("job-{}", id);
+                let bundle = stage.path().join(&name);
+                std::fs::create_dir_all(&bundle)?;
+                {
+                    let jobs = self.inner.lock().await;
+                    let k = jobs.check_job(id)?;
+                    let job = &jobs[k];
+                    if job.session.is_some() {
+                        if let Err(e) = job.record_programs() {
+                            warn!("failed to record programs of job {}: {:?}", id, e);
+                        }
+                    }
+                    gut::fs::write_to_file(bundle.join("job.toml"), &job.job.to_toml()?)?;
+                    let files = vec![job.run_file(), job.inp_file(), job.meta_file()];
+                    for src in files.into_iter().chain(job.extra_files()) {
+                        if !src.exists() {
+                            continue;
+                        }
+                        let dst = bundle.join(src.file_name().context("invalid file name")?);
+                        std::fs::copy(&src, &dst).with_context(|| format!("copy {:?} into bundle", src))?;
+                    }
+                }
+                let status = std::process::Command::new("tar")
+                    .arg("czf")
+                    .arg(path)
+                    .arg("-C")
+                    .arg(stage.path())
+                    .arg(&name)
+                    .status()?;
+                ensure!(status.success(), "failed to bundle job {} into {:?}", id, path);
+                info!("job {} bundled into {:?}", id, path);
+                Ok(())
+            }
+            .await;
+            self.audit("bundle", id.into(), &r);
+            r
+        }
+
+        /// Recover jobs started in working directories under `dir` before
+        /// the runner restarts, reattaching to sessions still running.
+        /// Return ids of recovered jobs. Recovered jobs are not accounted
+        /// by the scheduler until waited, and their working directories are
+        /// kept after removal.
+        pub async fn recover_jobs(&mut self, dir: &Path) -> Result<Vec<JobId>> {
+            let mut recovered = vec![];
+            for entry in std::fs::read_dir(dir).with_context(|| format!("read dir {:?}", dir))? {
+                let path = entry?.path();
+                if !path.join(RECOVERY_FILE).is_file() {
+                    continue;
+                }
+                match Computation::recover(&path) {
+                    Ok(job) => {
+                        let id = self.inner.lock().await.insert(job);
+                        info!("recovered job {} in {:?}", id, path);
+                        recovered.push(id);
+                    }
+                    Err(e) => warn!("failed to recover job in {:?}: {:?}", path, e),
+                }
+            }
+            Ok(recovered)
+        }
+
+        /// Spawn a background task that cleans up orphaned processes of
+        /// finished jobs every `interval` seconds.
+        pub fn spawn_reaper(&self, interval: f64) -> tokio::task::JoinHandle<()> {
+            let db = self.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(std::time::Duration::from_secs_f64(interval)).await;
+                    db.reap_orphans().await;
+                }
+            })
+        }
+
+        /// Terminate all running jobs for shutdown: running sessions are
+        /// notified with SIGTERM, and killed if still alive after `grace`
+        /// period. All jobs are removed afterwards.
+        pub async fn shutdown(&self, grace: std::time::Duration) {
+            let mut jobs = self.inner.lock().await;
+            // cancel jobs submitted to other backends before exiting
+            let submitted: Vec<_> = jobs
+                .iter()
+                .filter(|(_, job)| !job.submitted_status.is_finished())
+                .filter_map(|(id, job)| job.submitted.clone().map(|s| (id, s)))
+                .collect();
+            for (id, s) in submitted {
+                if let Ok(Err(e)) = tokio::task::spawn_blocking(move || s.cancel()).await {
+                    warn!("failed to cancel job {}: {:?}", id, e);
+                }
+            }
+            let handlers: Vec<_> = jobs
+                .iter()
+                .filter_map(|(id, job)| job.session.as_ref().map(|s| (id, s.handler().clone())))
+                .collect();
+            info!("shutdown: terminating {} running jobs", handlers.len());
+            let tasks: Vec<_> = handlers
+                .into_iter()
+                .map(|(id, h)| {
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = h.terminate_gracefully(grace) {
+                            warn!("failed to terminate job {}: {:?}", id, e);
+                        }
+                    })
+                })
+                .collect();
+            for task in tasks {
+                let _ = task.await;
+            }
+            jobs.clear();
+            self.audit("shutdown", None, &Ok(()));
+        }
+
+        /// Spawn a background task enforcing retention `policy` on finished
+        /// jobs every `interval` seconds.
+        pub fn spawn_gc(&self, policy: RetentionPolicy, interval: f64) -> tokio::task::JoinHandle<()> {
+            let mut db = self.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(std::time::Duration::from_secs_f64(interval)).await;
+                    if let Err(e) = db.collect_garbage(&policy).await {
+                        warn!("job garbage collection failed: {:?}", e);
+                    }
+                }
+            })
+        }
+
+        /// Remove finished jobs violating retention `policy`, archiving their
+        /// working directories if required. Return removed job IDs.
+        pub async fn collect_garbage(&mut self, policy: &RetentionPolicy) -> Result<Vec<JobId>> {
+            let mut jobs = self.inner.lock().await;
+            let ids: Vec<_> = jobs.iter().map(|(id, _)| id).collect();
+            let mut finished = vec![];
+            for id in ids {
+                let k = jobs.check_job(id)?;
+                if jobs[k].status().is_finished() {
+                    // jobs finished not by us, e.g. recovered, count from creation
+                    let age = jobs[k].finished.unwrap_or(jobs[k].created).elapsed();
+                    finished.push((id, age));
+                }
+            }
+            let removed = policy.select(&finished);
+            let mut taken = vec![];
+            for &id in &removed {
+                taken.push((id, jobs.take(id)?));
+                info!("job {} removed by retention policy", id);
+            }
+            drop(jobs);
+            // archiving and deleting working directories take a while
+            let policy = policy.clone();
+            tokio::task::spawn_blocking(move || {
+                for (id, mut job) in taken {
+                    if let Err(e) = policy.archive(id, job.wrk_dir()) {
+                        // keep the working directory rather than losing it
+                        let dir = job.wrk_dir.keep();
+                        warn!("archive job {} failed, keeping {:?}: {:?}", id, dir, e);
+                    }
+                }
+            })
+            .await?;
+            for &id in &removed {
+                self.audit("gc", id.into(), &Ok(()));
+            }
+            Ok(removed)
+        }
+
+        /// Terminate orphaned processes in sessions of all started jobs.
+        async fn reap_orphans(&self) {
+            let sessions: Vec<_> = {
+                let jobs = self.inner.lock().await;
+                jobs.iter()
+                    .filter_map(|(id, job)| Some((id, job.session_id()?)))
+                    .collect()
+            };
+            // scanning processes takes a while, without locking jobs
+            let _ = tokio::task::spawn_blocking(move || {
+                for (id, sid) in sessions {
+                    match crate::process::reap_orphans(sid) {
+                        Ok(reaped) if !reaped.is_empty() => {
+                            info!("reaped orphaned processes of job {}: {:?}", id, reaped);
+                        }
+                        Err(e) => {
+                            warn!("reap orphans for job {} failed: {:?}", id, e);
+                        }
+                        _ => {}
+                    }
+                }
+            })
+            .await;
         }
 
-        /// Start the job in background, and wait until it finish.
-        pub async fn wait_job(&self, id: JobId) -> Result<()> {
+        /// Start the job in background, wait until it finish, and return its
+        /// result. Waiting again for the job returns the same result without
+        /// running it again.
+        pub async fn wait_job(&self, id: JobId) -> Result<JobResult> {
             info!("wait_job: id={}", id);
+            // the job is run by the first waiter only, and later ones wait
+            // for its result
+            let waiting = {
+                let mut jobs = self.inner.lock().await;
+                let k = jobs.check_job(id)?;
+                match jobs[k].waited.as_ref() {
+                    Some(tx) => Some(tx.subscribe()),
+                    None => {
+                        jobs[k].waited = tokio::sync::watch::channel(None).0.into();
+                        None
+                    }
+                }
+            };
+            if let Some(mut rx) = waiting {
+                loop {
+                    let shared = rx.borrow().clone();
+                    if let Some(result) = shared {
+                        return result.map_err(|e| format_err!("{}", e));
+                    }
+                    rx.changed().await.map_err(|_| format_err!("job {} was deleted", id))?;
+                }
+            }
+            let result = self.run_and_wait_job(id).await;
+            let jobs = self.inner.lock().await;
+            if let Some(tx) = jobs.check_job(id).ok().and_then(|k| jobs[k].waited.as_ref()) {
+                let shared = result.as_ref().cloned().map_err(|e| format!("{:?}", e));
+                tx.send_replace(shared.into());
+            }
+            result
+        }
+
+        /// Run job `id` once resources are free, and wait until it finishes.
+        async fn run_and_wait_job(&self, id: JobId) -> Result<JobResult> {
+            let (req, priority, walltime, window, pause) = {
+                let jobs = self.inner.lock().await;
+                let k = jobs.check_job(id)?;
+                let job = &jobs[k].job;
+                let window = job.window.clone().or_else(|| self.window.clone());
+                let pause = job.pause_outside_window;
+                (job.resources.clone(), job.priority, job.timeout, window, pause)
+            };
+            if let Some(window) = window.as_ref() {
+                window.wait_open().await;
+            }
+            if let Some(throttle) = self.throttle.as_ref() {
+                crate::throttle::wait_unthrottled(throttle.as_ref()).await;
+            }
+            // wait until required resources are free
+            let (alloc, preempted) = self.acquire_preempting(id, priority, &req, walltime).await?;
+            let mut watchers = vec![];
+            if let Some(window) = window.filter(|_| pause) {
+                let db = self.clone();
+                let interval = crate::window::CHECK_INTERVAL;
+                watchers.push(tokio::spawn(async move {
+                    db.watch_pause(id, interval, PauseReason::Window, 0, || !window.is_open())
+                        .await
+                }));
+            }
+            let throttle = self.throttle.clone();
+            if let Some((throttle, limit)) = throttle.and_then(|t| t.running_limit().map(|n| (t, n))) {
+                let db = self.clone();
+                let interval = crate::throttle::CHECK_INTERVAL;
+                watchers.push(tokio::spawn(async move {
+                    db.watch_pause(id, interval, PauseReason::Throttle, limit, || throttle.is_throttled())
+                        .await
+                }));
+            }
+            let result = self.run_job(id, &alloc).await;
+            for watcher in watchers {
+                watcher.abort();
+            }
+            self.release_allocation(id, &alloc).await;
+            for victim in preempted {
+                let db = self.clone();
+                tokio::spawn(async move { db.resume_preempted(victim).await });
+            }
+            self.audit("run", id.into(), &result);
+            result?;
+            let (mut result, post_run) = {
+                let mut jobs = self.inner.lock().await;
+                let k = jobs.check_job(id)?;
+                if let Some(acct) = self.accounting.as_ref() {
+                    if let Err(e) = acct.append(&jobs[k].acct_record()) {
+                        warn!("failed to write accounting record: {:?}", e);
+                    }
+                }
+                if !jobs[k].job.notify.is_empty() {
+                    let targets = jobs[k].job.notify.clone();
+                    let summary = jobs[k].summary(id);
+                    let notifier = self.notifier.clone();
+                    tokio::task::spawn_blocking(move || notifier.notify(&targets, &summary));
+                }
+                (jobs[k].result(&self.failure_classifiers), jobs[k].post_run()?)
+            };
+            // outputs are read above before hooks may compress them
+            result.values = tokio::task::spawn_blocking(move || post_run.run()).await??;
+            Ok(result)
+        }
+
+        /// Wait for jobs `ids` according to `policy`, returning results of
+        /// finished jobs in the order they finished. Jobs not finished yet
+        /// when it returns keep running.
+        pub async fn wait_jobs(&self, ids: &[JobId], policy: WaitPolicy) -> Result<Vec<(JobId, JobResult)>> {
+            info!("wait_jobs: ids={:?}, policy={:?}", ids, policy);
+            // check all ids first before waiting any of them
+            {
+                let jobs = self.inner.lock().await;
+                for &id in ids {
+                    jobs.check_job(id)?;
+                }
+            }
+            let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+            for &id in ids {
+                let db = self.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let result = db.wait_job(id).await;
+                    let _ = tx.send((id, result));
+                });
+            }
+            drop(tx);
+
+            let mut results = vec![];
+            while let Some((id, result)) = rx.recv().await {
+                let result = result.with_context(|| format!("wait job {}", id))?;
+                let failed = result.status != JobStatus::Completed;
+                results.push((id, result));
+                match policy {
+                    WaitPolicy::Any => break,
+                    WaitPolicy::FirstFailure if failed => break,
+                    _ => {}
+                }
+            }
+            Ok(results)
+        }
+
+        /// Allocate `req` resources for job `id` with declared `walltime`.
+        /// If there are jobs with priority lower than `priority`, the job
+        /// skips the queue, and if the node is full, running ones are paused
+        /// to free their resources, lowest first. Return the allocation and
+        /// the preempted jobs to be resumed later.
+        async fn acquire_preempting(
+            &self,
+            id: JobId,
+            priority: i32,
+            req: &Resources,
+            walltime: Option<f64>,
+        ) -> Result<(Allocation, Vec<JobId>)> {
+            let mut preempted = vec![];
+            loop {
+                let mut jobs = self.inner.lock().await;
+                let candidates: Vec<_> = jobs
+                    .iter()
+                    .filter(|(_, c)| c.job.priority < priority)
+                    .map(|(i, c)| (c.job.priority, i))
+                    .sorted()
+                    .collect();
+                if candidates.is_empty() {
+                    break;
+                }
+                if let Some(alloc) = self.scheduler.try_acquire(req).await? {
+                    return Ok((alloc, preempted));
+                }
+                let mut victim = None;
+                for (_, i) in candidates {
+                    let k = jobs.check_job(i)?;
+                    if jobs[k].is_preemptible() && jobs[k].pause(PauseReason::Preempted)? {
+                        jobs[k].record_event(format!("preempted by job {}", id));
+                        victim = Some((i, jobs[k].allocation.clone()));
+                        break;
+                    }
+                }
+                drop(jobs);
+                match victim {
+                    Some((i, freed)) => {
+                        info!("job {} preempted by job {}", i, id);
+                        self.preempted.lock().await.insert(i);
+                        self.scheduler.release(&freed).await;
+                        preempted.push(i);
+                    }
+                    // nothing to preempt: wait for running jobs
+                    None => break,
+                }
+            }
+            // wait in the queue, backfilled if possible
+            let alloc = self.scheduler.acquire_within(req, walltime).await?;
+            Ok((alloc, preempted))
+        }
+
+        /// Pause running job `id` for `reason` while `should_pause` returns
+        /// true, checked every `interval`, and resume it afterwards. The
+        /// first `keep` running jobs in the order they started are not
+        /// paused, so that the pool of running jobs is reduced to `keep`.
+        async fn watch_pause<F>(
+            &self,
+            id: JobId,
+            interval: std::time::Duration,
+            reason: PauseReason,
+            keep: usize,
+            should_pause: F,
+        ) where
+            F: Fn() -> bool,
+        {
+            loop {
+                tokio::time::sleep(interval).await;
+                let pause = should_pause();
+                let mut jobs = self.inner.lock().await;
+                let k = match jobs.check_job(id) {
+                    Ok(k) => k,
+                    Err(_) => return,
+                };
+                // the number of running jobs started before this one
+                let started = jobs[k].started;
+                let rank = jobs
+                    .iter()
+                    .filter(|(_, c)| c.session.is_some() && c.finished.is_none())
+                    .filter(|(_, c)| c.started < started)
+                    .count();
+                let paused = jobs[k].paused.contains(&reason);
+                if pause && rank >= keep && !paused {
+                    match jobs[k].pause(reason) {
+                        Ok(true) => jobs[k].record_event(format!("paused {}", reason)),
+                        Ok(false) => {}
+                        Err(e) => warn!("failed to pause job {}: {:?}", id, e),
+                    }
+                } else if (!pause || rank < keep) && paused {
+                    if let Err(e) = jobs[k].resume(reason) {
+                        warn!("failed to resume job {}: {:?}", id, e);
+                    }
+                }
+            }
+        }
+
+        /// Release `alloc` resources of finished job `id`, unless they were
+        /// already released when it was preempted.
+        async fn release_allocation(&self, id: JobId, alloc: &Allocation) {
+            if !self.preempted.lock().await.remove(&id) {
+                self.scheduler.release(alloc).await;
+            }
+        }
+
+        /// Resume preempted job `victim` once its resources are free again.
+        async fn resume_preempted(&self, victim: JobId) {
+            let alloc = {
+                let jobs = self.inner.lock().await;
+                match jobs.check_job(victim) {
+                    Ok(k) => jobs[k].allocation.clone(),
+                    Err(_) => return,
+                }
+            };
+            self.scheduler.reclaim(&alloc).await;
+            // the job may have finished or been removed while paused
+            if !self.preempted.lock().await.remove(&victim) {
+                self.scheduler.release(&alloc).await;
+                return;
+            }
+            let mut jobs = self.inner.lock().await;
+            if let Ok(k) = jobs.check_job(victim) {
+                if let Err(e) = jobs[k].resume(PauseReason::Preempted) {
+                    warn!("failed to resume preempted job {}: {:?}", victim, e);
+                }
+            }
+        }
+
+        /// Start job `id` using `alloc` resources, and wait until it finish.
+        async fn run_job(&self, id: JobId, alloc: &Allocation) -> Result<()> {
+            if let Some(runner) = self.runner.as_ref() {
+                return self.run_job_with(runner.as_ref(), id, alloc).await;
+            }
+            let (handler, submitted, timeout) = {
+                let mut jobs = self.inner.lock().await;
+                let k = jobs.check_job(id)?;
+                jobs[k].allocation = alloc.clone();
+                if jobs[k].recovered {
+                    // started before the runner restarts: only wait for it
+                    let handler = jobs[k].reattached.clone();
+                    (handler, None, None)
+                } else {
+                    ensure!(!jobs[k].is_started(), "job {} already started", id);
+                    jobs[k].start().await?;
+                    let handler = jobs[k].session.as_ref().map(|s| s.handler().clone());
+                    (handler, jobs[k].submitted.clone(), jobs[k].job.timeout)
+                }
+            };
+            // wait without locking the job queue
+            if let Some(handler) = handler {
+                match timeout {
+                    Some(t) => {
+                        let duration = std::time::Duration::from_secs_f64(t);
+                        let exit = handler.on_exit();
+                        tokio::pin!(exit);
+                        loop {
+                            // time paused is not counted against the timeout
+                            let remaining = {
+                                let jobs = self.inner.lock().await;
+                                let k = jobs.check_job(id)?;
+                                duration.saturating_sub(jobs[k].run_time())
+                            };
+                            if remaining.is_zero() {
+                                warn!("job {} timed out after {} seconds", id, t);
+                                let h = handler.clone();
+                                let grace = std::time::Duration::from_secs(10);
+                                tokio::task::spawn_blocking(move || h.terminate_gracefully(grace)).await??;
+                                break;
+                            }
+                            tokio::select! {
+                                r = &mut exit => {
+                                    r?;
+                                    break;
+                                }
+                                _ = tokio::time::sleep(remaining) => {}
+                            }
+                        }
+                    }
+                    None => handler.on_exit().await?,
+                }
+            }
+            // poll the job submitted to other backends until finished
+            if let Some(submitted) = submitted {
+                loop {
+                    let s = submitted.clone();
+                    let status = tokio::task::spawn_blocking(move || s.poll()).await??;
+                    let mut jobs = self.inner.lock().await;
+                    let k = jobs.check_job(id)?;
+                    jobs[k].submitted_status = status;
+                    if status.is_finished() {
+                        break;
+                    }
+                    drop(jobs);
+                    tokio::time::sleep(SUBMITTED_POLL_INTERVAL).await;
+                }
+            }
             let mut jobs = self.inner.lock().await;
             let k = jobs.check_job(id)?;
-            jobs[k].start().await?;
             jobs[k].wait().await?;
             Ok(())
         }
+
+        /// Run job `id` using custom `runner`, and wait until it finish.
+        async fn run_job_with(&self, runner: &dyn JobRunner, id: JobId, alloc: &Allocation) -> Result<()> {
+            let ctx = {
+                let mut jobs = self.inner.lock().await;
+                let k = jobs.check_job(id)?;
+                ensure!(!jobs[k].is_started(), "job {} already started", id);
+                jobs[k].allocation = alloc.clone();
+                jobs[k].runner_status = JobStatus::Running.into();
+                jobs[k].started = std::time::Instant::now().into();
+                jobs[k].run_context()
+            };
+            let RunOutcome { status, exit_code } = runner.run(ctx).await;
+            info!("job {} run by custom runner: {:?}, exit code: {:?}", id, status, exit_code);
+            let mut jobs = self.inner.lock().await;
+            let k = jobs.check_job(id)?;
+            jobs[k].runner_status = status.into();
+            jobs[k].exit_code = exit_code;
+            jobs[k].finished = std::time::Instant::now().into();
+            Ok(())
+        }
     }
 }
 // f4436dc6 ends here
@@ -405,7 +4415,6 @@ mod impl_jobs_slotmap {
     use super::*;
 
     use bimap::BiMap;
-    use slotmap::Key;
     use slotmap::{DefaultKey, SlotMap};
 
     /// The job `Id` from user side
@@ -416,6 +4425,8 @@ mod impl_jobs_slotmap {
     pub struct Jobs {
         inner: SlotMap<DefaultKey, Computation>,
         mapping: BiMap<usize, JobKey>,
+        // the last assigned id, never reused after removal
+        last_id: Id,
     }
 
     impl Jobs {
@@ -424,23 +4435,24 @@ mod impl_jobs_slotmap {
             Self {
                 inner: SlotMap::new(),
                 mapping: BiMap::new(),
+                last_id: 0,
             }
         }
 
         /// Look for the Job with `id`, returning error if the job with `id`
         /// does not exist.
         pub fn check_job(&self, id: Id) -> Result<JobKey> {
-            if let Some(&k) = self.mapping.get_by_left(&id) {
-                Ok(k)
-            } else {
-                bail!("Job id not found: {}", id);
+            match self.mapping.get_by_left(&id) {
+                Some(&k) if self.inner.contains_key(k) => Ok(k),
+                _ => bail!("Job id not found: {}", id),
             }
         }
 
         /// Insert a new Job into database, returning Id for later operations.
         pub fn insert(&mut self, job: Computation) -> Id {
             let k = self.inner.insert(job);
-            let n = self.mapping.len() + 1;
+            self.last_id += 1;
+            let n = self.last_id;
             if let Err(e) = self.mapping.insert_no_overwrite(n, k) {
                 panic!("invalid {:?}", e);
             }
@@ -449,14 +4461,22 @@ mod impl_jobs_slotmap {
 
         /// Remove the job with `id`
         pub fn remove(&mut self, id: Id) -> Result<()> {
+            // The session will be terminated on drop
+            let _ = self.take(id)?;
+            Ok(())
+        }
+
+        /// Remove the job with `id`, and return it.
+        pub fn take(&mut self, id: Id) -> Result<Computation> {
             let k = self.check_job(id)?;
-            let job = &self.inner[k];
-            if job.is_started() {
+            if self.inner[k].is_started() {
                 info!("Job {} has been started.", id);
             }
-            // The session will be terminated on drop
-            let _ = self.inner.remove(k);
-            Ok(())
+            self.mapping.remove_by_left(&id);
+            let job = self.inner.remove(k).expect("checked job key");
+            // removed jobs are not recovered after runner restarts
+            let _ = std::fs::remove_file(job.recovery_file());
+            Ok(job)
         }
 
         /// Remove all created jobs
@@ -465,9 +4485,11 @@ mod impl_jobs_slotmap {
                 if job.is_started() {
                     info!("job {} already started.", self.to_id(k));
                 }
+                let _ = std::fs::remove_file(job.recovery_file());
             }
             // The session will be terminated on drop
             self.inner.clear();
+            self.mapping.clear();
         }
 
         /// Iterator over a tuple of `Id` and `Job`.
@@ -504,3 +4526,669 @@ mod impl_jobs_slotmap {
 pub use self::db::Db;
 pub use self::db::Id as JobId;
 // pub:1 ends here
+
+// [[file:../runners.note::c57e1b04][c57e1b04]]
+#[tokio::test]
+async fn test_computation_interleaved_output() -> Result<()> {
+    let script = "#!/usr/bin/env bash
+for i in $(seq 1 20000); do
+  echo \"stdout line $i\"
+  echo \"stderr line $i\" >&2
+done
+";
+    let comp = run_job(Job::new(script)).await?;
+    let out = std::fs::read_to_string(comp.out_file())?;
+    let err = std::fs::read_to_string(comp.err_file())?;
+    assert_eq!(out.lines().count(), 20000);
+    assert_eq!(err.lines().count(), 20000);
+    assert_eq!(err.lines().last(), Some("stderr line 20000"));
+
+    Ok(())
+}
+// c57e1b04 ends here
+
+// [[file:../runners.note::0d6b8e27][0d6b8e27]]
+#[test]
+fn test_job_spec_roundtrip() -> Result<()> {
+    let mut job = Job::from_template("vasp", &[("nprocs", "16")])?;
+    job.set_env("OMP_NUM_THREADS", "1");
+    job.set_timeout(3600.0);
+    job.set_output_limit(1 << 20, OutputLimitAction::RotateGzip { keep: 2 });
+    job.attach_file("INCAR");
+    job.set_tag("method", "PBE0");
+
+    for s in [job.to_toml()?, job.to_yaml()?] {
+        let x = Job::from_toml(&s).or_else(|_| Job::from_yaml(&s))?;
+        assert_eq!(x.script, job.script);
+        assert_eq!(x.env, job.env);
+        assert_eq!(x.timeout, Some(3600.0));
+        assert_eq!(x.extra_files, job.extra_files);
+        assert_eq!(x.template, job.template);
+        assert_eq!(x.tags["method"], "PBE0");
+        let action = x.output_limit.map(|l| l.action);
+        assert_eq!(action, Some(OutputLimitAction::RotateGzip { keep: 2 }));
+    }
+    let action = serde_json::to_value(OutputLimitAction::RotateGzip { keep: 2 })?;
+    assert_eq!(action, serde_json::json!({"RotateGzip": {"keep": 2}}));
+    Ok(())
+}
+// 0d6b8e27 ends here
+
+// [[file:../runners.note::9f4b1d63][9f4b1d63]]
+#[test]
+fn test_wrk_dir_hint() -> Result<()> {
+    assert!(resolve_wrk_dir_hint("../escaped".as_ref()).is_err());
+    assert!(resolve_wrk_dir_hint("/etc/gosh-runner-test".as_ref()).is_err());
+
+    let mut job = Job::new("#!/bin/sh");
+    job.wrk_dir_hint("target/gosh-runner-wrk-dir-hint");
+    let comp = job.submit()?;
+    let dir = comp.wrk_dir().to_owned();
+    assert!(dir.ends_with("target/gosh-runner-wrk-dir-hint"));
+    drop(comp);
+    // the directory is kept
+    assert!(dir.exists());
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_wrk_dir_hint_shared() -> Result<()> {
+    let mut db = Db::new();
+    let job = |hint: &str| {
+        let mut job = Job::new("#!/bin/sh");
+        job.wrk_dir_hint(hint);
+        job
+    };
+    let hint = "target/gosh-runner-wrk-dir-shared";
+    // normal users may not work in the scratch directory
+    assert!(db.try_insert_job_as(job(hint), &User::new("bob")).await.is_err());
+    let id = db.try_insert_job_as(job(hint), &User::admin("alice")).await?;
+    assert!(db.try_insert_job(job(hint)).await.is_err());
+    assert!(db.try_insert_job(job(&format!("{hint}/sub"))).await.is_err());
+    db.delete_job(id).await?;
+    std::fs::remove_dir_all(hint)?;
+    Ok(())
+}
+// 9f4b1d63 ends here
+
+// [[file:../runners.note::c81f4a2d][c81f4a2d]]
+#[test]
+fn test_run_as() -> Result<()> {
+    let mut job = Job::new("#!/bin/sh");
+    job.run_as("gosh-runner-no-such-user");
+    assert!(job.submit().is_err());
+
+    // no privileges needed for running as current user
+    let me = nix::unistd::User::from_uid(nix::unistd::geteuid())?.expect("current user");
+    let run_as = RunAs::resolve(&me.name)?;
+    assert!(run_as.is_current());
+    let tdir = tempfile::tempdir()?;
+    gut::fs::write_to_file(tdir.path().join("x"), "")?;
+    run_as.chown_recursive(tdir.path())?;
+    Ok(())
+}
+// c81f4a2d ends here
+
+// [[file:../runners.note::b2c5e8f1][b2c5e8f1]]
+#[test]
+fn test_stage_attachments() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let data = tdir.path().join("dataset");
+    std::fs::create_dir(&data)?;
+    gut::fs::write_to_file(data.join("a.txt"), "a")?;
+    let potcar = tdir.path().join("POTCAR");
+    gut::fs::write_to_file(&potcar, "PAW")?;
+
+    let mut job = Job::new("#!/bin/sh");
+    job.attach_local_file(&data, AttachMode::Symlink);
+    job.attach_local_file(&potcar, AttachMode::Hardlink);
+    let comp = job.submit()?;
+    comp.stage_attachments()?;
+    let wdir = comp.wrk_dir();
+    assert!(wdir.join("dataset").symlink_metadata()?.file_type().is_symlink());
+    assert_eq!(std::fs::read_to_string(wdir.join("dataset/a.txt"))?, "a");
+    assert_eq!(std::fs::read_to_string(wdir.join("POTCAR"))?, "PAW");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_attachments_admin_only() -> Result<()> {
+    let mut db = Db::new();
+    let mut job = Job::new("#!/bin/sh");
+    job.attach_local_file("/etc/shadow", AttachMode::Hardlink);
+    let e = db.try_insert_job_as(job, &User::new("bob")).await.unwrap_err();
+    assert!(e.to_string().contains("may not attach"));
+    Ok(())
+}
+// b2c5e8f1 ends here
+
+// [[file:../runners.note::e83d0a5c][e83d0a5c]]
+#[tokio::test]
+async fn test_exit_status_sentinel() -> Result<()> {
+    let mut comp = Job::new("#!/bin/sh\nexit 3").submit()?;
+    assert!(!comp.is_done());
+    comp.start().await?;
+    assert!(comp.wait_done(Some(std::time::Duration::from_secs(10)))?);
+    assert_eq!(comp.recorded_exit_status(), Some(3));
+    let stat = comp.run_stat()?;
+    assert_eq!(stat.exit_code, 3);
+    assert!(stat.end >= stat.start);
+    assert!(!comp.is_done());
+    comp.fake_done();
+    assert!(comp.is_done());
+    Ok(())
+}
+
+#[test]
+fn test_wrapper_script() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let wdir = tdir.path();
+    gut::fs::write_to_file(wdir.join(WRAPPER_FILE), WRAPPER_SCRIPT)?;
+    // burn some CPU time in children of the wrapper
+    let script = "i=0; while [ $i -lt 200000 ]; do i=$((i+1)); done; exit 2";
+    let status = std::process::Command::new("/bin/sh")
+        .args([WRAPPER_FILE, "/bin/sh", "-c", script])
+        .current_dir(wdir)
+        .status()?;
+    assert_eq!(status.code(), Some(2));
+    let stat = RunStat::from_json(&gut::fs::read_file(wdir.join("run.stat.json"))?)?;
+    assert_eq!(stat.exit_code, 2);
+    assert!(stat.end >= stat.start);
+    assert!(stat.user_time + stat.system_time > 0.0);
+    Ok(())
+}
+// e83d0a5c ends here
+
+// [[file:../runners.note::3a9c6f28][3a9c6f28]]
+/// Submit `job`, and run it until it ends.
+#[cfg(test)]
+async fn run_job(job: Job) -> Result<Computation> {
+    let mut comp = job.submit()?;
+    comp.start().await?;
+    comp.wait().await?;
+    Ok(comp)
+}
+
+#[tokio::test]
+async fn test_job_runs() -> Result<()> {
+    let job = |script: &str, setup: fn(&mut Job)| {
+        let mut job = Job::new(script);
+        setup(&mut job);
+        job
+    };
+    // job, expected status, and the last line of its stdout
+    let cases = [
+        (
+            job("import sys\nprint(sys.version_info.major)\n", |j| {
+                j.interpreter("python")
+            }),
+            JobStatus::Completed,
+            "3",
+        ),
+        (
+            job("echo $FOO", |j| {
+                j.interpreter("bash");
+                j.set_env("FOO", "bar");
+            }),
+            JobStatus::Completed,
+            "bar",
+        ),
+        (
+            job("#!/bin/sh\nread a\necho ready\nread b\nread c\necho $a $b $c\n", |j| {
+                j.set_input("1\n");
+                j.add_input_phase(InputTrigger::Output("^ready".into()), "2\n");
+                j.add_input_phase(InputTrigger::After(0.1), "3\n");
+            }),
+            JobStatus::Completed,
+            "1 2 3",
+        ),
+        (
+            // byte offsets of patterns matched in non-UTF-8 output
+            job(
+                "#!/bin/sh\nprintf '\\377\\376 ready\\n'\nread a\nprintf '\\377 go\\n'\nread b\necho $a $b\n",
+                |j| {
+                    j.add_input_phase(InputTrigger::Output("ready".into()), "1\n");
+                    j.add_input_phase(InputTrigger::Output("go".into()), "2\n");
+                },
+            ),
+            JobStatus::Completed,
+            "1 2",
+        ),
+        (
+            job("#!/bin/sh\necho done\n", |j| {
+                j.add_input_phase(InputTrigger::Output("^never".into()), "1\n");
+            }),
+            JobStatus::Completed,
+            "done",
+        ),
+        (
+            job("#!/bin/sh\necho 'Error termination'\n", |j| {
+                j.expect_output("job.out", Some("Normal termination"))
+            }),
+            JobStatus::Incomplete,
+            "Error termination",
+        ),
+        (
+            job("#!/bin/sh\necho 'Normal termination'\ntouch forces.dat\n", |j| {
+                j.expect_output("job.out", Some("Normal termination"));
+                j.expect_output("forces.dat", None);
+            }),
+            JobStatus::Completed,
+            "Normal termination",
+        ),
+        (job("#!/bin/sh\nexit 1\n", |_| {}), JobStatus::Failed, ""),
+    ];
+    for (job, status, last_line) in cases {
+        let mut comp = run_job(job).await?;
+        let out = String::from_utf8_lossy(&std::fs::read(comp.out_file())?).into_owned();
+        assert_eq!(comp.status(), status);
+        assert_eq!(out.lines().last().unwrap_or_default(), last_line);
+        assert_eq!(comp.exit_code == Some(0), status != JobStatus::Failed);
+        assert_eq!(comp.missing_outputs().is_empty(), status != JobStatus::Incomplete);
+        assert_eq!(comp.is_done(), status == JobStatus::Completed);
+        // not waiting for output after the job exits
+        assert!(comp.input_task.is_none());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_job_umask() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut job = Job::new("#!/bin/sh\nmkdir sub\ntouch out.dat sub/x\n");
+    job.umask(0o027);
+    job.group_sticky(true);
+    let comp = run_job(job).await?;
+    let mode = |p: &Path| p.metadata().unwrap().permissions().mode() & 0o7777;
+    assert_eq!(mode(&comp.wrk_dir().join("out.dat")), 0o640);
+    assert_eq!(mode(&comp.out_file()), 0o640);
+    assert_eq!(mode(&comp.wrk_dir().join("sub")) & 0o2000, 0o2000);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_job_keep_stdin_open() -> Result<()> {
+    let mut job = Job::new("#!/bin/sh\nread a\nread b\necho $a $b\n");
+    job.set_input("hello\n");
+    job.keep_stdin_open(true);
+    let mut comp = job.submit()?;
+    comp.start().await?;
+    comp.send_stdin(b"stop\n")?;
+    comp.wait().await?;
+    assert_eq!(std::fs::read_to_string(comp.out_file())?.trim(), "hello stop");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_job_record_usage() -> Result<()> {
+    let mut job = Job::new("#!/bin/sh\nsleep 1\nexit 1\n");
+    job.record_usage(0.2);
+    let mut comp = run_job(job).await?;
+    let csv = std::fs::read_to_string(comp.usage_file())?;
+    let mut lines = csv.lines();
+    assert_eq!(lines.next(), Some("time,nprocs,cpu_time,rss"));
+    assert!(lines.count() >= 2);
+    let result = comp.result(&[]);
+    // failed without OOM killing
+    assert_eq!(result.status, JobStatus::Failed);
+    assert!(result.peak_rss.unwrap_or(0) > 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_job_stray_files() -> Result<()> {
+    let name = format!("gosh-runner-stray-{}", std::process::id());
+    let stray = Path::new("/tmp").join(&name);
+    // only report, as other tests may create files in /tmp meanwhile, and
+    // never clean files of the server user
+    for action in [StrayFiles::Report, StrayFiles::Clean] {
+        let mut job = Job::new(&format!("#!/bin/sh\ntouch /tmp/{name}\n"));
+        job.track_stray_files(action);
+        let comp = run_job(job).await?;
+        assert!(comp.stray_files().contains(&stray));
+        assert!(gut::fs::read_file(comp.wrk_dir().join("stray-files.txt"))?.contains(&name));
+        assert!(stray.exists());
+        std::fs::remove_file(&stray)?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_job_scratch_vars() -> Result<()> {
+    let comp = run_job(Job::new("#!/bin/sh\necho $TMPDIR $GAUSS_SCRDIR\n")).await?;
+    let out = std::fs::read_to_string(comp.out_file())?;
+    let tmp = comp.wrk_dir().canonicalize()?.join("tmp");
+    let expected = format!("{} {}", tmp.display(), tmp.display());
+    assert_eq!(out.trim(), expected);
+    assert!(tmp.is_dir());
+    Ok(())
+}
+// 3a9c6f28 ends here
+
+// [[file:../runners.note::e61b8d2f][e61b8d2f]]
+#[tokio::test]
+async fn test_job_array() -> Result<()> {
+    assert_eq!(parse_array_range("1..100")?, 1..=100);
+    assert_eq!(parse_array_range("0-9")?, 0..=9);
+    assert!(parse_array_range("9..1").is_err());
+
+    let mut db = Db::new();
+    let mut job = Job::new("#!/bin/sh\necho $GOSH_ARRAY_INDEX\n");
+    job.array("1..3");
+    let (array, ids) = db.try_insert_job_array_as(job, &User::current()).await?;
+    assert_eq!(ids.len(), 3);
+    for (i, &id) in ids.iter().enumerate() {
+        db.wait_job(id).await?;
+        let out = db.get_job_file(id, "job.out".as_ref()).await?;
+        assert_eq!(String::from_utf8_lossy(&out).trim(), (i + 1).to_string());
+    }
+    let status = db.get_array_status(array).await?;
+    assert!(status.is_finished());
+    assert_eq!(status.counts["Completed"], 3);
+
+    db.delete_job_array(array).await?;
+    assert!(db.get_array_status(array).await.is_err());
+    Ok(())
+}
+// e61b8d2f ends here
+
+// [[file:../runners.note::a4f09b3e][a4f09b3e]]
+#[tokio::test]
+async fn test_wait_jobs() -> Result<()> {
+    let mut db = Db::new();
+    let fast = db.try_insert_job(Job::new("#!/bin/sh\nexit 1\n")).await?;
+    let slow = db.try_insert_job(Job::new("#!/bin/sh\nsleep 1\n")).await?;
+
+    let results = db.wait_jobs(&[slow, fast], WaitPolicy::FirstFailure).await?;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].0, fast);
+    assert_eq!(results[0].1.status, JobStatus::Failed);
+    // the slow job keeps running, and is not run again by later waits
+    assert_eq!(db.wait_job(slow).await?.status, JobStatus::Completed);
+    assert_eq!(db.wait_job(slow).await?.status, JobStatus::Completed);
+    assert_eq!(db.get_job_history(slow).await?.len(), 2);
+
+    let a = db.try_insert_job(Job::new("#!/bin/sh\nexit 1\n")).await?;
+    let b = db.try_insert_job(Job::new("#!/bin/sh\necho done\n")).await?;
+    let results = db.wait_jobs(&[a, b], WaitPolicy::All).await?;
+    assert_eq!(results.len(), 2);
+    Ok(())
+}
+// a4f09b3e ends here
+
+// [[file:../runners.note::5c2e8b71][5c2e8b71]]
+#[tokio::test]
+async fn test_job_preemption() -> Result<()> {
+    let node = crate::node::NodeInventory {
+        cpus: 1,
+        ..Default::default()
+    };
+    let mut db = Db::with_scheduler(Scheduler::from_node(node));
+    let resources = Resources {
+        cpus: 1,
+        ..Default::default()
+    };
+    let mut job = Job::new("#!/bin/sh\nsleep 1\n");
+    job.set_resources(resources.clone());
+    let low = db.try_insert_job(job).await?;
+    let mut job = Job::new("#!/bin/sh\nsleep 0.5\n");
+    job.set_resources(resources);
+    job.priority(10);
+    let high = db.try_insert_job(job).await?;
+
+    let db_ = db.clone();
+    let waiter = tokio::spawn(async move { db_.wait_job(low).await });
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    let db_ = db.clone();
+    let urgent = tokio::spawn(async move { db_.wait_job(high).await });
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert_eq!(db.get_job_status(low).await?, JobStatus::Paused);
+
+    assert_eq!(urgent.await??.status, JobStatus::Completed);
+    assert_eq!(waiter.await??.status, JobStatus::Completed);
+    let events: Vec<_> = db.get_job_history(low).await?.into_iter().map(|e| e.event).collect();
+    assert_eq!(
+        events,
+        ["started", "preempted by job 2", "resumed", "finished: Completed"]
+    );
+    Ok(())
+}
+// 5c2e8b71 ends here
+
+// [[file:../runners.note::b6d13e48][b6d13e48]]
+#[tokio::test]
+async fn test_job_timeout_paused() -> Result<()> {
+    let node = crate::node::NodeInventory {
+        cpus: 1,
+        ..Default::default()
+    };
+    let mut db = Db::with_scheduler(Scheduler::from_node(node));
+    let resources = Resources {
+        cpus: 1,
+        ..Default::default()
+    };
+    // runs about 1 second, only exceeding timeout if paused time counted
+    let mut job = Job::new("#!/bin/sh\nfor i in 1 2 3 4 5 6 7 8 9 10; do sleep 0.1; done\n");
+    job.set_resources(resources.clone());
+    job.set_timeout(1.5);
+    let low = db.try_insert_job(job).await?;
+    let urgent = || {
+        let mut job = Job::new("#!/bin/sh\nsleep 1\n");
+        job.set_resources(resources.clone());
+        job.priority(10);
+        job
+    };
+    // normal users may not preempt jobs of others
+    assert!(db.try_insert_job_as(urgent(), &User::new("bob")).await.is_err());
+    let high = db.try_insert_job(urgent()).await?;
+
+    let db_ = db.clone();
+    let waiter = tokio::spawn(async move { db_.wait_job(low).await });
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert_eq!(db.wait_job(high).await?.status, JobStatus::Completed);
+    assert_eq!(waiter.await??.status, JobStatus::Completed);
+    Ok(())
+}
+// b6d13e48 ends here
+
+// [[file:../runners.note::3d7a1f96][3d7a1f96]]
+#[test]
+fn test_job_file_path() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let wdir = tdir.path();
+    assert!(job_file_path(wdir, "job.out".as_ref()).is_ok());
+    assert!(job_file_path(wdir, "".as_ref()).is_ok());
+    assert!(job_file_path(wdir, "/etc/passwd".as_ref()).is_err());
+    assert!(job_file_path(wdir, "../x".as_ref()).is_err());
+    // escape by symlink created in work dir
+    std::os::unix::fs::symlink("/etc", wdir.join("etc"))?;
+    assert!(job_file_path(wdir, "etc/passwd".as_ref()).is_err());
+    Ok(())
+}
+// 3d7a1f96 ends here
+
+// [[file:../runners.note::8e4c2a7d][8e4c2a7d]]
+#[tokio::test]
+async fn test_job_hooks() -> Result<()> {
+    let mut db = Db::new();
+    let mut job = Job::new("#!/bin/sh\necho E = -1.5\n");
+    // compressed last, though listed first
+    job.add_hook(Hook::GzipOutputs);
+    job.add_hook(Hook::Extract {
+        name: "energy".into(),
+        pattern: r"E = (\S+)".into(),
+        file: "job.out".into(),
+    });
+    job.add_hook(Hook::Shell("test -f job.out".into()));
+    let id = db.try_insert_job(job).await?;
+    assert_eq!(db.wait_job(id).await?.status, JobStatus::Completed);
+    let meta = db.get_job_metadata(id).await?;
+    let outputs: Vec<_> = meta.hooks.iter().map(|h| (h.success, h.output.as_str())).collect();
+    assert_eq!(outputs[0], (true, "-1.5"));
+    assert!(outputs[1].0);
+    assert!(outputs[2].0);
+    Ok(())
+}
+// 8e4c2a7d ends here
+
+// [[file:../runners.note::c2f85e19][c2f85e19]]
+#[tokio::test]
+async fn test_job_gc() -> Result<()> {
+    let mut db = Db::new();
+    let a = db.try_insert_job(Job::new("#!/bin/sh\n")).await?;
+    let b = db.try_insert_job(Job::new("#!/bin/sh\n")).await?;
+    db.wait_job(a).await?;
+    db.wait_job(b).await?;
+    let removed = db.collect_garbage(&RetentionPolicy::default().keep_last(1)).await?;
+    assert_eq!(removed, [a]);
+    assert!(db.get_job_status(a).await.is_err());
+
+    // ids of removed jobs are never reused
+    let c = db.try_insert_job(Job::new("#!/bin/sh\n")).await?;
+    assert!(c > b);
+    assert!(db.get_job_status(a).await.is_err());
+    Ok(())
+}
+// c2f85e19 ends here
+
+// [[file:../runners.note::5a9d0c3b][5a9d0c3b]]
+#[tokio::test]
+async fn test_job_accounting() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let acct = Accounting::new(tdir.path().join("acct.csv"));
+    let mut db = Db::new().with_accounting(acct);
+    // burn some CPU time
+    let job = Job::new("#!/bin/sh\ni=0; while [ $i -lt 200000 ]; do i=$((i+1)); done\n");
+    let id = db.try_insert_job_as(job, &User::admin("alice")).await?;
+    db.wait_job(id).await?;
+    let records = db.search_jobs(&"user=alice".parse()?)?;
+    assert_eq!(records.len(), 1);
+    assert!(records[0].cpu_seconds > 0.0);
+    Ok(())
+}
+// 5a9d0c3b ends here
+
+// [[file:../runners.note::2e7b4f90][2e7b4f90]]
+#[tokio::test]
+async fn test_recover_jobs() -> Result<()> {
+    let tdir = tempfile::tempdir_in(".")?;
+    let dir_ = tdir.path().join("job1");
+    let mut job = Job::new("#!/bin/sh\nsleep 0.5\necho recovered\n");
+    // renaming the dir of a starting job races with its wrapper
+    job.wrk_dir_hint(&dir_);
+    let mut comp = job.submit()?;
+    comp.start().await?;
+    // leave the job running and its dir kept, as if the runner were killed
+    comp.session.as_mut().unwrap().detach();
+    drop(comp);
+
+    let mut db = Db::new();
+    let ids = db.recover_jobs(tdir.path()).await?;
+    assert_eq!(ids.len(), 1);
+    assert_eq!(db.wait_job(ids[0]).await?.status, JobStatus::Completed);
+    assert_eq!(std::fs::read_to_string(dir_.join("job.out"))?, "recovered\n");
+    // removed jobs are not recovered again
+    db.delete_job(ids[0]).await?;
+    assert!(db.recover_jobs(tdir.path()).await?.is_empty());
+    Ok(())
+}
+// 2e7b4f90 ends here
+
+// [[file:../runners.note::9a3e5c27][9a3e5c27]]
+#[tokio::test]
+async fn test_job_metadata_env() -> Result<()> {
+    std::env::set_var("GOSH_GATEWAY_TOKEN", "secret");
+    let mut db = Db::new();
+    let mut job = Job::new("#!/bin/sh\ntest -z \"$GOSH_GATEWAY_TOKEN\"\n");
+    job.set_env("FOO", "bar");
+    let id = db.try_insert_job(job).await?;
+    assert_eq!(db.wait_job(id).await?.status, JobStatus::Completed);
+    let meta = db.get_job_metadata(id).await?;
+    assert_eq!(meta.env["FOO"], "bar");
+    assert!(!meta.env.contains_key("GOSH_GATEWAY_TOKEN"));
+    Ok(())
+}
+// 9a3e5c27 ends here
+
+// [[file:../runners.note::3f6a9b12][3f6a9b12]]
+#[test]
+fn test_job_dry_run() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let potcar = tdir.path().join("POTCAR");
+    gut::fs::write_to_file(&potcar, "PAW")?;
+
+    let mut job = Job::new("#!/bin/sh\necho $FOO\n");
+    job.attach_local_file(&potcar, AttachMode::Copy);
+    job.set_env("FOO", "bar");
+    let wdir = job.dry_run()?;
+    // staged as a real run, without spawning the script
+    assert_eq!(std::fs::read_to_string(wdir.join("POTCAR"))?, "PAW");
+    assert!(wdir.join("tmp").is_dir());
+    assert!(std::fs::read_to_string(wdir.join("run"))?.contains("export FOO=bar"));
+    assert!(!wdir.join("job.out").exists());
+    std::fs::remove_dir_all(&wdir)?;
+    Ok(())
+}
+// 3f6a9b12 ends here
+
+// [[file:../runners.note::c7e4a0d5][c7e4a0d5]]
+#[test]
+fn test_job_progress() -> Result<()> {
+    let mut job = Job::new("#!/bin/sh");
+    job.set_timeout(f64::NAN);
+    assert!(job.submit().is_err());
+
+    let mut job = Job::new("#!/bin/sh");
+    job.set_timeout(60.0);
+    job.set_progress_marker("^step", 4);
+    let mut comp = job.submit()?;
+    comp.started = std::time::Instant::now().into();
+    gut::fs::write_to_file(comp.out_file(), "step 1\nstep 2\nst")?;
+    assert_eq!(comp.progress().markers, Some(2));
+    // only complete lines appended are scanned
+    gut::fs::write_to_file(comp.out_file(), "step 1\nstep 2\nstep 3\n")?;
+    assert_eq!(comp.progress().markers, Some(3));
+    assert_eq!(comp.progress().percent, Some(75.0));
+    Ok(())
+}
+// c7e4a0d5 ends here
+
+// [[file:../runners.note::d2a85f17][d2a85f17]]
+#[test]
+fn test_job_invalid_durations() -> Result<()> {
+    let mut job = Job::new("#!/bin/sh");
+    job.set_heartbeat(-1.0, 60.0);
+    assert!(job.submit().is_err());
+    let mut job = Job::new("#!/bin/sh");
+    job.set_heartbeat(1.0, f64::INFINITY);
+    assert!(job.submit().is_err());
+    let mut job = Job::new("#!/bin/sh");
+    job.record_usage(0.0);
+    assert!(job.submit().is_err());
+    let mut job = Job::new("#!/bin/sh");
+    job.add_input_phase(InputTrigger::After(f64::NAN), "stop\n");
+    assert!(job.submit().is_err());
+    Ok(())
+}
+// d2a85f17 ends here
+
+// [[file:../runners.note::6e1d9b3a][6e1d9b3a]]
+#[tokio::test]
+async fn test_job_pause_reasons() -> Result<()> {
+    let mut comp = Job::new("#!/bin/sh\nsleep 0.5\n").submit()?;
+    comp.start().await?;
+    assert!(comp.pause(PauseReason::Window)?);
+    assert!(comp.pause(PauseReason::Throttle)?);
+    assert!(!comp.pause(PauseReason::Throttle)?);
+    // still paused outside execution window after throttling is over
+    comp.resume(PauseReason::Throttle)?;
+    assert_eq!(comp.status(), JobStatus::Paused);
+    comp.resume(PauseReason::Window)?;
+    assert_eq!(comp.status(), JobStatus::Running);
+    comp.wait().await?;
+    assert_eq!(comp.status(), JobStatus::Completed);
+    Ok(())
+}
+// 6e1d9b3a ends here
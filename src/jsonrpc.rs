@@ -0,0 +1,962 @@
+// [[file:../runners.note::6a0e3f58][6a0e3f58]]
+//! JSON-RPC over stdio or TCP for driving the runner as a subprocess
+use super::*;
+
+use crate::audit::AuditEntry;
+use crate::auth::{User, Users};
+use crate::job::{Db, Job, JobId, QueueFull, ScratchFull, WaitPolicy};
+use crate::parser::JobResult;
+use crate::process::ProcessNode;
+use crate::validate::InvalidScript;
+use crate::ratelimit::RateLimiter;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+// 6a0e3f58 ends here
+
+// [[file:../runners.note::d2b97c41][d2b97c41]]
+/// A JSON-RPC 2.0 request.
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// A JSON-RPC error with `code` and `message`.
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Value>,
+}
+
+impl RpcError {
+    const PARSE_ERROR: i64 = -32700;
+    const METHOD_NOT_FOUND: i64 = -32601;
+    const INVALID_PARAMS: i64 = -32602;
+    const SERVER_ERROR: i64 = -32000;
+    /// Too many requests from the client, like HTTP 429
+    const RATE_LIMITED: i64 = -32029;
+    /// The server is saturated, like HTTP 503
+    const SERVER_BUSY: i64 = -32003;
+    /// No space left for job files, like HTTP 507
+    const STORAGE_FULL: i64 = -32007;
+
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+
+    /// Tell the client to retry after `secs` seconds.
+    fn retry_after(mut self, secs: f64) -> Self {
+        self.data = json!({ "retry_after": secs }).into();
+        self
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(e: Error) -> Self {
+        if e.is::<QueueFull>() || e.is::<ScratchFull>() {
+            return Self::new(Self::SERVER_BUSY, e).retry_after(10.0);
+        }
+        if let Some(invalid) = e.downcast_ref::<InvalidScript>() {
+            let mut err = Self::new(Self::INVALID_PARAMS, invalid);
+            err.data = json!({ "errors": invalid.errors }).into();
+            return err;
+        }
+        let no_space = e.chain().any(|cause| {
+            cause
+                .downcast_ref::<std::io::Error>()
+                .and_then(|e| e.raw_os_error())
+                .map_or(false, |code| code == libc::ENOSPC || code == libc::EDQUOT)
+        });
+        if no_space {
+            return Self::new(Self::STORAGE_FULL, format!("{:?}", e));
+        }
+        Self::new(Self::SERVER_ERROR, format!("{:?}", e))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JobParams {
+    id: JobId,
+}
+
+#[derive(Debug, Deserialize)]
+struct WaitJobsParams {
+    ids: Vec<JobId>,
+    #[serde(default)]
+    policy: WaitPolicy,
+}
+
+#[derive(Debug, Deserialize)]
+struct ArrayParams {
+    array: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ListParams {
+    /// Only list jobs having all the tags
+    #[serde(default)]
+    tags: std::collections::BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TagParams {
+    id: JobId,
+    tags: std::collections::BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchParams {
+    query: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AuditParams {
+    /// Only entries for the job
+    id: Option<JobId>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckpointParams {
+    id: JobId,
+    /// The directory for images inside working directory
+    #[serde(default = "default_checkpoint_dir")]
+    dir: PathBuf,
+}
+
+fn default_checkpoint_dir() -> PathBuf {
+    "checkpoint".into()
+}
+
+#[derive(Debug, Deserialize)]
+struct CloneParams {
+    id: JobId,
+    #[serde(default)]
+    files: Vec<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileParams {
+    id: JobId,
+    file: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeltaParams {
+    id: JobId,
+    file: String,
+    /// The signature or delta in base64
+    data: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct WaitFileParams {
+    id: JobId,
+    pattern: String,
+    /// Timeout in seconds, at most `MAX_WAIT_FILE_SECS`
+    timeout: Option<f64>,
+}
+
+/// The maximum time in seconds a `wait_file` request holds a blocking
+/// thread. Clients may wait again after it elapsed.
+const MAX_WAIT_FILE_SECS: f64 = 3600.0;
+
+#[derive(Debug, Deserialize)]
+struct StdinParams {
+    id: JobId,
+    #[serde(default)]
+    data: String,
+    /// Close stdin after sending data
+    #[serde(default)]
+    close: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct GetFileParams {
+    id: JobId,
+    file: PathBuf,
+    /// Read from the offset in bytes
+    #[serde(default)]
+    offset: u64,
+    /// The maximum number of bytes to read
+    length: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PutFileParams {
+    id: JobId,
+    file: String,
+    /// The file content in base64
+    data: String,
+    /// Write a chunk of the file at the offset in bytes. The whole file is
+    /// written if not set.
+    offset: Option<u64>,
+}
+
+/// The maximum size in bytes of file chunks read in one request.
+const FILE_CHUNK_SIZE: u64 = 1 << 20;
+
+#[derive(Debug, Deserialize)]
+struct CachedFileParams {
+    id: JobId,
+    file: String,
+    /// The SHA-256 hash of file content
+    hash: String,
+}
+
+/// Check if all child jobs of job `array` are accessible by `user`.
+async fn check_array_owner(db: &Db, array: u64, user: &User) -> Result<(), RpcError> {
+    // deleted child jobs are not counted
+    for id in db.get_array_status(array).await?.jobs {
+        db.check_job_owner(id, user).await?;
+    }
+    Ok(())
+}
+
+/// Decode binary `data` in base64 of params.
+fn decode_base64(data: &str) -> Result<Vec<u8>, RpcError> {
+    use base64::Engine;
+
+    let data = base64::engine::general_purpose::STANDARD.decode(data);
+    data.map_err(|e| RpcError::new(RpcError::INVALID_PARAMS, e))
+}
+
+/// Parse `params` for a method.
+fn params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(RpcError::INVALID_PARAMS, e))
+}
+
+/// Call `method` with `params` on jobs in `db` for `user`.
+async fn dispatch(db: &mut Db, user: &User, method: &str, p: Value) -> Result<Value, RpcError> {
+    let result = match method {
+        "submit" => {
+            // optional key for retrying submission without duplicates
+            let key = p.get("idempotency_key").and_then(|k| k.as_str()).map(|k| k.to_owned());
+            let job: Job = params(p)?;
+            let id = match key {
+                Some(key) => db.try_insert_job_idempotent(job, user, &key).await?,
+                None => db.try_insert_job_as(job, user).await?,
+            };
+            json!(id)
+        }
+        "submit_array" => {
+            let job: Job = params(p)?;
+            let (array, ids) = db.try_insert_job_array_as(job, user).await?;
+            json!({ "array": array, "jobs": ids })
+        }
+        "array_status" => {
+            let ArrayParams { array } = params(p)?;
+            check_array_owner(db, array, user).await?;
+            json!(db.get_array_status(array).await?)
+        }
+        "cancel_array" => {
+            let ArrayParams { array } = params(p)?;
+            check_array_owner(db, array, user).await?;
+            db.delete_job_array(array).await?;
+            Value::Null
+        }
+        "wait" => {
+            let JobParams { id } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            json!(db.wait_job(id).await?)
+        }
+        "wait_jobs" => {
+            let WaitJobsParams { ids, policy } = params(p)?;
+            for &id in &ids {
+                db.check_job_owner(id, user).await?;
+            }
+            let results = db.wait_jobs(&ids, policy).await?;
+            let results: Vec<_> = results
+                .into_iter()
+                .map(|(id, result)| json!({ "id": id, "result": result }))
+                .collect();
+            json!(results)
+        }
+        "status" => {
+            let JobParams { id } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            json!(db.get_job_status(id).await?)
+        }
+        "history" => {
+            let JobParams { id } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            json!(db.get_job_history(id).await?)
+        }
+        "usage" => {
+            let JobParams { id } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            json!(db.get_job_usage(id).await?)
+        }
+        "processes" => {
+            let JobParams { id } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            json!(db.get_job_processes(id).await?)
+        }
+        "checkpoint" => {
+            let CheckpointParams { id, dir } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            // never written outside working directory
+            let dir = db.get_job_file_path(id, &dir).await?;
+            db.checkpoint_job(id, &dir).await?;
+            json!(dir)
+        }
+        "progress" => {
+            let JobParams { id } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            json!(db.get_job_progress(id).await?)
+        }
+        "list_jobs" => {
+            let ListParams { tags } = if p.is_null() { ListParams::default() } else { params(p)? };
+            json!(db.get_job_list_tagged(user, &tags).await)
+        }
+        "tag" => {
+            let TagParams { id, tags } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            db.set_job_tags(id, &tags).await?;
+            Value::Null
+        }
+        "search" => {
+            let SearchParams { query } = params(p)?;
+            let query = query.parse().map_err(|e| RpcError::new(RpcError::INVALID_PARAMS, e))?;
+            let mut records = db.search_jobs(&query)?;
+            // jobs of other users are hidden for normal users
+            records.retain(|r| user.admin || r.user == user.name);
+            json!(records)
+        }
+        "audit" => {
+            let AuditParams { id } = if p.is_null() { AuditParams::default() } else { params(p)? };
+            let mut entries = db.get_audit(id).await?;
+            // operations of other users are hidden for normal users
+            entries.retain(|e| user.admin || e.who == user.name);
+            json!(entries)
+        }
+        "list_files" => {
+            let JobParams { id } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            json!(db.list_job_files(id).await?)
+        }
+        "file_stats" => {
+            let JobParams { id } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            json!(db.list_job_file_stats(id).await?)
+        }
+        "get_file" => {
+            use base64::Engine;
+
+            let GetFileParams {
+                id,
+                file,
+                offset,
+                length,
+            } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            let length = length.unwrap_or(FILE_CHUNK_SIZE).min(FILE_CHUNK_SIZE);
+            let (data, size, content_type) = db.read_job_file_chunk(id, &file, offset, length).await?;
+            // binary safe
+            let data = base64::engine::general_purpose::STANDARD.encode(data);
+            json!({"data": data, "offset": offset, "size": size, "content_type": content_type})
+        }
+        "wait_file" => {
+            let WaitFileParams { id, pattern, timeout } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            let timeout = match timeout.unwrap_or(MAX_WAIT_FILE_SECS) {
+                t if t >= 0.0 => std::time::Duration::from_secs_f64(t.min(MAX_WAIT_FILE_SECS)),
+                t => {
+                    let msg = format!("invalid timeout: {}", t);
+                    return Err(RpcError::new(RpcError::INVALID_PARAMS, msg));
+                }
+            };
+            json!(db.wait_job_file(id, &pattern, Some(timeout)).await?)
+        }
+        "stdin" => {
+            let StdinParams { id, data, close } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            db.send_job_stdin(id, data.as_bytes(), close).await?;
+            json!(null)
+        }
+        "put_file" => {
+            let PutFileParams { id, file, data, offset } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            let data = decode_base64(&data)?;
+            match offset {
+                Some(offset) => json!(db.put_job_file_chunk(id, file, offset, &data).await?),
+                // also added into the file cache if enabled
+                None => json!(db.put_job_file_stream(id, file, &data[..]).await?),
+            }
+        }
+        "file_signature" => {
+            use base64::Engine;
+
+            let FileParams { id, file } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            let sig = db.get_job_file_signature(id, &file).await?;
+            json!(base64::engine::general_purpose::STANDARD.encode(sig))
+        }
+        "file_delta" => {
+            use base64::Engine;
+
+            let DeltaParams { id, file, data } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            let sig = decode_base64(&data)?;
+            let delta = db.get_job_file_delta(id, file.as_ref(), sig.into()).await?;
+            json!(base64::engine::general_purpose::STANDARD.encode(delta))
+        }
+        "patch_file" => {
+            let DeltaParams { id, file, data } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            let delta = decode_base64(&data)?;
+            json!(db.patch_job_file(id, file, delta.into()).await?)
+        }
+        "link_cached_file" => {
+            let CachedFileParams { id, file, hash } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            json!(db.link_cached_job_file(id, file, &hash).await?)
+        }
+        "export" => {
+            let JobParams { id } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            json!(db.export_job(id).await?)
+        }
+        "clone" => {
+            let CloneParams { id, files } = params(p)?;
+            db.check_job_owner(id, user).await?;
+            json!(db.clone_job(id, &files).await?)
+        }
+        "delete" => {
+            let JobParams { id } = params(p)?;
+            db.delete_job_as(id, user).await?;
+            Value::Null
+        }
+        "clear" => {
+            db.clear_jobs_as(user).await?;
+            Value::Null
+        }
+        _ => return Err(RpcError::new(RpcError::METHOD_NOT_FOUND, format!("unknown method: {}", method))),
+    };
+    Ok(result)
+}
+
+/// Handle one line of JSON-RPC request from `user`.
+pub(crate) async fn handle_as(db: Db, user: &User, line: &str) -> Value {
+    // operations are audited as requested by the user
+    let mut db = db.as_user(user);
+    let (id, result) = match serde_json::from_str::<Request>(line) {
+        Ok(req) => {
+            debug!("jsonrpc request from {}: {:?}", user.name, req);
+            (req.id, dispatch(&mut db, user, &req.method, req.params).await)
+        }
+        Err(e) => (Value::Null, Err(RpcError::new(RpcError::PARSE_ERROR, e))),
+    };
+    match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(e) => error_response(id, e),
+    }
+}
+
+fn error_response(id: Value, e: RpcError) -> Value {
+    let mut error = json!({"code": e.code, "message": e.message});
+    if let Some(data) = e.data {
+        error["data"] = data;
+    }
+    json!({"jsonrpc": "2.0", "id": id, "error": error})
+}
+
+/// Serve JSON-RPC requests on stdin, one request per line, writing
+/// responses to stdout. Requests are handled concurrently, so long-running
+/// `wait` calls do not block status queries.
+pub async fn serve_stdio(db: Db) -> Result<()> {
+    serve_lines(db, User::current(), None, tokio::io::stdin(), tokio::io::stdout()).await
+}
+
+/// Serve JSON-RPC requests from TCP clients, one request per line as on
+/// stdio.
+#[derive(Default)]
+pub struct TcpServer {
+    users: Option<Users>,
+    limiter: Option<RateLimiter>,
+}
+
+impl TcpServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require clients to send their token as the first line before any
+    /// request. Each user can only access own jobs unless admin.
+    pub fn users(mut self, users: Users) -> Self {
+        self.users = users.into();
+        self
+    }
+
+    /// Allow each client `rate` requests per second on average, with
+    /// bursts up to `burst` requests. Clients are identified by user name
+    /// if authenticated, or by IP address otherwise.
+    pub fn rate_limit(mut self, rate: f64, burst: usize) -> Self {
+        self.limiter = RateLimiter::new(rate, burst).into();
+        self
+    }
+
+    /// Serve clients accepted on `listener`. Without users set, clients
+    /// are not authenticated and only allowed on loopback address, each
+    /// as a normal user named by its address.
+    pub async fn serve(self, db: Db, listener: tokio::net::TcpListener) -> Result<()> {
+        let addr = listener.local_addr()?;
+        ensure!(
+            self.users.is_some() || addr.ip().is_loopback(),
+            "refuse to serve on {} without authentication",
+            addr
+        );
+        info!("serving JSON-RPC on {}", addr);
+        let users = self.users.map(std::sync::Arc::new);
+        let limiter = self.limiter.map(std::sync::Arc::new);
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            debug!("accepted connection from {}", peer);
+            let db = db.clone();
+            let users = users.clone();
+            let limiter = limiter.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut reader = tokio::io::BufReader::new(reader);
+                let (user, client) = match users {
+                    Some(users) => match authenticate(&users, &mut reader, &mut writer, peer).await {
+                        Some(user) => {
+                            let client = user.name.clone();
+                            (user, client)
+                        }
+                        None => return,
+                    },
+                    None => {
+                        let client = peer.ip().to_string();
+                        (User::new(&client), client)
+                    }
+                };
+                let limit = limiter.map(|l| (l, client));
+                if let Err(e) = serve_lines(db, user, limit, reader, writer).await {
+                    warn!("connection from {} failed: {:?}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+/// Read the token line sent by the client connected from `peer`, returning
+/// the user authenticated by `users`, or None if rejected.
+pub(crate) async fn authenticate<R, W>(
+    users: &Users,
+    reader: &mut R,
+    writer: &mut W,
+    peer: std::net::SocketAddr,
+) -> Option<User>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+
+    // do not wait forever for the token, or read a huge line
+    let mut line = String::new();
+    let mut limited = (&mut *reader).take(MAX_TOKEN_LEN);
+    let read = limited.read_line(&mut line);
+    if !matches!(tokio::time::timeout(AUTH_TIMEOUT, read).await, Ok(Ok(_))) {
+        warn!("rejected connection from {}: no token received", peer);
+        return None;
+    }
+    let user = users.authenticate(line.trim());
+    if user.is_none() {
+        warn!("rejected connection from {}: invalid token", peer);
+        let _ = writer.write_all(b"invalid token\n").await;
+    }
+    user
+}
+
+/// The maximum length of the token line sent by a client.
+const MAX_TOKEN_LEN: u64 = 4096;
+
+/// The time for a client to send its token after connected.
+const AUTH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The maximum number of requests handled concurrently for a connection.
+/// Reading stops when reached, pushing back on the client.
+const MAX_INFLIGHT: usize = 64;
+
+/// Serve requests from `reader` for `user`, rate limited by `limit` for
+/// the client if set.
+async fn serve_lines<R, W>(
+    db: Db,
+    user: User,
+    limit: Option<(std::sync::Arc<RateLimiter>, String)>,
+    reader: R,
+    writer: W,
+) -> Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let handler = move |line: String| {
+        let (db, user) = (db.clone(), user.clone());
+        async move { handle_as(db, &user, &line).await }
+    };
+    serve_requests(handler, limit, reader, writer).await
+}
+
+/// Serve requests from `reader` by `handler`, one request per line and
+/// handled concurrently, rate limited by `limit` for the client if set.
+pub(crate) async fn serve_requests<R, W, F, Fut>(
+    handler: F,
+    limit: Option<(std::sync::Arc<RateLimiter>, String)>,
+    reader: R,
+    mut writer: W,
+) -> Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin + Send + 'static,
+    F: Fn(String) -> Fut,
+    Fut: std::future::Future<Output = Value> + Send + 'static,
+{
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Value>(16);
+    let writer = tokio::spawn(async move {
+        while let Some(resp) = rx.recv().await {
+            writer.write_all(format!("{}\n", resp).as_bytes()).await?;
+            writer.flush().await?;
+        }
+        Result::<()>::Ok(())
+    });
+
+    let inflight = std::sync::Arc::new(tokio::sync::Semaphore::new(MAX_INFLIGHT));
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some((limiter, client)) = &limit {
+            if let Err(retry) = limiter.check(client) {
+                let id = serde_json::from_str::<Request>(&line).map(|r| r.id).unwrap_or_default();
+                let e = RpcError::new(RpcError::RATE_LIMITED, "too many requests").retry_after(retry.as_secs_f64());
+                tx.send(error_response(id, e)).await?;
+                continue;
+            }
+        }
+        let permit = inflight.clone().acquire_owned().await?;
+        let tx = tx.clone();
+        let resp = handler(line);
+        tokio::spawn(async move {
+            let resp = resp.await;
+            let _ = tx.send(resp).await;
+            drop(permit);
+        });
+    }
+    drop(tx);
+    writer.await??;
+    Ok(())
+}
+// d2b97c41 ends here
+
+// [[file:../runners.note::e4a81c6d][e4a81c6d]]
+/// Call `method` with `params` on the server at `endpoint` over TCP,
+/// returning the result.
+pub fn call(endpoint: &crate::discovery::Endpoint, method: &str, params: Value) -> Result<Value> {
+    use std::io::BufRead;
+
+    let stream = std::net::TcpStream::connect(&endpoint.address)
+        .with_context(|| format!("connect to server at {}", endpoint.address))?;
+    let mut writer = stream.try_clone()?;
+    let req = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+    writeln!(writer, "{}", endpoint.token)?;
+    writeln!(writer, "{}", req)?;
+
+    let mut line = String::new();
+    std::io::BufReader::new(stream).read_line(&mut line)?;
+    ensure!(!line.is_empty(), "connection closed by server");
+    let mut resp: Value = serde_json::from_str(&line).with_context(|| format!("invalid response: {:?}", line))?;
+    if let Some(error) = resp.get("error") {
+        bail!("server error: {}", error["message"]);
+    }
+    Ok(resp["result"].take())
+}
+
+/// A client of the JSON-RPC server over TCP. Each call is made in a new
+/// connection, so that long waits do not hold up other calls.
+#[derive(Debug, Clone)]
+pub struct RpcClient {
+    endpoint: crate::discovery::Endpoint,
+}
+
+impl RpcClient {
+    /// Create a client for the server at `endpoint`.
+    pub fn new(endpoint: crate::discovery::Endpoint) -> Self {
+        Self { endpoint }
+    }
+
+    /// Return the endpoint of the server.
+    pub fn endpoint(&self) -> &crate::discovery::Endpoint {
+        &self.endpoint
+    }
+
+    /// Call `method` with `params`, returning the result of type `T`.
+    pub(crate) fn call<T: serde::de::DeserializeOwned>(&self, method: &str, params: Value) -> Result<T> {
+        let result = call(&self.endpoint, method, params)?;
+        let result = serde_json::from_value(result).with_context(|| format!("invalid result of {}", method))?;
+        Ok(result)
+    }
+
+    /// Wait until all jobs `ids` are done in a single request, returning
+    /// their results in the order they finished.
+    pub fn wait_all(&self, ids: &[JobId]) -> Result<Vec<(JobId, JobResult)>> {
+        self.wait_jobs(ids, WaitPolicy::All)
+    }
+
+    /// Wait for jobs `ids` according to `policy` in a single request,
+    /// returning results of finished jobs in the order they finished.
+    pub fn wait_jobs(&self, ids: &[JobId], policy: WaitPolicy) -> Result<Vec<(JobId, JobResult)>> {
+        #[derive(Deserialize)]
+        struct Finished {
+            id: JobId,
+            result: JobResult,
+        }
+
+        let finished: Vec<Finished> = self.call("wait_jobs", json!({ "ids": ids, "policy": policy }))?;
+        Ok(finished.into_iter().map(|f| (f.id, f.result)).collect())
+    }
+
+    /// Return a snapshot of the process tree of running job `id`.
+    pub fn get_job_processes(&self, id: JobId) -> Result<Vec<ProcessNode>> {
+        self.call("processes", json!({ "id": id }))
+    }
+
+    /// Checkpoint running job `id` using CRIU into directory `dir` inside
+    /// its working directory, returning the full path. The job is
+    /// terminated after dumping.
+    pub fn checkpoint_job(&self, id: JobId, dir: &str) -> Result<PathBuf> {
+        self.call("checkpoint", json!({ "id": id, "dir": dir }))
+    }
+
+    /// Return audit log entries of operations on jobs, optionally only for
+    /// job `id`. Normal users only see their own operations.
+    pub fn get_audit(&self, id: Option<JobId>) -> Result<Vec<AuditEntry>> {
+        self.call("audit", json!({ "id": id }))
+    }
+
+    /// Download `file` in working directory of job `id` into `writer` in
+    /// chunks, returning the number of bytes.
+    pub fn download_job_file(&self, id: JobId, file: &Path, mut writer: impl std::io::Write) -> Result<u64> {
+        use base64::Engine;
+
+        #[derive(Deserialize)]
+        struct Chunk {
+            data: String,
+            size: u64,
+        }
+
+        let mut offset = 0;
+        loop {
+            let chunk: Chunk = self.call("get_file", json!({ "id": id, "file": file, "offset": offset }))?;
+            let data = base64::engine::general_purpose::STANDARD.decode(chunk.data)?;
+            writer.write_all(&data)?;
+            offset += data.len() as u64;
+            if data.is_empty() || offset >= chunk.size {
+                break;
+            }
+        }
+        writer.flush()?;
+        Ok(offset)
+    }
+
+    /// Return the signature of `file` in working directory of job `id` for
+    /// uploading only changed blocks.
+    pub fn get_job_file_signature(&self, id: JobId, file: &Path) -> Result<Vec<u8>> {
+        use base64::Engine;
+
+        let sig: String = self.call("file_signature", json!({ "id": id, "file": file }))?;
+        Ok(base64::engine::general_purpose::STANDARD.decode(sig)?)
+    }
+
+    /// Return the delta turning the file with `signature` into `file` in
+    /// working directory of job `id`, for downloading only changed blocks.
+    pub fn get_job_file_delta(&self, id: JobId, file: &Path, signature: &[u8]) -> Result<Vec<u8>> {
+        use base64::Engine;
+
+        let data = base64::engine::general_purpose::STANDARD.encode(signature);
+        let delta: String = self.call("file_delta", json!({ "id": id, "file": file, "data": data }))?;
+        Ok(base64::engine::general_purpose::STANDARD.decode(delta)?)
+    }
+
+    /// Update `file` in working directory of job `id` by applying `delta`
+    /// computed against its signature. Return the new size of the file.
+    pub fn patch_job_file(&self, id: JobId, file: &Path, delta: &[u8]) -> Result<u64> {
+        use base64::Engine;
+
+        let data = base64::engine::general_purpose::STANDARD.encode(delta);
+        self.call("patch_file", json!({ "id": id, "file": file, "data": data }))
+    }
+
+    /// Upload content of `reader` as `file` in working directory of job
+    /// `id` in chunks, returning the number of bytes.
+    pub fn upload_job_file(&self, id: JobId, file: &str, mut reader: impl std::io::Read) -> Result<u64> {
+        use base64::Engine;
+        use std::io::Read;
+
+        let mut offset = 0;
+        loop {
+            let mut data = vec![];
+            (&mut reader).take(FILE_CHUNK_SIZE).read_to_end(&mut data)?;
+            let n = data.len() as u64;
+            let data = base64::engine::general_purpose::STANDARD.encode(data);
+            let params = json!({ "id": id, "file": file, "data": data, "offset": offset });
+            offset = self.call("put_file", params)?;
+            if n < FILE_CHUNK_SIZE {
+                break;
+            }
+        }
+        Ok(offset)
+    }
+}
+// e4a81c6d ends here
+
+// [[file:../runners.note::03f6b9ea][03f6b9ea]]
+#[tokio::test]
+async fn test_jsonrpc_dispatch() -> Result<()> {
+    // requests from the user running current process
+    async fn handle(db: Db, line: &str) -> Value {
+        handle_as(db, &User::current(), line).await
+    }
+
+    let db = Db::new();
+    let resp = handle(db.clone(), r#"{"jsonrpc": "2.0", "id": 1, "method": "list_jobs"}"#).await;
+    assert_eq!(resp["result"], json!([]));
+
+    let resp = handle(db.clone(), r#"{"jsonrpc": "2.0", "id": 2, "method": "foo"}"#).await;
+    assert_eq!(resp["error"]["code"], json!(RpcError::METHOD_NOT_FOUND));
+
+    let resp = handle(db.clone(), "not json").await;
+    assert_eq!(resp["error"]["code"], json!(RpcError::PARSE_ERROR));
+
+    // retried submission with the same key
+    let req = r##"{"jsonrpc": "2.0", "id": 3, "method": "submit", "params": {"script": "#!/bin/sh", "idempotency_key": "k1"}}"##;
+    let id1 = handle(db.clone(), req).await["result"].clone();
+    let id2 = handle(db.clone(), req).await["result"].clone();
+    assert!(!id1.is_null());
+    assert_eq!(id1, id2);
+
+    // upload a file in base64
+    let req = json!({"jsonrpc": "2.0", "id": 7, "method": "put_file", "params": {"id": id1, "file": "a.txt", "data": "aGVsbG8="}});
+    assert_eq!(handle(db.clone(), &req.to_string()).await["result"], json!(5));
+    // append a binary chunk, and read it back
+    let req = json!({"jsonrpc": "2.0", "id": 7, "method": "put_file", "params": {"id": id1, "file": "a.txt", "data": "/wA=", "offset": 5}});
+    assert_eq!(handle(db.clone(), &req.to_string()).await["result"], json!(7));
+    let req =
+        json!({"jsonrpc": "2.0", "id": 8, "method": "get_file", "params": {"id": id1, "file": "a.txt", "offset": 4}});
+    let resp = handle(db.clone(), &req.to_string()).await;
+    assert_eq!(resp["result"]["data"], json!("b/8A"));
+    assert_eq!(resp["result"]["size"], json!(7));
+
+    // update the file by delta against its signature
+    use base64::Engine;
+    let b64 = base64::engine::general_purpose::STANDARD;
+    let req = json!({"jsonrpc": "2.0", "id": 9, "method": "file_signature", "params": {"id": id1, "file": "a.txt"}});
+    let sig = b64.decode(handle(db.clone(), &req.to_string()).await["result"].as_str().unwrap())?;
+    let tdir = tempfile::tempdir()?;
+    let new = tdir.path().join("a.txt");
+    std::fs::write(&new, "hello world")?;
+    let delta = b64.encode(crate::delta::delta_file(&sig, &new)?);
+    let req = json!({"jsonrpc": "2.0", "id": 10, "method": "patch_file", "params": {"id": id1, "file": "a.txt", "data": delta}});
+    assert_eq!(handle(db.clone(), &req.to_string()).await["result"], json!(11));
+
+    // no processes before started
+    let req = json!({"jsonrpc": "2.0", "id": 8, "method": "processes", "params": {"id": id1}});
+    assert!(handle(db.clone(), &req.to_string()).await["error"].is_object());
+    let req = json!({"jsonrpc": "2.0", "id": 9, "method": "checkpoint", "params": {"id": id1, "dir": "../x"}});
+    assert!(handle(db.clone(), &req.to_string()).await["error"].is_object());
+
+    // wait for a file already there, and reject invalid timeout
+    let req = json!({"jsonrpc": "2.0", "id": 11, "method": "wait_file", "params": {"id": id1, "pattern": "a.*"}});
+    assert_eq!(handle(db.clone(), &req.to_string()).await["result"], json!("a.txt"));
+    let params = json!({"id": id1, "pattern": "b.*", "timeout": -1.0});
+    let req = json!({"jsonrpc": "2.0", "id": 12, "method": "wait_file", "params": params});
+    let resp = handle(db.clone(), &req.to_string()).await;
+    assert_eq!(resp["error"]["code"], json!(RpcError::INVALID_PARAMS));
+
+    // tag the job later, and filter by tags
+    let req = json!({"jsonrpc": "2.0", "id": 4, "method": "tag", "params": {"id": id1, "tags": {"project": "perovskites"}}});
+    let resp = handle(db.clone(), &req.to_string()).await;
+    assert_eq!(resp["result"], Value::Null);
+    let req = r#"{"jsonrpc": "2.0", "id": 5, "method": "list_jobs", "params": {"tags": {"project": "perovskites"}}}"#;
+    assert_eq!(handle(db.clone(), req).await["result"], json!([id1]));
+    let req = r#"{"jsonrpc": "2.0", "id": 6, "method": "list_jobs", "params": {"tags": {"project": "other"}}}"#;
+    assert_eq!(handle(db.clone(), req).await["result"], json!([]));
+    Ok(())
+}
+// 03f6b9ea ends here
+
+// [[file:../runners.note::7b3e91d4][7b3e91d4]]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_rpc_client() -> Result<()> {
+    use crate::discovery::Endpoint;
+    use crate::job::JobStatus;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let endpoint = Endpoint::new(&listener.local_addr()?.to_string())?;
+    let mut users = Users::default();
+    users.insert(&endpoint.token, User::current());
+    tokio::spawn(TcpServer::new().users(users).serve(Db::new(), listener));
+
+    let client = RpcClient::new(endpoint);
+    let results = tokio::task::spawn_blocking(move || {
+        let a: JobId = client.call("submit", json!(Job::new("#!/bin/sh\nexit 1\n")))?;
+        let b: JobId = client.call("submit", json!(Job::new("#!/bin/sh\necho done\n")))?;
+        client.wait_all(&[a, b])
+    })
+    .await??;
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().any(|(_, r)| r.status == JobStatus::Failed));
+    assert!(results.iter().any(|(_, r)| r.status == JobStatus::Completed));
+    Ok(())
+}
+// 7b3e91d4 ends here
+
+// [[file:../runners.note::e90c5b2f][e90c5b2f]]
+#[tokio::test]
+async fn test_jsonrpc_search() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let mut db = Db::new().with_accounting(crate::acct::Accounting::new(tdir.path().join("acct.csv")));
+    for name in ["alice", "bob"] {
+        let id = db.try_insert_job_as(Job::new("#!/bin/sh\n"), &User::admin(name)).await?;
+        db.wait_job(id).await?;
+    }
+    // normal users only find their own jobs
+    let req = r#"{"jsonrpc": "2.0", "id": 1, "method": "search", "params": {"query": "status=completed"}}"#;
+    let resp = handle_as(db.clone(), &User::new("alice"), req).await;
+    let users: Vec<_> = resp["result"].as_array().unwrap().iter().map(|r| r["user"].clone()).collect();
+    assert_eq!(users, [json!("alice")]);
+    let resp = handle_as(db.clone(), &User::admin("root"), req).await;
+    assert_eq!(resp["result"].as_array().unwrap().len(), 2);
+    Ok(())
+}
+// e90c5b2f ends here
+
+// [[file:../runners.note::41d8a6e3][41d8a6e3]]
+#[tokio::test]
+async fn test_jsonrpc_audit() -> Result<()> {
+    let tdir = tempfile::tempdir()?;
+    let db = Db::new().with_audit(crate::audit::AuditLog::new(tdir.path().join("audit.log")));
+    let req = r##"{"jsonrpc": "2.0", "id": 1, "method": "submit", "params": {"script": "#!/bin/sh"}}"##;
+    let id = handle_as(db.clone(), &User::admin("alice"), req).await["result"].clone();
+    assert!(!id.is_null());
+
+    // recorded as the authenticated user
+    let req = r#"{"jsonrpc": "2.0", "id": 2, "method": "audit"}"#;
+    let resp = handle_as(db.clone(), &User::admin("alice"), req).await;
+    assert_eq!(resp["result"][0]["who"], json!("alice"));
+    assert_eq!(resp["result"][0]["job"], id);
+    let resp = handle_as(db.clone(), &User::new("bob"), req).await;
+    assert_eq!(resp["result"], json!([]));
+    Ok(())
+}
+// 41d8a6e3 ends here
@@ -13,13 +13,48 @@ fn timestamp_now() -> String {
 // 16bab924 ends here
 
 // [[file:../runners.note::9fd14bf8][9fd14bf8]]
+pub mod acct;
+pub mod adaptor;
+pub mod audit;
+pub mod auth;
+pub mod backend;
+pub mod cache;
 pub mod cli;
+pub mod client;
+pub mod delta;
+pub mod discovery;
+pub mod failure;
+pub mod federation;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hooks;
 pub mod interactive;
 pub mod job;
+pub mod jsonrpc;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod nailgun;
+pub mod node;
+pub mod notify;
+pub mod parser;
 pub mod process;
+pub mod ratelimit;
+pub mod retention;
+pub mod runner;
+pub mod scheduler;
+pub mod session;
+pub mod signals;
+pub mod spool;
 pub mod stop;
-
-mod session;
+pub mod templates;
+pub mod throttle;
+pub mod validate;
+pub mod watch;
+pub mod window;
+#[cfg(feature = "zmq")]
+pub mod zmq_server;
 
 /// Some extension traits
 pub mod prelude {
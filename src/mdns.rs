@@ -0,0 +1,78 @@
+// [[file:../runners.note::7a3c1e58][7a3c1e58]]
+//! mDNS announcement and discovery of job servers on the LAN
+use super::*;
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::time::{Duration, Instant};
+// 7a3c1e58 ends here
+
+// [[file:../runners.note::c81f4d26][c81f4d26]]
+/// The mDNS service type of job servers
+pub const SERVICE_TYPE: &str = "_gosh-runner._tcp.local.";
+
+/// A job server found on the LAN.
+#[derive(Debug, Clone)]
+pub struct DiscoveredServer {
+    /// The instance name of the server, defaults to its host name
+    pub name: String,
+    /// The server address as `ip:port`
+    pub address: String,
+}
+
+/// Announce a job server on `port` with mDNS. The announcement is withdrawn
+/// when the returned value is dropped.
+pub struct Announcement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Announcement {
+    /// Announce the server listening on `port` with instance `name`.
+    pub fn new(name: &str, port: u16) -> Result<Self> {
+        let daemon = ServiceDaemon::new()?;
+        let host_name = format!("{}.local.", name);
+        let properties = [("version", env!("CARGO_PKG_VERSION"))];
+        let info = ServiceInfo::new(SERVICE_TYPE, name, &host_name, "", port, &properties[..])?.enable_addr_auto();
+        let fullname = info.get_fullname().to_owned();
+        daemon.register(info)?;
+        info!("announced {} on port {}", fullname, port);
+        Ok(Self { daemon, fullname })
+    }
+}
+
+impl Drop for Announcement {
+    fn drop(&mut self) {
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Browse job servers on the LAN for `timeout`.
+pub fn discover(timeout: Duration) -> Result<Vec<DiscoveredServer>> {
+    let daemon = ServiceDaemon::new()?;
+    let receiver = daemon.browse(SERVICE_TYPE)?;
+    let deadline = Instant::now() + timeout;
+    let mut servers: Vec<DiscoveredServer> = vec![];
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        match receiver.recv_timeout(remaining) {
+            Ok(ServiceEvent::ServiceResolved(info)) => {
+                let name = info.get_fullname().trim_end_matches(SERVICE_TYPE).trim_end_matches('.');
+                for ip in info.get_addresses() {
+                    let address = format!("{}:{}", ip, info.get_port());
+                    if !servers.iter().any(|s| s.address == address) {
+                        debug!("found server {} at {}", name, address);
+                        servers.push(DiscoveredServer {
+                            name: name.to_owned(),
+                            address,
+                        });
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    let _ = daemon.shutdown();
+    Ok(servers)
+}
+// c81f4d26 ends here
@@ -0,0 +1,147 @@
+// [[file:../runners.note::c4a81e6f][c4a81e6f]]
+//! Consume jobs from a MQTT broker and publish results
+use super::*;
+
+use crate::job::{Db, Job, JobStatus};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use std::time::Duration;
+// c4a81e6f ends here
+
+// [[file:../runners.note::7fd2093b][7fd2093b]]
+/// A job message pulled from the queue.
+#[derive(Debug, Deserialize)]
+pub struct JobMessage {
+    /// A name chosen by the publisher for identifying results. It is a
+    /// single topic level, so wildcards and `/` are not allowed.
+    pub name: String,
+    pub job: Job,
+}
+
+/// The result of a job published to the queue.
+#[derive(Debug, Serialize)]
+pub struct JobResult {
+    pub name: String,
+    pub status: JobStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Options for connecting to the MQTT broker.
+#[derive(Debug, Clone)]
+pub struct MqttConsumer {
+    host: String,
+    port: u16,
+    client_id: String,
+    /// Topic prefix: jobs are pulled from `{prefix}/jobs`, and status and
+    /// results are published to `{prefix}/status/{name}` and
+    /// `{prefix}/results/{name}`.
+    prefix: String,
+}
+
+impl MqttConsumer {
+    /// Consume jobs from broker at `host:port` with topic `prefix`.
+    pub fn new(host: &str, port: u16, prefix: &str) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            client_id: format!("gosh-runner-{}", std::process::id()),
+            prefix: prefix.trim_end_matches('/').into(),
+        }
+    }
+
+    /// Subscribe to the job topic, and execute jobs in `db` as they come.
+    pub async fn run(&self, db: Db) -> Result<()> {
+        let mut opts = MqttOptions::new(&self.client_id, &self.host, self.port);
+        opts.set_keep_alive(Duration::from_secs(30));
+        let (client, mut eventloop) = AsyncClient::new(opts, 16);
+        let topic = format!("{}/jobs", self.prefix);
+        client.subscribe(&topic, QoS::AtLeastOnce).await?;
+        info!("consuming jobs from mqtt://{}:{}/{}", self.host, self.port, topic);
+
+        loop {
+            let event = eventloop.poll().await?;
+            if let Event::Incoming(Packet::Publish(msg)) = event {
+                let msg: JobMessage = match serde_json::from_slice(&msg.payload) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        warn!("invalid job message: {:?}", e);
+                        continue;
+                    }
+                };
+                let client = client.clone();
+                let prefix = self.prefix.clone();
+                let db = db.clone();
+                tokio::spawn(async move {
+                    let name = msg.name.clone();
+                    if let Err(e) = execute(db, client, &prefix, msg).await {
+                        warn!("job {} from mqtt failed: {:?}", name, e);
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Check that job `name` fits in one topic level, so it can not publish
+/// to the topics of other jobs.
+fn check_name(name: &str) -> Result<()> {
+    ensure!(
+        !name.is_empty() && !name.contains(['/', '+', '#', '\0']),
+        "invalid job name for mqtt topic: {name:?}"
+    );
+    Ok(())
+}
+
+/// Execute job in `msg`, publishing its status and result.
+async fn execute(mut db: Db, client: AsyncClient, prefix: &str, msg: JobMessage) -> Result<()> {
+    let JobMessage { name, job } = msg;
+    check_name(&name)?;
+    let status_topic = format!("{}/status/{}", prefix, name);
+    let (out_file, err_file) = (job.out_file().to_owned(), job.err_file().to_owned());
+
+    let id = db.try_insert_job(job).await?;
+    let publish_status = |status: JobStatus| {
+        let client = client.clone();
+        let topic = status_topic.clone();
+        async move {
+            let payload = serde_json::to_vec(&status)?;
+            client.publish(topic, QoS::AtLeastOnce, true, payload).await?;
+            Result::<()>::Ok(())
+        }
+    };
+    publish_status(JobStatus::Running).await?;
+    if let Err(e) = db.wait_job(id).await {
+        warn!("job {} failed: {:?}", name, e);
+    }
+    let status = db.get_job_status(id).await?;
+    publish_status(status).await?;
+
+    let read = |content: Result<Vec<u8>>| {
+        content
+            .map(|x| String::from_utf8_lossy(&x).into_owned())
+            .unwrap_or_default()
+    };
+    let result = JobResult {
+        name: name.clone(),
+        status,
+        stdout: read(db.get_job_file(id, &out_file).await),
+        stderr: read(db.get_job_file(id, &err_file).await),
+    };
+    let topic = format!("{}/results/{}", prefix, name);
+    client
+        .publish(topic, QoS::AtLeastOnce, false, serde_json::to_vec(&result)?)
+        .await?;
+    db.delete_job(id).await?;
+    Ok(())
+}
+// 7fd2093b ends here
+
+// [[file:../runners.note::9b3e5f27][9b3e5f27]]
+#[test]
+fn test_mqtt_check_name() {
+    assert!(check_name("h2o-opt").is_ok());
+    for name in ["", "a/b", "+", "x#", "a\0b"] {
+        assert!(check_name(name).is_err());
+    }
+}
+// 9b3e5f27 ends here
@@ -0,0 +1,466 @@
+// [[file:../runners.note::3b8e5a1d][3b8e5a1d]]
+//! Nailgun protocol for fast repeated program startup
+use super::*;
+
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+// 3b8e5a1d ends here
+
+// [[file:../runners.note::c07f4a92][c07f4a92]]
+mod codec {
+    use super::*;
+
+    /// Chunk types of the Nailgun protocol.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ChunkType {
+        Argument,
+        Environment,
+        WorkingDirectory,
+        Command,
+        Stdin,
+        Stdout,
+        Stderr,
+        /// Server requests client to send stdin
+        StartReadingInput,
+        /// Client has no more stdin
+        StdinEof,
+        Exit,
+        Heartbeat,
+    }
+
+    impl ChunkType {
+        fn to_byte(self) -> u8 {
+            match self {
+                Self::Argument => b'A',
+                Self::Environment => b'E',
+                Self::WorkingDirectory => b'D',
+                Self::Command => b'C',
+                Self::Stdin => b'0',
+                Self::Stdout => b'1',
+                Self::Stderr => b'2',
+                Self::StartReadingInput => b'S',
+                Self::StdinEof => b'.',
+                Self::Exit => b'X',
+                Self::Heartbeat => b'H',
+            }
+        }
+
+        fn from_byte(b: u8) -> Result<Self> {
+            let t = match b {
+                b'A' => Self::Argument,
+                b'E' => Self::Environment,
+                b'D' => Self::WorkingDirectory,
+                b'C' => Self::Command,
+                b'0' => Self::Stdin,
+                b'1' => Self::Stdout,
+                b'2' => Self::Stderr,
+                b'S' => Self::StartReadingInput,
+                b'.' => Self::StdinEof,
+                b'X' => Self::Exit,
+                b'H' => Self::Heartbeat,
+                _ => bail!("invalid nailgun chunk type: {:?}", b as char),
+            };
+            Ok(t)
+        }
+    }
+
+    /// A chunk of the Nailgun protocol: 4 bytes big-endian payload length,
+    /// 1 byte chunk type, and the payload.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Chunk {
+        pub kind: ChunkType,
+        pub data: Bytes,
+    }
+
+    impl Chunk {
+        /// Create a chunk of `kind` with payload `data`.
+        pub fn new(kind: ChunkType, data: impl Into<Bytes>) -> Self {
+            Self { kind, data: data.into() }
+        }
+
+        /// Read a chunk from `r`. Return None on EOF.
+        pub async fn read_from<R: AsyncRead + Unpin>(r: &mut R) -> Result<Option<Self>> {
+            let mut header = [0u8; 5];
+            match r.read_exact(&mut header).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+            let n = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            let kind = ChunkType::from_byte(header[4])?;
+            let mut data = vec![0u8; n];
+            r.read_exact(&mut data).await?;
+            Ok(Some(Self::new(kind, data)))
+        }
+
+        /// Write the chunk into `w`.
+        pub async fn write_to<W: AsyncWrite + Unpin>(&self, w: &mut W) -> Result<()> {
+            let n = self.data.len() as u32;
+            w.write_all(&n.to_be_bytes()).await?;
+            w.write_u8(self.kind.to_byte()).await?;
+            w.write_all(&self.data).await?;
+            w.flush().await?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nailgun_chunk() -> Result<()> {
+        let chunk = Chunk::new(ChunkType::Argument, "--help");
+        let mut buf = vec![];
+        chunk.write_to(&mut buf).await?;
+        assert_eq!(&buf[..5], &[0, 0, 0, 6, b'A']);
+
+        let mut r = &buf[..];
+        let chunk2 = Chunk::read_from(&mut r).await?;
+        assert_eq!(chunk2, Some(chunk));
+        assert_eq!(Chunk::read_from(&mut r).await?, None);
+        Ok(())
+    }
+}
+// c07f4a92 ends here
+
+// [[file:../runners.note::58d1e6b0][58d1e6b0]]
+mod client {
+    use super::*;
+
+    use std::time::Duration;
+    use tokio::net::tcp::OwnedWriteHalf;
+    use tokio::net::TcpStream;
+
+    /// A client for calling commands on a Nailgun server.
+    pub struct NailgunClient {
+        stream: TcpStream,
+        heartbeat: Duration,
+        token: Option<String>,
+    }
+
+    impl NailgunClient {
+        /// Connect to Nailgun server at `addr`, e.g. "127.0.0.1:2113".
+        pub async fn connect(addr: &str) -> Result<Self> {
+            let stream = TcpStream::connect(addr)
+                .await
+                .with_context(|| format!("connect to nailgun server {}", addr))?;
+            Ok(Self {
+                stream,
+                heartbeat: Duration::from_secs(1),
+                token: None,
+            })
+        }
+
+        /// Present `token` to the server, as required by `NailgunServer`.
+        pub fn token(mut self, token: &str) -> Self {
+            self.token = Some(token.into());
+            self
+        }
+
+        /// Set the interval for sending heartbeats to the server.
+        pub fn heartbeat(mut self, interval: Duration) -> Self {
+            self.heartbeat = interval;
+            self
+        }
+
+        /// Call `command` with `args` in working directory `cwd` on the
+        /// server, streaming `stdin`, `stdout` and `stderr`. Return the
+        /// exit code of the command.
+        pub async fn run<I, O, E>(
+            self,
+            command: &str,
+            args: &[String],
+            cwd: &Path,
+            stdin: I,
+            stdout: O,
+            stderr: E,
+        ) -> Result<i32>
+        where
+            I: AsyncRead + Unpin,
+            O: AsyncWrite + Unpin,
+            E: AsyncWrite + Unpin,
+        {
+            let (mut reader, mut writer) = self.stream.into_split();
+            send_command(&mut writer, command, args, cwd, self.token.as_deref()).await?;
+
+            // read chunks in background, since reading a chunk is not
+            // cancel safe
+            let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+            tokio::spawn(async move {
+                loop {
+                    let chunk = Chunk::read_from(&mut reader).await;
+                    let eof = matches!(chunk, Ok(None) | Err(_));
+                    if tx.send(chunk).await.is_err() || eof {
+                        break;
+                    }
+                }
+            });
+
+            let (mut stdin, mut stdout, mut stderr) = (stdin, stdout, stderr);
+            let mut heartbeat = tokio::time::interval(self.heartbeat);
+            let mut want_input = false;
+            let mut buf = vec![0u8; 8192];
+            loop {
+                tokio::select! {
+                    chunk = rx.recv() => {
+                        let chunk = match chunk {
+                            Some(chunk) => chunk?,
+                            None => None,
+                        };
+                        let chunk = chunk.ok_or(format_err!("nailgun server closed connection unexpectedly"))?;
+                        match chunk.kind {
+                            ChunkType::Stdout => stdout.write_all(&chunk.data).await?,
+                            ChunkType::Stderr => stderr.write_all(&chunk.data).await?,
+                            ChunkType::StartReadingInput => want_input = true,
+                            ChunkType::Exit => {
+                                stdout.flush().await?;
+                                stderr.flush().await?;
+                                let code = String::from_utf8_lossy(&chunk.data).trim().parse()?;
+                                return Ok(code);
+                            }
+                            kind => warn!("unexpected nailgun chunk from server: {:?}", kind),
+                        }
+                    }
+                    n = stdin.read(&mut buf), if want_input => {
+                        let n = n?;
+                        let chunk = if n == 0 {
+                            Chunk::new(ChunkType::StdinEof, Bytes::new())
+                        } else {
+                            Chunk::new(ChunkType::Stdin, buf[..n].to_vec())
+                        };
+                        chunk.write_to(&mut writer).await?;
+                        want_input = false;
+                    }
+                    _ = heartbeat.tick() => {
+                        Chunk::new(ChunkType::Heartbeat, Bytes::new()).write_to(&mut writer).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send arguments, environment variables with `token`, working
+    /// directory and the command to the server.
+    async fn send_command(
+        w: &mut OwnedWriteHalf,
+        command: &str,
+        args: &[String],
+        cwd: &Path,
+        token: Option<&str>,
+    ) -> Result<()> {
+        for arg in args {
+            Chunk::new(ChunkType::Argument, arg.clone()).write_to(w).await?;
+        }
+        let token = token.map(|t| (TOKEN_VAR.to_owned(), t.to_owned()));
+        for (k, v) in std::env::vars().filter(|(k, _)| k != TOKEN_VAR).chain(token) {
+            Chunk::new(ChunkType::Environment, format!("{}={}", k, v)).write_to(w).await?;
+        }
+        let cwd = cwd.to_string_lossy().into_owned();
+        Chunk::new(ChunkType::WorkingDirectory, cwd).write_to(w).await?;
+        Chunk::new(ChunkType::Command, command.to_owned()).write_to(w).await?;
+        Ok(())
+    }
+}
+// 58d1e6b0 ends here
+
+// [[file:../runners.note::2c6f9d15][2c6f9d15]]
+mod server {
+    use super::*;
+
+    use std::collections::HashMap;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc::Sender;
+
+    /// A command received from a Nailgun client.
+    #[derive(Debug, Default)]
+    struct Request {
+        command: String,
+        args: Vec<String>,
+        envs: Vec<(String, String)>,
+        cwd: PathBuf,
+    }
+
+    /// A server executing commands from Nailgun clients in a pre-initialized
+    /// environment, avoiding expensive setup for each invocation. As clients
+    /// run arbitrary commands as the server user, they must present the
+    /// token set by `token`.
+    #[derive(Debug, Clone, Default)]
+    pub struct NailgunServer {
+        envs: HashMap<String, String>,
+        token: Option<String>,
+    }
+
+    impl NailgunServer {
+        /// Create a server executing commands in current environment.
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Prepare environment for commands by sourcing shell `script` once,
+        /// e.g. for loading environment modules or activating a conda env.
+        pub fn init_script(mut self, script: &Path) -> Result<Self> {
+            let out = std::process::Command::new("sh")
+                .args(&["-c", ". \"$1\" >/dev/null && env -0", "sh"])
+                .arg(script)
+                .output()?;
+            ensure!(
+                out.status.success(),
+                "init script {:?} failed: {}",
+                script,
+                String::from_utf8_lossy(&out.stderr)
+            );
+            self.envs = String::from_utf8_lossy(&out.stdout)
+                .split('\0')
+                .filter_map(|kv| kv.split_once('='))
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect();
+            info!("prepared {} environment variables from {:?}", self.envs.len(), script);
+            Ok(self)
+        }
+
+        /// Only accept clients presenting `token`, sent in `NAILGUN_TOKEN`
+        /// environment variable.
+        pub fn token(mut self, token: &str) -> Self {
+            self.token = Some(token.into());
+            self
+        }
+
+        /// Listen on `addr` and serve clients forever.
+        pub async fn serve(&self, addr: &str) -> Result<()> {
+            ensure!(self.token.is_some(), "refuse to serve on {} without a token", addr);
+            let listener = TcpListener::bind(addr).await?;
+            info!("nailgun server listening on {}", addr);
+            loop {
+                let (stream, peer) = listener.accept().await?;
+                debug!("nailgun client connected: {}", peer);
+                let server = self.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = server.handle(stream).await {
+                        warn!("nailgun client {} failed: {:?}", peer, e);
+                    }
+                });
+            }
+        }
+
+        /// Execute the command requested in `stream`.
+        async fn handle(&self, stream: TcpStream) -> Result<()> {
+            let (mut reader, mut writer) = stream.into_split();
+
+            let mut req = Request::default();
+            loop {
+                let chunk = Chunk::read_from(&mut reader)
+                    .await?
+                    .ok_or(format_err!("client closed connection before sending command"))?;
+                let data = String::from_utf8_lossy(&chunk.data).into_owned();
+                match chunk.kind {
+                    ChunkType::Argument => req.args.push(data),
+                    ChunkType::Environment => {
+                        if let Some((k, v)) = data.split_once('=') {
+                            req.envs.push((k.to_owned(), v.to_owned()));
+                        }
+                    }
+                    ChunkType::WorkingDirectory => req.cwd = data.into(),
+                    ChunkType::Command => {
+                        req.command = data;
+                        break;
+                    }
+                    ChunkType::Heartbeat => {}
+                    kind => bail!("unexpected nailgun chunk before command: {:?}", kind),
+                }
+            }
+            // the token is not passed on to the command
+            let token = req
+                .envs
+                .iter()
+                .rev()
+                .find(|(k, _)| k == TOKEN_VAR)
+                .map(|(_, v)| v.clone());
+            req.envs.retain(|(k, _)| k != TOKEN_VAR);
+            if token.is_none() || token != self.token {
+                warn!("rejected nailgun command {:?}: invalid token", req.command);
+                let msg = Chunk::new(ChunkType::Stderr, "invalid nailgun token\n");
+                msg.write_to(&mut writer).await?;
+                Chunk::new(ChunkType::Exit, "1").write_to(&mut writer).await?;
+                return Ok(());
+            }
+            info!("nailgun command: {} {:?}", req.command, req.args);
+
+            // the prepared environment takes precedence over client's
+            let mut child = tokio::process::Command::new(&req.command)
+                .args(&req.args)
+                .envs(req.envs)
+                .envs(&self.envs)
+                .current_dir(&req.cwd)
+                .stdin(std::process::Stdio::piped())
+                .stdout(std::process::Stdio::piped())
+                .stderr(std::process::Stdio::piped())
+                .spawn()
+                .with_context(|| format!("failed to spawn {:?}", req.command))?;
+            let mut stdin = child.stdin.take();
+            let stdout = child.stdout.take().expect("child stdout");
+            let stderr = child.stderr.take().expect("child stderr");
+
+            // all chunks to client are sent from one task
+            let (tx, mut rx) = tokio::sync::mpsc::channel::<Chunk>(16);
+            let sender = tokio::spawn(async move {
+                while let Some(chunk) = rx.recv().await {
+                    chunk.write_to(&mut writer).await?;
+                }
+                Result::<()>::Ok(())
+            });
+            let out = tokio::spawn(forward_output(stdout, ChunkType::Stdout, tx.clone()));
+            let err = tokio::spawn(forward_output(stderr, ChunkType::Stderr, tx.clone()));
+
+            // forward stdin from client on request
+            let _ = tx.send(Chunk::new(ChunkType::StartReadingInput, Bytes::new())).await;
+            let ecode = loop {
+                tokio::select! {
+                    status = child.wait() => break status?,
+                    chunk = Chunk::read_from(&mut reader), if stdin.is_some() => {
+                        match chunk? {
+                            Some(Chunk { kind: ChunkType::Stdin, data }) => {
+                                if let Some(w) = stdin.as_mut() {
+                                    w.write_all(&data).await?;
+                                }
+                                let _ = tx.send(Chunk::new(ChunkType::StartReadingInput, Bytes::new())).await;
+                            }
+                            Some(Chunk { kind: ChunkType::Heartbeat, .. }) => {}
+                            // close stdin on EOF or client disconnected
+                            _ => stdin = None,
+                        }
+                    }
+                }
+            };
+            out.await??;
+            err.await??;
+            let code = ecode.code().unwrap_or(-1);
+            info!("nailgun command {:?} exited with {}", req.command, code);
+            let _ = tx.send(Chunk::new(ChunkType::Exit, code.to_string())).await;
+            drop(tx);
+            sender.await??;
+            Ok(())
+        }
+    }
+
+    /// Send output read from `r` to client as chunks of `kind`.
+    async fn forward_output<R: AsyncRead + Unpin>(mut r: R, kind: ChunkType, tx: Sender<Chunk>) -> Result<()> {
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let n = r.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            if tx.send(Chunk::new(kind, buf[..n].to_vec())).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+// 2c6f9d15 ends here
+
+// [[file:../runners.note::e8a7f3c4][e8a7f3c4]]
+/// The environment variable carrying the token from client to server.
+const TOKEN_VAR: &str = "NAILGUN_TOKEN";
+
+pub use self::client::NailgunClient;
+pub use self::codec::{Chunk, ChunkType};
+pub use self::server::NailgunServer;
+// e8a7f3c4 ends here
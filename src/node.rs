@@ -0,0 +1,101 @@
+// [[file:../runners.note::c2e8a0f5][c2e8a0f5]]
+//! Resource inventory of the local node
+use super::*;
+
+use serde::{Deserialize, Serialize};
+// c2e8a0f5 ends here
+
+// [[file:../runners.note::7b45d9e3][7b45d9e3]]
+/// Computational resources available on the node.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct NodeInventory {
+    /// The number of CPU cores
+    pub cpus: usize,
+    /// Available memory in bytes when detected
+    pub memory: u64,
+    /// Indices of GPU devices
+    pub gpus: Vec<usize>,
+    /// Free space of scratch directory in bytes
+    pub scratch: u64,
+}
+
+impl NodeInventory {
+    /// Detect resources of the node, using `scratch_dir` for creating job
+    /// working directories.
+    pub fn detect(scratch_dir: &Path) -> Result<Self> {
+        let cpus = procfs::CpuInfo::new()?.num_cores();
+        // memory used by other programs on a shared node is not available
+        let meminfo = procfs::Meminfo::new()?;
+        let memory = meminfo.mem_available.unwrap_or(meminfo.mem_free);
+        let gpus = crate::scheduler::detect_gpus();
+        let scratch = free_disk_space(scratch_dir)?;
+        let node = Self {
+            cpus,
+            memory,
+            gpus,
+            scratch,
+        };
+        info!("node resources: {:?}", node);
+        Ok(node)
+    }
+}
+
+/// Return free space in bytes of the file system containing `path`.
+pub fn free_disk_space(path: &Path) -> Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// Return the total size of files under `path` in bytes.
+pub fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += meta.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Guess the MIME type of file in `path` from its extension, or from its
+/// leading bytes for files without a known extension.
+pub fn content_type(path: &Path) -> Result<&'static str> {
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+    let mime = match ext {
+        "json" => "application/json",
+        "toml" => "application/toml",
+        "gz" | "tgz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "zip" => "application/zip",
+        "png" => "image/png",
+        "pdf" => "application/pdf",
+        _ => {
+            let mut head = vec![0; 8192];
+            let n = std::fs::File::open(path)?.read(&mut head)?;
+            head.truncate(n);
+            match std::str::from_utf8(&head) {
+                // the last char may be cut off at the buffer end
+                Err(e) if e.error_len().is_some() => "application/octet-stream",
+                _ if head.contains(&0) => "application/octet-stream",
+                _ => "text/plain; charset=utf-8",
+            }
+        }
+    };
+    Ok(mime)
+}
+// 7b45d9e3 ends here
+
+// [[file:../runners.note::e5a0b17c][e5a0b17c]]
+#[test]
+fn test_node_inventory() -> Result<()> {
+    let node = NodeInventory::detect(".".as_ref())?;
+    assert!(node.cpus > 0);
+    assert!(node.memory > 0);
+
+    Ok(())
+}
+// e5a0b17c ends here
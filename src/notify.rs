@@ -0,0 +1,192 @@
+// [[file:../runners.note::a83f1c6e][a83f1c6e]]
+//! Notifications on job completion
+use super::*;
+
+use crate::job::{JobId, JobStatus};
+// a83f1c6e ends here
+
+// [[file:../runners.note::5d0b97e2][5d0b97e2]]
+/// A summary of finished job for notification.
+#[derive(Debug, Clone, Serialize)]
+pub struct JobSummary {
+    pub id: JobId,
+    pub status: JobStatus,
+    /// Wall time in seconds
+    pub runtime: f64,
+    pub exit_code: Option<i32>,
+    /// The last lines of stdout
+    pub tail: String,
+}
+
+impl JobSummary {
+    /// The number of last output lines included in summary.
+    pub(crate) const TAIL_LINES: usize = 20;
+
+    fn subject(&self) -> String {
+        format!("[gosh-runner] job {} {:?}", self.id, self.status)
+    }
+
+    fn text(&self) -> String {
+        let code = self.exit_code.map(|c| c.to_string()).unwrap_or("none".into());
+        format!(
+            "job {} {:?} after {:.1} seconds, exit code: {}\n\nlast {} lines of output:\n{}",
+            self.id,
+            self.status,
+            self.runtime,
+            code,
+            Self::TAIL_LINES,
+            self.tail
+        )
+    }
+}
+
+/// Return the last `n` lines of `text`.
+pub(crate) fn tail_lines(text: &str, n: usize) -> String {
+    let lines: Vec<_> = text.lines().collect();
+    lines[lines.len().saturating_sub(n)..].join("\n")
+}
+
+/// Server side notification settings. Emails are sent using a
+/// sendmail-compatible command, and webhooks are posted using `curl`.
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    sendmail: String,
+    from: Option<String>,
+    /// The default webhook URL, e.g. a Slack incoming webhook
+    webhook: Option<String>,
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Self {
+            sendmail: "sendmail".into(),
+            from: None,
+            webhook: None,
+        }
+    }
+}
+
+impl Notifier {
+    /// Use `cmd` for sending emails instead of `sendmail`.
+    pub fn sendmail(mut self, cmd: &str) -> Self {
+        self.sendmail = cmd.into();
+        self
+    }
+
+    /// Set the sender address of emails.
+    pub fn from(mut self, addr: &str) -> Self {
+        self.from = addr.to_owned().into();
+        self
+    }
+
+    /// Set the default webhook URL for jobs requesting `webhook`.
+    pub fn webhook(mut self, url: &str) -> Self {
+        self.webhook = url.to_owned().into();
+        self
+    }
+
+    /// Send `summary` to `targets`: `email:<addr>`, `webhook:<url>`, or
+    /// `webhook` for the default URL.
+    pub fn notify(&self, targets: &[String], summary: &JobSummary) {
+        for target in targets {
+            let r = match target.split_once(':') {
+                Some(("email", addr)) => self.send_email(addr, summary),
+                Some(("webhook", url)) => post_webhook(url, summary),
+                None if target == "webhook" => match &self.webhook {
+                    Some(url) => post_webhook(url, summary),
+                    None => Err(format_err!("no default webhook configured")),
+                },
+                _ => Err(format_err!("invalid notification target: {}", target)),
+            };
+            if let Err(e) = r {
+                warn!("failed to notify {}: {:?}", target, e);
+            }
+        }
+    }
+
+    fn send_email(&self, addr: &str, summary: &JobSummary) -> Result<()> {
+        use std::process::{Command, Stdio};
+
+        ensure!(is_valid_email(addr), "invalid email address: {:?}", addr);
+        let mut mail = String::new();
+        if let Some(from) = &self.from {
+            writeln!(mail, "From: {}", from)?;
+        }
+        writeln!(mail, "To: {}", addr)?;
+        writeln!(mail, "Subject: {}\n", summary.subject())?;
+        mail.push_str(&summary.text());
+
+        // the recipient is passed as argument, not read from headers
+        let mut child = Command::new(&self.sendmail)
+            .args(&["-i", "--", addr])
+            .stdin(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(mail.as_bytes())?;
+        let status = child.wait()?;
+        ensure!(status.success(), "{} failed: {}", self.sendmail, status);
+        info!("job {} notification sent to {}", summary.id, addr);
+        Ok(())
+    }
+}
+
+/// Test if `addr` is a plain email address like `user@example.com`, which
+/// cannot inject mail headers or sendmail options.
+fn is_valid_email(addr: &str) -> bool {
+    let valid_char = |c: char| c.is_ascii_alphanumeric() || "._+-".contains(c);
+    match addr.split_once('@') {
+        Some((user, domain)) => {
+            !user.is_empty()
+                && !user.starts_with('-')
+                && domain.contains('.')
+                && user.chars().all(valid_char)
+                && domain.chars().all(valid_char)
+        }
+        None => false,
+    }
+}
+
+/// Test if `url` is a plain http(s) URL.
+fn is_valid_webhook(url: &str) -> bool {
+    (url.starts_with("https://") || url.starts_with("http://"))
+        && !url.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Post `summary` to webhook `url` in JSON, with a `text` field for Slack.
+fn post_webhook(url: &str, summary: &JobSummary) -> Result<()> {
+    use std::process::{Command, Stdio};
+
+    ensure!(is_valid_webhook(url), "invalid webhook URL: {:?}", url);
+    let mut payload = serde_json::to_value(summary)?;
+    payload["text"] = format!("{}\n{}", summary.subject(), summary.text()).into();
+    // the payload is sent on stdin, not visible in process list
+    let mut child = Command::new("curl")
+        .args(&["-fsS", "-X", "POST", "-H", "Content-Type: application/json"])
+        .args(&["--data-binary", "@-"])
+        .args(&["--", url])
+        .stdin(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(payload.to_string().as_bytes())?;
+    let status = child.wait()?;
+    ensure!(status.success(), "curl failed: {}", status);
+    info!("job {} notification posted to {}", summary.id, url);
+    Ok(())
+}
+// 5d0b97e2 ends here
+
+// [[file:../runners.note::e27a05bf][e27a05bf]]
+#[test]
+fn test_notify_summary() {
+    let text: String = (1..=30).map(|i| format!("line {}\n", i)).collect();
+    let tail = tail_lines(&text, JobSummary::TAIL_LINES);
+    assert!(tail.starts_with("line 11"));
+    assert!(tail.ends_with("line 30"));
+
+    assert!(is_valid_email("alice@example.com"));
+    assert!(!is_valid_email("alice@example.com\nBcc: bob@example.com"));
+    assert!(!is_valid_email("-oQ/tmp@example.com"));
+    assert!(!is_valid_email("alice"));
+    assert!(is_valid_webhook("https://hooks.slack.com/services/x"));
+    assert!(!is_valid_webhook("file:///etc/passwd"));
+    assert!(!is_valid_webhook("-o/tmp/x"));
+}
+// e27a05bf ends here
@@ -0,0 +1,148 @@
+// [[file:../runners.note::5b7c2e94][5b7c2e94]]
+//! Extract structured results from job output
+use super::*;
+
+use crate::failure::FailureReason;
+use crate::job::JobStatus;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+// 5b7c2e94 ends here
+
+// [[file:../runners.note::e3f06a1c][e3f06a1c]]
+/// A parser extracting a named value from stdout of a job.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputParser {
+    /// The last match of regex `pattern` (the first capture group if any).
+    /// Numeric values are parsed as numbers.
+    Regex { name: String, pattern: String },
+    /// True if regex `pattern` matches, e.g. for a convergence flag.
+    Flag { name: String, pattern: String },
+    /// The trimmed stdout of a shell `command` run in working directory,
+    /// with job stdout file path in `$GOSH_JOB_OUT` environment variable.
+    Script { name: String, command: String },
+}
+
+/// Structured result of a finished job.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JobResult {
+    pub status: JobStatus,
+    pub exit_code: Option<i32>,
+    /// Values extracted by output parsers
+    pub values: BTreeMap<String, Value>,
+    /// The classified reason if the job failed
+    #[serde(default)]
+    pub failure: Option<FailureReason>,
+    /// The peak total RSS of job processes in bytes, sampled every second
+    #[serde(default)]
+    pub peak_rss: Option<u64>,
+}
+
+impl OutputParser {
+    /// Return the name of extracted value.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Regex { name, .. } | Self::Flag { name, .. } | Self::Script { name, .. } => name,
+        }
+    }
+
+    /// Extract value from job stdout `out_file` in `wrk_dir`.
+    pub fn parse(&self, wrk_dir: &Path, out_file: &Path) -> Result<Value> {
+        self.parse_with(out_file, &sh_in(wrk_dir))
+    }
+
+    /// Extract value like `parse`, with scripts run by the command from
+    /// `shell`, e.g. inside the sandbox of the job.
+    pub fn parse_with(&self, out_file: &Path, shell: &dyn Fn(&str) -> std::process::Command) -> Result<Value> {
+        let value = match self {
+            Self::Regex { pattern, .. } => to_value(&crate::hooks::extract(pattern, out_file)?),
+            Self::Flag { pattern, .. } => {
+                let re = regex::Regex::new(pattern)?;
+                Value::Bool(re.is_match(&gut::fs::read_file(out_file)?))
+            }
+            Self::Script { command, .. } => {
+                let out = shell(command).env("GOSH_JOB_OUT", out_file).output()?;
+                ensure!(
+                    out.status.success(),
+                    "parser script failed: {}",
+                    String::from_utf8_lossy(&out.stderr)
+                );
+                to_value(String::from_utf8_lossy(&out.stdout).trim())
+            }
+        };
+        Ok(value)
+    }
+}
+
+/// Convert extracted text `s` into a number if possible.
+fn to_value(s: &str) -> Value {
+    s.parse::<f64>()
+        .ok()
+        .and_then(serde_json::Number::from_f64)
+        .map(Value::Number)
+        .unwrap_or_else(|| Value::String(s.to_owned()))
+}
+
+/// Return the shell running scripts by plain `sh` in `wrk_dir`.
+fn sh_in(wrk_dir: &Path) -> impl Fn(&str) -> std::process::Command + '_ {
+    move |cmd| {
+        let mut command = std::process::Command::new("sh");
+        command.args(&["-c", cmd]).current_dir(wrk_dir);
+        command
+    }
+}
+
+/// Run `parsers` on job output, skipping failed ones with a warning.
+pub fn parse_all(parsers: &[OutputParser], wrk_dir: &Path, out_file: &Path) -> BTreeMap<String, Value> {
+    parse_all_with(parsers, out_file, &sh_in(wrk_dir))
+}
+
+/// Run `parsers` like `parse_all`, with scripts run by the command from
+/// `shell`.
+pub fn parse_all_with(
+    parsers: &[OutputParser],
+    out_file: &Path,
+    shell: &dyn Fn(&str) -> std::process::Command,
+) -> BTreeMap<String, Value> {
+    let mut values = BTreeMap::new();
+    for parser in parsers {
+        match parser.parse_with(out_file, shell) {
+            Ok(v) => {
+                values.insert(parser.name().to_owned(), v);
+            }
+            Err(e) => warn!("output parser {:?} failed: {:?}", parser.name(), e),
+        }
+    }
+    values
+}
+// e3f06a1c ends here
+
+// [[file:../runners.note::96d4b8f3][96d4b8f3]]
+#[test]
+fn test_output_parser() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let out = dir.path().join("job.out");
+    gut::fs::write_to_file(&out, "energy = -76.4\nSCF converged\n")?;
+
+    let parsers = vec![
+        OutputParser::Regex {
+            name: "energy".into(),
+            pattern: r"energy = (\S+)".into(),
+        },
+        OutputParser::Flag {
+            name: "converged".into(),
+            pattern: "SCF converged".into(),
+        },
+        OutputParser::Script {
+            name: "lines".into(),
+            command: "wc -l < $GOSH_JOB_OUT".into(),
+        },
+    ];
+    let values = parse_all(&parsers, dir.path(), &out);
+    assert_eq!(values["energy"], serde_json::json!(-76.4));
+    assert_eq!(values["converged"], Value::Bool(true));
+    assert_eq!(values["lines"], serde_json::json!(2.0));
+    Ok(())
+}
+// 96d4b8f3 ends here
@@ -91,6 +91,27 @@ mod impl_process_procfs {
             Ok(p)
         }
 
+        /// Construct from process ID and its start time (in clock ticks after
+        /// system boot), returning error if the process ID has been reused by
+        /// another process.
+        pub fn from_pid_and_start_time(pid: u32, starttime: u64) -> Result<Self> {
+            let p = Self::from_pid(pid)?;
+            ensure!(
+                p.create_time == starttime,
+                "process {} has a different start time: {} != {}",
+                pid,
+                p.create_time,
+                starttime
+            );
+            Ok(p)
+        }
+
+        /// Return the time the process started after system boot in clock
+        /// ticks.
+        pub fn start_time(&self) -> u64 {
+            self.create_time
+        }
+
         /// Return the system assigned process ID
         pub fn id(&self) -> u32 {
             self.inner.pid as u32
@@ -124,6 +145,52 @@ mod impl_process_procfs {
             Ok(cmdline)
         }
 
+        /// Returns the parent process ID.
+        pub fn parent_id(&self) -> u32 {
+            self.inner.stat.ppid as u32
+        }
+
+        /// Return the current state of the process, such as `R`, `S`, `T` or
+        /// `Z`.
+        pub fn get_state(&self) -> Result<char> {
+            let stat = self.inner.stat()?;
+            Ok(stat.state)
+        }
+
+        /// Return the resident set size of the process in bytes.
+        pub fn get_rss(&self) -> Result<u64> {
+            let stat = self.inner.stat()?;
+            Ok(stat.rss_bytes().max(0) as u64)
+        }
+
+        /// Return the CPU time (user + system) consumed by the process in
+        /// seconds.
+        pub fn get_cpu_time(&self) -> Result<f64> {
+            let stat = self.inner.stat()?;
+            let tps = procfs::ticks_per_second()? as f64;
+            Ok((stat.utime + stat.stime) as f64 / tps)
+        }
+
+        /// Return the CPU time (user + system) consumed by the process and
+        /// its waited-for children in seconds. For an exited but not yet
+        /// reaped process, this is the total CPU time of its process tree.
+        pub fn get_total_cpu_time(&self) -> Result<f64> {
+            let stat = self.inner.stat()?;
+            let tps = procfs::ticks_per_second()? as f64;
+            let ticks = stat.utime + stat.stime + (stat.cutime + stat.cstime) as u64;
+            Ok(ticks as f64 / tps)
+        }
+
+        /// Return the elapsed wall time since the process started in seconds.
+        pub fn get_elapsed_time(&self) -> Result<f64> {
+            let tps = procfs::ticks_per_second()? as f64;
+            let start = procfs::boot_time_secs()? as f64 + self.create_time as f64 / tps;
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs_f64();
+            Ok((now - start).max(0.0))
+        }
+
         /// Test if process is paused
         pub fn is_paused(&self) -> bool {
             if let Ok(stat) = self.inner.stat() {
@@ -175,11 +242,280 @@ mod impl_process_procfs {
 }
 // process:1 ends here
 
+// [[file:../runners.note::5e0b7c1d][5e0b7c1d]]
+mod priority {
+    use super::*;
+
+    /// The I/O scheduling class, see ioprio_set(2)
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum IoPriorityClass {
+        RealTime,
+        BestEffort,
+        Idle,
+    }
+
+    impl IoPriorityClass {
+        fn as_raw(&self) -> i32 {
+            match self {
+                Self::RealTime => 1,
+                Self::BestEffort => 2,
+                Self::Idle => 3,
+            }
+        }
+    }
+
+    impl Process {
+        /// Set the scheduling priority (nice value) of the process.
+        pub fn set_nice(&self, level: i32) -> Result<()> {
+            let r = unsafe { libc::setpriority(libc::PRIO_PROCESS as _, self.id() as libc::id_t, level) };
+            if r != 0 {
+                let e = std::io::Error::last_os_error();
+                bail!("set nice level {} for process {} failed: {}", level, self.id(), e);
+            }
+            Ok(())
+        }
+
+        /// Set the I/O scheduling class and priority `level` (0-7, lower is
+        /// higher priority) of the process.
+        pub fn set_ionice(&self, class: IoPriorityClass, level: u8) -> Result<()> {
+            // IOPRIO_WHO_PROCESS
+            let which = 1;
+            // IOPRIO_PRIO_VALUE(class, data)
+            let ioprio = (class.as_raw() << 13) | (level.min(7) as i32);
+            let r = unsafe { libc::syscall(libc::SYS_ioprio_set, which, self.id() as libc::c_int, ioprio) };
+            if r != 0 {
+                let e = std::io::Error::last_os_error();
+                bail!("set ionice {:?}/{} for process {} failed: {}", class, level, self.id(), e);
+            }
+            Ok(())
+        }
+    }
+}
+// 5e0b7c1d ends here
+
+// [[file:../runners.note::b3d81f27][b3d81f27]]
+mod tree {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    /// A snapshot of a process and its child processes.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct ProcessNode {
+        pub pid: u32,
+        pub ppid: u32,
+        pub cmdline: Vec<String>,
+        pub state: char,
+        /// Average CPU usage since the process started
+        pub cpu_percent: f64,
+        /// Resident set size in bytes
+        pub rss: u64,
+        pub children: Vec<ProcessNode>,
+    }
+
+    impl ProcessNode {
+        fn from_process(p: &Process) -> Self {
+            let elapsed = p.get_elapsed_time().unwrap_or(0.0);
+            let cpu_time = p.get_cpu_time().unwrap_or(0.0);
+            let cpu_percent = if elapsed > 0.0 { 100.0 * cpu_time / elapsed } else { 0.0 };
+            Self {
+                pid: p.id(),
+                ppid: p.parent_id(),
+                cmdline: p.get_cmdline().unwrap_or_default(),
+                state: p.get_state().unwrap_or('?'),
+                cpu_percent,
+                rss: p.get_rss().unwrap_or(0),
+                children: vec![],
+            }
+        }
+
+        fn render(&self, depth: usize, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            writeln!(
+                f,
+                "{:indent$}{} [{}] cpu={:.1}% rss={}K {}",
+                "",
+                self.pid,
+                self.state,
+                self.cpu_percent,
+                self.rss / 1024,
+                self.cmdline.join(" "),
+                indent = depth * 2
+            )?;
+            for child in &self.children {
+                child.render(depth + 1, f)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Render in a `pstree` like view.
+    impl std::fmt::Display for ProcessNode {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            self.render(0, f)
+        }
+    }
+
+    /// Build process trees from a flat list of processes. Processes whose
+    /// parent is not in the list become roots.
+    pub fn build_process_tree(processes: &[Process]) -> Vec<ProcessNode> {
+        let pids: std::collections::HashSet<_> = processes.iter().map(|p| p.id()).collect();
+        let nodes: Vec<_> = processes.iter().map(ProcessNode::from_process).collect();
+
+        fn attach(node: &mut ProcessNode, nodes: &[ProcessNode]) {
+            node.children = nodes.iter().filter(|n| n.ppid == node.pid).cloned().collect();
+            for child in node.children.iter_mut() {
+                attach(child, nodes);
+            }
+        }
+
+        let mut roots: Vec<_> = nodes.iter().filter(|n| !pids.contains(&n.ppid)).cloned().collect();
+        for root in roots.iter_mut() {
+            attach(root, &nodes);
+        }
+        roots
+    }
+}
+// b3d81f27 ends here
+
+// [[file:../runners.note::7c2e9a40][7c2e9a40]]
+mod pidfd {
+    use super::*;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::time::{Duration, Instant};
+
+    /// A file descriptor referring to a process, see pidfd_open(2)
+    pub struct PidFd(RawFd);
+
+    impl PidFd {
+        /// Obtain a file descriptor for the process `pid`. Requires Linux 5.3
+        /// or later.
+        pub fn open(pid: u32) -> Result<Self> {
+            let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+            if fd < 0 {
+                let e = std::io::Error::last_os_error();
+                bail!("pidfd_open for process {} failed: {}", pid, e);
+            }
+            Ok(Self(fd as RawFd))
+        }
+
+        /// Wait until the process exits. The pidfd becomes readable when
+        /// the process terminates.
+        pub async fn wait(self) -> Result<()> {
+            let fd = tokio::io::unix::AsyncFd::new(self)?;
+            let _ = fd.readable().await?;
+            Ok(())
+        }
+
+        /// Block until the process exits or `timeout` elapses, returning
+        /// true if the process exited.
+        pub fn poll_exited(&self, timeout: Duration) -> Result<bool> {
+            let deadline = Instant::now() + timeout;
+            let mut fds = [libc::pollfd {
+                fd: self.0,
+                events: libc::POLLIN,
+                revents: 0,
+            }];
+            loop {
+                let left = deadline.saturating_duration_since(Instant::now());
+                let ms = left.as_millis().min(i32::MAX as u128) as i32;
+                let n = unsafe { libc::poll(fds.as_mut_ptr(), 1, ms) };
+                if n >= 0 {
+                    return Ok(n > 0);
+                }
+                let e = std::io::Error::last_os_error();
+                if e.kind() != std::io::ErrorKind::Interrupted {
+                    bail!("poll pidfd failed: {}", e);
+                }
+            }
+        }
+    }
+
+    /// The longest interval between checks when polling procfs.
+    const MAX_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    impl Process {
+        /// Wait until the process exits for at most `timeout`, returning
+        /// true if it exited. Use pidfd on Linux 5.3 or later, otherwise
+        /// poll procfs with increasing intervals up to 0.5 seconds.
+        pub fn wait_exited(&self, timeout: Duration) -> Result<bool> {
+            match PidFd::open(self.id()) {
+                // the PID could be reused before pidfd opened
+                Ok(fd) if self.is_alive() => return fd.poll_exited(timeout),
+                Ok(_) => return Ok(true),
+                Err(e) => debug!("pidfd not available: {:?}, fall back to polling.", e),
+            }
+            let deadline = Instant::now() + timeout;
+            let mut interval = Duration::from_millis(10);
+            loop {
+                if !self.is_alive() {
+                    return Ok(true);
+                }
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Ok(false);
+                }
+                std::thread::sleep(interval.min(left));
+                interval = (interval * 2).min(MAX_POLL_INTERVAL);
+            }
+        }
+    }
+
+    impl AsRawFd for PidFd {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0
+        }
+    }
+
+    impl Drop for PidFd {
+        fn drop(&mut self) {
+            let _ = nix::unistd::close(self.0);
+        }
+    }
+}
+// 7c2e9a40 ends here
+
+// [[file:../runners.note::e41a6d93][e41a6d93]]
+mod criu {
+    use super::*;
+
+    /// Call `criu` with `args`, returning error if it fails.
+    fn call_criu(args: &[&str]) -> Result<()> {
+        debug!("call criu with {:?}", args);
+        let out = std::process::Command::new("criu")
+            .args(args)
+            .output()
+            .context("failed to execute criu, is it installed?")?;
+        if !out.status.success() {
+            bail!("criu failed: {}", String::from_utf8_lossy(&out.stderr));
+        }
+        Ok(())
+    }
+
+    /// Dump the process tree rooted at `pid` into images in `dir`. The
+    /// processes are terminated after dumping.
+    pub fn checkpoint(pid: u32, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let pid = pid.to_string();
+        let dir = dir.to_string_lossy();
+        call_criu(&["dump", "--tree", &pid, "--images-dir", &dir])
+    }
+
+    /// Restore the process tree from images in `dir` in background, and
+    /// return the process ID of the restored root process.
+    pub fn restore(dir: &Path) -> Result<u32> {
+        let pidfile = dir.join("restore.pid");
+        let pidfile_s = pidfile.to_string_lossy();
+        let dir = dir.to_string_lossy();
+        call_criu(&["restore", "--restore-detached", "--images-dir", &dir, "--pidfile", &pidfile_s])?;
+        let pid = gut::fs::read_file(&pidfile)?.trim().parse()?;
+        Ok(pid)
+    }
+}
+// e41a6d93 ends here
+
 // [[file:../runners.note::49b16e9d][49b16e9d]]
 mod session {
     use super::*;
-    use std::process::Child;
-    use std::process::ExitStatus;
+    use serde::{Deserialize, Serialize};
 
     /// Manange a group of processes in the same session. The child processes
     /// will be terminated, if `Session` dropped.
@@ -201,6 +537,25 @@ mod session {
     pub struct Session<T> {
         pub child: T,
         session_handler: SessionHandler,
+        kill_on_drop: KillOnDrop,
+    }
+
+    /// The policy for processes in the session when `Session` dropped.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum KillOnDrop {
+        /// Send SIGTERM to all processes (the default).
+        Yes,
+        /// Leave the processes running.
+        No,
+        /// Send SIGTERM, and SIGKILL the remaining processes after timeout,
+        /// without blocking the drop.
+        Graceful(std::time::Duration),
+    }
+
+    impl Default for KillOnDrop {
+        fn default() -> Self {
+            Self::Yes
+        }
     }
 
     impl<T> Session<T> {
@@ -208,12 +563,65 @@ mod session {
         pub fn handler(&self) -> &SessionHandler {
             &self.session_handler
         }
+
+        /// Set the policy for processes in the session on drop.
+        pub fn set_kill_on_drop(&mut self, policy: KillOnDrop) {
+            self.kill_on_drop = policy;
+        }
+
+        /// Let child processes outlive the `Session`.
+        pub fn detach(&mut self) {
+            self.set_kill_on_drop(KillOnDrop::No);
+        }
     }
 
-    // Send SIGTERM to processes in the session on drop
+    // Terminate processes in the session on drop according to `kill_on_drop`
     impl<T> Drop for Session<T> {
         fn drop(&mut self) {
-            let _ = self.session_handler.terminate();
+            match self.kill_on_drop {
+                KillOnDrop::Yes => {
+                    let _ = self.session_handler.terminate();
+                }
+                KillOnDrop::No => {
+                    debug!("leave session {:?} running", self.session_handler.id());
+                }
+                KillOnDrop::Graceful(timeout) => {
+                    // waiting for the timeout in a thread, as the session
+                    // could be dropped in async context
+                    let handler = self.session_handler.clone();
+                    std::thread::spawn(move || handler.terminate_gracefully(timeout));
+                }
+            }
+        }
+    }
+
+    /// The identity of a session leader, which can be saved to disk for
+    /// reattaching a `SessionHandler` after the runner restarts.
+    #[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+    pub struct SessionLeader {
+        pub pid: u32,
+        pub starttime: u64,
+    }
+
+    /// Resource usage of all processes in a session at a moment.
+    #[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+    pub struct SessionUsage {
+        /// The number of processes
+        pub nprocs: usize,
+        /// Total CPU time consumed in seconds
+        pub cpu_time: f64,
+        /// Total resident set size in bytes
+        pub rss: u64,
+    }
+
+    impl SessionUsage {
+        /// Sum resource usage of `processes`.
+        pub fn collect(processes: &[Process]) -> Self {
+            Self {
+                nprocs: processes.len(),
+                cpu_time: processes.iter().filter_map(|p| p.get_cpu_time().ok()).sum(),
+                rss: processes.iter().filter_map(|p| p.get_rss().ok()).sum(),
+            }
         }
     }
 
@@ -236,6 +644,43 @@ mod session {
             Self { process }
         }
 
+        /// Reattach to a running session with the leader of `pid`, started
+        /// at `starttime` (clock ticks after system boot).
+        pub fn reattach(pid: u32, starttime: u64) -> Result<Self> {
+            let process = Process::from_pid_and_start_time(pid, starttime)?;
+            info!("reattached to session {}", pid);
+            Ok(Self {
+                process: process.into(),
+            })
+        }
+
+        /// Return true if the session leader is still alive.
+        pub fn is_alive(&self) -> bool {
+            self.process.as_ref().map_or(false, |p| p.is_alive())
+        }
+
+        /// Return the identity of the session leader.
+        pub fn leader(&self) -> Option<SessionLeader> {
+            self.process.as_ref().map(|p| SessionLeader {
+                pid: p.id(),
+                starttime: p.start_time(),
+            })
+        }
+
+        /// Save the session leader into `path` in JSON format.
+        pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+            let leader = self.leader().ok_or(format_err!("no session leader!"))?;
+            gut::fs::write_to_file(path, &leader.to_json()?)?;
+            Ok(())
+        }
+
+        /// Reattach to the session saved in `path`.
+        pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+            let s = gut::fs::read_file(path)?;
+            let leader = SessionLeader::from_json(&s)?;
+            Self::reattach(leader.pid, leader.starttime)
+        }
+
         /// Return process ID of the session leader.
         pub fn id(&self) -> Option<u32> {
             self.process.as_ref().map(|p| p.id())
@@ -259,6 +704,12 @@ mod session {
             Ok(())
         }
 
+        /// Return current resource usage of processes in the session.
+        pub fn usage(&self) -> Result<SessionUsage> {
+            let processes = self.get_processes()?;
+            Ok(SessionUsage::collect(&processes))
+        }
+
         /// Return the processes in the session.
         pub fn get_processes(&self) -> Result<Vec<Process>> {
             if let Some(id) = self.id() {
@@ -269,6 +720,60 @@ mod session {
             }
         }
 
+        /// Send `signal` to all processes in the session, e.g. `SIGUSR1` for
+        /// asking the program to write a checkpoint.
+        pub fn signal(&self, signal: Signal) -> Result<()> {
+            debug!("signal session {:?} with {}", self.id(), signal);
+            self.send_signal(signal.as_str())
+        }
+
+        /// Send signal by `name` (e.g. "SIGUSR1") to all processes in the
+        /// session.
+        pub fn signal_by_name(&self, name: &str) -> Result<()> {
+            let signal: Signal = name.parse().with_context(|| format!("invalid signal name: {}", name))?;
+            self.signal(signal)
+        }
+
+        /// Wait until the session leader exits. On Linux 5.3 or later this
+        /// is event driven using pidfd, otherwise it falls back to polling.
+        pub async fn on_exit(&self) -> Result<()> {
+            let p = self.process.clone().ok_or(format_err!("no session leader!"))?;
+            match pidfd::PidFd::open(p.id()) {
+                // the PID could be reused before pidfd opened
+                Ok(fd) if p.is_alive() => fd.wait().await?,
+                Ok(_) => {}
+                Err(e) => {
+                    debug!("pidfd not available: {:?}, fall back to polling.", e);
+                    while p.is_alive() {
+                        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+                    }
+                }
+            }
+            debug!("session leader {} exited", p.id());
+            Ok(())
+        }
+
+        /// Checkpoint all processes in the session into `dir` using CRIU.
+        /// The processes will be terminated after dumping.
+        pub fn checkpoint<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+            let id = self.id().ok_or(format_err!("no session leader!"))?;
+            info!("checkpoint session {} into {}", id, dir.as_ref().display());
+            criu::checkpoint(id, dir.as_ref())
+        }
+
+        /// Restore a session checkpointed in `dir` using CRIU.
+        pub fn restore<P: AsRef<Path>>(dir: P) -> Result<Self> {
+            let id = criu::restore(dir.as_ref())?;
+            info!("restored session {} from {}", id, dir.as_ref().display());
+            Ok(Self::from(id))
+        }
+
+        /// Return a snapshot of processes in the session organized as trees.
+        pub fn process_tree(&self) -> Result<Vec<ProcessNode>> {
+            let processes = self.get_processes()?;
+            Ok(build_process_tree(&processes))
+        }
+
         /// Pause all processes in the session.
         pub fn pause(&self) -> Result<()> {
             debug!("pause session {:?}", self.id());
@@ -283,6 +788,25 @@ mod session {
             Ok(())
         }
 
+        /// Set nice `level` for all processes in the session.
+        pub fn set_nice(&self, level: i32) -> Result<()> {
+            debug!("set nice level {} for session {:?}", level, self.id());
+            for p in self.get_processes()? {
+                p.set_nice(level)?;
+            }
+            Ok(())
+        }
+
+        /// Set I/O scheduling `class` and priority `level` for all processes
+        /// in the session.
+        pub fn set_ionice(&self, class: IoPriorityClass, level: u8) -> Result<()> {
+            debug!("set ionice {:?}/{} for session {:?}", class, level, self.id());
+            for p in self.get_processes()? {
+                p.set_ionice(class, level)?;
+            }
+            Ok(())
+        }
+
         /// Terminate processes in the session.
         pub fn terminate(&self) -> Result<()> {
             debug!("terminate session {:?}", self.id());
@@ -292,6 +816,47 @@ mod session {
             self.send_signal("SIGTERM")?;
             Ok(())
         }
+
+        /// Terminate processes in the session, and kill the remaining
+        /// processes if they are still alive after `timeout`. Processes left
+        /// in the session are also terminated if the leader already exited.
+        pub fn terminate_gracefully(&self, timeout: std::time::Duration) -> Result<()> {
+            let p_old = self.process.as_ref().ok_or(format_err!("no session leader!"))?;
+            let sid = p_old.id();
+            match Process::from_pid(sid) {
+                Ok(p_now) if p_now.is_same(p_old) => self.terminate()?,
+                // processes in the session of a reused PID are not ours
+                Ok(_) => {
+                    warn!("session leader {} was reused, not terminating", sid);
+                    return Ok(());
+                }
+                Err(_) => {
+                    super::reap_orphans(sid)?;
+                }
+            }
+            let now = std::time::Instant::now();
+            loop {
+                let alive: Vec<_> = get_processes_in_session(sid)?
+                    .into_iter()
+                    .filter(|p| p.is_alive())
+                    .collect();
+                if alive.is_empty() {
+                    break;
+                }
+                if now.elapsed() >= timeout {
+                    for p in alive {
+                        warn!("kill process {} after {:?} timeout", p.id(), timeout);
+                        // the process may exit in the meantime
+                        if let Err(e) = p.send_signal("SIGKILL") {
+                            warn!("failed to kill process {}: {:?}", p.id(), e);
+                        }
+                    }
+                    break;
+                }
+                gut::utils::sleep(0.1);
+            }
+            Ok(())
+        }
     }
 
     impl SpawnSessionExt<std::process::Child> for std::process::Command {
@@ -299,7 +864,11 @@ mod session {
             let child = self.new_process_group().spawn()?;
             let id = child.id();
             let session_handler = SessionHandler::from(id);
-            let s = Session { child, session_handler };
+            let s = Session {
+                child,
+                session_handler,
+                kill_on_drop: KillOnDrop::default(),
+            };
             Ok(s)
         }
     }
@@ -308,7 +877,11 @@ mod session {
             let child = self.new_process_group().spawn()?;
             let id = child.id().ok_or(format_err!("no id: child process already exited"))?;
             let session_handler = SessionHandler::from(id);
-            let s = Session { child, session_handler };
+            let s = Session {
+                child,
+                session_handler,
+                kill_on_drop: KillOnDrop::default(),
+            };
             Ok(s)
         }
     }
@@ -329,9 +902,39 @@ pub(crate) fn signal_processes_by_session_id(sid: u32, signal: &str) -> Result<(
     Ok(())
 }
 
+/// Terminate the processes left in session `sid` after its session leader
+/// exited. Return the IDs of processes that have been cleaned up.
+pub fn reap_orphans(sid: u32) -> Result<Vec<u32>> {
+    let pp: Vec<_> = get_processes_in_session(sid)?
+        .into_iter()
+        .filter(|p| p.is_alive())
+        .collect();
+    // the session is still alive
+    if pp.iter().any(|p| p.id() == sid) {
+        return Ok(vec![]);
+    }
+
+    let mut reaped = vec![];
+    for p in pp {
+        let cmdline = p.get_cmdline().unwrap_or_default();
+        info!("reap orphaned process {} in session {}: {:?}", p.id(), sid, cmdline);
+        if let Err(e) = p.send_signal("SIGTERM") {
+            warn!("failed to terminate orphaned process {}: {:?}", p.id(), e);
+        } else {
+            reaped.push(p.id());
+        }
+    }
+
+    Ok(reaped)
+}
+
 pub use impl_process_procfs::{get_processes_in_session, Process};
+pub use priority::IoPriorityClass;
+pub use pidfd::PidFd;
+pub use tree::{build_process_tree, ProcessNode};
+pub use nix::sys::signal::Signal;
 pub use process_group::ProcessGroupExt;
-pub use session::{Session, SessionHandler, SpawnSessionExt};
+pub use session::{KillOnDrop, Session, SessionHandler, SessionLeader, SessionUsage, SpawnSessionExt};
 // pub:1 ends here
 
 // [[file:../runners.note::3ceaa6e9][3ceaa6e9]]
@@ -387,4 +990,58 @@ async fn test_spawn_session_tokio() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_session_detach() -> Result<()> {
+    use std::process::Command;
+
+    let mut session = Command::new("sleep").arg("10").spawn_session()?;
+    session.detach();
+    let handler = session.handler().clone();
+    drop(session);
+    gut::utils::sleep(0.2);
+    assert!(!handler.get_processes()?.is_empty());
+    handler.terminate()?;
+
+    Ok(())
+}
 // 3ceaa6e9 ends here
+
+// [[file:../runners.note::b6e04c19][b6e04c19]]
+#[test]
+fn test_process_wait_exited() -> Result<()> {
+    use std::time::{Duration, Instant};
+
+    let mut child = std::process::Command::new("sleep").arg("0.5").spawn()?;
+    let p = Process::from_pid(child.id())?;
+    assert!(!p.wait_exited(Duration::from_millis(100))?);
+    let t0 = Instant::now();
+    assert!(p.wait_exited(Duration::from_secs(5))?);
+    assert!(t0.elapsed() < Duration::from_secs(2));
+    child.wait()?;
+    // already exited
+    assert!(p.wait_exited(Duration::from_millis(100))?);
+    Ok(())
+}
+// b6e04c19 ends here
+
+// [[file:../runners.note::6a2f8d31][6a2f8d31]]
+#[test]
+fn test_terminate_gracefully_orphans() -> Result<()> {
+    use std::process::Command;
+    use std::time::Duration;
+
+    // the leader exits, leaving a child ignoring SIGTERM in the session
+    let mut session = Command::new("sh")
+        .args(["-c", "trap '' TERM; sleep 30 & exit 0"])
+        .spawn_session()?;
+    session.child.wait()?;
+    let sid = session.handler().id().unwrap();
+    assert_eq!(get_processes_in_session(sid)?.len(), 1);
+
+    session.handler().terminate_gracefully(Duration::from_millis(300))?;
+    gut::utils::sleep(0.2);
+    assert!(get_processes_in_session(sid)?.iter().all(|p| !p.is_alive()));
+    Ok(())
+}
+// 6a2f8d31 ends here
@@ -0,0 +1,101 @@
+// [[file:../runners.note::d3e6b0a4][d3e6b0a4]]
+//! Per-client rate limiting of requests
+use super::*;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+// d3e6b0a4 ends here
+
+// [[file:../runners.note::6f2a9c85][6f2a9c85]]
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    clients: HashMap<String, Bucket>,
+    /// The last time idle buckets were dropped
+    swept: Instant,
+}
+
+/// A token-bucket rate limiter keyed by client, allowing `rate` requests
+/// per second on average with bursts up to `burst` requests.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `rate` requests per second and bursts of
+    /// `burst` requests for each client.
+    pub fn new(rate: f64, burst: usize) -> Self {
+        assert!(rate > 0.0, "invalid rate: {}", rate);
+        Self {
+            rate,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(Buckets {
+                clients: HashMap::new(),
+                swept: Instant::now(),
+            }),
+        }
+    }
+
+    /// The time for an idle bucket to fill up, after which it is no
+    /// different from a new one.
+    fn refill_time(&self) -> Duration {
+        Duration::from_secs_f64(self.burst / self.rate)
+    }
+
+    /// Take one request from the quota of `client`. Return the time to wait
+    /// before retrying if the quota is used up.
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        // drop full buckets of idle clients, so that the map does not grow
+        // with every client ever seen
+        let refill = self.refill_time();
+        if now.duration_since(buckets.swept) >= refill {
+            buckets.clients.retain(|_, b| now.duration_since(b.updated) < refill);
+            buckets.swept = now;
+        }
+        let bucket = buckets.clients.entry(client.to_owned()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
+// 6f2a9c85 ends here
+
+// [[file:../runners.note::0b7d4e19][0b7d4e19]]
+#[test]
+fn test_rate_limiter() {
+    let limiter = RateLimiter::new(1.0, 2);
+    assert!(limiter.check("a").is_ok());
+    assert!(limiter.check("a").is_ok());
+    let retry = limiter.check("a").unwrap_err();
+    assert!(retry <= Duration::from_secs(1));
+    // other clients have their own quota
+    assert!(limiter.check("b").is_ok());
+
+    // idle clients are forgotten
+    let limiter = RateLimiter::new(1000.0, 1);
+    assert!(limiter.check("a").is_ok());
+    std::thread::sleep(Duration::from_millis(5));
+    assert!(limiter.check("b").is_ok());
+    assert_eq!(limiter.buckets.lock().unwrap().clients.len(), 1);
+}
+// 0b7d4e19 ends here
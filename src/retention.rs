@@ -0,0 +1,86 @@
+// [[file:../runners.note::6d2f8b07][6d2f8b07]]
+//! Retention policy for finished jobs
+use super::*;
+
+use crate::job::JobId;
+use std::time::Duration;
+// 6d2f8b07 ends here
+
+// [[file:../runners.note::e9a4c135][e9a4c135]]
+/// Which finished jobs to keep. Jobs violating any limit are removed, with
+/// their working directories archived if `archive_dir` is set.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Keep at most the last N finished jobs
+    pub keep_last: Option<usize>,
+    /// Keep finished jobs younger than this
+    pub max_age: Option<Duration>,
+    /// Archive working directories into tarballs here before deletion
+    pub archive_dir: Option<PathBuf>,
+}
+
+impl RetentionPolicy {
+    /// Keep at most the last `n` finished jobs.
+    pub fn keep_last(mut self, n: usize) -> Self {
+        self.keep_last = n.into();
+        self
+    }
+
+    /// Keep finished jobs younger than `age`.
+    pub fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = age.into();
+        self
+    }
+
+    /// Archive working directories into `dir` before deletion.
+    pub fn archive_into<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.archive_dir = dir.as_ref().to_owned().into();
+        self
+    }
+
+    /// Select jobs to be removed from finished `jobs` with their ages.
+    pub fn select(&self, jobs: &[(JobId, Duration)]) -> Vec<JobId> {
+        // the youngest first
+        let mut jobs = jobs.to_vec();
+        jobs.sort_by_key(|(_, age)| *age);
+        jobs.iter()
+            .enumerate()
+            .filter(|(i, (_, age))| {
+                self.keep_last.map_or(false, |n| *i >= n) || self.max_age.map_or(false, |max| *age > max)
+            })
+            .map(|(_, (id, _))| *id)
+            .collect()
+    }
+
+    /// Archive working directory `wrk_dir` of job `id` if required.
+    pub(crate) fn archive(&self, id: JobId, wrk_dir: &Path) -> Result<()> {
+        if let Some(dir) = &self.archive_dir {
+            std::fs::create_dir_all(dir)?;
+            let tarball = dir.join(format!("job-{}-{}.tar.gz", id, chrono::Local::now().format("%Y%m%d%H%M%S")));
+            let status = std::process::Command::new("tar")
+                .arg("czf")
+                .arg(&tarball)
+                .arg("-C")
+                .arg(wrk_dir)
+                .arg(".")
+                .status()?;
+            ensure!(status.success(), "failed to archive job {} into {:?}", id, tarball);
+            info!("job {} archived into {:?}", id, tarball);
+        }
+        Ok(())
+    }
+}
+// e9a4c135 ends here
+
+// [[file:../runners.note::37b1f0ad][37b1f0ad]]
+#[test]
+fn test_retention_policy() {
+    let hour = Duration::from_secs(3600);
+    let jobs = vec![(1, hour * 3), (2, hour * 2), (3, hour)];
+    let policy = RetentionPolicy::default().keep_last(2);
+    assert_eq!(policy.select(&jobs), vec![1]);
+    let policy = RetentionPolicy::default().max_age(hour + hour / 2);
+    assert_eq!(policy.select(&jobs), vec![2, 1]);
+    assert!(RetentionPolicy::default().select(&jobs).is_empty());
+}
+// 37b1f0ad ends here
@@ -0,0 +1,57 @@
+// [[file:../runners.note::7c3e90a5][7c3e90a5]]
+//! Pluggable execution strategies for computational jobs
+use super::*;
+
+use crate::job::JobStatus;
+use crate::scheduler::Allocation;
+use serde::{Deserialize, Serialize};
+// 7c3e90a5 ends here
+
+// [[file:../runners.note::e41b8d2f][e41b8d2f]]
+/// Everything a `JobRunner` needs to know for running a job.
+#[derive(Debug, Clone)]
+pub struct RunContext {
+    /// The working directory of the job
+    pub wrk_dir: PathBuf,
+    /// The command line to start the job, run in `wrk_dir`
+    pub cmdline: Vec<String>,
+    /// The content to be fed into stdin
+    pub input: String,
+    /// The full path to input file (stdin)
+    pub inp_file: PathBuf,
+    /// The full path to output file (stdout)
+    pub out_file: PathBuf,
+    /// The full path to error file (stderr)
+    pub err_file: PathBuf,
+    /// Resources allocated by the scheduler
+    pub allocation: Allocation,
+}
+
+/// The outcome of a job run by a `JobRunner`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RunOutcome {
+    /// The final status of the job
+    pub status: JobStatus,
+    /// The exit code of the job process if available
+    pub exit_code: Option<i32>,
+}
+
+impl RunOutcome {
+    /// Construct outcome from `code`, the exit code of the job process.
+    pub fn from_exit_code(code: i32) -> Self {
+        let status = if code == 0 { JobStatus::Completed } else { JobStatus::Failed };
+        Self {
+            status,
+            exit_code: code.into(),
+        }
+    }
+}
+
+/// Custom launch logic for running jobs, such as license checks or staging
+/// files into burst buffers. The runner should return after the job
+/// finished.
+#[async_trait::async_trait]
+pub trait JobRunner: Send + Sync {
+    async fn run(&self, ctx: RunContext) -> RunOutcome;
+}
+// e41b8d2f ends here
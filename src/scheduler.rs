@@ -0,0 +1,534 @@
+// [[file:../runners.note::3d7b5e21][3d7b5e21]]
+//! Resource-aware scheduling of computational jobs on the local node
+use super::*;
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Notify};
+
+use crate::node::NodeInventory;
+// 3d7b5e21 ends here
+
+// [[file:../runners.note::a80c6f4e][a80c6f4e]]
+/// Resources requested by a job. Zero means not declared.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Resources {
+    /// The number of CPU cores
+    #[serde(default)]
+    pub cpus: usize,
+    /// Memory in bytes
+    #[serde(default)]
+    pub memory: u64,
+    /// The number of GPU devices
+    #[serde(default)]
+    pub gpus: usize,
+    /// Scratch space in bytes for job files
+    #[serde(default)]
+    pub scratch: u64,
+}
+
+/// Resources allocated for a running job.
+#[derive(Debug, Clone, Default)]
+pub struct Allocation {
+    pub cpus: usize,
+    pub memory: u64,
+    /// Indices of allocated GPU devices
+    pub gpus: Vec<usize>,
+    pub scratch: u64,
+    // for tracking running allocations in the scheduler
+    ticket: u64,
+}
+
+impl Allocation {
+    /// Environment variables for the job to use allocated resources.
+    pub fn env_vars(&self) -> Vec<(String, String)> {
+        let mut vars = vec![];
+        if !self.gpus.is_empty() {
+            let ids = self.gpus.iter().map(|i| i.to_string()).join(",");
+            vars.push(("CUDA_VISIBLE_DEVICES".to_owned(), ids));
+        }
+        vars
+    }
+}
+// a80c6f4e ends here
+
+// [[file:../runners.note::6f1e2c9b][6f1e2c9b]]
+mod gpu {
+    /// Detect indices of GPU devices on the node using `nvidia-smi`, or
+    /// `/proc/driver/nvidia/gpus` as a fallback.
+    pub fn detect_gpus() -> Vec<usize> {
+        let out = std::process::Command::new("nvidia-smi")
+            .args(&["--query-gpu=index", "--format=csv,noheader"])
+            .output();
+        if let Ok(out) = out {
+            if out.status.success() {
+                return String::from_utf8_lossy(&out.stdout)
+                    .lines()
+                    .filter_map(|line| line.trim().parse().ok())
+                    .collect();
+            }
+        }
+        std::fs::read_dir("/proc/driver/nvidia/gpus")
+            .map(|entries| (0..entries.count()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Track free GPU devices on the node.
+    #[derive(Debug, Clone, Default)]
+    pub struct GpuPool {
+        free: Vec<usize>,
+    }
+
+    impl GpuPool {
+        /// Create a pool with GPU devices of `ids`.
+        pub fn new(ids: Vec<usize>) -> Self {
+            Self { free: ids }
+        }
+
+        /// Return the number of free GPUs.
+        pub fn num_free(&self) -> usize {
+            self.free.len()
+        }
+
+        /// Take `n` free GPUs from the pool. Return None if not enough.
+        pub fn acquire(&mut self, n: usize) -> Option<Vec<usize>> {
+            if n > self.free.len() {
+                return None;
+            }
+            Some(self.free.drain(..n).collect())
+        }
+
+        /// Take GPUs of `ids` from the pool if all of them are free.
+        pub fn take(&mut self, ids: &[usize]) -> bool {
+            if !ids.iter().all(|i| self.free.contains(i)) {
+                return false;
+            }
+            self.free.retain(|i| !ids.contains(i));
+            true
+        }
+
+        /// Return GPUs of `ids` back into the pool.
+        pub fn release(&mut self, ids: &[usize]) {
+            self.free.extend_from_slice(ids);
+            self.free.sort_unstable();
+        }
+    }
+
+    #[test]
+    fn test_gpu_pool() {
+        let mut pool = GpuPool::new(vec![0, 1, 2]);
+        let a = pool.acquire(2).unwrap();
+        assert_eq!(a, vec![0, 1]);
+        assert!(pool.acquire(2).is_none());
+        pool.release(&a);
+        assert_eq!(pool.num_free(), 3);
+        assert!(pool.take(&[1]));
+        assert!(!pool.take(&[1, 2]));
+        assert_eq!(pool.acquire(2).unwrap(), vec![0, 2]);
+    }
+}
+// 6f1e2c9b ends here
+
+// [[file:../runners.note::0e9c4d7a][0e9c4d7a]]
+/// Return true if `req` fits into `free` resources.
+pub(crate) fn fits(req: &Resources, free: &Resources) -> bool {
+    req.cpus <= free.cpus && req.memory <= free.memory && req.gpus <= free.gpus && req.scratch <= free.scratch
+}
+
+/// A request waiting for resources in the queue.
+#[derive(Debug)]
+struct Waiting {
+    ticket: u64,
+    req: Resources,
+}
+
+/// A running allocation, and when it is expected to end by its declared
+/// wall time.
+#[derive(Debug)]
+struct Running {
+    ticket: u64,
+    res: Resources,
+    end: Option<Instant>,
+}
+
+/// Free resources tracked by the scheduler, with queued requests and
+/// running allocations for backfill.
+#[derive(Debug)]
+struct FreeResources {
+    cpus: usize,
+    memory: u64,
+    gpus: GpuPool,
+    scratch: u64,
+    queue: Vec<Waiting>,
+    running: Vec<Running>,
+    next_ticket: u64,
+}
+
+impl FreeResources {
+    fn new(node: &NodeInventory) -> Self {
+        Self {
+            cpus: node.cpus,
+            memory: node.memory,
+            gpus: GpuPool::new(node.gpus.clone()),
+            scratch: node.scratch,
+            queue: vec![],
+            running: vec![],
+            next_ticket: 1,
+        }
+    }
+
+    fn free(&self) -> Resources {
+        Resources {
+            cpus: self.cpus,
+            memory: self.memory,
+            gpus: self.gpus.num_free(),
+            scratch: self.scratch,
+        }
+    }
+
+    /// Put `req` into the queue, returning its ticket.
+    fn enqueue(&mut self, req: &Resources) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.queue.push(Waiting {
+            ticket,
+            req: req.clone(),
+        });
+        ticket
+    }
+
+    /// Remove request of `ticket` from the queue.
+    fn dequeue(&mut self, ticket: u64) {
+        self.queue.retain(|w| w.ticket != ticket);
+    }
+
+    /// Return when the request at the head of the queue is expected to
+    /// start, and resources free at that time, assuming running jobs end
+    /// by their declared wall time. Return None if unknown.
+    fn shadow(&self, head: &Resources) -> Option<(Instant, Resources)> {
+        let mut free = self.free();
+        if fits(head, &free) {
+            return Some((Instant::now(), free));
+        }
+        // running jobs without declared wall time end last
+        for r in self.running.iter().sorted_by_key(|r| (r.end.is_none(), r.end)) {
+            let end = r.end?;
+            free.cpus += r.res.cpus;
+            free.memory += r.res.memory;
+            free.gpus += r.res.gpus;
+            free.scratch += r.res.scratch;
+            if fits(head, &free) {
+                return Some((end, free));
+            }
+        }
+        None
+    }
+
+    /// Return true if queued request of `ticket` may start now: either it
+    /// is at the head of the queue, or it is backfilled without delaying
+    /// the head, because it ends before the head could start, or it only
+    /// uses resources the head does not need.
+    fn may_start(&self, ticket: u64, req: &Resources, walltime: Option<Duration>) -> bool {
+        if !fits(req, &self.free()) {
+            return false;
+        }
+        let head = match self.queue.first() {
+            Some(head) if head.ticket != ticket => &head.req,
+            _ => return true,
+        };
+        let (start, mut free) = match self.shadow(head) {
+            Some(shadow) => shadow,
+            None => return false,
+        };
+        if let Some(end) = walltime.and_then(|t| Instant::now().checked_add(t)) {
+            if end <= start {
+                return true;
+            }
+        }
+        if !fits(req, &free) {
+            return false;
+        }
+        free.cpus -= req.cpus;
+        free.memory -= req.memory;
+        free.gpus -= req.gpus;
+        free.scratch -= req.scratch;
+        fits(head, &free)
+    }
+
+    /// Allocate `req` resources expected to be used for `walltime` if all
+    /// of them are free.
+    fn allocate(&mut self, req: &Resources, walltime: Option<Duration>) -> Option<Allocation> {
+        if !fits(req, &self.free()) {
+            return None;
+        }
+        self.cpus -= req.cpus;
+        self.memory -= req.memory;
+        self.scratch -= req.scratch;
+        let gpus = self.gpus.acquire(req.gpus)?;
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.running.push(Running {
+            ticket,
+            res: req.clone(),
+            end: walltime.and_then(|t| Instant::now().checked_add(t)),
+        });
+        Some(Allocation {
+            cpus: req.cpus,
+            memory: req.memory,
+            gpus,
+            scratch: req.scratch,
+            ticket,
+        })
+    }
+
+    /// Take exactly the resources of `alloc` if all of them are free.
+    fn take(&mut self, alloc: &Allocation) -> bool {
+        if alloc.cpus > self.cpus || alloc.memory > self.memory || alloc.scratch > self.scratch {
+            return false;
+        }
+        if !self.gpus.take(&alloc.gpus) {
+            return false;
+        }
+        self.cpus -= alloc.cpus;
+        self.memory -= alloc.memory;
+        self.scratch -= alloc.scratch;
+        self.running.push(Running {
+            ticket: alloc.ticket,
+            res: Resources {
+                cpus: alloc.cpus,
+                memory: alloc.memory,
+                gpus: alloc.gpus.len(),
+                scratch: alloc.scratch,
+            },
+            end: None,
+        });
+        true
+    }
+
+    fn release(&mut self, alloc: &Allocation) {
+        self.cpus += alloc.cpus;
+        self.memory += alloc.memory;
+        self.scratch += alloc.scratch;
+        self.gpus.release(&alloc.gpus);
+        self.running.retain(|r| r.ticket != alloc.ticket);
+    }
+}
+
+/// Remove a waiting request from the queue when the waiting is cancelled.
+struct QueueGuard {
+    free: Arc<Mutex<FreeResources>>,
+    notify: Arc<Notify>,
+    ticket: Option<u64>,
+}
+
+impl Drop for QueueGuard {
+    fn drop(&mut self) {
+        if let Some(ticket) = self.ticket.take() {
+            let free = self.free.clone();
+            let notify = self.notify.clone();
+            tokio::spawn(async move {
+                free.lock().await.dequeue(ticket);
+                notify.notify_waiters();
+            });
+        }
+    }
+}
+
+/// A scheduler that only starts jobs when their requested resources are
+/// free, preventing oversubscription of the node.
+#[derive(Clone)]
+pub struct Scheduler {
+    node: Arc<NodeInventory>,
+    free: Arc<Mutex<FreeResources>>,
+    notify: Arc<Notify>,
+}
+
+impl Scheduler {
+    /// Create a scheduler managing resources detected on the node.
+    pub fn new() -> Self {
+        let node = NodeInventory::detect(".".as_ref()).unwrap_or_else(|e| {
+            warn!("failed to detect node resources: {:?}", e);
+            NodeInventory::default()
+        });
+        Self::from_node(node)
+    }
+
+    /// Create a scheduler managing resources of `node`.
+    pub fn from_node(node: NodeInventory) -> Self {
+        let free = FreeResources::new(&node);
+        Self {
+            node: Arc::new(node),
+            free: Arc::new(Mutex::new(free)),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Return resources of the node managed by the scheduler.
+    pub fn node(&self) -> &NodeInventory {
+        &self.node
+    }
+
+    /// Return currently free resources.
+    pub async fn free_resources(&self) -> Resources {
+        self.free.lock().await.free()
+    }
+
+    /// Check if `req` resources could be satisfied by the node at all.
+    pub fn admit(&self, req: &Resources) -> Result<()> {
+        let node = &self.node;
+        ensure!(req.cpus <= node.cpus, "requested {} CPUs, but the node has {}", req.cpus, node.cpus);
+        ensure!(
+            req.memory <= node.memory,
+            "requested {} bytes memory, but the node has {} available",
+            req.memory,
+            node.memory
+        );
+        ensure!(
+            req.scratch <= node.scratch,
+            "requested {} bytes scratch space, but the node has {}",
+            req.scratch,
+            node.scratch
+        );
+        ensure!(
+            req.gpus <= node.gpus.len(),
+            "requested {} GPUs, but the node has {}",
+            req.gpus,
+            node.gpus.len()
+        );
+        Ok(())
+    }
+
+    /// Wait until `req` resources are free, and allocate them. Return error
+    /// if the request exceeds resources of the node.
+    pub async fn acquire(&self, req: &Resources) -> Result<Allocation> {
+        self.acquire_within(req, None).await
+    }
+
+    /// Wait for `req` resources like `acquire` for a job declaring its wall
+    /// time in seconds. Requests are served in order, but a later request
+    /// is backfilled into idle resources if it does not delay the first
+    /// one, judged from wall times of running jobs.
+    pub async fn acquire_within(&self, req: &Resources, walltime: Option<f64>) -> Result<Allocation> {
+        self.admit(req)?;
+        // checked before taking the lock
+        let walltime = walltime
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .context("invalid wall time")?;
+        let ticket = self.free.lock().await.enqueue(req);
+        let mut guard = QueueGuard {
+            free: self.free.clone(),
+            notify: self.notify.clone(),
+            ticket: Some(ticket),
+        };
+        loop {
+            // created before checking to avoid missing notifications
+            let notified = self.notify.notified();
+            {
+                let mut free = self.free.lock().await;
+                if free.may_start(ticket, req, walltime) {
+                    if let Some(alloc) = free.allocate(req, walltime) {
+                        free.dequeue(ticket);
+                        guard.ticket = None;
+                        debug!("allocated resources: {:?}", alloc);
+                        // the head of the queue may change
+                        self.notify.notify_waiters();
+                        return Ok(alloc);
+                    }
+                }
+            }
+            debug!("waiting for free resources: {:?}", req);
+            notified.await;
+        }
+    }
+
+    /// Allocate `req` resources if free now, without waiting, ignoring
+    /// requests in the queue. Return error if the request exceeds resources
+    /// of the node.
+    pub async fn try_acquire(&self, req: &Resources) -> Result<Option<Allocation>> {
+        self.admit(req)?;
+        Ok(self.free.lock().await.allocate(req, None))
+    }
+
+    /// Wait until exactly the resources of `alloc` are free, and take them
+    /// back, for resuming a preempted job on the same GPU devices.
+    pub async fn reclaim(&self, alloc: &Allocation) {
+        loop {
+            let notified = self.notify.notified();
+            if self.free.lock().await.take(alloc) {
+                debug!("reclaimed resources: {:?}", alloc);
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Release resources of `alloc` for other jobs.
+    pub async fn release(&self, alloc: &Allocation) {
+        self.free.lock().await.release(alloc);
+        self.notify.notify_waiters();
+    }
+}
+// 0e9c4d7a ends here
+
+// [[file:../runners.note::b6a2f85d][b6a2f85d]]
+pub use self::gpu::{detect_gpus, GpuPool};
+// b6a2f85d ends here
+
+// [[file:../runners.note::e3a94c17][e3a94c17]]
+#[tokio::test]
+async fn test_scheduler_backfill() -> Result<()> {
+    let node = NodeInventory {
+        cpus: 4,
+        ..Default::default()
+    };
+    let scheduler = Scheduler::from_node(node);
+    let cpus = |n| Resources {
+        cpus: n,
+        ..Default::default()
+    };
+    assert!(scheduler.acquire_within(&cpus(2), Some(f64::NAN)).await.is_err());
+    let running = scheduler.acquire_within(&cpus(2), Some(10.0)).await?;
+
+    // the big job waits at the head of the queue
+    let s = scheduler.clone();
+    let big = tokio::spawn(async move { s.acquire(&cpus(4)).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // a short job ending before the big job could start is backfilled
+    let short = scheduler.acquire_within(&cpus(2), Some(1.0)).await?;
+    scheduler.release(&short).await;
+    // but a job without wall time would delay the big job
+    let t = Duration::from_millis(100);
+    assert!(tokio::time::timeout(t, scheduler.acquire(&cpus(2))).await.is_err());
+
+    scheduler.release(&running).await;
+    let alloc = big.await??;
+    assert_eq!(alloc.cpus, 4);
+    Ok(())
+}
+// e3a94c17 ends here
+
+// [[file:../runners.note::4f8c2d19][4f8c2d19]]
+#[tokio::test]
+async fn test_scheduler_scratch() -> Result<()> {
+    let node = NodeInventory {
+        cpus: 4,
+        scratch: 100,
+        ..Default::default()
+    };
+    let scheduler = Scheduler::from_node(node);
+    let scratch = |n| Resources {
+        scratch: n,
+        ..Default::default()
+    };
+    assert!(scheduler.acquire(&scratch(200)).await.is_err());
+
+    let alloc = scheduler.acquire(&scratch(60)).await?;
+    assert!(scheduler.try_acquire(&scratch(60)).await?.is_none());
+    scheduler.release(&alloc).await;
+    assert!(scheduler.try_acquire(&scratch(60)).await?.is_some());
+    Ok(())
+}
+// 4f8c2d19 ends here
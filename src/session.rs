@@ -4,13 +4,10 @@ use super::*;
 
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
-use tokio::signal::ctrl_c;
 use tokio::time::{sleep as delay_for, Duration};
 // 7507fa23 ends here
 
 // [[file:../runners.note::1520aa92][1520aa92]]
-use crate::process::SpawnSessionExt;
-
 /// Manage a group of processes in a session
 pub struct Session {
     /// Arguments that will be passed to `program`
@@ -21,6 +18,67 @@ pub struct Session {
 
     /// The external command
     command: Command,
+
+    /// Write a replayable trace into the file when done
+    trace_file: Option<PathBuf>,
+
+    /// Interrupt the program after the delay, as when replaying a trace
+    interrupt_after: Option<Duration>,
+
+    /// Restart the program on failure for at most this many times
+    retries: u32,
+
+    /// The delay before restarting the failed program
+    retry_delay: Duration,
+
+    /// Capture stdout and stderr with timestamps into the file
+    log_file: Option<PathBuf>,
+
+    /// Also pass captured output through to the terminal
+    tee: bool,
+
+    /// Terminate the program gracefully when the file appears
+    stop_file: Option<PathBuf>,
+
+    /// Terminate the program when total RSS of the session exceeds the
+    /// limit in bytes
+    max_rss: Option<u64>,
+
+    /// Terminate the program when total CPU time of the session exceeds
+    /// the limit in seconds
+    max_cpu_time: Option<f64>,
+
+    /// Capture output of the program instead of passing it through
+    capture: bool,
+
+    /// Captured output of the last run
+    output: SessionOutput,
+
+    /// Feed the program's stdin from data or file
+    stdin: Option<SessionInput>,
+
+    /// Commands following the program in a pipeline
+    pipeline: Vec<Command>,
+}
+
+/// Input fed into stdin of the program, again on each restart.
+#[derive(Debug, Clone)]
+enum SessionInput {
+    Data(Vec<u8>),
+    File(PathBuf),
+}
+
+/// Captured result of a program run in a session.
+#[derive(Debug, Clone, Default)]
+pub struct SessionOutput {
+    /// The exit code, following conventions of `Session::run`
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+    /// The wall time of the run, including restarts
+    pub duration: Duration,
+    /// Peak total RSS of the session in bytes, sampled periodically
+    pub peak_rss: u64,
 }
 
 impl Session {
@@ -31,6 +89,19 @@ impl Session {
             command,
             timeout: None,
             rest: vec![],
+            trace_file: None,
+            interrupt_after: None,
+            retries: 0,
+            retry_delay: Duration::from_secs(10),
+            log_file: None,
+            tee: false,
+            stop_file: None,
+            max_rss: None,
+            max_cpu_time: None,
+            capture: false,
+            output: SessionOutput::default(),
+            stdin: None,
+            pipeline: vec![],
         }
     }
 
@@ -72,69 +143,584 @@ impl Session {
         self.timeout = Some(t);
         self
     }
+
+    /// Restart the program for at most `n` times if it exits with failure,
+    /// e.g. for license server hiccups. Timeout or interruption is not
+    /// retried.
+    pub fn retries(mut self, n: u32) -> Self {
+        self.retries = n;
+        self
+    }
+
+    /// Wait for `delay` before restarting the failed program.
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Capture stdout and stderr of the program into file `path`, with a
+    /// timestamp on each line. If `tee` is true, the output is also shown
+    /// in the terminal as usual.
+    pub fn log<P: AsRef<Path>>(mut self, path: P, tee: bool) -> Self {
+        self.log_file = path.as_ref().to_owned().into();
+        self.tee = tee;
+        self
+    }
+
+    /// Terminate the program gracefully when file `path` is created, e.g.
+    /// "STOP" in current directory. An existing file is removed on start.
+    pub fn stop_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.stop_file = path.as_ref().to_owned().into();
+        self
+    }
+
+    /// Terminate the program when total resident memory of all processes
+    /// in the session, including grandchildren, exceeds `bytes`.
+    pub fn max_rss(mut self, bytes: u64) -> Self {
+        self.max_rss = bytes.into();
+        self
+    }
+
+    /// Terminate the program when total CPU time of all processes in the
+    /// session exceeds `secs` seconds.
+    pub fn max_cpu_time(mut self, secs: f64) -> Self {
+        self.max_cpu_time = secs.into();
+        self
+    }
+
+    /// Feed `data` into stdin of the program, which is closed afterwards.
+    ///
+    /// # Example
+    ///
+    /// ```rust, no_run
+    /// use gosh_runner::session::Session;
+    ///
+    /// let code = Session::new("xtb").arg("-").stdin_data("3\n\nO 0 0 0\nH 0 0 1\nH 0 1 0\n").run()?;
+    /// let code = Session::new("orca").stdin_file("input.inp").timeout(600).run()?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn stdin_data<B: Into<Vec<u8>>>(mut self, data: B) -> Self {
+        self.stdin = SessionInput::Data(data.into()).into();
+        self
+    }
+
+    /// Feed stdin of the program from file `path`, like shell redirection
+    /// with `< path`.
+    pub fn stdin_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.stdin = SessionInput::File(path.as_ref().to_owned()).into();
+        self
+    }
+
+    /// Pipe stdout of the program, or the last command in the pipeline,
+    /// into stdin of `next`, like `generate_input | solver | extract`. All
+    /// commands are started together and terminated as a unit. The exit
+    /// code is the first failure in the pipeline, as with `set -o
+    /// pipefail` in bash.
+    ///
+    /// # Example
+    ///
+    /// ```rust, no_run
+    /// use gosh_runner::session::Session;
+    /// use std::process::Command;
+    ///
+    /// let mut extract = Command::new("grep");
+    /// extract.arg("TOTAL ENERGY");
+    /// let out = Session::new("generate_input")
+    ///     .pipe(Command::new("solver"))
+    ///     .pipe(extract)
+    ///     .timeout(3600)
+    ///     .run_captured()?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn pipe<C: Into<Command>>(mut self, next: C) -> Self {
+        self.pipeline.push(next.into());
+        self
+    }
+
+    /// Record the command, environment, signals and exit status into a
+    /// replayable trace in JSON file `path`.
+    pub fn record<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.trace_file = path.as_ref().to_owned().into();
+        self
+    }
 }
 // 1520aa92 ends here
 
+// [[file:../runners.note::6c1f8b27][6c1f8b27]]
+/// An event happened in a session, at `time` seconds after start.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TraceEvent {
+    pub time: f64,
+    pub event: String,
+}
+
+/// A trace of a session for replaying it when debugging.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SessionTrace {
+    pub program: String,
+    pub args: Vec<String>,
+    pub dir: Option<PathBuf>,
+    /// The complete environment of the program
+    pub env: std::collections::BTreeMap<String, String>,
+    /// Timeout in seconds
+    pub timeout: Option<u32>,
+    /// The date when started
+    pub date: String,
+    pub events: Vec<TraceEvent>,
+    pub exit_code: Option<i32>,
+}
+
+impl SessionTrace {
+    /// Capture the command in `session` before starting.
+    fn capture(session: &Session) -> Self {
+        let cmd = session.command.as_std();
+        let mut env: std::collections::BTreeMap<_, _> = std::env::vars().collect();
+        for (k, v) in cmd.get_envs() {
+            let k = k.to_string_lossy().into_owned();
+            match v {
+                Some(v) => env.insert(k, v.to_string_lossy().into_owned()),
+                None => env.remove(&k),
+            };
+        }
+        Self {
+            program: cmd.get_program().to_string_lossy().into_owned(),
+            args: cmd.get_args().map(|a| a.to_string_lossy().into_owned()).collect(),
+            dir: cmd.get_current_dir().map(|d| d.to_owned()),
+            env,
+            timeout: session.timeout,
+            date: timestamp_now(),
+            ..Default::default()
+        }
+    }
+
+    /// Read trace from JSON file `path`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let s = gut::fs::read_file(path)?;
+        let trace = Self::from_json(&s).with_context(|| format!("invalid session trace {:?}", path))?;
+        Ok(trace)
+    }
+
+    /// Create a session re-executing the traced command with the same
+    /// environment, timeout and user interruption.
+    pub fn to_session(&self) -> Session {
+        let mut session = Session::new(&self.program).args(&self.args);
+        session.command.env_clear().envs(&self.env);
+        if let Some(dir) = &self.dir {
+            session = session.dir(dir);
+        }
+        if let Some(t) = self.timeout {
+            session = session.timeout(t);
+        }
+        if let Some(e) = self.events.iter().find(|e| e.event == "interrupted") {
+            session.interrupt_after = Duration::from_secs_f64(e.time).into();
+        }
+        session
+    }
+}
+// 6c1f8b27 ends here
+
 // [[file:../runners.note::*core][core:1]]
 impl Session {
-    async fn start(&mut self) -> Result<()> {
-        use crate::process::SpawnSessionExt;
+    /// Start the command and wait for it, restarting on failure if asked,
+    /// and returning its exit code. The exit code is 124 on timeout, and
+    /// 128 + N if killed or interrupted by signal N, following shell
+    /// conventions. On SIGINT, SIGTERM, SIGHUP or SIGQUIT, the program is
+    /// terminated gracefully before returning.
+    async fn start(&mut self) -> Result<i32> {
+        let mut trace = self.trace_file.as_ref().map(|_| SessionTrace::capture(self));
+        let t0 = std::time::Instant::now();
+        let mut record = |event: &str| {
+            if let Some(trace) = trace.as_mut() {
+                let time = t0.elapsed().as_secs_f64();
+                trace.events.push(TraceEvent { time, event: event.into() });
+            }
+        };
+        let log = match self.log_file.as_ref() {
+            Some(f) => {
+                let file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(f)
+                    .with_context(|| format!("open log file {:?}", f))?;
+                Some(std::sync::Arc::new(std::sync::Mutex::new(file)))
+            }
+            None => None,
+        };
+        // user interruption, killed by batch scheduler, or hangup, also
+        // during the delay between retries
+        let shutdown = crate::signals::Shutdown::install();
+        let mut attempt = 0;
+        let code = loop {
+            let (code, exited) = self.start_once(&mut record, log.clone(), &shutdown).await?;
+            if code == 0 || !exited || attempt >= self.retries {
+                break code;
+            }
+            attempt += 1;
+            eprintln!(
+                "program failed with code {}, restarting in {:?} ({}/{})",
+                code, self.retry_delay, attempt, self.retries
+            );
+            record(&format!("restarted after code {}", code));
+            tokio::select! {
+                _ = delay_for(self.retry_delay) => {}
+                _ = shutdown.requested() => {
+                    let sig = shutdown.signal().context("no signal received")?;
+                    eprintln!("interrupted by {}", sig);
+                    record("interrupted");
+                    break 128 + sig as i32;
+                }
+            }
+        };
+        if let (Some(mut trace), Some(f)) = (trace, self.trace_file.as_ref()) {
+            trace.exit_code = code.into();
+            gut::fs::write_to_file(f, &trace.to_json()?)?;
+            info!("session trace written to {:?}", f);
+        }
+
+        Ok(code)
+    }
 
-        let mut session = self.command.spawn_session()?;
+    /// Start the command once and wait for it, recording events with
+    /// `record`. Return the exit code, and true if the program exited by
+    /// itself instead of being stopped.
+    async fn start_once(
+        &mut self,
+        record: &mut impl FnMut(&str),
+        log: Option<LogFile>,
+        shutdown: &crate::signals::Shutdown,
+    ) -> Result<(i32, bool)> {
+        use crate::process::{SessionUsage, SpawnSessionExt};
+
+        let piped = log.is_some() || self.capture;
+        match self.stdin.as_ref() {
+            Some(SessionInput::Data(_)) => {
+                self.command.stdin(std::process::Stdio::piped());
+            }
+            Some(SessionInput::File(f)) => {
+                let file = std::fs::File::open(f).with_context(|| format!("open stdin file {:?}", f))?;
+                self.command.stdin(file);
+            }
+            None => {}
+        }
+        // connect stdout of each command to stdin of the next one
+        let last = self.pipeline.len();
+        let mut stages = vec![];
+        let mut input: Option<std::process::Stdio> = None;
+        for (i, command) in std::iter::once(&mut self.command).chain(&mut self.pipeline).enumerate() {
+            if let Some(input) = input.take() {
+                command.stdin(input);
+            }
+            if i < last || piped {
+                command.stdout(std::process::Stdio::piped());
+            }
+            if piped {
+                command.stderr(std::process::Stdio::piped());
+            }
+            let mut stage = command.spawn_session()?;
+            if i < last {
+                let out = stage.child.stdout.take().context("no stdout for pipe")?;
+                input = Some(out.try_into()?);
+            }
+            stages.push(stage);
+        }
+        record("started");
+        let handlers: Vec<_> = stages.iter().map(|s| s.handler().clone()).collect();
+        if let (Some(SessionInput::Data(data)), Some(mut stdin)) = (self.stdin.clone(), stages[0].child.stdin.take()) {
+            tokio::spawn(async move {
+                // the program may exit without reading all input
+                if let Err(e) = stdin.write_all(&data).await {
+                    debug!("failed to feed stdin: {:?}", e);
+                }
+            });
+        }
+        let (tee, capture) = (self.tee, self.capture);
+        let stdout_copier = stages[last]
+            .child
+            .stdout
+            .take()
+            .map(|out| tokio::spawn(copy_output(out, log.clone(), None, tee, capture)));
+        let mut stderr_copiers = vec![];
+        for stage in stages.iter_mut() {
+            if let Some(err) = stage.child.stderr.take() {
+                let copier = copy_output(err, log.clone(), Some("[stderr]"), tee, capture);
+                stderr_copiers.push(tokio::spawn(copier));
+            }
+        }
         // running timeout for 2 days
         let default_timeout = 3600 * 2;
         let timeout = tokio::time::sleep(Duration::from_secs(self.timeout.unwrap_or(default_timeout) as u64));
         tokio::pin!(timeout);
-        // user interruption
-        let ctrl_c = tokio::signal::ctrl_c();
+        // user interruption, killed by batch scheduler, or hangup
+        let requested = shutdown.requested();
+        tokio::pin!(requested);
+        // interruption replayed from trace
+        let interrupt = async {
+            match self.interrupt_after {
+                Some(t) => delay_for(t).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(interrupt);
+        // stop requested by creating the stop file
+        let stop = self.stop_file.as_ref().map(crate::stop::StopFileHandler::with_path);
+        let stop = async {
+            match stop.as_ref() {
+                Some(h) => h.wait(Duration::from_secs(1)).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(stop);
+        // user-space limits covering the whole session
+        let (max_rss, max_cpu_time) = (self.max_rss, self.max_cpu_time);
+        // sample more often for peak memory of short runs
+        let interval = Duration::from_millis(if capture { 200 } else { 1000 });
+        let peak_rss = std::cell::Cell::new(0);
+        let watchdog = async {
+            if max_rss.is_none() && max_cpu_time.is_none() && !capture {
+                return std::future::pending().await;
+            }
+            loop {
+                delay_for(interval).await;
+                let usage = handlers.iter().map(|h| h.usage()).collect::<Result<Vec<_>>>();
+                let usage = match usage {
+                    Ok(usage) => usage.into_iter().fold(SessionUsage::default(), |a, u| SessionUsage {
+                        nprocs: a.nprocs + u.nprocs,
+                        cpu_time: a.cpu_time + u.cpu_time,
+                        rss: a.rss + u.rss,
+                    }),
+                    Err(e) => {
+                        debug!("failed to get session usage: {:?}", e);
+                        continue;
+                    }
+                };
+                peak_rss.set(peak_rss.get().max(usage.rss));
+                if max_rss.map_or(false, |m| usage.rss > m) {
+                    let reason = format!("RSS {} bytes exceeds limit", usage.rss);
+                    break (reason, 128 + libc::SIGKILL);
+                }
+                if max_cpu_time.map_or(false, |m| usage.cpu_time > m) {
+                    let reason = format!("CPU time {} s exceeds limit", usage.cpu_time);
+                    break (reason, 128 + libc::SIGXCPU);
+                }
+            }
+        };
+        tokio::pin!(watchdog);
 
-        let v: usize = loop {
+        let (v, code): (usize, i32) = loop {
             tokio::select! {
                 _ = &mut timeout => {
                     eprintln!("program timed out");
-                    break 1;
+                    record("timed out");
+                    break (1, 124);
+                }
+                _ = &mut requested => {
+                    let sig = shutdown.signal().context("no signal received")?;
+                    eprintln!("interrupted by {}", sig);
+                    record("interrupted");
+                    break (1, 128 + sig as i32);
                 }
-                _ = ctrl_c => {
-                    eprintln!("user interruption");
-                    break 1;
+                (reason, code) = &mut watchdog => {
+                    eprintln!("{}", reason);
+                    record(&reason);
+                    break (1, code);
                 }
-                o = session.child.wait() => {
+                _ = &mut stop => {
+                    eprintln!("found stop file");
+                    record("stopped by stop file");
+                    break (1, 130);
+                }
+                _ = &mut interrupt => {
+                    eprintln!("replayed user interruption");
+                    record("interrupted");
+                    break (1, 130);
+                }
+                o = wait_pipeline(&mut stages) => {
                     println!("program completed");
                     match o {
-                        Ok(o) => {
-                            dbg!(o);
+                        Ok(codes) => {
+                            debug!("exit codes of pipeline: {:?}", codes);
+                            let code = codes.iter().copied().find(|&c| c != 0).unwrap_or(0);
+                            break (0, code);
                         }
                         Err(e) => {
                             error!("cmd error: {:?}", e);
+                            break (0, 1);
                         }
                     }
-                    break 0;
                 }
             }
         };
 
         if v == 1 {
             info!("program was interrupted.");
-            // self.kill()?;
+            let hs = handlers.clone();
+            tokio::task::spawn_blocking(move || {
+                hs.iter()
+                    .try_for_each(|h| h.terminate_gracefully(Duration::from_secs(10)))
+            })
+            .await??;
+            record("terminated");
         } else {
+            record("exited");
             info!("checking orphaned processes ...");
             // self.kill()?;
         }
-        let pps = session.handler().get_processes()?;
-        for p in pps {
-            dbg!(p);
+        for sid in handlers.iter().filter_map(|h| h.id()) {
+            let reaped = crate::process::reap_orphans(sid)?;
+            if !reaped.is_empty() {
+                info!("cleaned up {} orphaned processes: {:?}", reaped.len(), reaped);
+                record(&format!("reaped {} orphans", reaped.len()));
+            }
+        }
+        let stdout = match stdout_copier {
+            Some(copier) => copier.await??,
+            None => vec![],
+        };
+        let mut stderr = vec![];
+        for copier in stderr_copiers {
+            stderr.extend(copier.await??);
+        }
+        if capture {
+            self.output.stdout = String::from_utf8_lossy(&stdout).into_owned();
+            self.output.stderr = String::from_utf8_lossy(&stderr).into_owned();
+            self.output.peak_rss = self.output.peak_rss.max(peak_rss.get());
         }
 
-        Ok(())
+        Ok((code, v == 0))
     }
 
-    /// Run command with session manager.
-    pub fn run(mut self) -> Result<()> {
-        let mut rt = tokio::runtime::Runtime::new().context("tokio runtime failure")?;
-        rt.block_on(self.start())?;
+    /// Run command with session manager, capturing its output instead of
+    /// passing it through.
+    ///
+    /// # Example
+    ///
+    /// ```rust, no_run
+    /// use gosh_runner::session::Session;
+    ///
+    /// let out = Session::new("vasp-program").timeout(3600).run_captured()?;
+    /// println!("exit code {} in {:?}", out.status, out.duration);
+    /// println!("peak RSS {} bytes, stdout: {}", out.peak_rss, out.stdout);
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn run_captured(mut self) -> Result<SessionOutput> {
+        self.capture = true;
+        let t0 = std::time::Instant::now();
+        let status = block_on(|rt| rt.block_on(self.start()))?;
+        let mut output = std::mem::take(&mut self.output);
+        output.status = status;
+        output.duration = t0.elapsed();
+        Ok(output)
+    }
 
-        Ok(())
+    /// Run command with session manager, returning its exit code. It is
+    /// safe to call within a tokio runtime, but `run_async` is preferred
+    /// there.
+    pub fn run(mut self) -> Result<i32> {
+        let code = block_on(|rt| rt.block_on(self.start()))?;
+
+        Ok(code)
+    }
+
+    /// Run command with session manager in current tokio runtime,
+    /// returning its exit code.
+    ///
+    /// # Example
+    ///
+    /// ```rust, no_run
+    /// use gosh_runner::session::Session;
+    ///
+    /// # async fn run() -> anyhow::Result<()> {
+    /// let code = Session::new("vasp-program").timeout(3600).run_async().await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn run_async(mut self) -> Result<i32> {
+        self.start().await
+    }
+}
+
+/// Call `f` with a new tokio runtime for blocking on it. Blocking inside an
+/// ambient runtime panics, so in that case the new runtime is driven from
+/// a scoped thread instead.
+fn block_on<T, F>(f: F) -> Result<T>
+where
+    T: Send,
+    F: FnOnce(&tokio::runtime::Runtime) -> Result<T> + Send,
+{
+    let run = || {
+        let rt = tokio::runtime::Runtime::new().context("tokio runtime failure")?;
+        f(&rt)
+    };
+    if tokio::runtime::Handle::try_current().is_err() {
+        return run();
+    }
+    debug!("called within a tokio runtime, blocking on another thread");
+    std::thread::scope(|s| match s.spawn(run).join() {
+        Ok(r) => r,
+        Err(_) => bail!("session thread panicked"),
+    })
+}
+
+/// Wait for all commands in the pipeline, returning their exit codes in
+/// order.
+async fn wait_pipeline(stages: &mut [crate::process::Session<tokio::process::Child>]) -> Result<Vec<i32>> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let mut codes = vec![];
+    for stage in stages.iter_mut() {
+        let o = stage.child.wait().await?;
+        codes.push(o.code().unwrap_or_else(|| 128 + o.signal().unwrap_or(0)));
+    }
+    Ok(codes)
+}
+
+/// A log file shared by output copiers.
+type LogFile = std::sync::Arc<std::sync::Mutex<std::fs::File>>;
+
+/// Copy lines from `reader` into `log` with timestamp and `tag`, and also
+/// into stdout, or stderr for tagged lines, if `tee` is true. Return all
+/// output if `capture` is true.
+async fn copy_output<R>(
+    reader: R,
+    log: Option<LogFile>,
+    tag: Option<&'static str>,
+    tee: bool,
+    capture: bool,
+) -> Result<Vec<u8>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncBufReadExt;
+
+    let mut reader = tokio::io::BufReader::new(reader);
+    let mut captured = vec![];
+    let mut line = vec![];
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            break;
+        }
+        if tee {
+            match tag {
+                Some(_) => std::io::stderr().write_all(&line)?,
+                None => std::io::stdout().write_all(&line)?,
+            }
+        }
+        if let Some(log) = log.as_ref() {
+            let ts = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches('\n');
+            let mut f = log.lock().unwrap();
+            match tag {
+                Some(tag) => writeln!(f, "{} {} {}", ts, tag, text)?,
+                None => writeln!(f, "{} {}", ts, text)?,
+            }
+        }
+        if capture {
+            captured.extend_from_slice(&line);
+        }
     }
+    Ok(captured)
 }
 // core:1 ends here
 
@@ -147,3 +733,170 @@ fn test_tokio() -> Result<()> {
     Ok(())
 }
 // test:1 ends here
+
+// [[file:../runners.note::e2a94d0c][e2a94d0c]]
+#[test]
+fn test_session_replay() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let f = dir.path().join("trace.json");
+    let session = Session::new("sh").args(["-c", "exit $CODE"]).env("CODE", "3").record(&f);
+    assert_eq!(session.run()?, 3);
+
+    let trace = SessionTrace::from_file(&f)?;
+    assert_eq!(trace.program, "sh");
+    assert_eq!(trace.env["CODE"], "3");
+    assert_eq!(trace.exit_code, Some(3));
+    assert!(trace.events.iter().any(|e| e.event == "exited"));
+    assert_eq!(trace.to_session().run()?, 3);
+    Ok(())
+}
+// e2a94d0c ends here
+
+// [[file:../runners.note::3b8e61f4][3b8e61f4]]
+#[test]
+fn test_session_retries() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    // fail on the first two runs
+    let script = "echo >> count; test $(wc -l < count) -gt 2";
+    let session = Session::new("sh")
+        .args(["-c", script])
+        .dir(dir.path())
+        .retries(3)
+        .retry_delay(Duration::from_millis(10));
+    assert_eq!(session.run()?, 0);
+    let count = std::fs::read_to_string(dir.path().join("count"))?;
+    assert_eq!(count.lines().count(), 3);
+
+    let session = Session::new("false").retries(1).retry_delay(Duration::from_millis(10));
+    assert_eq!(session.run()?, 1);
+    Ok(())
+}
+// 3b8e61f4 ends here
+
+// [[file:../runners.note::9d4c2e07][9d4c2e07]]
+#[test]
+fn test_session_log() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let f = dir.path().join("out.log");
+    let session = Session::new("sh")
+        .args(["-c", "echo hello; echo oops >&2"])
+        .log(&f, true);
+    assert_eq!(session.run()?, 0);
+    let log = std::fs::read_to_string(&f)?;
+    let lines: Vec<_> = log.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines.iter().any(|l| l.ends_with(" hello")));
+    assert!(lines.iter().any(|l| l.ends_with(" [stderr] oops")));
+    Ok(())
+}
+// 9d4c2e07 ends here
+
+// [[file:../runners.note::6fa0d83c][6fa0d83c]]
+#[test]
+fn test_session_stop_file() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let stop = dir.path().join("STOP");
+    // stale stop file is removed on start
+    gut::fs::write_to_file(&stop, "")?;
+    let script = format!("sleep 0.5; touch {}; sleep 10", stop.display());
+    let t0 = std::time::Instant::now();
+    let session = Session::new("sh").args(["-c", &script]).stop_file(&stop);
+    assert_eq!(session.run()?, 130);
+    assert!(t0.elapsed().as_secs() < 10);
+    Ok(())
+}
+// 6fa0d83c ends here
+
+// [[file:../runners.note::b2e5c791][b2e5c791]]
+#[test]
+fn test_session_max_cpu_time() -> Result<()> {
+    // CPU time is spent in a grandchild
+    let session = Session::new("sh")
+        .args(["-c", "sh -c 'while :; do :; done'"])
+        .max_cpu_time(0.5);
+    assert_eq!(session.run()?, 128 + libc::SIGXCPU);
+    Ok(())
+}
+// b2e5c791 ends here
+
+// [[file:../runners.note::0c7d4a2b][0c7d4a2b]]
+#[test]
+fn test_session_run_captured() -> Result<()> {
+    let out = Session::new("sh")
+        .args(["-c", "echo hello; echo oops >&2; sleep 0.5; exit 2"])
+        .run_captured()?;
+    assert_eq!(out.status, 2);
+    assert_eq!(out.stdout, "hello\n");
+    assert_eq!(out.stderr, "oops\n");
+    assert!(out.duration.as_secs_f64() >= 0.5);
+    assert!(out.peak_rss > 0);
+    Ok(())
+}
+// 0c7d4a2b ends here
+
+// [[file:../runners.note::5e91b3c6][5e91b3c6]]
+#[test]
+fn test_session_stdin() -> Result<()> {
+    let out = Session::new("cat").stdin_data("hello\nworld\n").run_captured()?;
+    assert_eq!(out.stdout, "hello\nworld\n");
+
+    let dir = tempfile::tempdir()?;
+    let f = dir.path().join("input.txt");
+    gut::fs::write_to_file(&f, "3\n")?;
+    let session = Session::new("sh").args(["-c", "read n; exit $n"]).stdin_file(&f);
+    assert_eq!(session.run()?, 3);
+    Ok(())
+}
+// 5e91b3c6 ends here
+
+// [[file:../runners.note::a83d6f10][a83d6f10]]
+#[test]
+fn test_session_pipe() -> Result<()> {
+    let mut tr = std::process::Command::new("tr");
+    tr.args(["a-z", "A-Z"]);
+    let out = Session::new("echo")
+        .arg("hello world")
+        .pipe(tr)
+        .pipe(std::process::Command::new("rev"))
+        .run_captured()?;
+    assert_eq!(out.status, 0);
+    assert_eq!(out.stdout, "DLROW OLLEH\n");
+
+    // the first failure in the pipeline
+    let out = Session::new("sh")
+        .args(["-c", "exit 3"])
+        .pipe(std::process::Command::new("cat"))
+        .run_captured()?;
+    assert_eq!(out.status, 3);
+
+    // terminate the whole pipeline on timeout
+    let mut sleep = std::process::Command::new("sleep");
+    sleep.arg("10");
+    let t0 = std::time::Instant::now();
+    let code = Session::new("sleep").arg("10").pipe(sleep).timeout(1).run()?;
+    assert_eq!(code, 124);
+    assert!(t0.elapsed().as_secs() < 10);
+    Ok(())
+}
+// a83d6f10 ends here
+
+// [[file:../runners.note::3fb8e2d4][3fb8e2d4]]
+#[tokio::test]
+async fn test_session_run_in_runtime() -> Result<()> {
+    let code = Session::new("sh").args(["-c", "exit 2"]).run_async().await?;
+    assert_eq!(code, 2);
+    // blocking run within a runtime should not panic
+    let code = Session::new("sh").args(["-c", "exit 3"]).run()?;
+    assert_eq!(code, 3);
+    let out = Session::new("echo").arg("hi").run_captured()?;
+    assert_eq!(out.stdout, "hi\n");
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_session_run_in_multi_thread_runtime() -> Result<()> {
+    let code = Session::new("sh").args(["-c", "exit 3"]).run()?;
+    assert_eq!(code, 3);
+    Ok(())
+}
+// 3fb8e2d4 ends here
@@ -0,0 +1,122 @@
+// [[file:../runners.note::f47b2d90][f47b2d90]]
+//! Coordinated shutdown on termination signals
+use super::*;
+
+use crate::job::Db;
+use nix::sys::signal::Signal;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+// f47b2d90 ends here
+
+// [[file:../runners.note::0c93e6a5][0c93e6a5]]
+/// Wait until SIGINT or SIGTERM is received, returning the signal name.
+pub async fn shutdown_signal() -> Result<&'static str> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let name = tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    };
+    info!("received {}, shutting down ...", name);
+    Ok(name)
+}
+
+/// Wait until SIGINT, SIGTERM, SIGHUP or SIGQUIT is received, as when a
+/// wrapper is killed by the batch scheduler or its terminal is closed,
+/// returning the signal.
+pub async fn termination_signal() -> Result<Signal> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigquit = signal(SignalKind::quit())?;
+    let sig = tokio::select! {
+        _ = sigterm.recv() => Signal::SIGTERM,
+        _ = sigint.recv() => Signal::SIGINT,
+        _ = sighup.recv() => Signal::SIGHUP,
+        _ = sigquit.recv() => Signal::SIGQUIT,
+    };
+    info!("received {}, terminating ...", sig);
+    Ok(sig)
+}
+
+/// A handle for being notified of shutdown requested by signals.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    token: CancellationToken,
+    signal: Arc<Mutex<Option<Signal>>>,
+    _listener: Arc<Listener>,
+}
+
+// stop listening when the last handle is dropped
+#[derive(Debug)]
+struct Listener(tokio::task::JoinHandle<()>);
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+impl Shutdown {
+    /// Listen for SIGINT, SIGTERM, SIGHUP or SIGQUIT in background, see
+    /// `termination_signal`. Must be called inside tokio runtime.
+    pub fn install() -> Self {
+        let token = CancellationToken::new();
+        let signal = Arc::new(Mutex::new(None));
+        let (t, s) = (token.clone(), signal.clone());
+        let task = tokio::spawn(async move {
+            match termination_signal().await {
+                Ok(sig) => {
+                    *s.lock().unwrap() = Some(sig);
+                    t.cancel();
+                }
+                Err(e) => error!("failed to listen for signals: {:?}", e),
+            }
+        });
+        Self {
+            token,
+            signal,
+            _listener: Arc::new(Listener(task)),
+        }
+    }
+
+    /// Return the signal requesting shutdown, if received.
+    pub fn signal(&self) -> Option<Signal> {
+        *self.signal.lock().unwrap()
+    }
+
+    /// Return a token cancelled when shutdown is requested.
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// Return true if shutdown has been requested.
+    pub fn is_requested(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Wait until shutdown is requested.
+    pub async fn requested(&self) {
+        self.token.cancelled().await
+    }
+}
+
+/// Run `fut` until it completes or a shutdown signal is received. On
+/// shutdown, running jobs in `db` are terminated with `grace` period.
+pub async fn run_until_shutdown<F>(db: &Db, fut: F, grace: Duration) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    tokio::select! {
+        r = fut => r,
+        r = shutdown_signal() => {
+            r?;
+            db.shutdown(grace).await;
+            Ok(())
+        }
+    }
+}
+// 0c93e6a5 ends here
@@ -0,0 +1,200 @@
+// [[file:../runners.note::0b6e4d92][0b6e4d92]]
+//! Submit jobs dropped as files into a spool directory
+use super::*;
+
+use crate::auth::User;
+use crate::job::{Db, Job, JobId, ScratchFull};
+use std::os::unix::fs::MetadataExt;
+use std::time::{Duration, SystemTime};
+// 0b6e4d92 ends here
+
+// [[file:../runners.note::a7c31f5e][a7c31f5e]]
+/// The suffix of job files to be picked up.
+const JOB_SUFFIX: &str = ".job.toml";
+
+/// Return job files in `dir` ready for submission. Recently modified files
+/// are skipped, since they may be still being copied in.
+fn find_job_files(dir: &Path, settle: Duration) -> Result<Vec<PathBuf>> {
+    let now = SystemTime::now();
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir).with_context(|| format!("read spool dir {:?}", dir))? {
+        let p = entry?.path();
+        let is_job = p.file_name().and_then(|s| s.to_str()).map_or(false, |s| s.ends_with(JOB_SUFFIX));
+        // symlinks may point to job files of others
+        let meta = p.symlink_metadata()?;
+        if !is_job || !meta.is_file() {
+            continue;
+        }
+        let modified = meta.modified()?;
+        if now.duration_since(modified).unwrap_or_default() >= settle {
+            files.push(p);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Return the user submitting job `file` dropped in: the server user as
+/// admin if owning it, or the normal user of the file owner otherwise, so
+/// that the job runs as its owner.
+fn file_owner(file: &Path) -> Result<User> {
+    let uid = file.symlink_metadata()?.uid();
+    if uid == nix::unistd::geteuid().as_raw() {
+        return Ok(User::current());
+    }
+    let user = nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(uid))?.with_context(|| format!("unknown owner of {:?}", file))?;
+    Ok(User::new(&user.name))
+}
+
+/// Parse job `file`, and upload its extra files from the spool directory.
+/// Extra files must be inside the spool directory and owned by the owner
+/// of job `file`. The job is deleted if any of them fails.
+async fn submit_job_file(db: &mut Db, file: &Path) -> Result<JobId> {
+    let job = Job::from_toml(&gut::fs::read_file(file)?).with_context(|| format!("parse job file {:?}", file))?;
+    let dir = file.parent().unwrap_or(".".as_ref());
+    let uid = file.symlink_metadata()?.uid();
+    let mut extra_files = vec![];
+    for f in job.extra_files() {
+        let path = crate::job::job_file_path(dir, f)?;
+        ensure!(
+            path.symlink_metadata()?.uid() == uid,
+            "extra file {:?} not owned by owner of job file",
+            f
+        );
+        extra_files.push((f.to_string_lossy().into_owned(), path));
+    }
+    let id = db.try_insert_job_as(job, &file_owner(file)?).await?;
+    let r: Result<()> = async {
+        for (f, path) in extra_files {
+            let body = tokio::fs::read(&path)
+                .await
+                .with_context(|| format!("read extra file {:?}", f))?;
+            db.put_job_file(id, f, body.into()).await?;
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(e) = r {
+        db.delete_job(id).await?;
+        return Err(e);
+    }
+    Ok(id)
+}
+
+/// Wait for job `id` to finish, and write its files and status into
+/// `results` directory.
+async fn collect_results(mut db: Db, id: JobId, results: PathBuf) -> Result<()> {
+    let r = db.wait_job(id).await;
+    std::fs::create_dir_all(&results)?;
+    for f in db.list_job_files(id).await? {
+        if let Some(name) = f.file_name() {
+            std::fs::copy(&f, results.join(name))?;
+        }
+    }
+    let status = match r {
+        Ok(result) => {
+            gut::fs::write_to_file(results.join("result.json"), &serde_json::to_string_pretty(&result)?)?;
+            format!("{:?}", result.status)
+        }
+        Err(e) => format!("Failed: {:?}", e),
+    };
+    gut::fs::write_to_file(results.join("status"), &status)?;
+    db.delete_job(id).await?;
+    Ok(())
+}
+
+/// Watch `dir` every `interval` seconds for `*.job.toml` files dropped in.
+/// Each job file is renamed with a `.queued` suffix when submitted, and the
+/// results are written into `<name>.results` directory alongside.
+pub async fn watch_spool(db: Db, dir: &Path, interval: f64) -> Result<()> {
+    info!("watching spool directory {:?}", dir);
+    let settle = Duration::from_secs_f64(interval);
+    loop {
+        match find_job_files(dir, settle) {
+            Ok(files) => {
+                for file in files {
+                    // a bad job file never stops watching for others
+                    match spool_job_file(&db, &file).await {
+                        Ok(true) => {}
+                        Ok(false) => break,
+                        Err(e) => warn!("failed to spool job file {:?}: {:?}", file, e),
+                    }
+                }
+            }
+            Err(e) => warn!("failed to find job files: {:?}", e),
+        }
+        tokio::time::sleep(Duration::from_secs_f64(interval)).await;
+    }
+}
+
+/// Submit job `file` found in spool directory. Return false if it should
+/// be submitted later.
+async fn spool_job_file(db: &Db, file: &Path) -> Result<bool> {
+    let path = file.to_string_lossy();
+    let name = path.trim_end_matches(JOB_SUFFIX);
+    let results = PathBuf::from(format!("{}.results", name));
+    let queued = PathBuf::from(format!("{}.queued", path));
+    std::fs::rename(file, &queued)?;
+    let mut db = db.clone();
+    match submit_job_file(&mut db, &queued).await {
+        Ok(id) => {
+            info!("job {} submitted from {:?}", id, file);
+            tokio::spawn(async move {
+                if let Err(e) = collect_results(db, id, results).await {
+                    warn!("failed to collect results for job {}: {:?}", id, e);
+                }
+            });
+        }
+        Err(e) if e.is::<ScratchFull>() => {
+            // try again later
+            info!("{}, job file {:?} will be submitted later", e, file);
+            std::fs::rename(&queued, file)?;
+            return Ok(false);
+        }
+        Err(e) => {
+            warn!("failed to submit job file {:?}: {:?}", file, e);
+            std::fs::create_dir_all(&results)?;
+            gut::fs::write_to_file(results.join("status"), &format!("Invalid: {:?}", e))?;
+        }
+    }
+    Ok(true)
+}
+// a7c31f5e ends here
+
+// [[file:../runners.note::e5d08b13][e5d08b13]]
+#[test]
+fn test_find_job_files() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    gut::fs::write_to_file(dir.path().join("a.job.toml"), "script = \"echo hi\"")?;
+    gut::fs::write_to_file(dir.path().join("b.txt"), "")?;
+    let files = find_job_files(dir.path(), Duration::from_secs(0))?;
+    assert_eq!(files, vec![dir.path().join("a.job.toml")]);
+    let files = find_job_files(dir.path(), Duration::from_secs(60))?;
+    assert!(files.is_empty());
+
+    let job = Job::from_toml("script = \"echo hi\"")?;
+    assert!(job.extra_files().is_empty());
+    Ok(())
+}
+// e5d08b13 ends here
+
+// [[file:../runners.note::f3a2c7d4][f3a2c7d4]]
+#[tokio::test]
+async fn test_submit_job_file() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut db = Db::new();
+    let file = dir.path().join("a.job.toml");
+    gut::fs::write_to_file(&file, "script = \"echo hi\"\nextra_files = [\"../passwd\"]")?;
+    assert!(submit_job_file(&mut db, &file).await.is_err());
+    // no job left behind by failed upload
+    gut::fs::write_to_file(&file, "script = \"echo hi\"\nextra_files = [\"missing.txt\"]")?;
+    assert!(submit_job_file(&mut db, &file).await.is_err());
+    assert!(db.get_job_list().await.is_empty());
+
+    gut::fs::write_to_file(dir.path().join("input.txt"), "42")?;
+    gut::fs::write_to_file(&file, "script = \"echo hi\"\nextra_files = [\"input.txt\"]")?;
+    let id = submit_job_file(&mut db, &file).await?;
+    assert_eq!(db.get_job_file(id, "input.txt".as_ref()).await?, b"42".to_vec());
+    Ok(())
+}
+// f3a2c7d4 ends here
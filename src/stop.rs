@@ -2,26 +2,42 @@
 //! A simple file based handler for user interruption.
 use super::*;
 use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::process::SessionHandler;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
 /// A simple file based user interruption handler: return Err if STOP file
 /// exits.
+#[derive(Debug, Clone)]
 pub struct StopFileHandler {
     stop_file: PathBuf,
 }
 
 impl StopFileHandler {
     pub fn new() -> Self {
-        let stop_file = PathBuf::from("STOP");
+        Self::with_path("STOP")
+    }
+
+    /// Create a handler watching a custom stop file in `path`. Any existing
+    /// stop file will be removed.
+    pub fn with_path<P: AsRef<Path>>(path: P) -> Self {
+        let stop_file = path.as_ref().to_owned();
         if stop_file.exists() {
-            println!("Removing existing STOP file ...");
+            println!("Removing existing STOP file {:?} ...", stop_file);
             let _ = std::fs::remove_file(&stop_file);
         }
-        Self {
-            stop_file: PathBuf::from("STOP"),
-        }
+        Self { stop_file }
+    }
+
+    /// Return the path to the stop file.
+    pub fn path(&self) -> &Path {
+        &self.stop_file
     }
 
-    fn is_interrupted(&self) -> bool {
+    /// Return true if the stop file exists.
+    pub fn is_interrupted(&self) -> bool {
         self.stop_file.exists()
     }
 
@@ -35,3 +51,70 @@ impl StopFileHandler {
     }
 }
 // 809ad587 ends here
+
+// [[file:../runners.note::2e7c9a41][2e7c9a41]]
+impl StopFileHandler {
+    /// Wait until the stop file appears, checking every `interval`.
+    pub async fn wait(&self, interval: Duration) {
+        while !self.is_interrupted() {
+            tokio::time::sleep(interval).await;
+        }
+        info!("found stop file: {:?}", self.stop_file);
+    }
+
+    /// Spawn a task polling the stop file every `interval`, and call
+    /// `callback` once it appears.
+    pub fn spawn_watcher<F>(&self, interval: Duration, callback: F) -> JoinHandle<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let h = self.clone();
+        tokio::spawn(async move {
+            h.wait(interval).await;
+            callback();
+        })
+    }
+
+    /// Spawn a task cancelling `token` once the stop file appears. The task
+    /// exits without doing anything if `token` is cancelled elsewhere.
+    pub fn cancel_on_stop(&self, token: CancellationToken, interval: Duration) -> JoinHandle<()> {
+        let h = self.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = h.wait(interval) => token.cancel(),
+                _ = token.cancelled() => {}
+            }
+        })
+    }
+
+    /// Spawn a task terminating the session of `handler` gracefully once the
+    /// stop file appears: SIGTERM first, and SIGKILL after `grace` period.
+    pub fn terminate_on_stop(&self, handler: SessionHandler, grace: Duration, interval: Duration) -> JoinHandle<()> {
+        let h = self.clone();
+        tokio::spawn(async move {
+            h.wait(interval).await;
+            info!("terminating session {:?} on stop file", handler.id());
+            let r = tokio::task::spawn_blocking(move || handler.terminate_gracefully(grace)).await;
+            if let Ok(Err(e)) = r {
+                warn!("failed to terminate session: {:?}", e);
+            }
+        })
+    }
+}
+// 2e7c9a41 ends here
+
+// [[file:../runners.note::d6a1f835][d6a1f835]]
+#[tokio::test]
+async fn test_stop_file_watcher() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let stop = StopFileHandler::with_path(dir.path().join("STOP"));
+    let token = CancellationToken::new();
+    let task = stop.cancel_on_stop(token.clone(), Duration::from_millis(10));
+    assert!(!token.is_cancelled());
+    gut::fs::write_to_file(stop.path(), "")?;
+    task.await?;
+    assert!(token.is_cancelled());
+    assert!(stop.handle_user_interruption().is_err());
+    Ok(())
+}
+// d6a1f835 ends here
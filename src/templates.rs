@@ -0,0 +1,134 @@
+// [[file:../runners.note::9e3b51d7][9e3b51d7]]
+//! Run-script templates for common computational codes
+use super::*;
+
+use std::collections::BTreeMap;
+// 9e3b51d7 ends here
+
+// [[file:../runners.note::4c0a7f2e][4c0a7f2e]]
+/// Builtin templates. Placeholders are written as `{{name}}` or
+/// `{{name:default}}`. The job input is saved in `job.inp` and also fed
+/// into stdin.
+const BUILTIN: &[(&str, &str)] = &[
+    (
+        "gaussian",
+        "#!/usr/bin/env bash
+export GAUSS_SCRDIR={{scratch:.}}
+{{program:g16}} < job.inp
+",
+    ),
+    (
+        "orca",
+        "#!/usr/bin/env bash
+# orca requires full path for parallel runs
+{{program:orca}} job.inp
+",
+    ),
+    (
+        "vasp",
+        "#!/usr/bin/env bash
+{{mpirun:mpirun}} -np {{nprocs:1}} {{program:vasp_std}}
+",
+    ),
+    (
+        "xtb",
+        "#!/usr/bin/env bash
+cp job.inp input.xyz
+{{program:xtb}} input.xyz {{args:--gfn 2}}
+",
+    ),
+    (
+        "psi4",
+        "#!/usr/bin/env bash
+{{program:psi4}} -n {{nthreads:1}} -i job.inp -o stdout
+",
+    ),
+];
+
+/// The directory for user defined templates, overriding builtin ones:
+/// `~/.config/gosh-runner/templates/`. A template named `foo` is read from
+/// file `foo.sh` in it.
+pub fn user_template_dir() -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_else(|_| ".".into());
+    Path::new(&home).join(".config/gosh-runner/templates")
+}
+
+/// Return the template `name`, user defined ones first.
+fn find_template(name: &str) -> Result<String> {
+    let path = user_template_dir().join(format!("{}.sh", name));
+    if path.is_file() {
+        debug!("use user defined template: {:?}", path);
+        return gut::fs::read_file(&path);
+    }
+    BUILTIN
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, t)| t.to_string())
+        .ok_or(format_err!("template not found: {}", name))
+}
+
+/// Return names of all available templates.
+pub fn list_templates() -> Vec<String> {
+    let mut names: Vec<_> = BUILTIN.iter().map(|(n, _)| n.to_string()).collect();
+    if let Ok(entries) = std::fs::read_dir(user_template_dir()) {
+        for p in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+            if p.extension().map_or(false, |x| x == "sh") {
+                if let Some(stem) = p.file_stem() {
+                    names.push(stem.to_string_lossy().into_owned());
+                }
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Fill placeholders in `template` using `params`. Return error if any
+/// placeholder without default value is not given.
+fn render(template: &str, params: &BTreeMap<String, String>) -> Result<String> {
+    let re = regex::Regex::new(r"\{\{(\w+)(?::([^}]*))?\}\}").unwrap();
+    let mut missing = vec![];
+    let script = re.replace_all(template, |caps: &regex::Captures| {
+        let key = &caps[1];
+        match (params.get(key), caps.get(2)) {
+            (Some(v), _) => v.to_owned(),
+            (None, Some(default)) => default.as_str().to_owned(),
+            (None, None) => {
+                missing.push(key.to_owned());
+                String::new()
+            }
+        }
+    });
+    ensure!(missing.is_empty(), "missing template parameters: {:?}", missing);
+    Ok(script.into_owned())
+}
+
+/// The template and parameters a job script was rendered from, kept in the
+/// job spec for reference.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct TemplateSpec {
+    pub name: String,
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+}
+
+/// Render run script from template `name` with `params`.
+pub fn render_template(name: &str, params: &[(&str, &str)]) -> Result<String> {
+    let template = find_template(name)?;
+    let params = params.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    render(&template, &params)
+}
+// 4c0a7f2e ends here
+
+// [[file:../runners.note::b8e2d064][b8e2d064]]
+#[test]
+fn test_templates() -> Result<()> {
+    let script = render_template("vasp", &[("nprocs", "16")])?;
+    assert!(script.contains("mpirun -np 16 vasp_std"));
+    assert!(list_templates().contains(&"orca".to_string()));
+    assert!(render("{{foo}}", &BTreeMap::new()).is_err());
+    assert!(render_template("no-such-code", &[]).is_err());
+    Ok(())
+}
+// b8e2d064 ends here
@@ -0,0 +1,170 @@
+// [[file:../runners.note::4e7b2a90][4e7b2a90]]
+//! Throttling jobs by CPU temperature and load of the machine
+use super::*;
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+// 4e7b2a90 ends here
+
+// [[file:../runners.note::a1c85f3d][a1c85f3d]]
+/// A policy deciding when to hold back jobs to keep the machine usable,
+/// such as a desktop during the day.
+pub trait ThrottlePolicy: Send + Sync {
+    /// Return true if no more jobs should be started for now.
+    fn is_throttled(&self) -> bool;
+
+    /// Return the number of running jobs kept running while throttled, and
+    /// jobs started later are paused, or None to leave running jobs alone.
+    fn running_limit(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// Return the highest CPU temperature in Celsius from thermal zones in
+/// sysfs, or None if not available.
+pub fn cpu_temperature() -> Option<f64> {
+    let entries = std::fs::read_dir("/sys/class/thermal").ok()?;
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with("thermal_zone"))
+        .filter_map(|e| std::fs::read_to_string(e.path().join("temp")).ok())
+        .filter_map(|s| s.trim().parse::<f64>().ok())
+        // in millidegree Celsius
+        .map(|t| t / 1000.0)
+        .fold(None, |max, t| Some(max.map_or(t, |m: f64| m.max(t))))
+}
+
+/// Return the 1-minute load average from `/proc/loadavg`.
+pub fn load_average() -> Option<f64> {
+    let s = std::fs::read_to_string("/proc/loadavg").ok()?;
+    s.split_whitespace().next()?.parse().ok()
+}
+
+/// Throttle when CPU temperature or load average exceeds thresholds, and
+/// release when both stay below `resume_ratio` of the thresholds for `hold`
+/// time, to avoid flapping. Paused jobs no longer add to the load average,
+/// so it drops within a minute after pausing them.
+#[derive(Debug, Default)]
+pub struct SensorThrottle {
+    /// The maximum CPU temperature in Celsius
+    pub max_temperature: Option<f64>,
+    /// The maximum 1-minute load average
+    pub max_load: Option<f64>,
+    // the number of running jobs kept running while throttled
+    running_limit: Option<usize>,
+    resume_ratio: f64,
+    hold: Duration,
+    // if throttled, and since when readings are below thresholds
+    state: Mutex<(bool, Option<Instant>)>,
+}
+
+impl SensorThrottle {
+    /// Throttle above `max_temperature` in Celsius or `max_load`.
+    pub fn new(max_temperature: Option<f64>, max_load: Option<f64>) -> Self {
+        Self {
+            max_temperature,
+            max_load,
+            resume_ratio: 0.9,
+            hold: Duration::from_secs(300),
+            ..Default::default()
+        }
+    }
+
+    /// Also pause running jobs while throttled if `on`.
+    pub fn pause(mut self, on: bool) -> Self {
+        self.running_limit = if on { Some(0) } else { None };
+        self
+    }
+
+    /// Reduce the running jobs to `n` while throttled, pausing jobs started
+    /// later, instead of pausing all of them.
+    pub fn reduce_pool(mut self, n: usize) -> Self {
+        self.running_limit = Some(n);
+        self
+    }
+
+    /// Release throttling only after readings stay below thresholds for
+    /// `hold` time, 5 minutes by default.
+    pub fn hold(mut self, hold: Duration) -> Self {
+        self.hold = hold;
+        self
+    }
+
+    /// Decide with `temperature` and `load` readings at time `now`.
+    fn decide(&self, temperature: Option<f64>, load: Option<f64>, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (throttled, calm_since) = &mut *state;
+        let ratio = if *throttled { self.resume_ratio } else { 1.0 };
+        let exceeds = |value: Option<f64>, max: Option<f64>| match (value, max) {
+            (Some(v), Some(m)) => v > m * ratio,
+            _ => false,
+        };
+        let high = exceeds(temperature, self.max_temperature) || exceeds(load, self.max_load);
+        let decided = if high {
+            *calm_since = None;
+            true
+        } else if *throttled {
+            let since = *calm_since.get_or_insert(now);
+            now.duration_since(since) < self.hold
+        } else {
+            false
+        };
+        if decided != *throttled {
+            info!(
+                "throttle {}: temperature={:?}, load={:?}",
+                if decided { "on" } else { "off" },
+                temperature,
+                load
+            );
+            *throttled = decided;
+            *calm_since = None;
+        }
+        decided
+    }
+}
+
+impl ThrottlePolicy for SensorThrottle {
+    fn is_throttled(&self) -> bool {
+        let temperature = self.max_temperature.and_then(|_| cpu_temperature());
+        let load = self.max_load.and_then(|_| load_average());
+        self.decide(temperature, load, Instant::now())
+    }
+
+    fn running_limit(&self) -> Option<usize> {
+        self.running_limit
+    }
+}
+
+/// How often throttle policies are checked.
+pub const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Wait until `policy` is not throttled.
+pub async fn wait_unthrottled(policy: &dyn ThrottlePolicy) {
+    while policy.is_throttled() {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+    }
+}
+// a1c85f3d ends here
+
+// [[file:../runners.note::67d0e9b2][67d0e9b2]]
+#[test]
+fn test_sensor_throttle() {
+    let throttle = SensorThrottle::new(Some(80.0), Some(8.0)).hold(Duration::ZERO);
+    let now = Instant::now();
+    assert!(!throttle.decide(Some(60.0), Some(2.0), now));
+    assert!(throttle.decide(Some(85.0), Some(2.0), now));
+    // still throttled until below 90% of the threshold
+    assert!(throttle.decide(Some(75.0), Some(2.0), now));
+    assert!(!throttle.decide(Some(70.0), Some(2.0), now));
+    assert!(throttle.decide(None, Some(9.0), now));
+
+    // released only after staying low for the hold time
+    let throttle = SensorThrottle::new(None, Some(8.0)).hold(Duration::from_secs(300));
+    assert!(throttle.decide(None, Some(9.0), now));
+    assert!(throttle.decide(None, Some(2.0), now + Duration::from_secs(60)));
+    assert!(throttle.decide(None, Some(9.0), now + Duration::from_secs(120)));
+    assert!(throttle.decide(None, Some(2.0), now + Duration::from_secs(180)));
+    assert!(!throttle.decide(None, Some(2.0), now + Duration::from_secs(480)));
+    assert!(load_average().is_some());
+}
+// 67d0e9b2 ends here
@@ -0,0 +1,178 @@
+// [[file:../runners.note::2f8c5a17][2f8c5a17]]
+//! Validation of job scripts at submission
+use super::*;
+// 2f8c5a17 ends here
+
+// [[file:../runners.note::b4d91e06][b4d91e06]]
+/// A problem found in a job script.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ScriptError {
+    /// The script is empty or only has whitespace
+    Empty,
+    /// The script contains a NUL byte at `offset`
+    NulByte { offset: usize },
+    /// The script is larger than `max` bytes
+    TooLarge { size: usize, max: usize },
+    /// The shell script has syntax errors reported by `bash -n`
+    Syntax { message: String },
+    /// A command denied by admins is called on `line`
+    Forbidden { command: String, line: usize },
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty script"),
+            Self::NulByte { offset } => write!(f, "NUL byte at offset {}", offset),
+            Self::TooLarge { size, max } => write!(f, "script size {} exceeds limit {}", size, max),
+            Self::Syntax { message } => write!(f, "syntax error: {}", message),
+            Self::Forbidden { command, line } => write!(f, "forbidden command {:?} on line {}", command, line),
+        }
+    }
+}
+
+/// Error returned when a job script is rejected at submission, with all
+/// problems found.
+#[derive(Debug, Clone)]
+pub struct InvalidScript {
+    pub errors: Vec<ScriptError>,
+}
+
+impl std::fmt::Display for InvalidScript {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "invalid job script: {}", self.errors.iter().join("; "))
+    }
+}
+
+impl std::error::Error for InvalidScript {}
+
+/// Rules for validating job scripts at submission, configurable by admins
+/// in TOML format.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ScriptPolicy {
+    /// The maximum size of a script in bytes
+    pub max_size: usize,
+    /// Check syntax of shell scripts with `bash -n`
+    pub syntax_check: bool,
+    /// Commands not allowed in scripts, such as "rm" or "curl"
+    pub denylist: Vec<String>,
+}
+
+impl Default for ScriptPolicy {
+    fn default() -> Self {
+        Self {
+            max_size: 1 << 20,
+            syntax_check: false,
+            denylist: vec![],
+        }
+    }
+}
+
+impl ScriptPolicy {
+    /// Read the policy from TOML file `path`.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let s = gut::fs::read_file(path)?;
+        let policy = Self::from_toml(&s).with_context(|| format!("invalid script policy {:?}", path))?;
+        Ok(policy)
+    }
+
+    /// Validate `script`, which is run by a shell if `is_shell`. Return all
+    /// problems found.
+    pub fn validate(&self, script: &str, is_shell: bool) -> Result<(), InvalidScript> {
+        let mut errors = vec![];
+        if script.trim().is_empty() {
+            errors.push(ScriptError::Empty);
+        }
+        if let Some(offset) = script.find('\0') {
+            errors.push(ScriptError::NulByte { offset });
+        }
+        if script.len() > self.max_size {
+            errors.push(ScriptError::TooLarge {
+                size: script.len(),
+                max: self.max_size,
+            });
+        }
+        for (i, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                continue;
+            }
+            let words = line.split(|c: char| c.is_whitespace() || ";|&()`".contains(c));
+            for word in words.filter(|w| !w.is_empty()) {
+                let command = word.rsplit('/').next().unwrap_or(word);
+                if self.denylist.iter().any(|d| d == command) {
+                    errors.push(ScriptError::Forbidden {
+                        command: command.to_owned(),
+                        line: i + 1,
+                    });
+                }
+            }
+        }
+        // only check syntax if the script could be read
+        if self.syntax_check && is_shell && errors.is_empty() {
+            if let Err(message) = bash_syntax_check(script) {
+                errors.push(ScriptError::Syntax { message });
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(InvalidScript { errors })
+        }
+    }
+}
+
+/// Check syntax of shell `script` using `bash -n`.
+fn bash_syntax_check(script: &str) -> Result<(), String> {
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new("bash")
+        .arg("-n")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("failed to run bash: {}", e))?;
+    let mut stdin = child.stdin.take().expect("bash stdin");
+    // ignore broken pipe when bash exits early
+    let _ = stdin.write_all(script.as_bytes());
+    drop(stdin);
+    let out = child.wait_with_output().map_err(|e| e.to_string())?;
+    if out.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&out.stderr).trim().to_owned())
+    }
+}
+// b4d91e06 ends here
+
+// [[file:../runners.note::60ea3b79][60ea3b79]]
+#[test]
+fn test_script_policy() {
+    let policy = ScriptPolicy {
+        syntax_check: true,
+        denylist: vec!["curl".into()],
+        ..Default::default()
+    };
+    assert!(policy.validate("#!/bin/bash\necho hi\n", true).is_ok());
+
+    let e = policy.validate("  \n", true).unwrap_err();
+    assert_eq!(e.errors, vec![ScriptError::Empty]);
+
+    let e = policy.validate("#!/bin/bash\nx=1; /usr/bin/curl -O url\n", true).unwrap_err();
+    assert_eq!(
+        e.errors,
+        vec![ScriptError::Forbidden {
+            command: "curl".into(),
+            line: 2
+        }]
+    );
+
+    let e = policy.validate("#!/bin/bash\nif true; then\n", true).unwrap_err();
+    assert!(matches!(e.errors[0], ScriptError::Syntax { .. }));
+    // not a shell script
+    assert!(policy.validate("if True:\n", false).is_ok());
+}
+// 60ea3b79 ends here
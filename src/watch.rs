@@ -0,0 +1,93 @@
+// [[file:../runners.note::71b4e0c9][71b4e0c9]]
+//! Watch job working directory for new files using inotify
+use super::*;
+
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+// 71b4e0c9 ends here
+
+// [[file:../runners.note::c0e59a37][c0e59a37]]
+/// Convert shell glob `pattern` with `*` and `?` into an anchored regex.
+fn glob_to_regex(pattern: &str) -> Result<regex::Regex> {
+    let mut re = String::from("^");
+    for c in pattern.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    let re = regex::Regex::new(&re)?;
+    Ok(re)
+}
+
+/// Wait until a file with name matching glob `pattern` appears in `dir`,
+/// and return its name. A file appears when it is closed after writing or
+/// moved into `dir`, so that it is complete. Return None if `timeout`
+/// elapsed.
+pub fn wait_for_file(dir: &Path, pattern: &str, timeout: Option<Duration>) -> Result<Option<String>> {
+    let re = glob_to_regex(pattern)?;
+    let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+    let flags = AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_MOVED_TO;
+    inotify.add_watch(dir, flags).with_context(|| format!("watch {:?}", dir))?;
+
+    // check existing files after the watch is added to avoid missing any
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if re.is_match(&name) {
+            return Ok(Some(name));
+        }
+    }
+
+    let deadline = timeout.map(|t| Instant::now() + t);
+    loop {
+        let wait_ms = match deadline {
+            Some(d) => match d.checked_duration_since(Instant::now()) {
+                Some(left) => left.as_millis().min(i32::MAX as u128) as i32,
+                None => return Ok(None),
+            },
+            None => -1,
+        };
+        let mut fds = [PollFd::new(inotify.as_raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, wait_ms) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {}
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(e) => return Err(e.into()),
+        }
+        for event in inotify.read_events()? {
+            if let Some(name) = event.name {
+                let name = name.to_string_lossy().into_owned();
+                if re.is_match(&name) {
+                    return Ok(Some(name));
+                }
+            }
+        }
+    }
+}
+// c0e59a37 ends here
+
+// [[file:../runners.note::5a8f13d2][5a8f13d2]]
+#[test]
+fn test_wait_for_file() -> Result<()> {
+    assert!(glob_to_regex("forces.*")?.is_match("forces.dat"));
+    assert!(!glob_to_regex("forces.*")?.is_match("xforces.dat"));
+
+    let tdir = tempfile::tempdir()?;
+    let dir = tdir.path().to_owned();
+    let t = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(100));
+        gut::fs::write_to_file(dir.join("CONVERGED"), "").unwrap();
+    });
+    let found = wait_for_file(tdir.path(), "CONV*", Some(Duration::from_secs(5)))?;
+    assert_eq!(found.as_deref(), Some("CONVERGED"));
+    t.join().unwrap();
+
+    let found = wait_for_file(tdir.path(), "none", Some(Duration::from_millis(50)))?;
+    assert!(found.is_none());
+    Ok(())
+}
+// 5a8f13d2 ends here
@@ -0,0 +1,186 @@
+// [[file:../runners.note::8b3f1d62][8b3f1d62]]
+//! Time-of-day and calendar based execution windows
+use super::*;
+
+use chrono::{DateTime, Datelike, Local, NaiveTime, Weekday};
+// 8b3f1d62 ends here
+
+// [[file:../runners.note::d40c7e95][d40c7e95]]
+/// A period when jobs are allowed to run, like "20:00-08:00", "sat-sun", or
+/// "mon-fri 12:00-13:00". A time range ending before its start crosses
+/// midnight.
+#[derive(Debug, Clone, PartialEq)]
+struct Window {
+    /// Allowed days of week, Monday first
+    days: [bool; 7],
+    /// Allowed time range of day, or the whole day if None
+    time: Option<(NaiveTime, NaiveTime)>,
+}
+
+fn parse_weekday(s: &str) -> Result<Weekday> {
+    s.parse().map_err(|_| format_err!("invalid day of week: {:?}", s))
+}
+
+fn parse_days(s: &str) -> Result<[bool; 7]> {
+    let mut days = [false; 7];
+    match s {
+        "weekends" => days[5..].fill(true),
+        "weekdays" => days[..5].fill(true),
+        _ => match s.split_once('-') {
+            Some((a, b)) => {
+                let (a, b) = (parse_weekday(a)?, parse_weekday(b)?);
+                let mut d = a;
+                days[d.num_days_from_monday() as usize] = true;
+                while d != b {
+                    d = d.succ();
+                    days[d.num_days_from_monday() as usize] = true;
+                }
+            }
+            None => days[parse_weekday(s)?.num_days_from_monday() as usize] = true,
+        },
+    }
+    Ok(days)
+}
+
+fn parse_time_range(s: &str) -> Result<(NaiveTime, NaiveTime)> {
+    let (a, b) = s
+        .split_once('-')
+        .with_context(|| format!("invalid time range: {:?}", s))?;
+    let a = NaiveTime::parse_from_str(a, "%H:%M").with_context(|| format!("invalid time: {:?}", a))?;
+    let b = NaiveTime::parse_from_str(b, "%H:%M").with_context(|| format!("invalid time: {:?}", b))?;
+    Ok((a, b))
+}
+
+impl std::str::FromStr for Window {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut window = Self {
+            days: [true; 7],
+            time: None,
+        };
+        for part in s.split_whitespace() {
+            if part.contains(':') {
+                window.time = parse_time_range(part)?.into();
+            } else {
+                window.days = parse_days(&part.to_lowercase())?;
+            }
+        }
+        Ok(window)
+    }
+}
+
+impl Window {
+    fn contains(&self, t: &DateTime<Local>) -> bool {
+        let allowed = |d: Weekday| self.days[d.num_days_from_monday() as usize];
+        match self.time {
+            None => allowed(t.weekday()),
+            Some((a, b)) if a <= b => allowed(t.weekday()) && a <= t.time() && t.time() < b,
+            // the part after midnight belongs to the window started the
+            // day before
+            Some((a, b)) => (allowed(t.weekday()) && t.time() >= a) || (allowed(t.weekday().pred()) && t.time() < b),
+        }
+    }
+}
+
+/// Execution windows outside which queued jobs wait, separated by comma,
+/// e.g. "20:00-08:00, weekends".
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ExecWindows {
+    spec: String,
+    windows: Vec<Window>,
+}
+
+impl std::str::FromStr for ExecWindows {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let windows = s
+            .split(',')
+            .map(|w| w.trim().parse())
+            .collect::<Result<Vec<Window>>>()?;
+        ensure!(!windows.is_empty(), "no execution window");
+        Ok(Self {
+            spec: s.to_owned(),
+            windows,
+        })
+    }
+}
+
+impl TryFrom<String> for ExecWindows {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<ExecWindows> for String {
+    fn from(w: ExecWindows) -> Self {
+        w.spec
+    }
+}
+
+impl ExecWindows {
+    /// Return true if jobs are allowed to run at time `t`.
+    pub fn is_open_at(&self, t: &DateTime<Local>) -> bool {
+        self.windows.iter().any(|w| w.contains(t))
+    }
+
+    /// Return true if jobs are allowed to run now.
+    pub fn is_open(&self) -> bool {
+        self.is_open_at(&Local::now())
+    }
+
+    /// Wait until the execution windows open.
+    pub async fn wait_open(&self) {
+        let mut logged = false;
+        while !self.is_open() {
+            if !logged {
+                info!("waiting for execution window: {}", self.spec);
+                logged = true;
+            }
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+}
+
+/// How often execution windows are checked.
+pub const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+// d40c7e95 ends here
+
+// [[file:../runners.note::f7a21c58][f7a21c58]]
+#[test]
+fn test_exec_windows() -> Result<()> {
+    use chrono::{NaiveDate, TimeZone};
+
+    let w: ExecWindows = "20:00-08:00, weekends".parse()?;
+    // 2024-01-05 is a Friday
+    let at = |d, h| {
+        let t = NaiveDate::from_ymd_opt(2024, 1, d)
+            .unwrap()
+            .and_hms_opt(h, 0, 0)
+            .unwrap();
+        Local.from_local_datetime(&t).unwrap()
+    };
+    assert!(w.is_open_at(&at(5, 21)));
+    assert!(w.is_open_at(&at(5, 7)));
+    assert!(!w.is_open_at(&at(5, 12)));
+    assert!(w.is_open_at(&at(6, 12)));
+
+    // overnight windows started on weekdays only
+    let w: ExecWindows = "20:00-08:00 weekdays".parse()?;
+    assert!(w.is_open_at(&at(6, 7)));
+    assert!(!w.is_open_at(&at(6, 21)));
+    assert!(!w.is_open_at(&at(8, 7)));
+    assert!(w.is_open_at(&at(8, 21)));
+
+    let w: ExecWindows = "mon-wed 12:00-13:00".parse()?;
+    assert!(!w.is_open_at(&at(5, 12)));
+    assert!(w.is_open_at(&at(3, 12)));
+    assert!("25:00-08:00".parse::<ExecWindows>().is_err());
+    assert!("someday".parse::<ExecWindows>().is_err());
+    Ok(())
+}
+// f7a21c58 ends here
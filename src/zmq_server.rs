@@ -0,0 +1,164 @@
+// [[file:../runners.note::5e2c8a47][5e2c8a47]]
+//! ZeroMQ REQ/REP transport for job control
+use super::*;
+
+use crate::auth::{User, Users};
+use crate::interactive::InteractiveSession;
+use crate::job::Db;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+// 5e2c8a47 ends here
+
+// [[file:../runners.note::b91d7f3c][b91d7f3c]]
+/// Interactive sessions driven by remote clients, e.g. i-PI style drivers.
+#[derive(Default)]
+struct Interactions {
+    sessions: HashMap<usize, Arc<Mutex<InteractiveSession>>>,
+    next_id: usize,
+}
+
+impl Interactions {
+    /// Handle `interact_start`, `interact` and `interact_stop` methods.
+    /// `interactions` is only locked briefly, so that sessions are driven
+    /// concurrently.
+    fn call(interactions: &Mutex<Self>, method: &str, params: &Value) -> Result<Value> {
+        let result = match method {
+            "interact_start" => {
+                let cmdline: Vec<String> = serde_json::from_value(params["cmdline"].clone())?;
+                ensure!(!cmdline.is_empty(), "empty cmdline");
+                let mut cmd = std::process::Command::new(&cmdline[0]);
+                cmd.args(&cmdline[1..]);
+                let mut session = InteractiveSession::new(cmd);
+                session.spawn()?;
+                let mut interactions = interactions.lock().unwrap();
+                interactions.next_id += 1;
+                let id = interactions.next_id;
+                interactions.sessions.insert(id, Arc::new(Mutex::new(session)));
+                json!(id)
+            }
+            "interact" => {
+                let session = interactions.lock().unwrap().session(params)?;
+                let input = params["input"].as_str().unwrap_or_default();
+                let pattern = params["pattern"].as_str().context("no read pattern")?;
+                let mut session = session.lock().unwrap();
+                json!(session.interact(input, pattern)?)
+            }
+            "interact_stop" => {
+                let id = params["id"].as_u64().context("no session id")? as usize;
+                // dropping session will terminate child processes
+                let session = interactions.lock().unwrap().sessions.remove(&id);
+                ensure!(session.is_some(), "interactive session not found: {}", id);
+                Value::Null
+            }
+            _ => bail!("unknown method: {}", method),
+        };
+        Ok(result)
+    }
+
+    fn session(&self, params: &Value) -> Result<Arc<Mutex<InteractiveSession>>> {
+        let id = params["id"].as_u64().context("no session id")? as usize;
+        ensure!(self.sessions.contains_key(&id), "interactive session not found: {}", id);
+        Ok(self.sessions[&id].clone())
+    }
+}
+
+/// The time in milliseconds to wait for requests before sending back
+/// finished responses.
+const POLL_INTERVAL: i64 = 10;
+
+/// Return true if `endpoint` is only reachable from this host.
+fn is_local_endpoint(endpoint: &str) -> bool {
+    if endpoint.starts_with("ipc://") || endpoint.starts_with("inproc://") {
+        return true;
+    }
+    match endpoint.strip_prefix("tcp://").and_then(|s| s.rsplit_once(':')) {
+        Some((host, _)) => ["127.0.0.1", "localhost", "[::1]"].contains(&host),
+        None => false,
+    }
+}
+
+/// Handle request `line` from a client, returning the response.
+async fn handle(db: Db, users: Option<Arc<Users>>, interactions: Arc<Mutex<Interactions>>, line: String) -> Value {
+    let req: Value = serde_json::from_str(&line).unwrap_or_default();
+    let error = |msg: String| json!({"jsonrpc": "2.0", "id": req["id"], "error": {"code": -32000, "message": msg}});
+    // each request carries the token as no connection is seen
+    let user = match users {
+        Some(users) => match req["token"].as_str().and_then(|t| users.authenticate(t)) {
+            Some(user) => user,
+            None => return error("invalid token".into()),
+        },
+        None => User::new("local"),
+    };
+    let method = req["method"].as_str().unwrap_or_default().to_owned();
+    if !method.starts_with("interact") {
+        return crate::jsonrpc::handle_as(db, &user, &line).await;
+    }
+    // interactive sessions run arbitrary commands as the server user
+    if !user.admin {
+        return error(format!("user {} is not allowed to call {}", user.name, method));
+    }
+    let params = req["params"].clone();
+    let r = tokio::task::spawn_blocking(move || Interactions::call(&interactions, &method, &params)).await;
+    match r.context("interactive session task").and_then(|r| r) {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": req["id"], "result": result}),
+        Err(e) => error(format!("{:?}", e)),
+    }
+}
+
+/// Serve job operations on ZeroMQ socket bound to `endpoint`, e.g.
+/// "tcp://*:5555", for REQ clients. Messages are JSON-RPC requests as in
+/// the stdio mode, with additional `interact_start`, `interact` and
+/// `interact_stop` methods for driving interactive programs, which are
+/// only allowed for admins.
+///
+/// With `users`, each request is authenticated by its `token` field.
+/// Without, clients are normal users, and `endpoint` must be local.
+/// Requests are handled concurrently on runtime `rt`.
+pub fn serve(db: Db, endpoint: &str, users: Option<Users>, rt: &tokio::runtime::Runtime) -> Result<()> {
+    ensure!(
+        users.is_some() || is_local_endpoint(endpoint),
+        "refuse to serve on {} without authentication",
+        endpoint
+    );
+    let ctx = ::zmq::Context::new();
+    // a ROUTER socket for replying out of order
+    let socket = ctx.socket(::zmq::ROUTER)?;
+    socket.bind(endpoint)?;
+    info!("zmq server listening on {}", endpoint);
+
+    let users = users.map(Arc::new);
+    let interactions = Arc::new(Mutex::new(Interactions::default()));
+    let (tx, rx) = std::sync::mpsc::channel::<(Vec<Vec<u8>>, Value)>();
+    loop {
+        while let Ok((mut frames, resp)) = rx.try_recv() {
+            frames.push(resp.to_string().into_bytes());
+            socket.send_multipart(frames, 0)?;
+        }
+        if socket.poll(::zmq::POLLIN, POLL_INTERVAL)? == 0 {
+            continue;
+        }
+        // routing envelope followed by the request
+        let mut frames = socket.recv_multipart(0)?;
+        let line = match frames.pop() {
+            Some(msg) => String::from_utf8_lossy(&msg).into_owned(),
+            None => continue,
+        };
+        let tx = tx.clone();
+        let task = handle(db.clone(), users.clone(), interactions.clone(), line);
+        rt.spawn(async move {
+            let _ = tx.send((frames, task.await));
+        });
+    }
+}
+// b91d7f3c ends here
+
+// [[file:../runners.note::7c0e4f19][7c0e4f19]]
+#[test]
+fn test_local_endpoint() {
+    assert!(is_local_endpoint("ipc:///tmp/gosh.sock"));
+    assert!(is_local_endpoint("tcp://127.0.0.1:5555"));
+    assert!(!is_local_endpoint("tcp://*:5555"));
+    assert!(!is_local_endpoint("tcp://0.0.0.0:5555"));
+}
+// 7c0e4f19 ends here